@@ -0,0 +1,1104 @@
+//! An assembler for Cowgod's mnemonics, the syntax [`crate::disasm`] writes.
+//!
+//! Each line holds an optional `label:` followed by an instruction, a directive or a
+//! constant, and anything after a `;` is a comment:
+//!
+//! ```text
+//! SPEED   EQU 2               ; a constant
+//! INCLUDE "sprites.c8asm"     ; assembles another file in place
+//!
+//! main:
+//!     LD I, ball
+//!     LD V0, SPEED + 1
+//!     DRW V0, V0, 4
+//!     JP main
+//!
+//! ball:
+//!     DB 0x60, 0xF0, 0xF0, 0x60
+//!     DW 0x1234, main
+//! ```
+//!
+//! Mnemonics, registers and directives are not case sensitive, labels and constants are.
+//! Numbers may be decimal, `0x` or `#` hexadecimal, or `0b` binary, and values can be added
+//! and subtracted. Labels and constants can be used before they are defined.
+//!
+//! Octo source is compiled by [`octo`] into the same [`Assembly`].
+
+pub mod octo;
+
+use std::{
+    cell::RefCell,
+    collections::{BTreeMap, HashMap},
+    error::Error,
+    fmt, fs, io,
+    path::{Path, PathBuf},
+};
+
+use crate::{instructions::Instruction, memory::PROGRAM_START_OFFSET};
+
+// How deep files can include each other, which also stops a file including itself forever
+const MAX_INCLUDE_DEPTH: usize = 16;
+
+// How deep constants can be defined in terms of each other, which also catches cycles
+const MAX_CONSTANT_DEPTH: usize = 64;
+
+// The end of the XO-CHIP address space
+const MAX_ADDRESS: i64 = 0x10000;
+
+const MNEMONICS: [&str; 31] = [
+    "SYS", "CLS", "RET", "SCU", "SCD", "SCR", "SCL", "EXIT", "LOW", "HIGH", "JP", "CALL", "SE",
+    "SNE", "LD", "ADD", "OR", "AND", "XOR", "SUB", "SHR", "SUBN", "SHL", "RND", "DRW", "SKP",
+    "SKNP", "PLANE", "DB", "DW", "INCLUDE",
+];
+
+/// Errors raised while assembling a program.
+#[derive(Debug)]
+pub enum AsmError {
+    /// The file being assembled could not be read.
+    Io { path: PathBuf, error: io::Error },
+    /// The source is not valid. `line` and `column` start at 1.
+    Source {
+        /// The file the error is in, or `None` for source passed in as a string.
+        file: Option<PathBuf>,
+        line: usize,
+        column: usize,
+        message: String,
+    },
+}
+
+impl fmt::Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AsmError::Io { path, error } => {
+                write!(f, "unable to read {}: {}", path.display(), error)
+            }
+            AsmError::Source {
+                file: Some(file),
+                line,
+                column,
+                message,
+            } => write!(f, "{}:{}:{}: {}", file.display(), line, column, message),
+            AsmError::Source {
+                file: None,
+                line,
+                column,
+                message,
+            } => write!(f, "line {}, column {}: {}", line, column, message),
+        }
+    }
+}
+
+impl Error for AsmError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            AsmError::Io { error, .. } => Some(error),
+            _ => None,
+        }
+    }
+}
+
+/// Where a line of source came from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Location {
+    /// The file the line is in, or `None` for source passed in as a string.
+    pub file: Option<PathBuf>,
+    /// The line number, starting at 1.
+    pub line: usize,
+}
+
+/// An assembled program.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Assembly {
+    origin: u16,
+    bytes: Vec<u8>,
+    source_map: BTreeMap<u16, Location>,
+}
+
+impl Assembly {
+    /// The address the program was assembled to be loaded at.
+    pub fn origin(&self) -> u16 {
+        self.origin
+    }
+
+    pub fn bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.bytes
+    }
+
+    /// The line each instruction was assembled from, keyed by the instruction's address.
+    pub fn source_map(&self) -> &BTreeMap<u16, Location> {
+        &self.source_map
+    }
+}
+
+/// Assembles programs.
+///
+/// ```
+/// use chip8_rs::asm::Assembler;
+///
+/// let program = Assembler::new().assemble("loop: JP loop").unwrap();
+/// assert_eq!(program.bytes(), &[0x12, 0x00]);
+/// ```
+#[derive(Debug, Clone, Copy)]
+pub struct Assembler {
+    origin: u16,
+}
+
+impl Default for Assembler {
+    fn default() -> Self {
+        Self {
+            origin: PROGRAM_START_OFFSET as u16,
+        }
+    }
+}
+
+impl Assembler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the address the program will be loaded at, which labels are relative to.
+    /// Defaults to `0x200`.
+    pub fn origin(mut self, origin: u16) -> Self {
+        self.origin = origin;
+        self
+    }
+
+    /// Assembles source held in memory. Included files are relative to the working directory.
+    pub fn assemble(&self, source: &str) -> Result<Assembly, AsmError> {
+        let mut lines = vec![];
+        read_lines(source, None, 0, &mut lines)?;
+        self.assemble_lines(&lines)
+    }
+
+    /// Assembles a file. Included files are relative to the file that includes them.
+    pub fn assemble_file(&self, path: impl AsRef<Path>) -> Result<Assembly, AsmError> {
+        let path = path.as_ref();
+        let source = fs::read_to_string(path).map_err(|error| AsmError::Io {
+            path: path.to_path_buf(),
+            error,
+        })?;
+
+        let mut lines = vec![];
+        read_lines(&source, Some(path), 0, &mut lines)?;
+        self.assemble_lines(&lines)
+    }
+
+    fn assemble_lines(&self, lines: &[SourceLine]) -> Result<Assembly, AsmError> {
+        // The first pass finds the address of every label, the second encodes the program
+        let mut symbols = Symbols::default();
+        let mut statements = vec![];
+        let mut address = self.origin as i64;
+
+        for line in lines {
+            let mut tokens = &line.tokens[..];
+            if let [Token {
+                kind: TokenKind::Name(name),
+                column,
+            }, Token {
+                kind: TokenKind::Colon,
+                ..
+            }, rest @ ..] = tokens
+            {
+                symbols.define(line, name, *column, Symbol::Label(address))?;
+                tokens = rest;
+            }
+
+            let (name, column) = match tokens {
+                [] => continue,
+                [Token {
+                    kind: TokenKind::Name(name),
+                    column,
+                }, ..] => (name, *column),
+                [token, ..] => {
+                    return Err(line.error(
+                        token.column,
+                        format!("expected an instruction, found `{}`", token.kind),
+                    ))
+                }
+            };
+
+            if let Some(Token {
+                kind: TokenKind::Name(equ),
+                column: equ_column,
+            }) = tokens.get(1)
+            {
+                if equ.eq_ignore_ascii_case("EQU") {
+                    if tokens.len() == 2 {
+                        return Err(line.error(*equ_column, "expected a value after EQU"));
+                    }
+                    symbols.define(line, name, column, Symbol::Constant(line, &tokens[2..]))?;
+                    continue;
+                }
+            }
+
+            let mnemonic = name.to_ascii_uppercase();
+            let operands = split_operands(line, &tokens[1..])?;
+            let size = match mnemonic.as_str() {
+                "DB" | "DW" if operands.is_empty() => {
+                    return Err(line.error(column, format!("expected a value after {}", mnemonic)))
+                }
+                "DB" => operands
+                    .iter()
+                    .map(|operand| match operand {
+                        [Token {
+                            kind: TokenKind::Str(text),
+                            ..
+                        }] => text.len(),
+                        _ => 1,
+                    })
+                    .sum(),
+                "DW" => operands.len() * 2,
+                "LD" if is_long_load(&operands) => 4,
+                _ => 2,
+            } as i64;
+
+            if address + size > MAX_ADDRESS {
+                return Err(line.error(column, "the program does not fit in memory"));
+            }
+
+            statements.push(Statement {
+                line,
+                address: address as u16,
+                mnemonic,
+                column,
+                operands,
+            });
+            address += size;
+        }
+
+        let mut bytes = vec![];
+        let mut source_map = BTreeMap::new();
+        for statement in statements.iter() {
+            let line = statement.line;
+            match statement.mnemonic.as_str() {
+                "DB" => {
+                    for operand in statement.operands.iter() {
+                        match operand {
+                            [Token {
+                                kind: TokenKind::Str(text),
+                                ..
+                            }] => bytes.extend_from_slice(text.as_bytes()),
+                            _ => {
+                                let value = symbols.evaluate(line, operand, 0)?;
+                                bytes.push(value.byte(line)?);
+                            }
+                        }
+                    }
+                }
+                "DW" => {
+                    for operand in statement.operands.iter() {
+                        let value = symbols.evaluate(line, operand, 0)?;
+                        bytes.extend_from_slice(&value.word(line)?.to_be_bytes());
+                    }
+                }
+                _ => {
+                    let operands = statement
+                        .operands
+                        .iter()
+                        .map(|operand| symbols.operand(line, operand))
+                        .collect::<Result<Vec<_>, _>>()?;
+                    let instruction = encode(statement, &operands)?;
+                    bytes.extend(instruction.to_bytes());
+                    source_map.insert(
+                        statement.address,
+                        Location {
+                            file: line.file.clone(),
+                            line: line.number,
+                        },
+                    );
+                }
+            }
+        }
+
+        Ok(Assembly {
+            origin: self.origin,
+            bytes,
+            source_map,
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum TokenKind {
+    Name(String),
+    Number(i64),
+    Str(String),
+    Comma,
+    Colon,
+    Plus,
+    Minus,
+    OpenBracket,
+    CloseBracket,
+}
+
+impl fmt::Display for TokenKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TokenKind::Name(name) => write!(f, "{}", name),
+            TokenKind::Number(value) => write!(f, "{}", value),
+            TokenKind::Str(text) => write!(f, "\"{}\"", text),
+            TokenKind::Comma => write!(f, ","),
+            TokenKind::Colon => write!(f, ":"),
+            TokenKind::Plus => write!(f, "+"),
+            TokenKind::Minus => write!(f, "-"),
+            TokenKind::OpenBracket => write!(f, "["),
+            TokenKind::CloseBracket => write!(f, "]"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Token {
+    kind: TokenKind,
+    column: usize,
+}
+
+struct SourceLine {
+    file: Option<PathBuf>,
+    number: usize,
+    tokens: Vec<Token>,
+    // The column just past the end of the line, for errors about something missing
+    end: usize,
+}
+
+impl SourceLine {
+    fn error(&self, column: usize, message: impl Into<String>) -> AsmError {
+        AsmError::Source {
+            file: self.file.clone(),
+            line: self.number,
+            column,
+            message: message.into(),
+        }
+    }
+}
+
+/// Splits source into tokens, replacing `INCLUDE` lines with the lines of the file.
+fn read_lines(
+    source: &str,
+    file: Option<&Path>,
+    depth: usize,
+    lines: &mut Vec<SourceLine>,
+) -> Result<(), AsmError> {
+    for (i, text) in source.lines().enumerate() {
+        let mut line = SourceLine {
+            file: file.map(Path::to_path_buf),
+            number: i + 1,
+            tokens: vec![],
+            end: text.chars().count() + 1,
+        };
+        line.tokens = tokenize(text).map_err(|(column, message)| line.error(column, message))?;
+
+        match &line.tokens[..] {
+            [Token {
+                kind: TokenKind::Name(directive),
+                column,
+            }, rest @ ..]
+                if directive.eq_ignore_ascii_case("INCLUDE") =>
+            {
+                let (name, name_column) = match rest {
+                    [Token {
+                        kind: TokenKind::Str(name),
+                        column,
+                    }] => (name, *column),
+                    _ => {
+                        return Err(
+                            line.error(*column, "expected a file name in quotes after INCLUDE")
+                        )
+                    }
+                };
+
+                if depth >= MAX_INCLUDE_DEPTH {
+                    return Err(line.error(name_column, "files are included too deeply"));
+                }
+
+                let path = match file.and_then(Path::parent) {
+                    Some(directory) => directory.join(name),
+                    None => PathBuf::from(name),
+                };
+                let source = fs::read_to_string(&path).map_err(|e| {
+                    line.error(
+                        name_column,
+                        format!("unable to read {}: {}", path.display(), e),
+                    )
+                })?;
+                read_lines(&source, Some(&path), depth + 1, lines)?;
+            }
+            _ => lines.push(line),
+        }
+    }
+
+    Ok(())
+}
+
+fn tokenize(text: &str) -> Result<Vec<Token>, (usize, String)> {
+    let chars: Vec<char> = text.chars().collect();
+    let mut tokens = vec![];
+    let mut i = 0;
+
+    while i < chars.len() {
+        let column = i + 1;
+        let single = match chars[i] {
+            ';' => break,
+            c if c.is_whitespace() => {
+                i += 1;
+                continue;
+            }
+            ',' => Some(TokenKind::Comma),
+            ':' => Some(TokenKind::Colon),
+            '+' => Some(TokenKind::Plus),
+            '-' => Some(TokenKind::Minus),
+            '[' => Some(TokenKind::OpenBracket),
+            ']' => Some(TokenKind::CloseBracket),
+            _ => None,
+        };
+        if let Some(kind) = single {
+            tokens.push(Token { kind, column });
+            i += 1;
+            continue;
+        }
+
+        let start = i;
+        let kind = match chars[i] {
+            '"' => {
+                let end = chars[start + 1..]
+                    .iter()
+                    .position(|c| *c == '"')
+                    .ok_or((column, "unterminated string".to_string()))?;
+                i = start + end + 2;
+                TokenKind::Str(chars[start + 1..start + 1 + end].iter().collect())
+            }
+            c if c.is_ascii_digit() || c == '#' => {
+                i += 1;
+                while i < chars.len() && (chars[i].is_ascii_alphanumeric() || chars[i] == '_') {
+                    i += 1;
+                }
+                let text: String = chars[start..i].iter().collect();
+                let value = parse_number(&text)
+                    .ok_or_else(|| (column, format!("invalid number `{}`", text)))?;
+                TokenKind::Number(value)
+            }
+            c if c.is_alphabetic() || c == '_' || c == '.' => {
+                while i < chars.len()
+                    && (chars[i].is_alphanumeric() || chars[i] == '_' || chars[i] == '.')
+                {
+                    i += 1;
+                }
+                TokenKind::Name(chars[start..i].iter().collect())
+            }
+            c => return Err((column, format!("unexpected character `{}`", c))),
+        };
+        tokens.push(Token { kind, column });
+    }
+
+    Ok(tokens)
+}
+
+/// Parses a decimal, `0x` or `#` prefixed hexadecimal, or `0b` prefixed binary number.
+fn parse_number(text: &str) -> Option<i64> {
+    let text = text.replace('_', "");
+    let lower = text.to_ascii_lowercase();
+    if let Some(hex) = lower.strip_prefix("0x").or_else(|| lower.strip_prefix('#')) {
+        i64::from_str_radix(hex, 16).ok()
+    } else if let Some(binary) = lower.strip_prefix("0b") {
+        i64::from_str_radix(binary, 2).ok()
+    } else {
+        lower.parse().ok()
+    }
+}
+
+/// Splits the tokens after a mnemonic into its comma separated operands.
+fn split_operands<'a>(
+    line: &SourceLine,
+    tokens: &'a [Token],
+) -> Result<Vec<&'a [Token]>, AsmError> {
+    if tokens.is_empty() {
+        return Ok(vec![]);
+    }
+
+    let mut operands = vec![];
+    let mut start = 0;
+    for (i, token) in tokens.iter().enumerate() {
+        if token.kind == TokenKind::Comma {
+            if i == start {
+                return Err(line.error(token.column, "expected an operand before `,`"));
+            }
+            operands.push(&tokens[start..i]);
+            start = i + 1;
+        }
+    }
+
+    if start == tokens.len() {
+        return Err(line.error(line.end, "expected an operand after `,`"));
+    }
+    operands.push(&tokens[start..]);
+
+    Ok(operands)
+}
+
+fn is_long_load(operands: &[&[Token]]) -> bool {
+    match operands {
+        [_, [Token {
+            kind: TokenKind::Name(long),
+            ..
+        }, ..]] => long.eq_ignore_ascii_case("LONG"),
+        _ => false,
+    }
+}
+
+/// A `V` register, e.g. `V3` or `vA`.
+fn register(name: &str) -> Option<u8> {
+    let mut chars = name.chars();
+    match (chars.next(), chars.next(), chars.next()) {
+        (Some('V'), Some(digit), None) | (Some('v'), Some(digit), None) => {
+            digit.to_digit(16).map(|x| x as u8)
+        }
+        _ => None,
+    }
+}
+
+fn keyword(name: &str) -> Option<Operand> {
+    match name.to_ascii_uppercase().as_str() {
+        "I" => Some(Operand::I),
+        "DT" => Some(Operand::Dt),
+        "ST" => Some(Operand::St),
+        "K" => Some(Operand::K),
+        "F" => Some(Operand::F),
+        "HF" => Some(Operand::Hf),
+        "B" => Some(Operand::B),
+        "R" => Some(Operand::R),
+        "AUDIO" => Some(Operand::Audio),
+        "PITCH" => Some(Operand::Pitch),
+        _ => None,
+    }
+}
+
+fn is_reserved(name: &str) -> bool {
+    let upper = name.to_ascii_uppercase();
+    register(name).is_some()
+        || keyword(name).is_some()
+        || upper == "LONG"
+        || upper == "EQU"
+        || MNEMONICS.contains(&upper.as_str())
+}
+
+/// A number in the source, with the column it starts at for reporting errors.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Value {
+    value: i64,
+    column: usize,
+}
+
+impl Value {
+    fn check(self, line: &SourceLine, min: i64, max: i64, what: &str) -> Result<i64, AsmError> {
+        if (min..=max).contains(&self.value) {
+            Ok(self.value)
+        } else {
+            Err(line.error(
+                self.column,
+                format!(
+                    "{} does not fit in {} ({} to {})",
+                    self.value, what, min, max
+                ),
+            ))
+        }
+    }
+
+    fn nibble(self, line: &SourceLine) -> Result<u8, AsmError> {
+        Ok(self.check(line, 0, 0xF, "a nibble")? as u8)
+    }
+
+    // Negative bytes and words are stored as two's complement
+    fn byte(self, line: &SourceLine) -> Result<u8, AsmError> {
+        Ok(self.check(line, -0x80, 0xFF, "a byte")? as u8)
+    }
+
+    fn word(self, line: &SourceLine) -> Result<u16, AsmError> {
+        Ok(self.check(line, -0x8000, 0xFFFF, "a word")? as u16)
+    }
+
+    fn address(self, line: &SourceLine) -> Result<u16, AsmError> {
+        Ok(self.check(line, 0, 0xFFF, "a 12-bit address")? as u16)
+    }
+
+    fn long_address(self, line: &SourceLine) -> Result<u16, AsmError> {
+        Ok(self.check(line, 0, 0xFFFF, "a 16-bit address")? as u16)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Operand {
+    V(u8),
+    /// `Vx-Vy`
+    Range(u8, u8),
+    I,
+    /// `[I]`
+    IndirectI,
+    Dt,
+    St,
+    K,
+    F,
+    Hf,
+    B,
+    R,
+    Audio,
+    Pitch,
+    /// `LONG nnnn`
+    Long(Value),
+    Value(Value),
+}
+
+enum Symbol<'a> {
+    Label(i64),
+    Constant(&'a SourceLine, &'a [Token]),
+}
+
+#[derive(Default)]
+struct Symbols<'a> {
+    symbols: HashMap<String, Symbol<'a>>,
+    // The values of the constants evaluated so far, so each is only worked out once
+    constants: RefCell<HashMap<String, i64>>,
+}
+
+impl<'a> Symbols<'a> {
+    fn define(
+        &mut self,
+        line: &SourceLine,
+        name: &str,
+        column: usize,
+        symbol: Symbol<'a>,
+    ) -> Result<(), AsmError> {
+        if is_reserved(name) {
+            return Err(line.error(column, format!("`{}` is a reserved word", name)));
+        }
+        if self.symbols.contains_key(name) {
+            return Err(line.error(column, format!("`{}` is already defined", name)));
+        }
+
+        self.symbols.insert(name.to_string(), symbol);
+        Ok(())
+    }
+
+    /// Evaluates a sum of numbers, labels and constants, e.g. `sprites + 5 - OFFSET`.
+    fn evaluate(
+        &self,
+        line: &SourceLine,
+        tokens: &[Token],
+        depth: usize,
+    ) -> Result<Value, AsmError> {
+        let add = |token: &Token, total: i64, value: i64, negative: bool| {
+            let value = if negative {
+                value.checked_neg()
+            } else {
+                Some(value)
+            };
+            value
+                .and_then(|value| total.checked_add(value))
+                .ok_or_else(|| line.error(token.column, "the value is too large"))
+        };
+
+        let mut total = 0i64;
+        let mut negative = false;
+        let mut expect_value = true;
+
+        for token in tokens {
+            match (&token.kind, expect_value) {
+                (TokenKind::Plus, true) => {}
+                (TokenKind::Minus, true) => negative = !negative,
+                (TokenKind::Number(value), true) => {
+                    total = add(token, total, *value, negative)?;
+                    expect_value = false;
+                }
+                (TokenKind::Name(name), true) => {
+                    let value = self.value(line, name, token.column, depth)?;
+                    total = add(token, total, value, negative)?;
+                    expect_value = false;
+                }
+                (TokenKind::Plus, false) => {
+                    negative = false;
+                    expect_value = true;
+                }
+                (TokenKind::Minus, false) => {
+                    negative = true;
+                    expect_value = true;
+                }
+                (kind, _) => {
+                    return Err(line.error(token.column, format!("unexpected `{}`", kind)));
+                }
+            }
+        }
+
+        if expect_value {
+            return Err(line.error(line.end, "expected a value"));
+        }
+
+        Ok(Value {
+            value: total,
+            column: tokens[0].column,
+        })
+    }
+
+    fn value(
+        &self,
+        line: &SourceLine,
+        name: &str,
+        column: usize,
+        depth: usize,
+    ) -> Result<i64, AsmError> {
+        match self.symbols.get(name) {
+            Some(Symbol::Label(address)) => Ok(*address),
+            Some(Symbol::Constant(definition, tokens)) => {
+                if let Some(value) = self.constants.borrow().get(name) {
+                    return Ok(*value);
+                }
+                if depth >= MAX_CONSTANT_DEPTH {
+                    return Err(
+                        line.error(column, format!("`{}` is defined in terms of itself", name))
+                    );
+                }
+
+                let value = self.evaluate(definition, tokens, depth + 1)?.value;
+                self.constants.borrow_mut().insert(name.to_string(), value);
+                Ok(value)
+            }
+            None => Err(line.error(column, format!("unknown label or constant `{}`", name))),
+        }
+    }
+
+    fn operand(&self, line: &SourceLine, tokens: &[Token]) -> Result<Operand, AsmError> {
+        let name = |token: &Token| match &token.kind {
+            TokenKind::Name(name) => Some(name.clone()),
+            _ => None,
+        };
+
+        match tokens {
+            [token] => {
+                if let Some(name) = name(token) {
+                    if let Some(x) = register(&name) {
+                        return Ok(Operand::V(x));
+                    }
+                    if let Some(keyword) = keyword(&name) {
+                        return Ok(keyword);
+                    }
+                }
+            }
+            [first, Token {
+                kind: TokenKind::Minus,
+                ..
+            }, last] => {
+                if let (Some(x), Some(y)) = (
+                    name(first).and_then(|name| register(&name)),
+                    name(last).and_then(|name| register(&name)),
+                ) {
+                    return Ok(Operand::Range(x, y));
+                }
+            }
+            [Token {
+                kind: TokenKind::OpenBracket,
+                ..
+            }, i, Token {
+                kind: TokenKind::CloseBracket,
+                ..
+            }] if name(i).is_some_and(|name| name.eq_ignore_ascii_case("I")) => {
+                return Ok(Operand::IndirectI);
+            }
+            [first, rest @ ..]
+                if name(first).is_some_and(|name| name.eq_ignore_ascii_case("LONG")) =>
+            {
+                if rest.is_empty() {
+                    return Err(line.error(line.end, "expected a value after LONG"));
+                }
+                return Ok(Operand::Long(self.evaluate(line, rest, 0)?));
+            }
+            _ => {}
+        }
+
+        Ok(Operand::Value(self.evaluate(line, tokens, 0)?))
+    }
+}
+
+struct Statement<'a> {
+    line: &'a SourceLine,
+    address: u16,
+    /// In upper case.
+    mnemonic: String,
+    column: usize,
+    operands: Vec<&'a [Token]>,
+}
+
+fn encode(statement: &Statement, operands: &[Operand]) -> Result<Instruction, AsmError> {
+    use Operand::*;
+
+    let line = statement.line;
+    let instruction = match (statement.mnemonic.as_str(), operands) {
+        ("CLS", []) => Instruction::Cls,
+        ("RET", []) => Instruction::Ret,
+        ("SCR", []) => Instruction::ScrollRight,
+        ("SCL", []) => Instruction::ScrollLeft,
+        ("EXIT", []) => Instruction::Exit,
+        ("LOW", []) => Instruction::LoRes,
+        ("HIGH", []) => Instruction::HiRes,
+        ("SCU", [Value(n)]) => Instruction::ScrollUp { n: n.nibble(line)? },
+        ("SCD", [Value(n)]) => Instruction::ScrollDown { n: n.nibble(line)? },
+        ("SYS", [Value(nnn)]) => Instruction::Sys {
+            nnn: nnn.address(line)?,
+        },
+        ("JP", [Value(nnn)]) => Instruction::Jp {
+            nnn: nnn.address(line)?,
+        },
+        ("JP", [V(0), Value(nnn)]) => Instruction::JpV0 {
+            nnn: nnn.address(line)?,
+        },
+        ("CALL", [Value(nnn)]) => Instruction::Call {
+            nnn: nnn.address(line)?,
+        },
+        ("SE", [V(x), V(y)]) => Instruction::SeXY { x: *x, y: *y },
+        ("SE", [V(x), Value(kk)]) => Instruction::SeByte {
+            x: *x,
+            kk: kk.byte(line)?,
+        },
+        ("SNE", [V(x), V(y)]) => Instruction::SneXY { x: *x, y: *y },
+        ("SNE", [V(x), Value(kk)]) => Instruction::SneByte {
+            x: *x,
+            kk: kk.byte(line)?,
+        },
+        ("LD", [V(x), V(y)]) => Instruction::LdXY { x: *x, y: *y },
+        ("LD", [V(x), Value(kk)]) => Instruction::LdByte {
+            x: *x,
+            kk: kk.byte(line)?,
+        },
+        ("LD", [I, Value(nnn)]) => Instruction::LdI {
+            nnn: nnn.address(line)?,
+        },
+        ("LD", [I, Long(nnnn)]) => Instruction::LdILong {
+            nnnn: nnnn.long_address(line)?,
+        },
+        ("LD", [V(x), Dt]) => Instruction::LdXDt { x: *x },
+        ("LD", [V(x), K]) => Instruction::LdXKey { x: *x },
+        ("LD", [Dt, V(x)]) => Instruction::LdDtX { x: *x },
+        ("LD", [St, V(x)]) => Instruction::LdStX { x: *x },
+        ("LD", [F, V(x)]) => Instruction::LdFont { x: *x },
+        ("LD", [Hf, V(x)]) => Instruction::LdHiFont { x: *x },
+        ("LD", [B, V(x)]) => Instruction::LdBcd { x: *x },
+        ("LD", [IndirectI, V(x)]) => Instruction::StoreRegs { x: *x },
+        ("LD", [V(x), IndirectI]) => Instruction::LoadRegs { x: *x },
+        ("LD", [IndirectI, Range(x, y)]) => Instruction::StoreRange { x: *x, y: *y },
+        ("LD", [Range(x, y), IndirectI]) => Instruction::LoadRange { x: *x, y: *y },
+        ("LD", [R, V(x)]) => Instruction::StoreFlags { x: *x },
+        ("LD", [V(x), R]) => Instruction::LoadFlags { x: *x },
+        ("LD", [Audio, IndirectI]) => Instruction::LdAudio,
+        ("LD", [Pitch, V(x)]) => Instruction::LdPitch { x: *x },
+        ("ADD", [V(x), V(y)]) => Instruction::AddXY { x: *x, y: *y },
+        ("ADD", [V(x), Value(kk)]) => Instruction::AddByte {
+            x: *x,
+            kk: kk.byte(line)?,
+        },
+        ("ADD", [I, V(x)]) => Instruction::AddIX { x: *x },
+        ("OR", [V(x), V(y)]) => Instruction::OrXY { x: *x, y: *y },
+        ("AND", [V(x), V(y)]) => Instruction::AndXY { x: *x, y: *y },
+        ("XOR", [V(x), V(y)]) => Instruction::XorXY { x: *x, y: *y },
+        ("SUB", [V(x), V(y)]) => Instruction::SubXY { x: *x, y: *y },
+        ("SUBN", [V(x), V(y)]) => Instruction::SubnXY { x: *x, y: *y },
+        // The shifts only use Vy on the original interpreter, so it can be left out
+        ("SHR", [V(x)]) => Instruction::ShrXY { x: *x, y: *x },
+        ("SHR", [V(x), V(y)]) => Instruction::ShrXY { x: *x, y: *y },
+        ("SHL", [V(x)]) => Instruction::ShlXY { x: *x, y: *x },
+        ("SHL", [V(x), V(y)]) => Instruction::ShlXY { x: *x, y: *y },
+        ("RND", [V(x), Value(kk)]) => Instruction::Rnd {
+            x: *x,
+            kk: kk.byte(line)?,
+        },
+        ("DRW", [V(x), V(y), Value(n)]) => Instruction::Draw {
+            x: *x,
+            y: *y,
+            n: n.nibble(line)?,
+        },
+        ("SKP", [V(x)]) => Instruction::Skp { x: *x },
+        ("SKNP", [V(x)]) => Instruction::Sknp { x: *x },
+        ("PLANE", [Value(n)]) => Instruction::Plane { n: n.nibble(line)? },
+        (mnemonic, _) if MNEMONICS.contains(&mnemonic) => {
+            return Err(line.error(
+                statement.column,
+                format!("invalid operands for {}", mnemonic),
+            ))
+        }
+        (mnemonic, _) => {
+            return Err(line.error(
+                statement.column,
+                format!("unknown instruction `{}`", mnemonic),
+            ))
+        }
+    };
+
+    Ok(instruction)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::{AsmError, Assembler};
+    use crate::{disasm::Disassembler, instructions::Instruction};
+
+    fn assemble(source: &str) -> Vec<u8> {
+        Assembler::new().assemble(source).unwrap().into_bytes()
+    }
+
+    fn error(source: &str) -> (usize, usize, String) {
+        match Assembler::new().assemble(source) {
+            Err(AsmError::Source {
+                line,
+                column,
+                message,
+                ..
+            }) => (line, column, message),
+            other => panic!("expected a source error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn should_assemble_every_instruction_the_decoder_formats() {
+        for raw in 0..=0xFFFF {
+            let instruction = Instruction::decode(raw);
+
+            assert_eq!(
+                assemble(&instruction.to_string()),
+                raw.to_be_bytes().to_vec(),
+                "{}",
+                instruction
+            );
+        }
+
+        assert_eq!(assemble("LD I, LONG 0x1234"), vec![0xF0, 0x00, 0x12, 0x34]);
+    }
+
+    #[test]
+    fn should_resolve_labels_and_constants() {
+        let source = "
+            SPEED EQU STEP + 1   ; defined before STEP
+            STEP  EQU 2
+            start:
+                LD V0, SPEED
+                JP end
+                LD I, data - 1
+            end: JP start
+            data: DB 0xFF
+        ";
+
+        assert_eq!(
+            assemble(source),
+            vec![0x60, 0x03, 0x12, 0x06, 0xA2, 0x07, 0x12, 0x00, 0xFF]
+        );
+    }
+
+    #[test]
+    fn should_assemble_data_directives() {
+        assert_eq!(
+            assemble("DB 1, 0b1010, #FF, -1, \"Hi\"\nDW 0x1234, here\nhere:"),
+            vec![0x01, 0x0A, 0xFF, 0xFF, b'H', b'i', 0x12, 0x34, 0x02, 0x0A]
+        );
+    }
+
+    #[test]
+    fn should_be_case_insensitive_for_mnemonics_and_registers() {
+        assert_eq!(assemble("ld va, vB\nshr v1"), vec![0x8A, 0xB0, 0x81, 0x16]);
+    }
+
+    #[test]
+    fn should_report_errors_with_line_and_column() {
+        assert_eq!(
+            error("CLS\n  FOO V0"),
+            (2, 3, "unknown instruction `FOO`".to_string())
+        );
+        assert_eq!(
+            error("LD V0, 0x100"),
+            (1, 8, "256 does not fit in a byte (-128 to 255)".to_string())
+        );
+        assert_eq!(
+            error("JP nowhere"),
+            (1, 4, "unknown label or constant `nowhere`".to_string())
+        );
+        assert_eq!(
+            error("a: CLS\na: RET"),
+            (2, 1, "`a` is already defined".to_string())
+        );
+        assert_eq!(
+            error("LD V0"),
+            (1, 1, "invalid operands for LD".to_string())
+        );
+        assert_eq!(
+            error("LD V0,"),
+            (1, 7, "expected an operand after `,`".to_string())
+        );
+        assert_eq!(
+            error("X EQU Y\nY EQU X\nJP X"),
+            (2, 7, "`X` is defined in terms of itself".to_string())
+        );
+        assert_eq!(
+            error("DB \"oops"),
+            (1, 4, "unterminated string".to_string())
+        );
+        assert_eq!(
+            error("BIG EQU 0x7FFFFFFFFFFFFFFF\nLD V0, BIG + 1"),
+            (2, 14, "the value is too large".to_string())
+        );
+    }
+
+    #[test]
+    fn should_evaluate_each_constant_once() {
+        // Re-expanding every use would take 2^50 steps
+        let mut source = "C0 EQU 1\n".to_string();
+        for n in 1..=50 {
+            source += &format!("C{} EQU C{} + C{}\n", n, n - 1, n - 1);
+        }
+        source += "LD V0, C50 - C50 + 5";
+
+        assert_eq!(assemble(&source), [0x60, 0x05]);
+    }
+
+    #[test]
+    fn should_include_files_relative_to_the_includer() {
+        let directory = std::env::temp_dir().join(format!("chip8rs_asm_{}", std::process::id()));
+        fs::create_dir_all(directory.join("lib")).unwrap();
+        fs::write(
+            directory.join("main.c8asm"),
+            "CALL draw\nINCLUDE \"lib/draw.c8asm\"\n",
+        )
+        .unwrap();
+        fs::write(directory.join("lib/draw.c8asm"), "draw:\n  CLS\n  RET\n").unwrap();
+
+        let program = Assembler::new()
+            .assemble_file(directory.join("main.c8asm"))
+            .unwrap();
+        fs::remove_dir_all(&directory).unwrap();
+
+        assert_eq!(program.bytes(), &[0x22, 0x02, 0x00, 0xE0, 0x00, 0xEE]);
+        let location = &program.source_map()[&0x204];
+        assert_eq!(location.file, Some(directory.join("lib/draw.c8asm")));
+        assert_eq!(location.line, 3);
+    }
+
+    #[test]
+    fn should_reassemble_disassembled_programs() {
+        // LD I, 0x20A; LD V0, 0x00; DRW V0, V0, 5; CALL 0x210; JP 0x208; a sprite and a RET
+        let program = [
+            0xA2, 0x0A, 0x60, 0x00, 0xD0, 0x05, 0x22, 0x10, 0x12, 0x08, 0xF0, 0x90, 0x90, 0x90,
+            0xF0, 0xFF, 0x00, 0xEE,
+        ];
+        let source = Disassembler::new().disassemble(&program);
+
+        assert_eq!(assemble(&source), program.to_vec());
+    }
+
+    #[test]
+    fn should_reassemble_instructions_that_are_jumped_into() {
+        // LD I, LONG 0x1202; JP 0x202, which lands on the second half of the load
+        let program = [0xF0, 0x00, 0x12, 0x02, 0x12, 0x02];
+        let source = Disassembler::new().disassemble(&program);
+
+        assert_eq!(assemble(&source), program.to_vec());
+    }
+}
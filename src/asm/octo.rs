@@ -0,0 +1,1151 @@
+//! A compiler for [Octo](https://github.com/JohnEarnest/Octo), the assembly language most
+//! modern CHIP-8 programs are written in.
+//!
+//! ```text
+//! :alias x v2
+//! :const LIMIT 10
+//!
+//! : main
+//!     x := 0
+//!     loop
+//!         x += 1
+//!         if x == LIMIT then x := 0
+//!     again
+//! ```
+//!
+//! Source is split into tokens on whitespace and `#` starts a comment. Bare numbers are
+//! emitted as bytes, which is how sprites are written (`0b01100000` reads well), and a bare
+//! label name calls it. Execution starts at `: main`; if anything comes before it, the
+//! program starts with a jump to it.
+//!
+//! Conditions compare with `==` and `!=`, or `<`, `>`, `<=` and `>=`, which Octo expands into
+//! a subtraction into `vF` and a test of the flag, so they overwrite `vF`.
+//!
+//! As in Octo, `:calc` expressions are evaluated right to left without operator precedence,
+//! so `2 * 3 + 1` is 8. Use parentheses, separated by spaces, to group.
+
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    convert::TryFrom,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use super::{AsmError, Assembly, Location};
+use crate::{instructions::Instruction, memory::PROGRAM_START_OFFSET};
+
+// The end of the XO-CHIP address space
+const MAX_ADDRESS: usize = 0x10000;
+
+// How many macros can expand inside each other, which also stops a macro expanding itself forever
+const MAX_MACRO_DEPTH: usize = 64;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Token {
+    text: String,
+    line: usize,
+    column: usize,
+    // How many macros this token was expanded through
+    depth: usize,
+}
+
+#[derive(Debug, Clone)]
+struct Macro {
+    args: Vec<String>,
+    body: Vec<Token>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Width {
+    /// The low 12 bits of an instruction.
+    Address,
+    /// A whole 16-bit word, for `i := long`.
+    Long,
+}
+
+/// A reference to a label that has not been defined yet, filled in at the end.
+struct Fixup {
+    address: usize,
+    width: Width,
+    name: Token,
+}
+
+/// Compiles Octo source to a ROM loaded at `0x200`.
+///
+/// ```
+/// use chip8_rs::asm::octo::Compiler;
+///
+/// let program = Compiler::new().compile(": main clear loop again").unwrap();
+/// assert_eq!(program.bytes(), &[0x00, 0xE0, 0x12, 0x02]);
+/// ```
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Compiler;
+
+impl Compiler {
+    pub fn new() -> Self {
+        Self
+    }
+
+    /// Compiles source held in memory.
+    pub fn compile(&self, source: &str) -> Result<Assembly, AsmError> {
+        Program::new(None, source).compile()
+    }
+
+    /// Compiles a file.
+    pub fn compile_file(&self, path: impl AsRef<Path>) -> Result<Assembly, AsmError> {
+        let path = path.as_ref();
+        let source = fs::read_to_string(path).map_err(|error| AsmError::Io {
+            path: path.to_path_buf(),
+            error,
+        })?;
+
+        Program::new(Some(path.to_path_buf()), &source).compile()
+    }
+}
+
+/// The state of a compilation.
+struct Program {
+    file: Option<PathBuf>,
+    tokens: VecDeque<Token>,
+    // The last token taken, for errors about something missing after it
+    last: Token,
+
+    // The ROM from 0x200, and the address the next byte goes at
+    rom: Vec<u8>,
+    pc: usize,
+    // Whether the first two bytes are saved for a jump to main
+    jump_to_main: bool,
+
+    labels: HashMap<String, usize>,
+    constants: HashMap<String, f64>,
+    aliases: HashMap<String, u8>,
+    macros: HashMap<String, Macro>,
+    fixups: Vec<Fixup>,
+
+    // The jumps over `begin` and `else` blocks waiting for their end
+    branches: Vec<(usize, Token)>,
+    // The start of each loop, with the `while` jumps waiting for its end
+    loops: Vec<(usize, Vec<usize>, Token)>,
+
+    source_map: BTreeMap<u16, Location>,
+    statement_line: usize,
+}
+
+impl Program {
+    fn new(file: Option<PathBuf>, source: &str) -> Self {
+        Self {
+            file,
+            tokens: tokenize(source),
+            last: Token {
+                text: String::new(),
+                line: 1,
+                column: 1,
+                depth: 0,
+            },
+            rom: vec![0, 0],
+            pc: PROGRAM_START_OFFSET + 2,
+            jump_to_main: true,
+            labels: HashMap::new(),
+            constants: HashMap::new(),
+            aliases: HashMap::new(),
+            macros: HashMap::new(),
+            fixups: vec![],
+            branches: vec![],
+            loops: vec![],
+            source_map: BTreeMap::new(),
+            statement_line: 1,
+        }
+    }
+
+    fn compile(mut self) -> Result<Assembly, AsmError> {
+        while let Some(token) = self.tokens.pop_front() {
+            self.statement_line = token.line;
+            self.last = token.clone();
+            self.statement(token)?;
+        }
+
+        if let Some((_, token)) = self.branches.pop() {
+            return Err(self.error(&token, "`begin` has no matching `end`"));
+        }
+        if let Some((_, _, token)) = self.loops.pop() {
+            return Err(self.error(&token, "`loop` has no matching `again`"));
+        }
+
+        for fixup in std::mem::take(&mut self.fixups) {
+            let target = match self.labels.get(&fixup.name.text) {
+                Some(target) => *target,
+                None => {
+                    return Err(self.error(
+                        &fixup.name,
+                        format!("undefined label `{}`", fixup.name.text),
+                    ))
+                }
+            };
+            self.patch(fixup.address, fixup.width, target, &fixup.name)?;
+        }
+
+        let mut rom = self.rom;
+        if self.jump_to_main {
+            let main = match self.labels.get("main") {
+                Some(main) => *main as u16,
+                None => {
+                    return Err(AsmError::Source {
+                        file: self.file,
+                        line: 1,
+                        column: 1,
+                        message: "the program has no `: main` label to start at".to_string(),
+                    })
+                }
+            };
+            rom[..2].copy_from_slice(&Instruction::Jp { nnn: main }.to_bytes());
+        }
+
+        Ok(Assembly {
+            origin: PROGRAM_START_OFFSET as u16,
+            bytes: rom,
+            source_map: self.source_map,
+        })
+    }
+
+    fn error(&self, token: &Token, message: impl Into<String>) -> AsmError {
+        AsmError::Source {
+            file: self.file.clone(),
+            line: token.line,
+            column: token.column,
+            message: message.into(),
+        }
+    }
+
+    fn next(&mut self) -> Result<Token, AsmError> {
+        match self.tokens.pop_front() {
+            Some(token) => {
+                self.last = token.clone();
+                Ok(token)
+            }
+            None => Err(self.error(
+                &self.last,
+                format!("expected more after `{}`", self.last.text),
+            )),
+        }
+    }
+
+    fn peek(&self) -> Option<&str> {
+        self.tokens.front().map(|token| token.text.as_str())
+    }
+
+    fn expect(&mut self, text: &str) -> Result<Token, AsmError> {
+        let token = self.next()?;
+        if token.text != text {
+            return Err(self.error(
+                &token,
+                format!("expected `{}`, found `{}`", text, token.text),
+            ));
+        }
+        Ok(token)
+    }
+
+    fn statement(&mut self, token: Token) -> Result<(), AsmError> {
+        match token.text.as_str() {
+            ":" => {
+                let name = self.name()?;
+                // Nothing has been written before main, so there is no need to jump to it
+                if name.text == "main"
+                    && self.pc == PROGRAM_START_OFFSET + 2
+                    && self.rom.len() == 2
+                    && self.labels.is_empty()
+                {
+                    self.rom.clear();
+                    self.pc = PROGRAM_START_OFFSET;
+                    self.jump_to_main = false;
+                }
+                self.define_label(&name, self.pc)
+            }
+            ":next" => {
+                // The label points at the second byte of the next instruction, for code that
+                // rewrites its own operands
+                let name = self.name()?;
+                self.define_label(&name, self.pc + 1)
+            }
+            ":alias" => {
+                let name = self.name()?;
+                let register = self.next()?;
+                let x = self.register(&register)?;
+                self.aliases.insert(name.text, x);
+                Ok(())
+            }
+            ":const" => {
+                let name = self.name()?;
+                let value = self.next()?;
+                let value = self.number(&value)?;
+                self.define_constant(&name, value)
+            }
+            ":calc" => {
+                let name = self.name()?;
+                let value = self.calc()?;
+                self.define_constant(&name, value)
+            }
+            ":macro" => self.define_macro(),
+            ":org" => {
+                let address = self.next()?;
+                let address = self.value(&address, 0, MAX_ADDRESS as i64 - 1)?;
+                if address < PROGRAM_START_OFFSET as i64 {
+                    return Err(self.error(&token, "`:org` cannot go before 0x200"));
+                }
+                self.pc = address as usize;
+                Ok(())
+            }
+            ":byte" => {
+                let value = if self.peek() == Some("{") {
+                    let start = self.tokens.front().cloned().unwrap();
+                    let value = self.calc()?;
+                    self.check(&start, value as i64, -0x80, 0xFF)?
+                } else {
+                    let value = self.next()?;
+                    self.value(&value, -0x80, 0xFF)?
+                };
+                self.emit(&token, &[value as u8])
+            }
+            ":call" => {
+                let nnn = self.address(Width::Address)?;
+                self.instruction(&token, Instruction::Call { nnn })
+            }
+            // Debugger hints, which the compiled program does not need
+            ":breakpoint" | ":monitor" => {
+                self.next()?;
+                if token.text == ":monitor" {
+                    self.next()?;
+                }
+                Ok(())
+            }
+            ";" | "return" => self.instruction(&token, Instruction::Ret),
+            "clear" => self.instruction(&token, Instruction::Cls),
+            "exit" => self.instruction(&token, Instruction::Exit),
+            "lores" => self.instruction(&token, Instruction::LoRes),
+            "hires" => self.instruction(&token, Instruction::HiRes),
+            "scroll-left" => self.instruction(&token, Instruction::ScrollLeft),
+            "scroll-right" => self.instruction(&token, Instruction::ScrollRight),
+            "audio" => self.instruction(&token, Instruction::LdAudio),
+            "scroll-down" | "scroll-up" | "plane" => {
+                let n = self.next()?;
+                let n = self.value(&n, 0, 0xF)? as u8;
+                let instruction = match token.text.as_str() {
+                    "scroll-down" => Instruction::ScrollDown { n },
+                    "scroll-up" => Instruction::ScrollUp { n },
+                    _ => Instruction::Plane { n },
+                };
+                self.instruction(&token, instruction)
+            }
+            "bcd" | "saveflags" | "loadflags" => {
+                let x = self.next()?;
+                let x = self.register(&x)?;
+                let instruction = match token.text.as_str() {
+                    "bcd" => Instruction::LdBcd { x },
+                    "saveflags" => Instruction::StoreFlags { x },
+                    _ => Instruction::LoadFlags { x },
+                };
+                self.instruction(&token, instruction)
+            }
+            "save" | "load" => {
+                let x = self.next()?;
+                let x = self.register(&x)?;
+                let instruction = if self.peek() == Some("-") {
+                    self.next()?;
+                    let y = self.next()?;
+                    let y = self.register(&y)?;
+                    match token.text.as_str() {
+                        "save" => Instruction::StoreRange { x, y },
+                        _ => Instruction::LoadRange { x, y },
+                    }
+                } else {
+                    match token.text.as_str() {
+                        "save" => Instruction::StoreRegs { x },
+                        _ => Instruction::LoadRegs { x },
+                    }
+                };
+                self.instruction(&token, instruction)
+            }
+            "sprite" => {
+                let x = self.next()?;
+                let x = self.register(&x)?;
+                let y = self.next()?;
+                let y = self.register(&y)?;
+                let n = self.next()?;
+                let n = self.value(&n, 0, 0xF)? as u8;
+                self.instruction(&token, Instruction::Draw { x, y, n })
+            }
+            "jump" => {
+                let nnn = self.address(Width::Address)?;
+                self.instruction(&token, Instruction::Jp { nnn })
+            }
+            "jump0" => {
+                let nnn = self.address(Width::Address)?;
+                self.instruction(&token, Instruction::JpV0 { nnn })
+            }
+            "native" => {
+                let nnn = self.address(Width::Address)?;
+                self.instruction(&token, Instruction::Sys { nnn })
+            }
+            "delay" | "buzzer" | "pitch" => {
+                self.expect(":=")?;
+                let x = self.next()?;
+                let x = self.register(&x)?;
+                let instruction = match token.text.as_str() {
+                    "delay" => Instruction::LdDtX { x },
+                    "buzzer" => Instruction::LdStX { x },
+                    _ => Instruction::LdPitch { x },
+                };
+                self.instruction(&token, instruction)
+            }
+            "i" => self.assign_i(&token),
+            "if" => self.branch(&token),
+            "else" => {
+                let (jump, _) = self
+                    .branches
+                    .pop()
+                    .ok_or_else(|| self.error(&token, "`else` without `if ... begin`"))?;
+                let end = self.pc;
+                self.instruction(&token, Instruction::Jp { nnn: 0 })?;
+                self.patch(jump, Width::Address, self.pc, &token)?;
+                self.branches.push((end, token));
+                Ok(())
+            }
+            "end" => {
+                let (jump, _) = self
+                    .branches
+                    .pop()
+                    .ok_or_else(|| self.error(&token, "`end` without `if ... begin`"))?;
+                self.patch(jump, Width::Address, self.pc, &token)
+            }
+            "loop" => {
+                self.loops.push((self.pc, vec![], token));
+                Ok(())
+            }
+            "while" => {
+                if self.loops.is_empty() {
+                    return Err(self.error(&token, "`while` outside of a `loop`"));
+                }
+                let skip = self.condition(true)?;
+                self.instructions(&token, skip)?;
+                let jump = self.pc;
+                self.instruction(&token, Instruction::Jp { nnn: 0 })?;
+                self.loops.last_mut().unwrap().1.push(jump);
+                Ok(())
+            }
+            "again" => {
+                let (start, whiles, _) = self
+                    .loops
+                    .pop()
+                    .ok_or_else(|| self.error(&token, "`again` without `loop`"))?;
+                self.instruction(&token, Instruction::Jp { nnn: start as u16 })?;
+                for jump in whiles {
+                    self.patch(jump, Width::Address, self.pc, &token)?;
+                }
+                Ok(())
+            }
+            _ => {
+                if let Some(x) = self.try_register(&token.text) {
+                    return self.assign_register(&token, x);
+                }
+                if let Some(definition) = self.macros.get(&token.text).cloned() {
+                    return self.expand(&token, definition);
+                }
+                if let Some(value) = parse_number(&token.text) {
+                    let value = self.check(&token, value, -0x80, 0xFF)?;
+                    return self.emit(&token, &[value as u8]);
+                }
+                if let Some(value) = self.constants.get(&token.text) {
+                    let value = self.check(&token, *value as i64, -0x80, 0xFF)?;
+                    return self.emit(&token, &[value as u8]);
+                }
+                if is_name(&token.text) {
+                    self.tokens.push_front(token.clone());
+                    let nnn = self.address(Width::Address)?;
+                    return self.instruction(&token, Instruction::Call { nnn });
+                }
+
+                Err(self.error(&token, format!("unexpected `{}`", token.text)))
+            }
+        }
+    }
+
+    fn assign_i(&mut self, token: &Token) -> Result<(), AsmError> {
+        let operator = self.next()?;
+        let instruction = match operator.text.as_str() {
+            ":=" => match self.peek() {
+                Some("hex") | Some("bighex") => {
+                    let kind = self.next()?;
+                    let x = self.next()?;
+                    let x = self.register(&x)?;
+                    if kind.text == "hex" {
+                        Instruction::LdFont { x }
+                    } else {
+                        Instruction::LdHiFont { x }
+                    }
+                }
+                Some("long") => {
+                    self.next()?;
+                    Instruction::LdILong {
+                        nnnn: self.address(Width::Long)?,
+                    }
+                }
+                _ => Instruction::LdI {
+                    nnn: self.address(Width::Address)?,
+                },
+            },
+            "+=" => {
+                let x = self.next()?;
+                Instruction::AddIX {
+                    x: self.register(&x)?,
+                }
+            }
+            _ => {
+                return Err(self.error(
+                    &operator,
+                    format!("expected `:=` or `+=` after `i`, found `{}`", operator.text),
+                ))
+            }
+        };
+
+        self.instruction(token, instruction)
+    }
+
+    fn assign_register(&mut self, token: &Token, x: u8) -> Result<(), AsmError> {
+        let operator = self.next()?;
+        let source = self.next()?;
+        let y = self.try_register(&source.text);
+
+        let instruction = match (operator.text.as_str(), source.text.as_str(), y) {
+            (":=", _, Some(y)) => Instruction::LdXY { x, y },
+            (":=", "key", None) => Instruction::LdXKey { x },
+            (":=", "delay", None) => Instruction::LdXDt { x },
+            (":=", "random", None) => {
+                let mask = self.next()?;
+                Instruction::Rnd {
+                    x,
+                    kk: self.value(&mask, -0x80, 0xFF)? as u8,
+                }
+            }
+            (":=", _, None) => Instruction::LdByte {
+                x,
+                kk: self.value(&source, -0x80, 0xFF)? as u8,
+            },
+            ("+=", _, Some(y)) => Instruction::AddXY { x, y },
+            ("+=", _, None) => Instruction::AddByte {
+                x,
+                kk: self.value(&source, -0x80, 0xFF)? as u8,
+            },
+            ("|=", _, Some(y)) => Instruction::OrXY { x, y },
+            ("&=", _, Some(y)) => Instruction::AndXY { x, y },
+            ("^=", _, Some(y)) => Instruction::XorXY { x, y },
+            ("-=", _, Some(y)) => Instruction::SubXY { x, y },
+            ("=-", _, Some(y)) => Instruction::SubnXY { x, y },
+            (">>=", _, Some(y)) => Instruction::ShrXY { x, y },
+            ("<<=", _, Some(y)) => Instruction::ShlXY { x, y },
+            ("|=", _, None)
+            | ("&=", _, None)
+            | ("^=", _, None)
+            | ("-=", _, None)
+            | ("=-", _, None)
+            | (">>=", _, None)
+            | ("<<=", _, None) => {
+                return Err(self.error(
+                    &source,
+                    format!("expected a register after `{}`", operator.text),
+                ))
+            }
+            _ => return Err(self.error(&operator, format!("unknown operator `{}`", operator.text))),
+        };
+
+        self.instruction(token, instruction)
+    }
+
+    /// `if <condition> then <statement>` or `if <condition> begin ... [else ...] end`.
+    fn branch(&mut self, token: &Token) -> Result<(), AsmError> {
+        // Work out which form it is before reading the condition, which is 2 or 3 tokens long
+        let form = self
+            .tokens
+            .iter()
+            .take(4)
+            .find(|token| token.text == "then" || token.text == "begin")
+            .map(|token| token.text.clone());
+
+        match form.as_deref() {
+            Some("then") => {
+                let skip = self.condition(false)?;
+                self.expect("then")?;
+                self.instructions(token, skip)
+            }
+            Some(_) => {
+                // Skip the jump over the block when the condition holds
+                let skip = self.condition(true)?;
+                self.expect("begin")?;
+                self.instructions(token, skip)?;
+                self.branches.push((self.pc, token.clone()));
+                self.instruction(token, Instruction::Jp { nnn: 0 })
+            }
+            None => Err(self.error(token, "expected `then` or `begin` after the condition")),
+        }
+    }
+
+    /// Reads a condition, returning the instructions that skip the next one when it is false, or
+    /// when it is true if `negated`.
+    fn condition(&mut self, negated: bool) -> Result<Vec<Instruction>, AsmError> {
+        let register = self.next()?;
+        let x = self.register(&register)?;
+        let operator = self.next()?;
+
+        // Whether the instruction should skip when the operands are equal, or the key is down
+        let skip_when = |holds: bool| holds == negated;
+        let instruction = match operator.text.as_str() {
+            "key" | "-key" => {
+                if skip_when(operator.text == "key") {
+                    Instruction::Skp { x }
+                } else {
+                    Instruction::Sknp { x }
+                }
+            }
+            "==" | "!=" => {
+                let skip_if_equal = skip_when(operator.text == "==");
+                let rhs = self.next()?;
+                match self.try_register(&rhs.text) {
+                    Some(y) if skip_if_equal => Instruction::SeXY { x, y },
+                    Some(y) => Instruction::SneXY { x, y },
+                    None => {
+                        let kk = self.value(&rhs, -0x80, 0xFF)? as u8;
+                        if skip_if_equal {
+                            Instruction::SeByte { x, kk }
+                        } else {
+                            Instruction::SneByte { x, kk }
+                        }
+                    }
+                }
+            }
+            "<" | ">" | "<=" | ">=" => return self.comparison(x, &operator, negated),
+            _ => {
+                return Err(self.error(
+                    &operator,
+                    format!("unsupported condition `{}`", operator.text),
+                ))
+            }
+        };
+
+        Ok(vec![instruction])
+    }
+
+    /// Compares the way Octo does, by loading the right hand side into `vF` and subtracting so
+    /// that `vF` holds the flag, which is 1 when there was no borrow, then testing it.
+    fn comparison(
+        &mut self,
+        x: u8,
+        operator: &Token,
+        negated: bool,
+    ) -> Result<Vec<Instruction>, AsmError> {
+        let operator = match (operator.text.as_str(), negated) {
+            ("<", true) => ">=",
+            (">", true) => "<=",
+            ("<=", true) => ">",
+            (">=", true) => "<",
+            (operator, _) => operator,
+        };
+
+        let rhs = self.next()?;
+        let load = match self.try_register(&rhs.text) {
+            Some(y) => Instruction::LdXY { x: 0xF, y },
+            None => Instruction::LdByte {
+                x: 0xF,
+                kk: self.value(&rhs, -0x80, 0xFF)? as u8,
+            },
+        };
+
+        // `vF -= vx` doesn't borrow when vx <= rhs, and `vF =- vx` when vx >= rhs
+        let subtract = match operator {
+            ">" | "<=" => Instruction::SubXY { x: 0xF, y: x },
+            _ => Instruction::SubnXY { x: 0xF, y: x },
+        };
+        // `>` and `<` hold when it borrowed, the others when it didn't
+        let test = match operator {
+            ">" | "<" => Instruction::SneByte { x: 0xF, kk: 0 },
+            _ => Instruction::SeByte { x: 0xF, kk: 0 },
+        };
+
+        Ok(vec![load, subtract, test])
+    }
+
+    fn define_macro(&mut self) -> Result<(), AsmError> {
+        let name = self.name()?;
+        let mut args = vec![];
+        loop {
+            let token = self.next()?;
+            if token.text == "{" {
+                break;
+            }
+            args.push(token.text);
+        }
+
+        let mut body = vec![];
+        let mut depth = 1;
+        loop {
+            let token = self.next()?;
+            match token.text.as_str() {
+                "{" => depth += 1,
+                "}" => {
+                    depth -= 1;
+                    if depth == 0 {
+                        break;
+                    }
+                }
+                _ => {}
+            }
+            body.push(token);
+        }
+
+        self.macros.insert(name.text, Macro { args, body });
+        Ok(())
+    }
+
+    fn expand(&mut self, call: &Token, definition: Macro) -> Result<(), AsmError> {
+        if call.depth >= MAX_MACRO_DEPTH {
+            return Err(self.error(call, format!("`{}` expands itself forever", call.text)));
+        }
+
+        let mut values = HashMap::new();
+        for arg in definition.args.iter() {
+            values.insert(arg.clone(), self.next()?.text);
+        }
+
+        for token in definition.body.into_iter().rev() {
+            self.tokens.push_front(Token {
+                text: values.get(&token.text).cloned().unwrap_or(token.text),
+                depth: call.depth + 1,
+                ..token
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Evaluates a `{ ... }` block.
+    fn calc(&mut self) -> Result<f64, AsmError> {
+        let open = self.expect("{")?;
+        let mut tokens = vec![];
+        loop {
+            let token = self.next()?;
+            if token.text == "}" {
+                break;
+            }
+            tokens.push(token);
+        }
+
+        let (value, rest) = self.expression(&tokens, &open)?;
+        match rest.first() {
+            Some(token) => Err(self.error(token, format!("unexpected `{}`", token.text))),
+            None => Ok(value),
+        }
+    }
+
+    // An operand, optionally followed by an operator and the rest of the expression
+    fn expression<'t>(
+        &self,
+        tokens: &'t [Token],
+        open: &Token,
+    ) -> Result<(f64, &'t [Token]), AsmError> {
+        let (lhs, rest) = self.term(tokens, open)?;
+        let operator = match rest.first() {
+            Some(token) if token.text != ")" => token,
+            _ => return Ok((lhs, rest)),
+        };
+
+        let (rhs, rest) = self.expression(&rest[1..], operator)?;
+        let (a, b) = (lhs, rhs);
+        let value = match operator.text.as_str() {
+            "+" => a + b,
+            "-" => a - b,
+            "*" => a * b,
+            "/" => a / b,
+            "%" => a % b,
+            "&" => ((a as i64) & (b as i64)) as f64,
+            "|" => ((a as i64) | (b as i64)) as f64,
+            "^" => ((a as i64) ^ (b as i64)) as f64,
+            "<<" | ">>" => {
+                let shift = u32::try_from(b as i64).ok();
+                let shifted = if operator.text == "<<" {
+                    shift.and_then(|shift| (a as i64).checked_shl(shift))
+                } else {
+                    shift.and_then(|shift| (a as i64).checked_shr(shift))
+                };
+                shifted.ok_or_else(|| self.error(operator, format!("cannot shift by {}", b)))?
+                    as f64
+            }
+            "<" => (a < b) as i64 as f64,
+            ">" => (a > b) as i64 as f64,
+            "<=" => (a <= b) as i64 as f64,
+            ">=" => (a >= b) as i64 as f64,
+            "==" => (a == b) as i64 as f64,
+            "!=" => (a != b) as i64 as f64,
+            "min" => a.min(b),
+            "max" => a.max(b),
+            "pow" => a.powf(b),
+            _ => return Err(self.error(operator, format!("unknown operator `{}`", operator.text))),
+        };
+
+        Ok((value, rest))
+    }
+
+    fn term<'t>(
+        &self,
+        tokens: &'t [Token],
+        before: &Token,
+    ) -> Result<(f64, &'t [Token]), AsmError> {
+        let token = match tokens.first() {
+            Some(token) => token,
+            None => {
+                return Err(self.error(before, format!("expected a value after `{}`", before.text)))
+            }
+        };
+        let rest = &tokens[1..];
+
+        match token.text.as_str() {
+            "(" => {
+                let (value, rest) = self.expression(rest, token)?;
+                match rest.first() {
+                    Some(close) if close.text == ")" => Ok((value, &rest[1..])),
+                    _ => Err(self.error(token, "`(` has no matching `)`")),
+                }
+            }
+            "-" => self.term(rest, token).map(|(value, rest)| (-value, rest)),
+            "~" => self
+                .term(rest, token)
+                .map(|(value, rest)| (!(value as i64) as f64, rest)),
+            "!" => self
+                .term(rest, token)
+                .map(|(value, rest)| ((value == 0.0) as i64 as f64, rest)),
+            "HERE" => Ok((self.pc as f64, rest)),
+            text => {
+                let value = parse_number(text)
+                    .map(|value| value as f64)
+                    .or_else(|| self.constants.get(text).copied())
+                    .or_else(|| self.labels.get(text).map(|address| *address as f64))
+                    .ok_or_else(|| {
+                        self.error(token, format!("unknown constant or label `{}`", text))
+                    })?;
+                Ok((value, rest))
+            }
+        }
+    }
+
+    /// Reads a 12 or 16-bit address. Labels that are not defined yet are filled in later.
+    fn address(&mut self, width: Width) -> Result<u16, AsmError> {
+        let token = self.next()?;
+        let max = match width {
+            Width::Address => 0xFFF,
+            Width::Long => 0xFFFF,
+        };
+
+        if let Some(address) = self.labels.get(&token.text) {
+            return Ok(self.check(&token, *address as i64, 0, max)? as u16);
+        }
+        if parse_number(&token.text).is_some() || self.constants.contains_key(&token.text) {
+            return Ok(self.value(&token, 0, max)? as u16);
+        }
+        if !is_name(&token.text) {
+            return Err(self.error(
+                &token,
+                format!("expected an address, found `{}`", token.text),
+            ));
+        }
+
+        // The instruction is written next, at the current address
+        self.fixups.push(Fixup {
+            address: self.pc,
+            width,
+            name: token,
+        });
+        Ok(0)
+    }
+
+    /// Writes an address into an instruction that has already been written.
+    fn patch(
+        &mut self,
+        address: usize,
+        width: Width,
+        target: usize,
+        token: &Token,
+    ) -> Result<(), AsmError> {
+        let offset = address - PROGRAM_START_OFFSET;
+        match width {
+            Width::Address => {
+                self.check(token, target as i64, 0, 0xFFF)?;
+                self.rom[offset] = (self.rom[offset] & 0xF0) | (target >> 8) as u8;
+                self.rom[offset + 1] = target as u8;
+            }
+            Width::Long => {
+                self.rom[offset + 2] = (target >> 8) as u8;
+                self.rom[offset + 3] = target as u8;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn instruction(&mut self, token: &Token, instruction: Instruction) -> Result<(), AsmError> {
+        self.source_map.insert(
+            self.pc as u16,
+            Location {
+                file: self.file.clone(),
+                line: self.statement_line,
+            },
+        );
+        self.emit(token, &instruction.to_bytes())
+    }
+
+    fn instructions(
+        &mut self,
+        token: &Token,
+        instructions: Vec<Instruction>,
+    ) -> Result<(), AsmError> {
+        instructions
+            .into_iter()
+            .try_for_each(|instruction| self.instruction(token, instruction))
+    }
+
+    fn emit(&mut self, token: &Token, bytes: &[u8]) -> Result<(), AsmError> {
+        if self.pc + bytes.len() > MAX_ADDRESS {
+            return Err(self.error(token, "the program does not fit in memory"));
+        }
+
+        let start = self.pc - PROGRAM_START_OFFSET;
+        let end = start + bytes.len();
+        if self.rom.len() < end {
+            self.rom.resize(end, 0);
+        }
+        self.rom[start..end].copy_from_slice(bytes);
+        self.pc += bytes.len();
+
+        Ok(())
+    }
+
+    fn name(&mut self) -> Result<Token, AsmError> {
+        let token = self.next()?;
+        if !is_name(&token.text) || self.try_register(&token.text).is_some() {
+            return Err(self.error(&token, format!("`{}` is not a valid name", token.text)));
+        }
+        Ok(token)
+    }
+
+    fn define_label(&mut self, name: &Token, address: usize) -> Result<(), AsmError> {
+        if self.labels.contains_key(&name.text) {
+            return Err(self.error(name, format!("`{}` is already defined", name.text)));
+        }
+        self.labels.insert(name.text.clone(), address);
+        Ok(())
+    }
+
+    fn define_constant(&mut self, name: &Token, value: f64) -> Result<(), AsmError> {
+        if self.labels.contains_key(&name.text) {
+            return Err(self.error(name, format!("`{}` is already a label", name.text)));
+        }
+        self.constants.insert(name.text.clone(), value);
+        Ok(())
+    }
+
+    fn try_register(&self, text: &str) -> Option<u8> {
+        if let Some(x) = self.aliases.get(text) {
+            return Some(*x);
+        }
+
+        let mut chars = text.chars();
+        match (chars.next(), chars.next(), chars.next()) {
+            (Some('v'), Some(digit), None) | (Some('V'), Some(digit), None) => {
+                digit.to_digit(16).map(|x| x as u8)
+            }
+            _ => None,
+        }
+    }
+
+    fn register(&self, token: &Token) -> Result<u8, AsmError> {
+        self.try_register(&token.text).ok_or_else(|| {
+            self.error(
+                token,
+                format!("expected a register, found `{}`", token.text),
+            )
+        })
+    }
+
+    /// A number or constant.
+    fn number(&self, token: &Token) -> Result<f64, AsmError> {
+        parse_number(&token.text)
+            .map(|value| value as f64)
+            .or_else(|| self.constants.get(&token.text).copied())
+            .ok_or_else(|| self.error(token, format!("expected a number, found `{}`", token.text)))
+    }
+
+    /// A number or constant between `min` and `max`.
+    fn value(&self, token: &Token, min: i64, max: i64) -> Result<i64, AsmError> {
+        let value = self.number(token)?;
+        self.check(token, value as i64, min, max)
+    }
+
+    fn check(&self, token: &Token, value: i64, min: i64, max: i64) -> Result<i64, AsmError> {
+        if (min..=max).contains(&value) {
+            Ok(value)
+        } else {
+            Err(self.error(
+                token,
+                format!("{} is out of range ({} to {})", value, min, max),
+            ))
+        }
+    }
+}
+
+fn tokenize(source: &str) -> VecDeque<Token> {
+    let mut tokens = VecDeque::new();
+    for (i, line) in source.lines().enumerate() {
+        let mut start = None;
+        // A space on the end closes the last token
+        for (column, c) in line.chars().chain(std::iter::once(' ')).enumerate() {
+            match (c.is_whitespace(), start) {
+                (false, None) => {
+                    if c == '#' {
+                        break;
+                    }
+                    start = Some(column);
+                }
+                (true, Some(first)) => {
+                    tokens.push_back(Token {
+                        text: line.chars().skip(first).take(column - first).collect(),
+                        line: i + 1,
+                        column: first + 1,
+                        depth: 0,
+                    });
+                    start = None;
+                }
+                _ => {}
+            }
+        }
+    }
+
+    tokens
+}
+
+/// Parses a decimal, `0x` prefixed hexadecimal or `0b` prefixed binary number, which may be
+/// negative.
+fn parse_number(text: &str) -> Option<i64> {
+    let (negative, digits) = match text.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, text),
+    };
+
+    let value = if let Some(hex) = digits.strip_prefix("0x") {
+        i64::from_str_radix(hex, 16).ok()?
+    } else if let Some(binary) = digits.strip_prefix("0b") {
+        i64::from_str_radix(binary, 2).ok()?
+    } else if !digits.is_empty() && digits.chars().all(|c| c.is_ascii_digit()) {
+        digits.parse().ok()?
+    } else {
+        return None;
+    };
+
+    Some(if negative { -value } else { value })
+}
+
+// Names may hold anything but whitespace, as long as they don't look like numbers or syntax
+fn is_name(text: &str) -> bool {
+    text.chars()
+        .next()
+        .is_some_and(|c| c.is_alphabetic() || c == '_')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Compiler;
+    use crate::asm::AsmError;
+
+    fn compile(source: &str) -> Vec<u8> {
+        Compiler::new().compile(source).unwrap().into_bytes()
+    }
+
+    fn error(source: &str) -> (usize, usize, String) {
+        match Compiler::new().compile(source) {
+            Err(AsmError::Source {
+                line,
+                column,
+                message,
+                ..
+            }) => (line, column, message),
+            other => panic!("expected a source error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn should_only_jump_to_main_when_something_comes_first() {
+        assert_eq!(compile(": main clear"), vec![0x00, 0xE0]);
+        assert_eq!(
+            compile("0xFF : main clear"),
+            vec![0x12, 0x03, 0xFF, 0x00, 0xE0]
+        );
+    }
+
+    #[test]
+    fn should_evaluate_calc_right_to_left() {
+        assert_eq!(
+            compile(":calc A { 2 * 3 + 1 }\n:calc B { ( 2 * 3 ) + 1 }\n: main :byte A :byte B"),
+            vec![0x08, 0x07]
+        );
+    }
+
+    #[test]
+    fn should_resolve_forward_references() {
+        assert_eq!(
+            compile(": main i := data jump0 table ; : table : data 0x01"),
+            vec![0xA2, 0x06, 0xB2, 0x06, 0x00, 0xEE, 0x01]
+        );
+    }
+
+    #[test]
+    fn should_call_labels_by_name() {
+        assert_eq!(
+            compile(": main draw : draw return"),
+            vec![0x22, 0x02, 0x00, 0xEE]
+        );
+    }
+
+    #[test]
+    fn should_report_errors_with_line_and_column() {
+        assert_eq!(
+            error(": main\n  jump nowhere"),
+            (2, 8, "undefined label `nowhere`".to_string())
+        );
+        assert_eq!(
+            error(": main v0 := 256"),
+            (1, 14, "256 is out of range (-128 to 255)".to_string())
+        );
+        assert_eq!(
+            error(": main loop"),
+            (1, 8, "`loop` has no matching `again`".to_string())
+        );
+        assert_eq!(
+            error(": main if v0 =< v1 then clear"),
+            (1, 14, "unsupported condition `=<`".to_string())
+        );
+        assert_eq!(
+            error(":calc BIG { 1 << 64 }"),
+            (1, 15, "cannot shift by 64".to_string())
+        );
+        assert_eq!(
+            error(":calc NEGATIVE { 1 >> -1 }"),
+            (1, 20, "cannot shift by -1".to_string())
+        );
+        assert_eq!(
+            error(":macro forever { forever }\n: main forever"),
+            (1, 18, "`forever` expands itself forever".to_string())
+        );
+        assert_eq!(
+            error("clear"),
+            (
+                1,
+                1,
+                "the program has no `: main` label to start at".to_string()
+            )
+        );
+    }
+}
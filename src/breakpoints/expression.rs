@@ -0,0 +1,429 @@
+//! Conditions such as `V5 == 0x20` or `I >= 0x300 && I < 0x340`, evaluated against a [`CPU`].
+//!
+//! Operands are numbers (decimal or `0x` hexadecimal), the registers `V0`-`VF`, `I`, `PC`,
+//! `SP`, `DT` and `ST`, and `[address]` for the byte in memory at an address. From the
+//! loosest binding, the operators are `||`, `&&`, the comparisons, `|`, `&`, `+` and `-`,
+//! then `!`. Comparisons and `!` give 1 or 0, and anything other than 0 is true. Values are
+//! 64 bit, and `+` and `-` wrap around rather than overflow.
+
+use std::{convert::TryFrom, error::Error, fmt, str::FromStr};
+
+use crate::{cpu::CPU, keyboard::Keyboard};
+
+/// A register an expression can read, or the debugger can change.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Register {
+    V(u8),
+    I,
+    Pc,
+    Sp,
+    Dt,
+    St,
+}
+
+impl Register {
+    /// Every register, in the order debuggers number them.
+    pub const ALL: [Register; 21] = [
+        Register::V(0x0),
+        Register::V(0x1),
+        Register::V(0x2),
+        Register::V(0x3),
+        Register::V(0x4),
+        Register::V(0x5),
+        Register::V(0x6),
+        Register::V(0x7),
+        Register::V(0x8),
+        Register::V(0x9),
+        Register::V(0xA),
+        Register::V(0xB),
+        Register::V(0xC),
+        Register::V(0xD),
+        Register::V(0xE),
+        Register::V(0xF),
+        Register::I,
+        Register::Pc,
+        Register::Sp,
+        Register::Dt,
+        Register::St,
+    ];
+
+    /// Parses a register name such as `v3` or `PC`, in any case.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "i" => Some(Register::I),
+            "pc" => Some(Register::Pc),
+            "sp" => Some(Register::Sp),
+            "dt" => Some(Register::Dt),
+            "st" => Some(Register::St),
+            name => {
+                let x = name.strip_prefix('v')?;
+                if x.len() != 1 {
+                    return None;
+                }
+                u8::from_str_radix(x, 16).ok().map(Register::V)
+            }
+        }
+    }
+
+    pub fn read<TKeyboard>(self, cpu: &CPU<TKeyboard>) -> u16
+    where
+        TKeyboard: Keyboard,
+    {
+        match self {
+            Register::V(x) => cpu.v[x as usize] as u16,
+            Register::I => cpu.vi,
+            Register::Pc => cpu.pc,
+            Register::Sp => cpu.sp as u16,
+            Register::Dt => cpu.delay_timer as u16,
+            Register::St => cpu.sound_timer as u16,
+        }
+    }
+
+    /// Sets the register, returning false if the value doesn't fit in it.
+    pub fn write<TKeyboard>(self, cpu: &mut CPU<TKeyboard>, value: u16) -> bool
+    where
+        TKeyboard: Keyboard,
+    {
+        let byte = u8::try_from(value).ok();
+        match (self, byte) {
+            (Register::V(x), Some(value)) => cpu.v[x as usize] = value,
+            (Register::I, _) => cpu.vi = value,
+            (Register::Pc, _) => cpu.pc = value,
+            (Register::Sp, Some(sp)) if (sp as usize) <= cpu.stack.len() => cpu.sp = sp,
+            (Register::Dt, Some(value)) => cpu.delay_timer = value,
+            (Register::St, Some(value)) => cpu.sound_timer = value,
+            _ => return false,
+        }
+
+        true
+    }
+}
+
+impl fmt::Display for Register {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Register::V(x) => write!(f, "V{:X}", x),
+            Register::I => write!(f, "I"),
+            Register::Pc => write!(f, "PC"),
+            Register::Sp => write!(f, "SP"),
+            Register::Dt => write!(f, "DT"),
+            Register::St => write!(f, "ST"),
+        }
+    }
+}
+
+/// An expression that could not be parsed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExpressionError {
+    pub column: usize,
+    pub message: String,
+}
+
+impl fmt::Display for ExpressionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "column {}: {}", self.column, self.message)
+    }
+}
+
+impl Error for ExpressionError {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum BinaryOp {
+    Or,
+    And,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    BitOr,
+    BitAnd,
+    Add,
+    Sub,
+}
+
+impl BinaryOp {
+    fn apply(self, a: i64, b: i64) -> i64 {
+        match self {
+            BinaryOp::Or => ((a != 0) || (b != 0)) as i64,
+            BinaryOp::And => ((a != 0) && (b != 0)) as i64,
+            BinaryOp::Eq => (a == b) as i64,
+            BinaryOp::Ne => (a != b) as i64,
+            BinaryOp::Lt => (a < b) as i64,
+            BinaryOp::Le => (a <= b) as i64,
+            BinaryOp::Gt => (a > b) as i64,
+            BinaryOp::Ge => (a >= b) as i64,
+            BinaryOp::BitOr => a | b,
+            BinaryOp::BitAnd => a & b,
+            BinaryOp::Add => a.wrapping_add(b),
+            BinaryOp::Sub => a.wrapping_sub(b),
+        }
+    }
+}
+
+// The binary operators of each precedence level, from the loosest binding
+const LEVELS: &[&[(&str, BinaryOp)]] = &[
+    &[("||", BinaryOp::Or)],
+    &[("&&", BinaryOp::And)],
+    &[
+        ("==", BinaryOp::Eq),
+        ("!=", BinaryOp::Ne),
+        ("<=", BinaryOp::Le),
+        (">=", BinaryOp::Ge),
+        ("<", BinaryOp::Lt),
+        (">", BinaryOp::Gt),
+    ],
+    &[("|", BinaryOp::BitOr)],
+    &[("&", BinaryOp::BitAnd)],
+    &[("+", BinaryOp::Add), ("-", BinaryOp::Sub)],
+];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Node {
+    Number(i64),
+    Register(Register),
+    Memory(Box<Node>),
+    Not(Box<Node>),
+    Binary(BinaryOp, Box<Node>, Box<Node>),
+}
+
+impl Node {
+    fn evaluate<TKeyboard>(&self, cpu: &CPU<TKeyboard>) -> i64
+    where
+        TKeyboard: Keyboard,
+    {
+        match self {
+            Node::Number(n) => *n,
+            Node::Register(register) => register.read(cpu) as i64,
+            // Reads straight from the data, so evaluating doesn't trigger read watchpoints
+            Node::Memory(address) => usize::try_from(address.evaluate(cpu))
+                .ok()
+                .and_then(|address| cpu.memory.data.get(address))
+                .map_or(0, |byte| *byte as i64),
+            Node::Not(node) => (node.evaluate(cpu) == 0) as i64,
+            Node::Binary(op, a, b) => op.apply(a.evaluate(cpu), b.evaluate(cpu)),
+        }
+    }
+}
+
+/// A parsed expression, which displays as the text it was parsed from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Expression {
+    source: String,
+    root: Node,
+}
+
+impl Expression {
+    pub fn evaluate<TKeyboard>(&self, cpu: &CPU<TKeyboard>) -> i64
+    where
+        TKeyboard: Keyboard,
+    {
+        self.root.evaluate(cpu)
+    }
+
+    pub fn is_true<TKeyboard>(&self, cpu: &CPU<TKeyboard>) -> bool
+    where
+        TKeyboard: Keyboard,
+    {
+        self.evaluate(cpu) != 0
+    }
+}
+
+impl fmt::Display for Expression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.source)
+    }
+}
+
+impl FromStr for Expression {
+    type Err = ExpressionError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parser = Parser { source: s, pos: 0 };
+        let root = parser.expression(0)?;
+
+        parser.skip_whitespace();
+        if parser.pos < s.len() {
+            return Err(parser.error("unexpected input"));
+        }
+
+        Ok(Expression {
+            source: s.trim().to_string(),
+            root,
+        })
+    }
+}
+
+struct Parser<'a> {
+    source: &'a str,
+    pos: usize,
+}
+
+impl Parser<'_> {
+    fn rest(&self) -> &str {
+        &self.source[self.pos..]
+    }
+
+    fn skip_whitespace(&mut self) {
+        let rest = self.rest();
+        self.pos += rest.len() - rest.trim_start().len();
+    }
+
+    fn error(&self, message: &str) -> ExpressionError {
+        ExpressionError {
+            column: self.source[..self.pos].chars().count() + 1,
+            message: message.to_string(),
+        }
+    }
+
+    /// Consumes the symbol if it comes next.
+    fn eat(&mut self, symbol: &str) -> bool {
+        self.skip_whitespace();
+        let found = self.rest().starts_with(symbol)
+            // `|` and `&` must not match the first half of `||` and `&&`
+            && !(symbol.len() == 1 && self.rest()[1..].starts_with(symbol) && symbol != "!");
+        if found {
+            self.pos += symbol.len();
+        }
+        found
+    }
+
+    fn expression(&mut self, level: usize) -> Result<Node, ExpressionError> {
+        let operators = match LEVELS.get(level) {
+            Some(operators) => operators,
+            None => return self.unary(),
+        };
+
+        let mut node = self.expression(level + 1)?;
+        'operators: loop {
+            for (symbol, op) in operators.iter() {
+                if self.eat(symbol) {
+                    let rhs = self.expression(level + 1)?;
+                    node = Node::Binary(*op, Box::new(node), Box::new(rhs));
+                    continue 'operators;
+                }
+            }
+            return Ok(node);
+        }
+    }
+
+    fn unary(&mut self) -> Result<Node, ExpressionError> {
+        if self.eat("!") {
+            return Ok(Node::Not(Box::new(self.unary()?)));
+        }
+        if self.eat("(") {
+            let node = self.expression(0)?;
+            return self.close(")", node);
+        }
+        if self.eat("[") {
+            let node = self.expression(0)?;
+            return self.close("]", Node::Memory(Box::new(node)));
+        }
+
+        self.skip_whitespace();
+        let word: &str = self
+            .rest()
+            .split(|c: char| !c.is_ascii_alphanumeric() && c != '_')
+            .next()
+            .unwrap_or("");
+        if word.is_empty() {
+            return Err(self.error("expected a number, register or `[`"));
+        }
+
+        let node = if word.starts_with(|c: char| c.is_ascii_digit()) {
+            let digits = word.replace('_', "");
+            let number = match digits.strip_prefix("0x") {
+                Some(hex) => i64::from_str_radix(hex, 16),
+                None => digits.parse(),
+            };
+            Node::Number(number.map_err(|_| self.error(&format!("invalid number `{}`", word)))?)
+        } else {
+            Node::Register(
+                Register::from_name(word)
+                    .ok_or_else(|| self.error(&format!("unknown register `{}`", word)))?,
+            )
+        };
+        self.pos += word.len();
+
+        Ok(node)
+    }
+
+    fn close(&mut self, symbol: &str, node: Node) -> Result<Node, ExpressionError> {
+        if self.eat(symbol) {
+            Ok(node)
+        } else {
+            Err(self.error(&format!("expected `{}`", symbol)))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Expression, Register};
+    use crate::{
+        cpu::CPU, display::Display, keyboard::dummy_keyboard::DummyKeyboard, memory::Memory,
+        quirks::Quirks,
+    };
+
+    fn get_cpu() -> CPU<DummyKeyboard> {
+        CPU::initialise(
+            Memory::initialise(),
+            Display::initialise(),
+            DummyKeyboard::initialise(),
+            Quirks::default(),
+        )
+    }
+
+    fn evaluate(source: &str, cpu: &CPU<DummyKeyboard>) -> i64 {
+        source
+            .parse::<Expression>()
+            .unwrap_or_else(|e| panic!("{}: {}", source, e))
+            .evaluate(cpu)
+    }
+
+    #[test]
+    fn should_parse_register_names() {
+        assert_eq!(Register::from_name("vA"), Some(Register::V(0xA)));
+        assert_eq!(Register::from_name("PC"), Some(Register::Pc));
+        assert_eq!(Register::from_name("v10"), None);
+        assert_eq!(Register::from_name("vg"), None);
+    }
+
+    #[test]
+    fn should_evaluate_registers_and_memory() {
+        let mut cpu = get_cpu();
+        cpu.v[5] = 0x20;
+        cpu.vi = 0x310;
+        cpu.delay_timer = 3;
+        cpu.memory.data[0x310] = 0x7F;
+
+        assert_eq!(evaluate("V5 == 0x20", &cpu), 1);
+        assert_eq!(evaluate("v5 != 32", &cpu), 0);
+        assert_eq!(evaluate("I >= 0x300 && I < 0x340", &cpu), 1);
+        assert_eq!(evaluate("[I] & 0xF0", &cpu), 0x70);
+        assert_eq!(evaluate("[I + 1] || DT > 2", &cpu), 1);
+        assert_eq!(evaluate("!(SP == 0) | ST", &cpu), 0);
+        assert_eq!(evaluate("V5 - 1 - 1", &cpu), 0x1E);
+        assert_eq!(evaluate("0x7FFFFFFFFFFFFFFF + 1", &cpu), i64::MIN);
+        assert_eq!(evaluate("0 - 0x7FFFFFFFFFFFFFFF - 2", &cpu), i64::MAX);
+    }
+
+    #[test]
+    fn should_report_where_parsing_failed() {
+        let error = "V5 == ".parse::<Expression>().unwrap_err();
+        assert_eq!(error.column, 7);
+
+        let error = "V5 == VX".parse::<Expression>().unwrap_err();
+        assert_eq!(error.to_string(), "column 7: unknown register `VX`");
+
+        assert!("(V5".parse::<Expression>().is_err());
+        assert!("V5 V6".parse::<Expression>().is_err());
+    }
+
+    #[test]
+    fn should_display_the_source() {
+        let expression: Expression = " V0 == 1 ".parse().unwrap();
+        assert_eq!(expression.to_string(), "V0 == 1");
+    }
+}
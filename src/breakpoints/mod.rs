@@ -0,0 +1,462 @@
+//! Breakpoints that stop a running program: at an address, when a condition becomes true, or
+//! when a watched range of memory is read, written or executed.
+//!
+//! [`Breakpoints::check`] is called before each instruction runs, and reports the first
+//! breakpoint that fired. Read and write watchpoints rely on the accesses [`Memory`] records,
+//! so [`Memory::record_accesses`] has to be enabled while any are set.
+//!
+//! [`Memory`]: crate::memory::Memory
+//! [`Memory::record_accesses`]: crate::memory::Memory::record_accesses
+
+pub mod expression;
+
+use std::{collections::BTreeMap, fmt};
+
+use crate::{
+    cpu::CPU,
+    keyboard::Keyboard,
+    memory::{Access, MemoryAccess},
+};
+
+pub use expression::{Expression, ExpressionError, Register};
+
+/// A range of memory watched for one kind of access.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Watchpoint {
+    pub address: usize,
+    pub len: usize,
+    pub access: Access,
+}
+
+impl Watchpoint {
+    fn contains(&self, address: usize) -> bool {
+        (self.address..self.address.saturating_add(self.len)).contains(&address)
+    }
+}
+
+/// What makes a breakpoint fire.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Trigger {
+    /// Before the instruction at the address runs.
+    Address(u16),
+    /// When the expression becomes true.
+    Condition(Expression),
+    Watch(Watchpoint),
+}
+
+impl fmt::Display for Trigger {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Trigger::Address(address) => write!(f, "break 0x{:03X}", address),
+            Trigger::Condition(expression) => write!(f, "break if {}", expression),
+            Trigger::Watch(watch) => {
+                let access = match watch.access {
+                    Access::Read => "read",
+                    Access::Write => "write",
+                    Access::Execute => "exec",
+                };
+                write!(f, "watch {} 0x{:03X} {}", access, watch.address, watch.len)
+            }
+        }
+    }
+}
+
+/// A breakpoint, with an optional condition that must also hold for it to fire.
+#[derive(Debug, Clone)]
+pub struct Breakpoint {
+    id: usize,
+    trigger: Trigger,
+    condition: Option<Expression>,
+
+    // Whether a `Condition` trigger was true at the last check, so it fires once per change
+    was_true: bool,
+}
+
+impl Breakpoint {
+    pub fn id(&self) -> usize {
+        self.id
+    }
+
+    pub fn trigger(&self) -> &Trigger {
+        &self.trigger
+    }
+
+    pub fn condition(&self) -> Option<&Expression> {
+        self.condition.as_ref()
+    }
+}
+
+impl fmt::Display for Breakpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.id, self.trigger)?;
+        if let Some(condition) = &self.condition {
+            write!(f, " if {}", condition)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Why a breakpoint fired.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Cause {
+    Address(u16),
+    Condition(Expression),
+    Access(MemoryAccess),
+}
+
+/// The breakpoint that fired, and why.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Hit {
+    pub id: usize,
+    pub cause: Cause,
+}
+
+impl fmt::Display for Hit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.cause {
+            Cause::Address(address) => write!(f, "Breakpoint {} at 0x{:03X}", self.id, address),
+            Cause::Condition(expression) => {
+                write!(f, "Breakpoint {}, {} is true", self.id, expression)
+            }
+            Cause::Access(access) => {
+                write!(f, "Watchpoint {}, ", self.id)?;
+                match access.access {
+                    Access::Read => write!(
+                        f,
+                        "read 0x{:02X} from 0x{:03X}",
+                        access.value, access.address
+                    ),
+                    Access::Write => {
+                        write!(
+                            f,
+                            "wrote 0x{:02X} to 0x{:03X}",
+                            access.value, access.address
+                        )
+                    }
+                    Access::Execute => write!(f, "executing 0x{:03X}", access.address),
+                }
+            }
+        }
+    }
+}
+
+/// The set of breakpoints, numbered in the order they were added.
+#[derive(Debug, Clone, Default)]
+pub struct Breakpoints {
+    breakpoints: BTreeMap<usize, Breakpoint>,
+    next_id: usize,
+
+    // Set when resuming, so the breakpoint that just stopped the program doesn't stop it again
+    resuming: bool,
+}
+
+impl Breakpoints {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a breakpoint, returning its id.
+    pub fn add(&mut self, trigger: Trigger, condition: Option<Expression>) -> usize {
+        self.next_id += 1;
+        let id = self.next_id;
+        self.breakpoints.insert(
+            id,
+            Breakpoint {
+                id,
+                trigger,
+                condition,
+                was_true: false,
+            },
+        );
+
+        id
+    }
+
+    pub fn get(&self, id: usize) -> Option<&Breakpoint> {
+        self.breakpoints.get(&id)
+    }
+
+    pub fn remove(&mut self, id: usize) -> bool {
+        self.breakpoints.remove(&id).is_some()
+    }
+
+    pub fn clear(&mut self) {
+        self.breakpoints.clear();
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.breakpoints.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Breakpoint> {
+        self.breakpoints.values()
+    }
+
+    /// Whether there is an address breakpoint at the address, conditional or not.
+    pub fn has_address(&self, address: u16) -> bool {
+        self.iter()
+            .any(|breakpoint| breakpoint.trigger == Trigger::Address(address))
+    }
+
+    /// Whether any watchpoint needs [`Memory::record_accesses`](crate::memory::Memory::record_accesses).
+    pub fn watches_memory(&self) -> bool {
+        self.iter().any(|breakpoint| {
+            matches!(
+                breakpoint.trigger,
+                Trigger::Watch(Watchpoint {
+                    access: Access::Read | Access::Write,
+                    ..
+                })
+            )
+        })
+    }
+
+    /// Lets the program run past a breakpoint at the current instruction on the next check.
+    pub fn resume(&mut self) {
+        self.resuming = true;
+    }
+
+    /// Checks the breakpoints before the next instruction runs, returning the first that fired.
+    /// Accesses the last instruction made to memory are taken from it, whether or not they
+    /// fire a watchpoint.
+    pub fn check<TKeyboard>(&mut self, cpu: &CPU<TKeyboard>) -> Option<Hit>
+    where
+        TKeyboard: Keyboard,
+    {
+        let accesses = cpu.memory.take_accesses();
+        let resuming = std::mem::take(&mut self.resuming);
+        let pc = cpu.pc;
+        let mut hit = None;
+
+        // Every condition is evaluated, even after a hit, to keep track of when they change
+        for breakpoint in self.breakpoints.values_mut() {
+            let cause = match &breakpoint.trigger {
+                Trigger::Address(address) if !resuming && *address == pc => {
+                    Some(Cause::Address(pc))
+                }
+                Trigger::Condition(expression) => {
+                    let is_true = expression.is_true(cpu);
+                    let became_true = is_true && !breakpoint.was_true;
+                    breakpoint.was_true = is_true;
+                    became_true.then(|| Cause::Condition(expression.clone()))
+                }
+                Trigger::Watch(watch) if watch.access == Access::Execute => {
+                    (!resuming && watch.contains(pc as usize)).then(|| {
+                        Cause::Access(MemoryAccess {
+                            address: pc as usize,
+                            access: Access::Execute,
+                            value: cpu.memory.data.get(pc as usize).copied().unwrap_or(0),
+                        })
+                    })
+                }
+                Trigger::Watch(watch) => accesses
+                    .iter()
+                    .find(|access| access.access == watch.access && watch.contains(access.address))
+                    .map(|access| Cause::Access(*access)),
+                _ => None,
+            };
+
+            let cause = cause.filter(|_| {
+                breakpoint
+                    .condition
+                    .as_ref()
+                    .is_none_or(|condition| condition.is_true(cpu))
+            });
+
+            if let (None, Some(cause)) = (&hit, cause) {
+                hit = Some(Hit {
+                    id: breakpoint.id,
+                    cause,
+                });
+            }
+        }
+
+        hit
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Breakpoints, Cause, Trigger, Watchpoint};
+    use crate::{
+        chip8::cpu_from_asm,
+        cpu::CPU,
+        keyboard::dummy_keyboard::DummyKeyboard,
+        memory::{Access, MemoryAccess},
+    };
+
+    // 0x200 LD V0, 0x10
+    // 0x202 ADD V0, 0x10
+    // 0x204 LD I, 0x300
+    // 0x206 LD [I], V0
+    // 0x208 LD V0, [I]
+    // 0x20A JP 0x20A
+    fn get_cpu() -> CPU<DummyKeyboard> {
+        cpu_from_asm(
+            "LD V0, 0x10\nADD V0, 0x10\nLD I, 0x300\nLD [I], V0\nLD V0, [I]\nloop: JP loop",
+        )
+    }
+
+    /// Runs instructions until a breakpoint fires, returning why and the pc it stopped at.
+    fn run(breakpoints: &mut Breakpoints, cpu: &mut CPU<DummyKeyboard>) -> (Cause, u16) {
+        for _ in 0..100 {
+            if let Some(hit) = breakpoints.check(cpu) {
+                return (hit.cause, cpu.pc);
+            }
+            cpu.execute_next_instruction().unwrap();
+        }
+        panic!("no breakpoint fired");
+    }
+
+    #[test]
+    fn should_record_memory_accesses() {
+        let mut cpu = get_cpu();
+        cpu.memory.record_accesses(true);
+
+        for _ in 0..5 {
+            cpu.execute_next_instruction().unwrap();
+        }
+
+        // Fetching instructions isn't recorded as reading memory
+        assert_eq!(
+            cpu.memory.take_accesses(),
+            [
+                MemoryAccess {
+                    address: 0x300,
+                    access: Access::Write,
+                    value: 0x20
+                },
+                MemoryAccess {
+                    address: 0x300,
+                    access: Access::Read,
+                    value: 0x20
+                }
+            ]
+        );
+        assert!(cpu.memory.take_accesses().is_empty());
+    }
+
+    #[test]
+    fn should_stop_at_addresses_once_resumed() {
+        let mut cpu = get_cpu();
+        let mut breakpoints = Breakpoints::new();
+        let id = breakpoints.add(Trigger::Address(0x20A), None);
+
+        assert_eq!(
+            run(&mut breakpoints, &mut cpu),
+            (Cause::Address(0x20A), 0x20A)
+        );
+        assert_eq!(id, 1);
+
+        // The jump loops back to the breakpoint after resuming
+        breakpoints.resume();
+        assert!(breakpoints.check(&cpu).is_none());
+        cpu.execute_next_instruction().unwrap();
+        assert!(breakpoints.check(&cpu).is_some());
+    }
+
+    #[test]
+    fn should_stop_when_conditions_become_true() {
+        let mut cpu = get_cpu();
+        let mut breakpoints = Breakpoints::new();
+        let condition = "V0 == 0x20".parse().unwrap();
+        breakpoints.add(Trigger::Condition(condition), None);
+        let condition = "I >= 0x300 && I < 0x340".parse().unwrap();
+        breakpoints.add(Trigger::Condition(condition), None);
+
+        let (cause, pc) = run(&mut breakpoints, &mut cpu);
+        assert_eq!(pc, 0x204);
+        assert!(
+            matches!(cause, Cause::Condition(expression) if expression.to_string() == "V0 == 0x20")
+        );
+
+        let (cause, pc) = run(&mut breakpoints, &mut cpu);
+        assert_eq!(pc, 0x206);
+        assert!(
+            matches!(cause, Cause::Condition(expression) if expression.to_string().starts_with("I"))
+        );
+
+        // Neither fires again while they stay true
+        for _ in 0..4 {
+            cpu.execute_next_instruction().unwrap();
+            assert!(breakpoints.check(&cpu).is_none());
+        }
+    }
+
+    #[test]
+    fn should_stop_at_watched_accesses() {
+        let mut cpu = get_cpu();
+        cpu.memory.record_accesses(true);
+        let mut breakpoints = Breakpoints::new();
+        for access in [Access::Read, Access::Write] {
+            breakpoints.add(
+                Trigger::Watch(Watchpoint {
+                    address: 0x2FF,
+                    len: 2,
+                    access,
+                }),
+                None,
+            );
+        }
+
+        let (cause, pc) = run(&mut breakpoints, &mut cpu);
+        assert_eq!(pc, 0x208);
+        assert_eq!(
+            cause,
+            Cause::Access(MemoryAccess {
+                address: 0x300,
+                access: Access::Write,
+                value: 0x20
+            })
+        );
+
+        let hit = breakpoints.check(&cpu);
+        assert!(hit.is_none());
+        cpu.execute_next_instruction().unwrap();
+        let hit = breakpoints.check(&cpu).unwrap();
+        assert_eq!(hit.id, 1);
+        assert_eq!(hit.to_string(), "Watchpoint 1, read 0x20 from 0x300");
+    }
+
+    #[test]
+    fn should_watch_to_the_end_of_memory_with_a_huge_length() {
+        let mut cpu = get_cpu();
+        cpu.memory.record_accesses(true);
+        let mut breakpoints = Breakpoints::new();
+        breakpoints.add(
+            Trigger::Watch(Watchpoint {
+                address: 0x300,
+                len: usize::MAX,
+                access: Access::Write,
+            }),
+            None,
+        );
+
+        let (cause, pc) = run(&mut breakpoints, &mut cpu);
+        assert_eq!(pc, 0x208);
+        assert!(matches!(cause, Cause::Access(access) if access.address == 0x300));
+    }
+
+    #[test]
+    fn should_only_fire_when_the_condition_holds() {
+        let mut cpu = get_cpu();
+        let mut breakpoints = Breakpoints::new();
+        breakpoints.add(
+            Trigger::Watch(Watchpoint {
+                address: 0x200,
+                len: 0x10,
+                access: Access::Execute,
+            }),
+            Some("V0 == 0x20".parse().unwrap()),
+        );
+
+        let (cause, pc) = run(&mut breakpoints, &mut cpu);
+        assert_eq!(pc, 0x204);
+        assert!(matches!(cause, Cause::Access(access) if access.address == 0x204));
+        assert_eq!(
+            breakpoints.get(1).unwrap().to_string(),
+            "1: watch exec 0x200 16 if V0 == 0x20"
+        );
+    }
+}
@@ -0,0 +1,113 @@
+#[cfg(test)]
+use crate::{asm::Assembler, keyboard::dummy_keyboard::DummyKeyboard, rom::RomLoader};
+use crate::{
+    cpu::{StepOutcome, CPU},
+    display::Display,
+    error::Chip8Error,
+    keyboard::Keyboard,
+    memory::Memory,
+    quirks::Quirks,
+    rom::Rom,
+};
+
+/// A complete CHIP-8 machine.
+///
+/// This is the entry point for embedding the interpreter. It owns a [`CPU`]
+/// along with the [`Memory`], [`Display`] and keyboard attached to it.
+/// The underlying [`CPU`] is still reachable through [`Chip8::cpu`] and
+/// [`Chip8::cpu_mut`] for tooling that needs to poke at registers directly.
+#[derive(Debug)]
+pub struct Chip8<TKeyboard>
+where
+    TKeyboard: Keyboard,
+{
+    cpu: CPU<TKeyboard>,
+}
+
+impl<TKeyboard> Chip8<TKeyboard>
+where
+    TKeyboard: Keyboard,
+{
+    pub fn new(memory: Memory, display: Display, keyboard: TKeyboard, quirks: Quirks) -> Self {
+        Self {
+            cpu: CPU::initialise(memory, display, keyboard, quirks),
+        }
+    }
+
+    /// Creates a machine with the ROM loaded and the program counter at its load address.
+    pub fn from_rom(rom: &Rom, keyboard: TKeyboard, quirks: Quirks) -> Self {
+        let mut cpu = CPU::initialise(
+            Memory::initialise_with_rom(rom),
+            Display::initialise(),
+            keyboard,
+            quirks,
+        );
+        cpu.pc = rom.load_address() as u16;
+
+        Self { cpu }
+    }
+
+    /// Executes a single instruction at the current program counter.
+    pub fn step(&mut self) -> Result<StepOutcome, Chip8Error> {
+        self.cpu.execute_next_instruction()
+    }
+
+    /// Decrements the delay and sound timers by one tick and starts a new frame.
+    ///
+    /// Timers are expected to be ticked at 60Hz.
+    pub fn tick_timers(&mut self) {
+        self.cpu.vblank();
+        self.cpu.decrement_delay_timer();
+        self.cpu.decrement_sound_timer();
+    }
+
+    pub fn cpu(&self) -> &CPU<TKeyboard> {
+        &self.cpu
+    }
+
+    pub fn cpu_mut(&mut self) -> &mut CPU<TKeyboard> {
+        &mut self.cpu
+    }
+
+    pub fn memory(&self) -> &Memory {
+        &self.cpu.memory
+    }
+
+    pub fn memory_mut(&mut self) -> &mut Memory {
+        &mut self.cpu.memory
+    }
+
+    pub fn display(&self) -> &Display {
+        &self.cpu.display
+    }
+
+    pub fn keyboard(&self) -> &TKeyboard {
+        &self.cpu.keyboard
+    }
+
+    pub fn keyboard_mut(&mut self) -> &mut TKeyboard {
+        &mut self.cpu.keyboard
+    }
+
+    pub fn into_cpu(self) -> CPU<TKeyboard> {
+        self.cpu
+    }
+}
+
+impl<TKeyboard> From<CPU<TKeyboard>> for Chip8<TKeyboard>
+where
+    TKeyboard: Keyboard,
+{
+    fn from(cpu: CPU<TKeyboard>) -> Self {
+        Self { cpu }
+    }
+}
+
+/// Assembles a program and loads it into a CPU with the default quirks, ready to run it.
+#[cfg(test)]
+pub(crate) fn cpu_from_asm(source: &str) -> CPU<DummyKeyboard> {
+    let program = Assembler::new().assemble(source).unwrap();
+    let rom = RomLoader::new().load_bytes(program.bytes()).unwrap();
+
+    Chip8::from_rom(&rom, DummyKeyboard::initialise(), Quirks::default()).into_cpu()
+}
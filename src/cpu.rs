@@ -1,19 +1,52 @@
-use std::num::Wrapping;
-
-use rand::Rng;
+use std::{convert::TryFrom, num::Wrapping};
 
 use crate::{
-    display::{DebugDisplay, Display},
-    keyboard::Keyboard,
-    memory::Memory,
+    display::{DebugDisplay, Display, HIRES_SCREEN_HEIGHT, HIRES_SCREEN_WIDTH},
+    error::Chip8Error,
+    instructions::Instruction,
+    keyboard::{KeyEvent, Keyboard},
+    memory::{Memory, BIG_DIGIT_SPRITES_OFFSET},
     opcode::OpCode,
+    quirks::Quirks,
+    rng::{RandomSource, SeededRng},
+    state::{Snapshot, StateError},
 };
 
-pub(crate) const DELAY_INCREMENT: u32 = 16;
-pub(crate) const SOUND_DELAY_INCREMENT: u32 = 16;
+// VF is used as a flag by arithmetic, shift and draw instructions
+pub const FLAG_REGISTER: usize = 0xF;
+
+// The pitch register value that plays the audio pattern at 4000 samples per second
+pub const DEFAULT_PITCH: u8 = 64;
+
+/// The result of successfully executing a single step of the CPU.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StepOutcome {
+    /// The instruction was executed.
+    Executed(Instruction),
+    /// A draw is blocked by the display wait quirk until the next frame starts.
+    /// The program counter still points at the draw.
+    WaitingForVBlank,
+    /// The program has exited with `00FD`, nothing was executed.
+    Exited,
+    /// `FX0A` is waiting for a key to be pressed and released, nothing was executed.
+    WaitingForKey,
+}
+
+/// Whether the CPU is able to execute instructions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CpuState {
+    Running,
+    /// The program exited with the SUPER-CHIP `00FD` instruction.
+    Exited,
+    /// `FX0A` is waiting for a key to be pressed and released, which it will store in
+    /// `V{register}`. No instructions are fetched until then, but the timers keep running.
+    WaitingForKey {
+        register: u8,
+    },
+}
 
 #[derive(Debug)]
-pub(crate) struct CPU<TKeyboard>
+pub struct CPU<TKeyboard>
 where
     TKeyboard: Keyboard,
 {
@@ -21,12 +54,14 @@ where
     pub display: Display,
     pub keyboard: TKeyboard,
 
+    // Interpreter specific behaviours
+    pub quirks: Quirks,
+
     // General purpose addresses
+    // VF (`v[FLAG_REGISTER]`) doubles as the flag register and is written after the result
+    // of an instruction, so an instruction that targets VF ends up holding the flag.
     pub v: [u8; 0x10],
 
-    // Used for program flags
-    pub vf: u8,
-
     // Commonly used to store memory addresses
     pub vi: u16,
 
@@ -49,70 +84,186 @@ where
     // Stores currently executing address
     pub pc: u16,
 
-    // Point to the top of the stack
+    // The number of return addresses on the stack, which are in stack[0..sp]
     pub sp: u8,
 
     // Stores the address that should be returned to once a subroutine has finished execution
     // This gives Chip-8 a max nested subroutine level of 16
     pub stack: [u16; 16],
+
+    // XO-CHIP audio: a 128 bit sample played while the sound timer is active, and its pitch
+    pub audio_pattern: [u8; 0x10],
+    pub pitch: u8,
+
+    // SUPER-CHIP "RPL user flags", which lived in the HP-48 calculator's registers
+    // SUPER-CHIP has 8 of them, XO-CHIP extends this to 16
+    pub rpl_flags: [u8; 0x10],
+
+    state: CpuState,
+
+    // Set at the start of each frame, cleared by a draw when the display wait quirk is enabled
+    vblank: bool,
+
+    // The source of CXKK's random numbers, seeded from the OS unless a seed is given
+    rng: Box<dyn RandomSource>,
 }
 
 impl<TKeyboard> CPU<TKeyboard>
 where
     TKeyboard: Keyboard,
 {
-    pub fn initialise(memory: Memory, display: Display, keyboard: TKeyboard) -> Self {
+    pub fn initialise(
+        memory: Memory,
+        display: Display,
+        keyboard: TKeyboard,
+        quirks: Quirks,
+    ) -> Self {
         Self {
             memory,
             display,
             keyboard,
+            quirks,
             v: [0; 0x10],
-            vf: 0x0,
             vi: 0x0,
             delay_timer: 0x0,
             sound_timer: 0x0,
             pc: 0x200,
             sp: 0x0,
             stack: [0x0; 16],
+            audio_pattern: [0x0; 0x10],
+            pitch: DEFAULT_PITCH,
+            rpl_flags: [0x0; 0x10],
+            state: CpuState::Running,
+            vblank: false,
+            rng: Box::new(SeededRng::from_entropy()),
         }
     }
 
-    pub fn execute_next_instruction(&mut self) {
-        if let Some(op) = self.get_op() {
-            self.execute_op(op);
-        }
+    /// Restarts the random numbers `CXKK` generates from `seed`, so a run can be repeated.
+    pub fn seed_rng(&mut self, seed: u64) {
+        self.rng = Box::new(SeededRng::new(seed));
     }
 
-    pub fn execute(&mut self) {
-        while let Some(op) = self.get_op() {
-            self.execute_op(op);
+    /// Replaces where `CXKK` gets its random numbers from, e.g. with a scripted sequence.
+    pub fn set_rng(&mut self, rng: Box<dyn RandomSource>) {
+        self.rng = rng;
+    }
+
+    /// Fetches, decodes and executes the instruction at the program counter.
+    ///
+    /// If the instruction faults the program counter is left pointing at it.
+    pub fn execute_next_instruction(&mut self) -> Result<StepOutcome, Chip8Error> {
+        match self.state {
+            CpuState::Running => {}
+            CpuState::Exited => return Ok(StepOutcome::Exited),
+            CpuState::WaitingForKey { register } => {
+                if !self.receive_key(register) {
+                    return Ok(StepOutcome::WaitingForKey);
+                }
+            }
+        }
+
+        let pc = self.pc;
+
+        let outcome = self
+            .fetch_instruction()
+            .and_then(|ins| self.execute_instruction(pc, ins));
+
+        if outcome.is_err() {
+            self.pc = pc;
         }
+
+        outcome
     }
 
-    fn execute_op(&mut self, op: OpCode) {
-        match op.raw() {
-            0x0000..=0x0ff => match op.raw() {
-                0x00e0 => self.cls(),
-                0x00ee => self.ret(),
-                _ => {} // no-op
-            },
-            0x1000..=0x1fff => self.jp(&op),
-            0x2000..=0x2fff => self.call(&op),
-            0x3000..=0x3fff => self.se(&op),
-            0x4000..=0x4fff => self.sne(&op),
-            0x5000..=0x5fff => self.se_r(&op),
-            0x6000..=0x6fff => self.ld_r(&op),
-            0x7000..=0x7fff => self.add(&op),
-            0x8000..=0x8fff => self.ops_8(&op),
-            0x9000..=0x9fff => self.sne_xy(&op),
-            0xa000..=0xafff => self.ld_i(&op),
-            0xb000..=0xbfff => self.jp_v0(&op),
-            0xc000..=0xcfff => self.rnd(&op),
-            0xd000..=0xdfff => self.drw(&op),
-            0xe000..=0xefff => self.ops_e(&op),
-            0xf000..=0xffff => self.ops_f(&op),
-            _ => {} // no-op
+    /// Executes instructions until the program exits, waits for a key, or the CPU faults.
+    pub fn execute(&mut self) -> Result<(), Chip8Error> {
+        while self.state == CpuState::Running {
+            self.execute_next_instruction()?;
+        }
+
+        Ok(())
+    }
+
+    pub fn state(&self) -> CpuState {
+        self.state
+    }
+
+    fn execute_instruction(
+        &mut self,
+        pc: u16,
+        ins: Instruction,
+    ) -> Result<StepOutcome, Chip8Error> {
+        match ins {
+            Instruction::Sys { .. } => {} // Machine code routines are not supported by any modern interpreter
+            Instruction::Cls => self.cls(),
+            Instruction::Ret => self.ret()?,
+            Instruction::ScrollUp { n } => self.scu(n),
+            Instruction::ScrollDown { n } => self.scd(n),
+            Instruction::ScrollRight => self.scr(),
+            Instruction::ScrollLeft => self.scl(),
+            Instruction::Exit => self.exit(),
+            Instruction::LoRes => self.low(),
+            Instruction::HiRes => self.high(),
+            Instruction::Jp { nnn } => self.jp(nnn),
+            Instruction::Call { nnn } => self.call(nnn)?,
+            Instruction::SeByte { x, kk } => self.se(x, kk)?,
+            Instruction::SneByte { x, kk } => self.sne(x, kk)?,
+            Instruction::SeXY { x, y } => self.se_r(x, y)?,
+            Instruction::StoreRange { x, y } => self.ld_mem_i_range(x, y)?,
+            Instruction::LoadRange { x, y } => self.ld_range_mem_i(x, y)?,
+            Instruction::LdByte { x, kk } => self.ld_r(x, kk),
+            Instruction::AddByte { x, kk } => self.add(x, kk),
+            Instruction::LdXY { x, y } => self.ld_xy(x, y),
+            Instruction::OrXY { x, y } => self.or_xy(x, y),
+            Instruction::AndXY { x, y } => self.and_xy(x, y),
+            Instruction::XorXY { x, y } => self.xor_xy(x, y),
+            Instruction::AddXY { x, y } => self.add_xy(x, y),
+            Instruction::SubXY { x, y } => self.sub_xy(x, y),
+            Instruction::ShrXY { x, y } => self.shr(x, y),
+            Instruction::SubnXY { x, y } => self.subn_yx(x, y),
+            Instruction::ShlXY { x, y } => self.shl(x, y),
+            Instruction::SneXY { x, y } => self.sne_xy(x, y)?,
+            Instruction::LdI { nnn } => self.ld_i(nnn),
+            Instruction::JpV0 { nnn } => self.jp_v0(nnn),
+            Instruction::Rnd { x, kk } => self.rnd(x, kk),
+            Instruction::Draw { x, y, n } => {
+                if !self.ready_to_draw() {
+                    return Ok(StepOutcome::WaitingForVBlank);
+                }
+
+                self.drw(x, y, n)?
+            }
+            Instruction::LdILong { nnnn } => self.ld_i_long(nnnn),
+            Instruction::Plane { n } => self.plane(n),
+            Instruction::LdAudio => self.ld_audio()?,
+            Instruction::Skp { x } => self.skp_vx(x)?,
+            Instruction::Sknp { x } => self.sknp_vx(x)?,
+            Instruction::LdXDt { x } => self.ld_vx_dt(x),
+            Instruction::LdXKey { x } => self.ld_vx_k(x),
+            Instruction::LdDtX { x } => self.ld_dt(x),
+            Instruction::LdStX { x } => self.ld_st(x),
+            Instruction::AddIX { x } => self.add_i(x),
+            Instruction::LdFont { x } => self.ld_f_vx(x),
+            Instruction::LdHiFont { x } => self.ld_hf_vx(x),
+            Instruction::LdPitch { x } => self.ld_pitch(x),
+            Instruction::LdBcd { x } => self.ld_b(x)?,
+            Instruction::StoreRegs { x } => self.ld_mem_i_vx(x)?,
+            Instruction::LoadRegs { x } => self.ld_mem_vx_i(x)?,
+            Instruction::StoreFlags { x } => self.ld_r_vx(x),
+            Instruction::LoadFlags { x } => self.ld_vx_r(x),
+            Instruction::Unknown(raw) => return Err(Chip8Error::InvalidOpcode { pc, raw }),
         };
+
+        Ok(StepOutcome::Executed(ins))
+    }
+
+    /// Signals the start of a new 60Hz frame.
+    ///
+    /// Front-ends call this once per frame alongside the timers; it releases a draw
+    /// that is blocked by the display wait quirk.
+    pub fn vblank(&mut self) {
+        self.vblank = true;
     }
 
     pub fn decrement_delay_timer(&mut self) {
@@ -127,26 +278,159 @@ where
         }
     }
 
+    /// The rate in samples per second that the audio pattern is played back at.
+    pub fn audio_playback_rate(&self) -> f64 {
+        4000.0 * 2f64.powf((self.pitch as f64 - DEFAULT_PITCH as f64) / 48.0)
+    }
+
+    /// Captures the registers, timers, stack, held keys, RNG, memory and screen.
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            rom_sha1: self.memory.rom_sha1(),
+            v: self.v,
+            vi: self.vi,
+            pc: self.pc,
+            sp: self.sp,
+            stack: self.stack,
+            rpl_flags: self.rpl_flags,
+            audio_pattern: self.audio_pattern,
+            pitch: self.pitch,
+            delay_timer: self.delay_timer,
+            sound_timer: self.sound_timer,
+            vblank: self.vblank,
+            exited: self.state == CpuState::Exited,
+            waiting_for_key: match self.state {
+                CpuState::WaitingForKey { register } => Some(register),
+                _ => None,
+            },
+            keys: self.keyboard.pressed(),
+            rng_state: self.rng.save(),
+            memory: self.memory.data.to_vec(),
+            screen_width: self.display.width(),
+            screen_height: self.display.height(),
+            screen: self.display.pixels().collect(),
+            selected_planes: self.display.selected_planes(),
+        }
+    }
+
+    /// Puts the machine back into the state it was in when `snapshot` was taken.
+    ///
+    /// The snapshot must have been taken with the same ROM loaded. Nothing is changed if the
+    /// snapshot can't be restored.
+    pub fn restore(&mut self, snapshot: &Snapshot) -> Result<(), StateError> {
+        if snapshot.rom_sha1 != self.memory.rom_sha1() {
+            return Err(StateError::RomMismatch);
+        }
+
+        let hires = snapshot.screen_width == HIRES_SCREEN_WIDTH
+            && snapshot.screen_height == HIRES_SCREEN_HEIGHT;
+        let mut display = Display::initialise();
+        display.set_hires(hires);
+
+        if snapshot.sp as usize > self.stack.len()
+            || snapshot
+                .waiting_for_key
+                .is_some_and(|register| register as usize >= self.v.len())
+        {
+            return Err(StateError::InvalidRegisters);
+        }
+
+        if snapshot.memory.len() != self.memory.data.len()
+            || snapshot.screen_width != display.width()
+            || snapshot.screen_height != display.height()
+            || snapshot.screen.len() != display.width() * display.height()
+        {
+            return Err(StateError::SizeMismatch);
+        }
+
+        self.v = snapshot.v;
+        self.vi = snapshot.vi;
+        self.pc = snapshot.pc;
+        self.sp = snapshot.sp;
+        self.stack = snapshot.stack;
+        self.rpl_flags = snapshot.rpl_flags;
+        self.audio_pattern = snapshot.audio_pattern;
+        self.pitch = snapshot.pitch;
+        self.state = match (snapshot.exited, snapshot.waiting_for_key) {
+            (true, _) => CpuState::Exited,
+            (false, Some(register)) => CpuState::WaitingForKey { register },
+            (false, None) => CpuState::Running,
+        };
+        self.delay_timer = snapshot.delay_timer;
+        self.sound_timer = snapshot.sound_timer;
+        self.vblank = snapshot.vblank;
+
+        self.keyboard.reset(snapshot.keys);
+        if let Some(state) = snapshot.rng_state {
+            self.rng.restore(state);
+        }
+
+        self.memory.data.copy_from_slice(&snapshot.memory);
+        for (i, pixel) in snapshot.screen.iter().enumerate() {
+            let (x, y) = (i % snapshot.screen_width, i / snapshot.screen_width);
+
+            display.screen[y][x] = pixel & 0b01 != 0;
+            display.second_plane[y][x] = pixel & 0b10 != 0;
+        }
+        display.select_planes(snapshot.selected_planes);
+        self.display = display;
+
+        Ok(())
+    }
+
+    /// Serialises the whole machine into the save state format. See [`crate::state`].
+    pub fn save_state(&self) -> Vec<u8> {
+        self.snapshot().to_bytes()
+    }
+
+    /// Restores a save state written by [`CPU::save_state`].
+    pub fn load_state(&mut self, bytes: &[u8]) -> Result<(), StateError> {
+        self.restore(&Snapshot::from_bytes(bytes)?)
+    }
+
     /// Get the next opcode
     ///
     /// Opcodes are constructed from 2 bytes, the most significant first (big endian)
     /// We fetch the next two values in memory and construct the opcode by shifting and bitwise AND'ing the bytes.
-    fn get_op(&mut self) -> Option<OpCode> {
-        let a = (self.memory.get(self.pc as _) as u16) << 8;
-        self.pc += 1;
-        let b = self.memory.get(self.pc as _) as u16;
-        self.pc += 1;
+    fn get_op(&mut self) -> Result<OpCode, Chip8Error> {
+        let a = (self.memory.fetch(self.pc as _)? as u16) << 8;
+        let b = self.memory.fetch(self.pc as usize + 1)? as u16;
+        self.advance_pc(2)?;
 
-        Some(OpCode::new((a | b) as u16))
+        Ok(OpCode::new(a | b))
     }
 
-    fn ops_e(&mut self, op: &OpCode) {
-        if op.raw() & 0x00FF == 0x9E {
-            self.skp_vx(op);
-        } else if op.raw() & 0x00FF == 0xA1 {
-            self.sknp_vx(op);
-        } else {
+    /// Moves the program counter forward, faulting if it would pass the end of XO-CHIP's 64KB
+    /// address space rather than wrapping around to 0.
+    fn advance_pc(&mut self, by: u16) -> Result<(), Chip8Error> {
+        let next = self.pc as usize + by as usize;
+        self.pc = u16::try_from(next).map_err(|_| Chip8Error::MemoryOutOfBounds { addr: next })?;
+
+        Ok(())
+    }
+
+    /// Fetches the instruction at the program counter, and the operand after it for
+    /// 4 byte instructions.
+    fn fetch_instruction(&mut self) -> Result<Instruction, Chip8Error> {
+        let op = self.get_op()?;
+
+        if Instruction::is_long(op.raw()) {
+            let operand = self.get_op()?;
+            return Ok(Instruction::decode_long(op.raw(), operand.raw()));
         }
+
+        Ok(op.decode())
+    }
+
+    /// Moves the program counter past the next instruction, which may be 4 bytes long.
+    fn skip_next_instruction(&mut self) -> Result<(), Chip8Error> {
+        let pc = self.pc as usize;
+        let next = match (self.memory.fetch(pc), self.memory.fetch(pc + 1)) {
+            (Ok(a), Ok(b)) => u16::from_be_bytes([a, b]),
+            _ => 0,
+        };
+
+        self.advance_pc(if Instruction::is_long(next) { 4 } else { 2 })
     }
 
     /// Asks the Display to clear the screen
@@ -154,386 +438,480 @@ where
         self.display.clear_screen();
     }
 
+    /// Scrolls the display up by n pixels
+    fn scu(&mut self, n: u8) {
+        self.display.scroll_up(n as usize);
+    }
+
+    /// Scrolls the display down by n pixels
+    fn scd(&mut self, n: u8) {
+        self.display.scroll_down(n as usize);
+    }
+
+    /// Scrolls the display right by 4 pixels
+    fn scr(&mut self) {
+        self.display.scroll_right(4);
+    }
+
+    /// Scrolls the display left by 4 pixels
+    fn scl(&mut self) {
+        self.display.scroll_left(4);
+    }
+
+    /// Stops the program, no further instructions are executed
+    fn exit(&mut self) {
+        self.state = CpuState::Exited;
+    }
+
+    /// Switches the display to the 64x32 low resolution mode
+    fn low(&mut self) {
+        self.display.set_hires(false);
+    }
+
+    /// Switches the display to the 128x64 high resolution mode
+    fn high(&mut self) {
+        self.display.set_hires(true);
+    }
+
     /// Used to return from a subroutine
-    fn ret(&mut self) {
-        match self.stack.get(self.sp as usize) {
-            Some(addr) => {
-                self.pc = *addr;
-                self.sp -= 1;
-            }
-            None => {}
+    fn ret(&mut self) -> Result<(), Chip8Error> {
+        if self.sp == 0 {
+            return Err(Chip8Error::StackUnderflow);
         }
+
+        self.sp -= 1;
+        self.pc = self.stack[self.sp as usize];
+
+        Ok(())
     }
 
     /// Jumps to a given memory location
-    fn jp(&mut self, op: &OpCode) {
-        self.pc = op.nnn();
+    fn jp(&mut self, nnn: u16) {
+        self.pc = nnn;
     }
 
     /// Calls the subroutine at the specific address
-    fn call(&mut self, op: &OpCode) {
-        self.sp += 1;
+    fn call(&mut self, nnn: u16) -> Result<(), Chip8Error> {
+        if self.sp as usize >= self.stack.len() {
+            return Err(Chip8Error::StackOverflow);
+        }
+
         self.stack[self.sp as usize] = self.pc;
-        self.pc = op.nnn();
+        self.sp += 1;
+        self.pc = nnn;
+
+        Ok(())
     }
 
     /// Skip if a register value is equal to a given byte
     ///
     /// Given a op of `0x3[X][KK]` if the  value of `V[X] == [KK]` skip the next instruction
-    fn se(&mut self, op: &OpCode) {
-        if (self.v[op.x() as usize]) == op.kk() {
-            self.pc += 2;
+    fn se(&mut self, x: u8, kk: u8) -> Result<(), Chip8Error> {
+        if self.v[x as usize] == kk {
+            self.skip_next_instruction()?;
         }
+
+        Ok(())
     }
 
     /// Skip if a register value is not equal to a given byte
     ///
-    /// Given a op of `0x3[X][KK]` if the  value of `V[X] != [KK]` skip the next instruction
-    fn sne(&mut self, op: &OpCode) {
-        if (self.v[op.x() as usize]) != op.kk() {
-            self.pc += 2;
+    /// Given a op of `0x4[X][KK]` if the  value of `V[X] != [KK]` skip the next instruction
+    fn sne(&mut self, x: u8, kk: u8) -> Result<(), Chip8Error> {
+        if self.v[x as usize] != kk {
+            self.skip_next_instruction()?;
         }
+
+        Ok(())
     }
 
     /// Skip if the register `Vx` == `Vy`
-    fn se_r(&mut self, op: &OpCode) {
-        if self.v[op.x() as usize] == self.v[op.y() as usize] {
-            self.pc += 2;
+    fn se_r(&mut self, x: u8, y: u8) -> Result<(), Chip8Error> {
+        if self.v[x as usize] == self.v[y as usize] {
+            self.skip_next_instruction()?;
         }
+
+        Ok(())
     }
 
     /// Loads the value `kk` into the register `Vx`
-    fn ld_r(&mut self, op: &OpCode) {
-        self.v[op.x() as usize] = op.kk();
+    fn ld_r(&mut self, x: u8, kk: u8) {
+        self.v[x as usize] = kk;
     }
 
     // Adds the value `kk` to the value in the register `Vx`
-    fn add(&mut self, op: &OpCode) {
-        self.v[op.x() as usize] = (Wrapping(self.v[op.x() as usize]) + Wrapping(op.kk())).0;
+    fn add(&mut self, x: u8, kk: u8) {
+        self.v[x as usize] = (Wrapping(self.v[x as usize]) + Wrapping(kk)).0;
     }
 
     /// Loads the value in the register `Vy` into the register `Vx`
-    fn ld_xy(&mut self, op: &OpCode) {
-        self.v[op.x() as usize] = self.v[op.y() as usize];
-    }
-
-    fn ops_8(&mut self, op: &OpCode) {
-        if op.raw() & 0x000F == 0x0 {
-            self.ld_xy(&op);
-        } else if op.raw() & 0x000F == 0x1 {
-            self.or_xy(&op);
-        } else if op.raw() & 0x000F == 0x2 {
-            self.and_xy(&op);
-        } else if op.raw() & 0x000F == 0x3 {
-            self.xor_xy(&op);
-        } else if op.raw() & 0x000F == 0x4 {
-            self.add_xy(&op);
-        } else if op.raw() & 0x000F == 0x5 {
-            self.sub_xy(&op);
-        } else if op.raw() & 0x000F == 0x6 {
-            self.shr(&op);
-        } else if op.raw() & 0x000F == 0x7 {
-            self.subn_yx(&op);
-        } else if op.raw() & 0x000F == 0xE {
-            self.shl(&op);
-        } else {
+    fn ld_xy(&mut self, x: u8, y: u8) {
+        self.v[x as usize] = self.v[y as usize];
+    }
+
+    /// Set I = location of sprite for digit Vx.
+    fn ld_f_vx(&mut self, x: u8) {
+        self.vi = (self.v[x as usize] << 4) as u16;
+    }
+
+    /// Set I = location of the big 10-byte sprite for digit Vx.
+    fn ld_hf_vx(&mut self, x: u8) {
+        let digit = (self.v[x as usize] & 0xF) as usize;
+
+        self.vi = (BIG_DIGIT_SPRITES_OFFSET + digit * 10) as u16;
+    }
+
+    /// Sets Vi to a 16-bit address.
+    fn ld_i_long(&mut self, nnnn: u16) {
+        self.vi = nnnn;
+    }
+
+    /// Selects the display planes that are drawn to, cleared and scrolled.
+    fn plane(&mut self, n: u8) {
+        self.display.select_planes(n);
+    }
+
+    /// Loads the 16 byte audio pattern starting at the address in I.
+    fn ld_audio(&mut self) -> Result<(), Chip8Error> {
+        for (i, byte) in self.audio_pattern.iter_mut().enumerate() {
+            *byte = self.memory.get(self.vi as usize + i)?;
         }
+
+        Ok(())
+    }
+
+    /// Sets the audio pitch register to Vx.
+    fn ld_pitch(&mut self, x: u8) {
+        self.pitch = self.v[x as usize];
     }
 
-    fn ops_f(&mut self, op: &OpCode) {
-        if op.raw() & 0x00FF == 0x07 {
-            self.ld_vx_dt(&op);
-        } else if op.raw() & 0x00FF == 0x15 {
-            self.ld_dt(&op);
-        } else if op.raw() & 0x00FF == 0x18 {
-            self.ld_st(&op);
-        } else if op.raw() & 0x00FF == 0x0A {
-            self.ld_vx_k(&op);
-        } else if op.raw() & 0x00FF == 0x1E {
-            self.add_i(&op);
-        } else if op.raw() & 0x00FF == 0x29 {
-            self.ld_f_vx(&op);
-        } else if op.raw() & 0x00FF == 0x33 {
-            self.ld_b(&op);
-        } else if op.raw() & 0x00FF == 0x55 {
-            self.ld_mem_i_vx(&op);
-        } else if op.raw() & 0x00FF == 0x65 {
-            self.ld_mem_vx_i(&op);
+    /// Stores registers Vx through Vy in memory starting at the address in I, without changing I.
+    ///
+    /// The registers are stored in reverse order if x > y.
+    fn ld_mem_i_range(&mut self, x: u8, y: u8) -> Result<(), Chip8Error> {
+        for (offset, register) in register_range(x, y).enumerate() {
+            self.memory
+                .write(self.vi as usize + offset, self.v[register])?;
         }
+
+        Ok(())
     }
 
-    /// Set I = location of sprite for digit Vx.
-    fn ld_f_vx(&mut self, op: &OpCode) {
-        self.vi = (self.v[op.x() as usize] << 4) as u16;
+    /// Reads registers Vx through Vy from memory starting at the address in I, without changing I.
+    ///
+    /// The registers are read in reverse order if x > y.
+    fn ld_range_mem_i(&mut self, x: u8, y: u8) -> Result<(), Chip8Error> {
+        for (offset, register) in register_range(x, y).enumerate() {
+            self.v[register] = self.memory.get(self.vi as usize + offset)?;
+        }
+
+        Ok(())
+    }
+
+    /// Stores registers V0 through Vx in the RPL user flags.
+    fn ld_r_vx(&mut self, max: u8) {
+        let count = max as usize + 1;
+
+        self.rpl_flags[..count].copy_from_slice(&self.v[..count]);
     }
 
-    fn ld_vx_k(&mut self, op: &OpCode) {
-        // Scuffed implementation of ld_vx_k, would ideally register some kind of callback on the keyboard?
-        // It could utilise Minifb callbacks maybe?
-        // In the meantime lets just get the first key we recognise as being pressed kekw
-        let curr_key = self.keyboard.get_current_keydowns().first();
+    /// Reads registers V0 through Vx from the RPL user flags.
+    fn ld_vx_r(&mut self, max: u8) {
+        let count = max as usize + 1;
+
+        self.v[..count].copy_from_slice(&self.rpl_flags[..count]);
+    }
+
+    /// Waits for a key to be pressed and released, then stores it in Vx.
+    fn ld_vx_k(&mut self, x: u8) {
+        // Only keys released from now on count, not ones released before the wait began
+        while self.keyboard.poll_event().is_some() {}
+
+        self.state = CpuState::WaitingForKey { register: x };
+    }
 
-        match curr_key {
-            Some(k) => {
-                self.v[op.x() as usize] = *k;
+    /// Completes an `FX0A` if a key has been released since it began waiting, as the COSMAC
+    /// VIP does. Returns whether it completed.
+    fn receive_key(&mut self, register: u8) -> bool {
+        while let Some(event) = self.keyboard.poll_event() {
+            if let KeyEvent::Up(key) = event {
+                self.v[register as usize] = key;
+                self.state = CpuState::Running;
+                return true;
             }
-            None => {}
-        };
+        }
+
+        false
     }
 
-    fn ld_vx_dt(&mut self, op: &OpCode) {
-        self.v[op.x() as usize] = self.delay_timer;
+    fn ld_vx_dt(&mut self, x: u8) {
+        self.v[x as usize] = self.delay_timer;
     }
 
-    fn ld_st(&mut self, op: &OpCode) {
-        self.sound_timer = self.v[op.x() as usize];
+    fn ld_st(&mut self, x: u8) {
+        self.sound_timer = self.v[x as usize];
     }
 
-    fn ld_dt(&mut self, op: &OpCode) {
-        self.delay_timer = self.v[op.x() as usize];
+    fn ld_dt(&mut self, x: u8) {
+        self.delay_timer = self.v[x as usize];
     }
 
     /// The values of I and Vx are added, and the results are stored in I.
-    fn add_i(&mut self, op: &OpCode) {
-        self.vi += self.v[op.x() as usize] as u16;
+    fn add_i(&mut self, x: u8) {
+        self.vi = self.vi.wrapping_add(self.v[x as usize] as u16);
     }
 
     /// Stores the BCD representation of the number in Vx in memory locations Vi, Vi + 1, Vi + 2
-    fn ld_b(&mut self, op: &OpCode) {
-        let value = self.v[op.x() as usize];
+    fn ld_b(&mut self, x: u8) -> Result<(), Chip8Error> {
+        let value = self.v[x as usize];
         let hund = (value / 100) % 10;
         let tens = (value / 10) % 10;
         let ones = value % 10;
 
-        let addr = self.vi;
-        self.memory.write(addr as usize, hund);
-        self.memory.write((addr + 1) as usize, tens);
-        self.memory.write((addr + 2) as usize, ones);
+        let addr = self.vi as usize;
+        self.memory.write(addr, hund)?;
+        self.memory.write(addr + 1, tens)?;
+        self.memory.write(addr + 2, ones)?;
+
+        Ok(())
     }
 
     /// Stores the values of the register in the range 0..=Vx starting at the address pointed at by Vi.
-    fn ld_mem_i_vx(&mut self, op: &OpCode) {
-        let max = op.x();
-
+    ///
+    /// With the load/store quirk enabled Vi is left pointing after the last register stored.
+    fn ld_mem_i_vx(&mut self, max: u8) -> Result<(), Chip8Error> {
         for x in 0..=max {
             let val = self.v[x as usize];
-            self.memory.write((self.vi + (x as u16)) as usize, val);
+            self.memory.write(self.vi as usize + x as usize, val)?;
+        }
+
+        if self.quirks.load_store_increments_i {
+            self.vi = self.vi.wrapping_add(max as u16 + 1);
         }
+
+        Ok(())
     }
 
     /// Read into registers V0 through Vx from memory starting at location I.
-    fn ld_mem_vx_i(&mut self, op: &OpCode) {
-        let max = op.x();
-
+    ///
+    /// With the load/store quirk enabled Vi is left pointing after the last register loaded.
+    fn ld_mem_vx_i(&mut self, max: u8) -> Result<(), Chip8Error> {
         for x in 0..=max {
-            self.v[x as usize] = self.memory.get((self.vi + (x as u16)) as usize);
+            self.v[x as usize] = self.memory.get(self.vi as usize + x as usize)?;
         }
+
+        if self.quirks.load_store_increments_i {
+            self.vi = self.vi.wrapping_add(max as u16 + 1);
+        }
+
+        Ok(())
     }
 
     /// Performs a bitwise OR operation on the values in the registers `Vx` and `Vy` and stores the result in `Vx`
-    fn or_xy(&mut self, op: &OpCode) {
-        self.v[op.x() as usize] |= self.v[op.y() as usize];
+    fn or_xy(&mut self, x: u8, y: u8) {
+        self.v[x as usize] |= self.v[y as usize];
+
+        if self.quirks.vf_reset {
+            self.v[FLAG_REGISTER] = 0;
+        }
     }
 
     /// Performs a bitwise AND operation on the values in the registers `Vx` and `Vy` and stores the result in `Vx`
-    fn and_xy(&mut self, op: &OpCode) {
-        self.v[op.x() as usize] &= self.v[op.y() as usize];
+    fn and_xy(&mut self, x: u8, y: u8) {
+        self.v[x as usize] &= self.v[y as usize];
+
+        if self.quirks.vf_reset {
+            self.v[FLAG_REGISTER] = 0;
+        }
     }
 
     /// Performs a bitwise XOR operation on the values in the registers `Vx` and `Vy` and stores the result in `Vx`
-    fn xor_xy(&mut self, op: &OpCode) {
-        self.v[op.x() as usize] ^= self.v[op.y() as usize];
+    fn xor_xy(&mut self, x: u8, y: u8) {
+        self.v[x as usize] ^= self.v[y as usize];
+
+        if self.quirks.vf_reset {
+            self.v[FLAG_REGISTER] = 0;
+        }
     }
 
     /// Adds the values of registers `Vx` and `Vy` and stores the lower byte into `Vx`
     ///
     /// If the result of the addition is greater than 255, the VF flag is set to 1 else 0;
-    fn add_xy(&mut self, op: &OpCode) {
-        let x = op.x();
-        let y = op.y();
-
-        let vx = self.v[x as usize] as u16;
-        let vy = self.v[y as usize] as u16;
-
-        let res = vx + vy;
-
-        if res > 255 {
-            self.vf = 1;
-        } else {
-            self.vf = 0;
-        }
+    fn add_xy(&mut self, x: u8, y: u8) {
+        let (res, carry) = self.v[x as usize].overflowing_add(self.v[y as usize]);
 
-        self.v[x as usize] = (res & 0x00FF) as u8;
+        self.v[x as usize] = res;
+        self.v[FLAG_REGISTER] = carry as u8;
     }
 
     /// Subtracts the value of the register `Vy` from `Vx` and stores it in `Vx`
     ///
-    /// If `Vx` > `Vy` set VF to 1, else 0
-    fn sub_xy(&mut self, op: &OpCode) {
-        let x = op.x();
-        let y = op.y();
-
-        let vx = self.v[x as usize] as u16;
-        let vy = self.v[y as usize] as u16;
-
-        let res = (Wrapping(vx) - Wrapping(vy)).0;
-
-        if vx > vy {
-            self.vf = 0;
-        } else {
-            self.vf = 1;
-        }
+    /// If `Vx` >= `Vy` (no borrow occurs) set VF to 1, else 0
+    fn sub_xy(&mut self, x: u8, y: u8) {
+        let (res, borrow) = self.v[x as usize].overflowing_sub(self.v[y as usize]);
 
-        self.v[x as usize] = (res & 0x00FF) as u8;
+        self.v[x as usize] = res;
+        self.v[FLAG_REGISTER] = !borrow as u8;
     }
 
-    /// Subtracts the value of the register `Vy` from `Vx` and stores it in `Vx`
+    /// Subtracts the value of the register `Vx` from `Vy` and stores it in `Vx`
     ///
-    /// If `Vx` > `Vy` set VF to 1, else 0
-    fn subn_yx(&mut self, op: &OpCode) {
-        let x = op.x();
-        let y = op.y();
-
-        let vx = self.v[x as usize] as u16;
-        let vy = self.v[y as usize] as u16;
-
-        let res = vy - vx;
+    /// If `Vy` >= `Vx` (no borrow occurs) set VF to 1, else 0
+    fn subn_yx(&mut self, x: u8, y: u8) {
+        let (res, borrow) = self.v[y as usize].overflowing_sub(self.v[x as usize]);
 
-        if vy > vx {
-            self.vf = 1;
-        } else {
-            self.vf = 0;
-        }
-
-        self.v[x as usize] = (res & 0x00FF) as u8;
+        self.v[x as usize] = res;
+        self.v[FLAG_REGISTER] = !borrow as u8;
     }
 
     /// Shifts the value of `Vx` right by 1 bit
     ///
     /// If the least-significant bit of `Vx` is 1, then VF is set to 1, else 0.
-    fn shr(&mut self, op: &OpCode) {
-        // TODO: Allow the additional `Vy` register to be shifted optionally
-
-        let x = op.x();
-        let vx = self.v[x as usize];
+    /// With the shift quirk enabled `Vy` is shifted instead and the result stored in `Vx`.
+    fn shr(&mut self, x: u8, y: u8) {
+        let vx = self.shift_operand(x, y);
 
         self.v[x as usize] = vx >> 1;
-
-        if vx & 0x0001 == 1 {
-            self.vf = 1;
-        } else {
-            self.vf = 0;
-        }
+        self.v[FLAG_REGISTER] = vx & 0x01;
     }
 
     /// Shifts the value of `Vx` left by 1 bit
     ///
     /// If the most-significant bit of `Vx` is 1, then VF is set to 1, else 0.
-    fn shl(&mut self, op: &OpCode) {
-        // TODO: Allow the additional `Vy` register to be shifted optionally
-
-        let x = op.x();
-        let vx = self.v[x as usize];
+    /// With the shift quirk enabled `Vy` is shifted instead and the result stored in `Vx`.
+    fn shl(&mut self, x: u8, y: u8) {
+        let vx = self.shift_operand(x, y);
 
         self.v[x as usize] = vx << 1;
+        self.v[FLAG_REGISTER] = vx >> 7;
+    }
 
-        if vx & 0x0001 == 1 {
-            self.vf = 1;
+    fn shift_operand(&self, x: u8, y: u8) -> u8 {
+        if self.quirks.shift_uses_vy {
+            self.v[y as usize]
         } else {
-            self.vf = 0;
+            self.v[x as usize]
         }
     }
 
     /// Skip if a register value is not equal to the value of another register
     ///
-    /// Given a op of `0x3[X][Y]0` if the  value of `V[X] != V[Y]` skip the next instruction
-    fn sne_xy(&mut self, op: &OpCode) {
-        let x = op.x();
-        let y = op.y();
-
+    /// Given a op of `0x9[X][Y]0` if the  value of `V[X] != V[Y]` skip the next instruction
+    fn sne_xy(&mut self, x: u8, y: u8) -> Result<(), Chip8Error> {
         let vx = self.v[x as usize];
         let vy = self.v[y as usize];
 
         if vx != vy {
-            self.pc += 2;
+            self.skip_next_instruction()?;
         }
+
+        Ok(())
     }
 
     /// Sets Vi to the value of NNN.
-    fn ld_i(&mut self, op: &OpCode) {
-        let addr = op.nnn();
-
-        self.vi = addr;
+    fn ld_i(&mut self, nnn: u16) {
+        self.vi = nnn;
     }
 
     /// Jump to the location NNN + V0
-    fn jp_v0(&mut self, op: &OpCode) {
-        let addr = op.nnn();
+    ///
+    /// With the jump quirk enabled this is treated as `BXNN` and jumps to XNN + Vx.
+    fn jp_v0(&mut self, nnn: u16) {
+        let x = if self.quirks.jump_uses_vx {
+            (nnn >> 8) as usize
+        } else {
+            0
+        };
 
-        self.pc = (self.v[0] as u16) + addr;
+        self.pc = (self.v[x] as u16) + nnn;
     }
 
     /// Sets Vx to a random byte AND'd with KK.
-    fn rnd(&mut self, op: &OpCode) {
-        let x = op.x();
-        let kk = op.kk();
-
-        let rand_number: u8 = rand::thread_rng().gen_range(0..=255);
+    fn rnd(&mut self, x: u8, kk: u8) {
+        let rand_number = self.rng.next_byte();
         let res = kk & rand_number;
 
         self.v[x as usize] = res;
     }
 
     /// Display n-byte sprite starting at memory location I at (Vx, Vy), set VF = collision.
-    /// If a sprite is going to be rendered outside the window boundries, then it will wrap around the display.
-    fn drw(&mut self, op: &OpCode) {
-        let x = self.v[op.x() as usize];
-        let y = self.v[op.y() as usize];
-        let n = (op.raw() & 0x000F) as u8;
+    /// If a sprite is going to be rendered outside the window boundries, then it will wrap around the display
+    /// unless the clipping quirk is enabled.
+    /// When n is 0 a 16x16 SUPER-CHIP sprite is drawn from the 32 bytes at I.
+    fn drw(&mut self, x: u8, y: u8, n: u8) -> Result<(), Chip8Error> {
+        let x = self.v[x as usize];
+        let y = self.v[y as usize];
+
+        // XO-CHIP draws the sprite to each selected plane, with the data for each plane in turn
+        let len = if n == 0 { 32 } else { n as usize };
+        let mut sprite = vec![0; len * self.display.selected_plane_count()];
+
+        for (i, byte) in sprite.iter_mut().enumerate() {
+            *byte = self.memory.get(self.vi as usize + i)?;
+        }
 
-        let mut sprite = vec![0; n as _];
+        let location = (&(x as usize), &(y as usize));
+        let collision = if n == 0 {
+            self.display
+                .display_wide_sprite(location, &sprite, self.quirks.clip_sprites)
+        } else {
+            self.display
+                .display_sprite(location, &sprite, self.quirks.clip_sprites)
+        };
+        self.v[FLAG_REGISTER] = collision as u8;
+
+        Ok(())
+    }
 
-        for i in 0..n {
-            sprite[i as usize] = self.memory.get((self.vi + (i as u16)) as _);
+    /// Whether a draw can go ahead in the current frame.
+    ///
+    /// With the display wait quirk enabled only one draw is allowed per frame; a blocked draw
+    /// rewinds the program counter so that it is re-executed once the next frame starts.
+    fn ready_to_draw(&mut self) -> bool {
+        if !self.quirks.display_wait {
+            return true;
         }
 
-        // dbg!(&sprite);
-        // dbg!(x as usize, y as usize);
-        // println!("sprite: {:#02x?}", sprite);
-        // println!("x: {:#02x?}", x as usize);
-        // println!("y: {:#02x?}", y as usize);
+        if !self.vblank {
+            self.pc -= 2;
+            return false;
+        }
 
-        self.vf = self
-            .display
-            .display_sprite((&(x as usize), &(y as usize)), &sprite) as u8;
+        self.vblank = false;
+        true
     }
 
     /// Skip the next instruction if the key corresponding to the value currently in Vx is pressed.
-    fn skp_vx(&mut self, op: &OpCode) {
-        let vx = self.v[op.x() as usize];
+    fn skp_vx(&mut self, x: u8) -> Result<(), Chip8Error> {
+        let vx = self.v[x as usize];
 
-        if self
-            .keyboard
-            .get_current_keydowns()
-            .iter()
-            .any(|&k| k == vx)
-        {
-            self.pc += 2;
+        if self.keyboard.is_pressed(vx) {
+            self.skip_next_instruction()?;
         }
+
+        Ok(())
     }
 
     /// Skips the next instruction if the key corresponding to the value currently in Vx is not pressed.
-    fn sknp_vx(&mut self, op: &OpCode) {
-        let vx = self.v[op.x() as usize];
+    fn sknp_vx(&mut self, x: u8) -> Result<(), Chip8Error> {
+        let vx = self.v[x as usize];
 
-        if self
-            .keyboard
-            .get_current_keydowns()
-            .iter()
-            .all(|&k| k != vx)
-        {
-            self.pc += 2;
+        if !self.keyboard.is_pressed(vx) {
+            self.skip_next_instruction()?;
         }
+
+        Ok(())
+    }
+}
+
+/// The registers from Vx to Vy inclusive, counting down if x > y.
+fn register_range(x: u8, y: u8) -> Box<dyn Iterator<Item = usize>> {
+    let (x, y) = (x as usize, y as usize);
+
+    if x <= y {
+        Box::new(x..=y)
+    } else {
+        Box::new((y..=x).rev())
     }
 }
 
@@ -544,9 +922,9 @@ where
     fn view_state(&self) {
         println!("Registers:");
         println!("{:#x?}", self.v);
-        println!("vf: {:#x?} vi: {:#x?}", self.vf, self.vi);
+        println!("vf: {:#x?} vi: {:#x?}", self.v[FLAG_REGISTER], self.vi);
         println!("pc: {:#x?} sp: {:#x?}", self.pc, self.sp);
-        println!("");
+        println!();
     }
 }
 
@@ -554,17 +932,33 @@ where
 mod tests {
     use crate::{
         display::{DebugDisplay, Display},
+        error::Chip8Error,
+        instructions::Instruction,
         keyboard::dummy_keyboard::DummyKeyboard,
-        memory::Memory,
+        memory::{Memory, XO_CHIP_MAX_MEM},
+        quirks::Quirks,
+        rng::ScriptedRng,
+        rom::RomLoader,
+        state::StateError,
     };
 
-    use super::CPU;
+    use super::{CpuState, StepOutcome, CPU};
 
     fn get_cpu() -> CPU<DummyKeyboard> {
         CPU::initialise(
             Memory::initialise(),
             Display::initialise(),
             DummyKeyboard::initialise(),
+            Quirks::default(),
+        )
+    }
+
+    fn get_xo_cpu() -> CPU<DummyKeyboard> {
+        CPU::initialise(
+            Memory::with_size(XO_CHIP_MAX_MEM),
+            Display::initialise(),
+            DummyKeyboard::initialise(),
+            Quirks::xo_chip(),
         )
     }
 
@@ -576,106 +970,260 @@ mod tests {
         cpu
     }
 
-    #[test]
-    fn ret() {
-        let mut cpu = load_new_cpu_with_instruction(0x00EE);
-
-        cpu.sp = 1;
-        cpu.stack[1] = 0x500;
-
-        cpu.execute_next_instruction();
+    fn load_new_cpu_with_quirks(op: u16, quirks: Quirks) -> CPU<DummyKeyboard> {
+        let mut cpu = load_new_cpu_with_instruction(op);
+        cpu.quirks = quirks;
 
-        assert_eq!(cpu.sp, 0);
-        assert_eq!(cpu.pc, 0x500);
+        cpu
     }
 
     #[test]
-    fn cls() {
-        // Do this some other time when we actually have shit that displays
+    fn execute_next_instruction_returns_decoded_instruction() {
+        let mut cpu = load_new_cpu_with_instruction(0x6066);
+
+        let outcome = cpu.execute_next_instruction();
+
+        assert_eq!(
+            outcome,
+            Ok(StepOutcome::Executed(Instruction::LdByte {
+                x: 0,
+                kk: 0x66
+            }))
+        );
     }
 
     #[test]
-    fn jp() {
-        let mut cpu = load_new_cpu_with_instruction(0x1666);
+    fn unknown_opcode_faults() {
+        let mut cpu = load_new_cpu_with_instruction(0x5061);
 
-        cpu.execute_next_instruction();
+        let outcome = cpu.execute_next_instruction();
 
-        assert_eq!(cpu.pc, 0x666);
+        assert_eq!(
+            outcome,
+            Err(Chip8Error::InvalidOpcode {
+                pc: 0x200,
+                raw: 0x5061
+            })
+        );
+        assert_eq!(cpu.pc, 0x200);
     }
 
     #[test]
-    fn call() {
+    fn call_faults_when_stack_is_full() {
         let mut cpu = load_new_cpu_with_instruction(0x2666);
+        cpu.sp = 16;
 
-        cpu.execute_next_instruction();
-
-        assert_eq!(cpu.sp, 1);
-        assert_eq!(cpu.stack[cpu.sp as usize], 0x202);
-        assert_eq!(cpu.pc, 0x666);
+        assert_eq!(
+            cpu.execute_next_instruction(),
+            Err(Chip8Error::StackOverflow)
+        );
+        assert_eq!(cpu.sp, 16);
+        assert_eq!(cpu.pc, 0x200);
     }
 
     #[test]
-    fn se_jumps_when_equal() {
-        let mut cpu = load_new_cpu_with_instruction(0x3000);
+    fn call_nests_sixteen_deep() {
+        let mut cpu = load_new_cpu_with_instruction(0x2200);
 
-        cpu.execute_next_instruction();
+        for _ in 0..16 {
+            cpu.execute_next_instruction().unwrap();
+        }
+        assert_eq!(cpu.sp, 16);
+        assert_eq!(cpu.stack, [0x202; 16]);
 
-        assert_eq!(cpu.pc, 0x204);
+        assert_eq!(
+            cpu.execute_next_instruction(),
+            Err(Chip8Error::StackOverflow)
+        );
     }
 
     #[test]
-    fn se_doesnt_jump_when_not_equal() {
-        let mut cpu = load_new_cpu_with_instruction(0x3066);
-
-        cpu.execute_next_instruction();
+    fn ret_faults_outside_subroutine() {
+        let mut cpu = load_new_cpu_with_instruction(0x00EE);
 
-        assert_eq!(cpu.pc, 0x202);
+        assert_eq!(
+            cpu.execute_next_instruction(),
+            Err(Chip8Error::StackUnderflow)
+        );
+        assert_eq!(cpu.sp, 0);
     }
 
     #[test]
-    fn sne_jumps_when_not_equal() {
-        let mut cpu = load_new_cpu_with_instruction(0x4066);
-
-        cpu.execute_next_instruction();
+    fn fetch_faults_past_end_of_memory() {
+        let mut cpu = get_cpu();
+        cpu.pc = 0xFFF;
 
-        assert_eq!(cpu.pc, 0x204);
+        assert_eq!(
+            cpu.execute_next_instruction(),
+            Err(Chip8Error::MemoryOutOfBounds { addr: 0x1000 })
+        );
+        assert_eq!(cpu.pc, 0xFFF);
     }
 
     #[test]
-    fn sne_doesnt_jump_when_equal() {
-        let mut cpu = load_new_cpu_with_instruction(0x4000);
-
-        cpu.execute_next_instruction();
+    fn fetch_faults_instead_of_wrapping_at_top_of_xo_chip_memory() {
+        let mut cpu = get_xo_cpu();
+        cpu.pc = 0xFFFE;
+        cpu.memory.insert_instruction(0xFFFE, 0x6066);
 
-        assert_eq!(cpu.pc, 0x202);
+        assert_eq!(
+            cpu.execute_next_instruction(),
+            Err(Chip8Error::MemoryOutOfBounds { addr: 0x10000 })
+        );
+        assert_eq!(cpu.pc, 0xFFFE);
+        assert_eq!(cpu.v[0], 0);
     }
 
     #[test]
-    fn se_r_jumps_when_equal() {
-        let mut cpu = load_new_cpu_with_instruction(0x5000);
-
-        cpu.execute_next_instruction();
+    fn skip_faults_instead_of_wrapping_at_top_of_xo_chip_memory() {
+        let mut cpu = get_xo_cpu();
+        cpu.pc = 0xFFFC;
+        cpu.memory.insert_instruction(0xFFFC, 0x3000);
+        cpu.memory.insert_instruction(0xFFFE, 0x6066);
 
-        assert_eq!(cpu.pc, 0x204);
+        assert_eq!(
+            cpu.execute_next_instruction(),
+            Err(Chip8Error::MemoryOutOfBounds { addr: 0x10000 })
+        );
+        assert_eq!(cpu.pc, 0xFFFC);
     }
 
     #[test]
-    fn se_r_doesnt_jump_when_not_equal() {
-        let mut cpu = load_new_cpu_with_instruction(0x5010);
-        cpu.v[1] = 1;
+    fn skip_over_long_instruction_faults_at_top_of_xo_chip_memory() {
+        let mut cpu = get_xo_cpu();
+        cpu.pc = 0xFFFA;
+        cpu.memory.insert_instruction(0xFFFA, 0x3000);
+        cpu.memory.insert_instruction(0xFFFC, 0xF000);
 
-        cpu.execute_next_instruction();
+        assert_eq!(
+            cpu.execute_next_instruction(),
+            Err(Chip8Error::MemoryOutOfBounds { addr: 0x10000 })
+        );
+        assert_eq!(cpu.pc, 0xFFFA);
+    }
 
-        assert_eq!(cpu.pc, 0x202);
+    #[test]
+    fn ld_mem_i_vx_faults_past_end_of_memory() {
+        let mut cpu = load_new_cpu_with_instruction(0xF255);
+        cpu.vi = 0xFFE;
+
+        assert_eq!(
+            cpu.execute_next_instruction(),
+            Err(Chip8Error::MemoryOutOfBounds { addr: 0x1000 })
+        );
     }
 
     #[test]
-    fn ld_r() {
-        let mut cpu = load_new_cpu_with_instruction(0x6066);
+    fn add_i_wraps_instead_of_overflowing() {
+        let mut cpu = load_new_cpu_with_instruction(0xF01E);
+        cpu.vi = 0xFFFF;
+        cpu.v[0] = 0x2;
 
-        cpu.execute_next_instruction();
+        cpu.execute_next_instruction().unwrap();
 
-        assert_eq!(cpu.v[0], 0x66);
+        assert_eq!(cpu.vi, 0x1);
+    }
+
+    #[test]
+    fn ret() {
+        let mut cpu = load_new_cpu_with_instruction(0x00EE);
+
+        cpu.sp = 1;
+        cpu.stack[0] = 0x500;
+
+        cpu.execute_next_instruction().unwrap();
+
+        assert_eq!(cpu.sp, 0);
+        assert_eq!(cpu.pc, 0x500);
+    }
+
+    #[test]
+    fn cls() {
+        // Do this some other time when we actually have shit that displays
+    }
+
+    #[test]
+    fn jp() {
+        let mut cpu = load_new_cpu_with_instruction(0x1666);
+
+        cpu.execute_next_instruction().unwrap();
+
+        assert_eq!(cpu.pc, 0x666);
+    }
+
+    #[test]
+    fn call() {
+        let mut cpu = load_new_cpu_with_instruction(0x2666);
+
+        cpu.execute_next_instruction().unwrap();
+
+        assert_eq!(cpu.sp, 1);
+        assert_eq!(cpu.stack[0], 0x202);
+        assert_eq!(cpu.pc, 0x666);
+    }
+
+    #[test]
+    fn se_jumps_when_equal() {
+        let mut cpu = load_new_cpu_with_instruction(0x3000);
+
+        cpu.execute_next_instruction().unwrap();
+
+        assert_eq!(cpu.pc, 0x204);
+    }
+
+    #[test]
+    fn se_doesnt_jump_when_not_equal() {
+        let mut cpu = load_new_cpu_with_instruction(0x3066);
+
+        cpu.execute_next_instruction().unwrap();
+
+        assert_eq!(cpu.pc, 0x202);
+    }
+
+    #[test]
+    fn sne_jumps_when_not_equal() {
+        let mut cpu = load_new_cpu_with_instruction(0x4066);
+
+        cpu.execute_next_instruction().unwrap();
+
+        assert_eq!(cpu.pc, 0x204);
+    }
+
+    #[test]
+    fn sne_doesnt_jump_when_equal() {
+        let mut cpu = load_new_cpu_with_instruction(0x4000);
+
+        cpu.execute_next_instruction().unwrap();
+
+        assert_eq!(cpu.pc, 0x202);
+    }
+
+    #[test]
+    fn se_r_jumps_when_equal() {
+        let mut cpu = load_new_cpu_with_instruction(0x5000);
+
+        cpu.execute_next_instruction().unwrap();
+
+        assert_eq!(cpu.pc, 0x204);
+    }
+
+    #[test]
+    fn se_r_doesnt_jump_when_not_equal() {
+        let mut cpu = load_new_cpu_with_instruction(0x5010);
+        cpu.v[1] = 1;
+
+        cpu.execute_next_instruction().unwrap();
+
+        assert_eq!(cpu.pc, 0x202);
+    }
+
+    #[test]
+    fn ld_r() {
+        let mut cpu = load_new_cpu_with_instruction(0x6066);
+
+        cpu.execute_next_instruction().unwrap();
+
+        assert_eq!(cpu.v[0], 0x66);
     }
 
     #[test]
@@ -683,7 +1231,7 @@ mod tests {
         let mut cpu = load_new_cpu_with_instruction(0x7066);
 
         cpu.v[0] = 0x10;
-        cpu.execute_next_instruction();
+        cpu.execute_next_instruction().unwrap();
 
         assert_eq!(cpu.v[0], 0x76);
     }
@@ -693,7 +1241,7 @@ mod tests {
         let mut cpu = load_new_cpu_with_instruction(0x8010);
 
         cpu.v[1] = 0x10;
-        cpu.execute_next_instruction();
+        cpu.execute_next_instruction().unwrap();
 
         assert_eq!(cpu.v[0], 0x10);
     }
@@ -704,7 +1252,7 @@ mod tests {
         cpu.v[0] = 0x01;
         cpu.v[1] = 0x10;
 
-        cpu.execute_next_instruction();
+        cpu.execute_next_instruction().unwrap();
 
         assert_eq!(cpu.v[0], 0x11);
     }
@@ -715,7 +1263,7 @@ mod tests {
         cpu.v[0] = 0x11;
         cpu.v[1] = 0x11;
 
-        cpu.execute_next_instruction();
+        cpu.execute_next_instruction().unwrap();
 
         assert_eq!(cpu.v[0], 0x11);
     }
@@ -726,7 +1274,7 @@ mod tests {
         cpu.v[0] = 0x01;
         cpu.v[1] = 0x10;
 
-        cpu.execute_next_instruction();
+        cpu.execute_next_instruction().unwrap();
 
         assert_eq!(cpu.v[0], 0x00);
     }
@@ -737,7 +1285,7 @@ mod tests {
         cpu.v[0] = 0x11;
         cpu.v[1] = 0x11;
 
-        cpu.execute_next_instruction();
+        cpu.execute_next_instruction().unwrap();
 
         assert_eq!(cpu.v[0], 0x00);
     }
@@ -748,7 +1296,7 @@ mod tests {
         cpu.v[0] = 0x01;
         cpu.v[1] = 0x10;
 
-        cpu.execute_next_instruction();
+        cpu.execute_next_instruction().unwrap();
 
         assert_eq!(cpu.v[0], 0x11);
     }
@@ -759,10 +1307,10 @@ mod tests {
         cpu.v[0] = 0xF0;
         cpu.v[1] = 0x0F;
 
-        cpu.execute_next_instruction();
+        cpu.execute_next_instruction().unwrap();
 
         assert_eq!(cpu.v[0], 0xFF);
-        assert_eq!(cpu.vf, 0x0);
+        assert_eq!(cpu.v[0xF], 0x0);
     }
 
     #[test]
@@ -771,50 +1319,112 @@ mod tests {
         cpu.v[0] = 0xFF;
         cpu.v[1] = 0x01;
 
-        cpu.execute_next_instruction();
+        cpu.execute_next_instruction().unwrap();
 
         assert_eq!(cpu.v[0], 0x0);
-        assert_eq!(cpu.vf, 0x1);
+        assert_eq!(cpu.v[0xF], 0x1);
     }
 
-    // TODO: Define what should occur when overflow subtraction occurs in the CPU?
-    // Doesnt seem to be defined in the spec very clearly.
+    #[test]
+    fn add_xy_vf_as_destination_holds_flag() {
+        let mut cpu = load_new_cpu_with_instruction(0x8F04);
+        cpu.v[0xF] = 0xFF;
+        cpu.v[0] = 0x02;
+
+        cpu.execute_next_instruction().unwrap();
 
-    // #[test]
-    // fn sub_xy_no_borrow() {
-    //     let mut cpu = load_new_cpu_with_instruction(0x8015);
-    //     cpu.v[0] = 0xF0;
-    //     cpu.v[1] = 0x10;
+        assert_eq!(cpu.v[0xF], 0x1);
+    }
 
-    //     cpu.execute_next_instruction();
+    #[test]
+    fn add_xy_vf_as_source() {
+        let mut cpu = load_new_cpu_with_instruction(0x80F4);
+        cpu.v[0] = 0x10;
+        cpu.v[0xF] = 0x01;
 
-    //     assert_eq!(cpu.v[0], 0xE0);
-    //     assert_eq!(cpu.vf, 0x1);
-    // }
+        cpu.execute_next_instruction().unwrap();
 
-    // #[test]
-    // fn sub_xy_borrow() {
-    //     let mut cpu = load_new_cpu_with_instruction(0x8015);
-    //     cpu.v[0] = 0x10;
-    //     cpu.v[1] = 0x20;
+        assert_eq!(cpu.v[0], 0x11);
+        assert_eq!(cpu.v[0xF], 0x0);
+    }
 
-    //     cpu.execute_next_instruction();
+    #[test]
+    fn sub_xy_no_borrow() {
+        let mut cpu = load_new_cpu_with_instruction(0x8015);
+        cpu.v[0] = 0xF0;
+        cpu.v[1] = 0x10;
 
-    //     assert_eq!(cpu.v[0], 0x0);
-    //     assert_eq!(cpu.vf, 0x0);
-    // }
+        cpu.execute_next_instruction().unwrap();
 
-    // TODO: Same as previous sub_xy tests
-    // Need to define behavior more fully.
-    // #[test]
-    // fn subn() {}
+        assert_eq!(cpu.v[0], 0xE0);
+        assert_eq!(cpu.v[0xF], 0x1);
+    }
+
+    #[test]
+    fn sub_xy_no_borrow_when_equal() {
+        let mut cpu = load_new_cpu_with_instruction(0x8015);
+        cpu.v[0] = 0x10;
+        cpu.v[1] = 0x10;
+
+        cpu.execute_next_instruction().unwrap();
+
+        assert_eq!(cpu.v[0], 0x0);
+        assert_eq!(cpu.v[0xF], 0x1);
+    }
+
+    #[test]
+    fn sub_xy_borrow() {
+        let mut cpu = load_new_cpu_with_instruction(0x8015);
+        cpu.v[0] = 0x10;
+        cpu.v[1] = 0x20;
+
+        cpu.execute_next_instruction().unwrap();
+
+        assert_eq!(cpu.v[0], 0xF0);
+        assert_eq!(cpu.v[0xF], 0x0);
+    }
+
+    #[test]
+    fn sub_xy_vf_as_destination_holds_flag() {
+        let mut cpu = load_new_cpu_with_instruction(0x8F05);
+        cpu.v[0xF] = 0x10;
+        cpu.v[0] = 0x20;
+
+        cpu.execute_next_instruction().unwrap();
+
+        assert_eq!(cpu.v[0xF], 0x0);
+    }
+
+    #[test]
+    fn subn() {
+        let mut cpu = load_new_cpu_with_instruction(0x8017);
+        cpu.v[0] = 0x10;
+        cpu.v[1] = 0xF0;
+
+        cpu.execute_next_instruction().unwrap();
+
+        assert_eq!(cpu.v[0], 0xE0);
+        assert_eq!(cpu.v[0xF], 0x1);
+    }
+
+    #[test]
+    fn subn_borrow() {
+        let mut cpu = load_new_cpu_with_instruction(0x8017);
+        cpu.v[0] = 0x20;
+        cpu.v[1] = 0x10;
+
+        cpu.execute_next_instruction().unwrap();
+
+        assert_eq!(cpu.v[0], 0xF0);
+        assert_eq!(cpu.v[0xF], 0x0);
+    }
 
     #[test]
     fn shr() {
         let mut cpu = load_new_cpu_with_instruction(0x8006);
         cpu.v[0] = 0x08;
 
-        cpu.execute_next_instruction();
+        cpu.execute_next_instruction().unwrap();
 
         assert_eq!(cpu.v[0], 0x4);
     }
@@ -824,35 +1434,62 @@ mod tests {
         let mut cpu = load_new_cpu_with_instruction(0x8006);
         cpu.v[0] = 0x09;
 
-        cpu.execute_next_instruction();
+        cpu.execute_next_instruction().unwrap();
 
         assert_eq!(cpu.v[0], 0x4);
-        assert_eq!(cpu.vf, 0x1);
+        assert_eq!(cpu.v[0xF], 0x1);
     }
 
-    // TODO: Define SHL behavior when Vx > 128
-    // Again, more behavior that im unsure what is actually expected
-    // SHL when used on a numbe > 128 will result in a 8-bit number overflow
-    // What should happen here? shitcan the bits? use set it to 255?
-    // #[test]
-    // fn shl() {
-    //     let mut cpu = load_new_cpu_with_instruction(0x800E);
-    //     cpu.v[0] = 0x08;
+    #[test]
+    fn shr_vf_as_destination_holds_flag() {
+        let mut cpu = load_new_cpu_with_instruction(0x8F06);
+        cpu.v[0xF] = 0x08;
 
-    //     cpu.execute_next_instruction();
+        cpu.execute_next_instruction().unwrap();
 
-    //     assert_eq!(cpu.v[0], 0x10);
-    // }
+        assert_eq!(cpu.v[0xF], 0x0);
+    }
 
-    // #[test]
-    // fn shl_vf_set_when_over_128() {
-    //     let mut cpu = load_new_cpu_with_instruction(0x800E);
-    //     cpu.v[0] = 0b10001111;
+    #[test]
+    fn shl() {
+        let mut cpu = load_new_cpu_with_instruction(0x800E);
+        cpu.v[0] = 0x08;
+
+        cpu.execute_next_instruction().unwrap();
+
+        assert_eq!(cpu.v[0], 0x10);
+        assert_eq!(cpu.v[0xF], 0x0);
+    }
+
+    #[test]
+    fn shl_vf_set_when_over_128() {
+        let mut cpu = load_new_cpu_with_instruction(0x800E);
+        cpu.v[0] = 0b10001111;
+
+        cpu.execute_next_instruction().unwrap();
+
+        assert_eq!(cpu.v[0], 0b00011110);
+        assert_eq!(cpu.v[0xF], 0x1);
+    }
+
+    #[test]
+    fn drw_collision_is_visible_in_vf() {
+        let mut cpu = get_cpu();
+        cpu.memory.data[0x600] = 0x80;
+        cpu.vi = 0x600;
+        cpu.memory.insert_instruction(0x200, 0xD001);
+        cpu.memory.insert_instruction(0x202, 0xD001);
+        cpu.memory.insert_instruction(0x204, 0x3F01);
 
-    //     cpu.execute_next_instruction();
+        cpu.execute_next_instruction().unwrap();
+        assert_eq!(cpu.v[0xF], 0x0);
 
-    //     assert_eq!(cpu.v[0], 0x10);
-    // }
+        cpu.execute_next_instruction().unwrap();
+        assert_eq!(cpu.v[0xF], 0x1);
+
+        cpu.execute_next_instruction().unwrap();
+        assert_eq!(cpu.pc, 0x208);
+    }
 
     #[test]
     fn sne_xy_skip_when_not_equal() {
@@ -860,7 +1497,7 @@ mod tests {
         cpu.v[0] = 0x08;
         cpu.v[1] = 0x80;
 
-        cpu.execute_next_instruction();
+        cpu.execute_next_instruction().unwrap();
 
         assert_eq!(cpu.pc, 0x204);
     }
@@ -871,7 +1508,7 @@ mod tests {
         cpu.v[0] = 0x08;
         cpu.v[1] = 0x08;
 
-        cpu.execute_next_instruction();
+        cpu.execute_next_instruction().unwrap();
 
         assert_eq!(cpu.pc, 0x202);
     }
@@ -880,7 +1517,7 @@ mod tests {
     fn ld_i() {
         let mut cpu = load_new_cpu_with_instruction(0xA666);
 
-        cpu.execute_next_instruction();
+        cpu.execute_next_instruction().unwrap();
 
         assert_eq!(cpu.vi, 0x666);
     }
@@ -890,21 +1527,24 @@ mod tests {
         let mut cpu = load_new_cpu_with_instruction(0xB666);
         cpu.v[0] = 0x4;
 
-        cpu.execute_next_instruction();
+        cpu.execute_next_instruction().unwrap();
 
         assert_eq!(cpu.pc, 0x66A);
     }
 
-    // TODO: PEPELAUGH somehow deal with the rng.
-    // #[test]
-    // fn rnd() {
-    //     let mut cpu = load_new_cpu_with_instruction(0xC066);
-    //     cpu.v[0] = 0x4;
+    #[test]
+    fn rnd() {
+        let mut cpu = load_new_cpu_with_instruction(0xC066);
+        cpu.memory.insert_instruction(0x202, 0xC166);
+        cpu.set_rng(Box::new(ScriptedRng::new(vec![0xAB, 0xFF])));
 
-    //     cpu.execute_next_instruction();
+        cpu.execute_next_instruction().unwrap();
+        cpu.execute_next_instruction().unwrap();
 
-    //     assert_eq!(cpu.pc, 0x66A);
-    // }
+        assert_eq!(cpu.v[0], 0xAB & 0x66);
+        assert_eq!(cpu.v[1], 0x66);
+        assert_eq!(cpu.pc, 0x204);
+    }
 
     #[test]
     fn drw() {
@@ -912,19 +1552,20 @@ mod tests {
 
         cpu.memory.data[0x600] = 0xFF;
         cpu.vi = 0x600;
+        cpu.v[1] = 0x1;
         cpu.memory.insert_instruction(0x200, 0xD111);
 
-        cpu.execute_next_instruction();
+        cpu.execute_next_instruction().unwrap();
         cpu.display.view_state();
 
-        assert_eq!(cpu.display.screen[1][1], true);
-        assert_eq!(cpu.display.screen[1][2], true);
-        assert_eq!(cpu.display.screen[1][3], true);
-        assert_eq!(cpu.display.screen[1][4], true);
-        assert_eq!(cpu.display.screen[1][5], true);
-        assert_eq!(cpu.display.screen[1][6], true);
-        assert_eq!(cpu.display.screen[1][7], true);
-        assert_eq!(cpu.display.screen[1][8], true);
+        assert!(cpu.display.screen[1][1]);
+        assert!(cpu.display.screen[1][2]);
+        assert!(cpu.display.screen[1][3]);
+        assert!(cpu.display.screen[1][4]);
+        assert!(cpu.display.screen[1][5]);
+        assert!(cpu.display.screen[1][6]);
+        assert!(cpu.display.screen[1][7]);
+        assert!(cpu.display.screen[1][8]);
     }
 
     #[test]
@@ -933,7 +1574,7 @@ mod tests {
         cpu.vi = 0x6;
         cpu.v[0] = 0x4;
 
-        cpu.execute_next_instruction();
+        cpu.execute_next_instruction().unwrap();
 
         assert_eq!(cpu.vi, 0xA);
     }
@@ -944,11 +1585,11 @@ mod tests {
         cpu.vi = 0x600;
         cpu.v[0] = 123;
 
-        cpu.execute_next_instruction();
+        cpu.execute_next_instruction().unwrap();
 
-        assert_eq!(cpu.memory.get(0x600), 1);
-        assert_eq!(cpu.memory.get(0x601), 2);
-        assert_eq!(cpu.memory.get(0x602), 3);
+        assert_eq!(cpu.memory.get(0x600).unwrap(), 1);
+        assert_eq!(cpu.memory.get(0x601).unwrap(), 2);
+        assert_eq!(cpu.memory.get(0x602).unwrap(), 3);
     }
 
     #[test]
@@ -956,19 +1597,49 @@ mod tests {
         let mut cpu = load_new_cpu_with_instruction(0xF029);
         cpu.v[0] = 0xD;
 
-        cpu.execute_next_instruction();
+        cpu.execute_next_instruction().unwrap();
 
         assert_eq!(cpu.vi, 0xD0);
     }
 
     #[test]
     fn ld_vx_k() {
+        let mut cpu = load_new_cpu_with_instruction(0xF30A);
+        cpu.memory.insert_instruction(0x202, 0x7101);
+
+        cpu.execute_next_instruction().unwrap();
+        assert_eq!(cpu.state(), CpuState::WaitingForKey { register: 3 });
+
+        // Holding the key isn't enough, it has to be released
+        cpu.keyboard.press(0x4);
+        assert_eq!(
+            cpu.execute_next_instruction(),
+            Ok(StepOutcome::WaitingForKey)
+        );
+        assert_eq!(cpu.pc, 0x202);
+
+        cpu.keyboard.release(0x4);
+        cpu.execute_next_instruction().unwrap();
+
+        assert_eq!(cpu.state(), CpuState::Running);
+        assert_eq!(cpu.v[3], 0x4);
+        // The instruction after it ran straight away
+        assert_eq!(cpu.v[1], 0x1);
+    }
+
+    #[test]
+    fn ld_vx_k_ignores_releases_before_waiting() {
         let mut cpu = load_new_cpu_with_instruction(0xF00A);
-        cpu.keyboard.curr_keydowns = vec![0x4];
+        cpu.keyboard.press(0x4);
+        cpu.keyboard.release(0x4);
 
-        cpu.execute_next_instruction();
+        cpu.execute_next_instruction().unwrap();
 
-        assert_eq!(cpu.v[0], 0x4);
+        assert_eq!(
+            cpu.execute_next_instruction(),
+            Ok(StepOutcome::WaitingForKey)
+        );
+        assert_eq!(cpu.state(), CpuState::WaitingForKey { register: 0 });
     }
 
     #[test]
@@ -976,7 +1647,7 @@ mod tests {
         let mut cpu = load_new_cpu_with_instruction(0xF018);
         cpu.v[0] = 0x4;
 
-        cpu.execute_next_instruction();
+        cpu.execute_next_instruction().unwrap();
 
         assert_eq!(cpu.sound_timer, 0x4);
     }
@@ -986,7 +1657,7 @@ mod tests {
         let mut cpu = load_new_cpu_with_instruction(0xF015);
         cpu.v[0] = 0x4;
 
-        cpu.execute_next_instruction();
+        cpu.execute_next_instruction().unwrap();
 
         assert_eq!(cpu.delay_timer, 0x4);
     }
@@ -996,7 +1667,7 @@ mod tests {
         let mut cpu = load_new_cpu_with_instruction(0xF007);
         cpu.delay_timer = 0x4;
 
-        cpu.execute_next_instruction();
+        cpu.execute_next_instruction().unwrap();
 
         assert_eq!(cpu.v[0], 0x4);
     }
@@ -1011,26 +1682,26 @@ mod tests {
         cpu.v[3] = 0x4;
         cpu.v[4] = 0x5;
 
-        cpu.execute_next_instruction();
+        cpu.execute_next_instruction().unwrap();
 
-        assert_eq!(cpu.memory.get(0x600), 0x1);
-        assert_eq!(cpu.memory.get(0x601), 0x2);
-        assert_eq!(cpu.memory.get(0x602), 0x3);
-        assert_eq!(cpu.memory.get(0x603), 0x4);
-        assert_eq!(cpu.memory.get(0x604), 0x5);
+        assert_eq!(cpu.memory.get(0x600).unwrap(), 0x1);
+        assert_eq!(cpu.memory.get(0x601).unwrap(), 0x2);
+        assert_eq!(cpu.memory.get(0x602).unwrap(), 0x3);
+        assert_eq!(cpu.memory.get(0x603).unwrap(), 0x4);
+        assert_eq!(cpu.memory.get(0x604).unwrap(), 0x5);
     }
 
     #[test]
     fn ld_mem_vx_i() {
         let mut cpu = load_new_cpu_with_instruction(0xF465);
         cpu.vi = 0x600;
-        cpu.memory.write(0x600, 0x1);
-        cpu.memory.write(0x601, 0x2);
-        cpu.memory.write(0x602, 0x3);
-        cpu.memory.write(0x603, 0x4);
-        cpu.memory.write(0x604, 0x5);
+        cpu.memory.write(0x600, 0x1).unwrap();
+        cpu.memory.write(0x601, 0x2).unwrap();
+        cpu.memory.write(0x602, 0x3).unwrap();
+        cpu.memory.write(0x603, 0x4).unwrap();
+        cpu.memory.write(0x604, 0x5).unwrap();
 
-        cpu.execute_next_instruction();
+        cpu.execute_next_instruction().unwrap();
 
         assert_eq!(cpu.v[0], 0x1);
         assert_eq!(cpu.v[1], 0x2);
@@ -1043,9 +1714,9 @@ mod tests {
     fn skp_vx_skip_if_pressed() {
         let mut cpu = load_new_cpu_with_instruction(0xE09E);
         cpu.v[0] = 0x4;
-        cpu.keyboard.curr_keydowns = vec![0x4];
+        cpu.keyboard.press(0x4);
 
-        cpu.execute_next_instruction();
+        cpu.execute_next_instruction().unwrap();
 
         assert_eq!(cpu.pc, 0x204);
     }
@@ -1054,11 +1725,9 @@ mod tests {
     fn skp_vx_dont_skip_if_not_pressed() {
         let mut cpu = load_new_cpu_with_instruction(0xE09E);
         cpu.v[0] = 0x4;
-        cpu.keyboard.curr_keydowns = vec![
-            0x0, 0x1, 0x2, 0x3, 0x5, 0x6, 0x7, 0x8, 0x9, 0xA, 0xB, 0xC, 0xD, 0xE, 0xF,
-        ];
+        cpu.keyboard.set_pressed(!(1 << 0x4));
 
-        cpu.execute_next_instruction();
+        cpu.execute_next_instruction().unwrap();
 
         assert_eq!(cpu.pc, 0x202);
     }
@@ -1067,11 +1736,9 @@ mod tests {
     fn sknp_vx_skip_if_not_pressed() {
         let mut cpu = load_new_cpu_with_instruction(0xE0A1);
         cpu.v[0] = 0x4;
-        cpu.keyboard.curr_keydowns = vec![
-            0x0, 0x1, 0x2, 0x3, 0x5, 0x6, 0x7, 0x8, 0x9, 0xA, 0xB, 0xC, 0xD, 0xE, 0xF,
-        ];
+        cpu.keyboard.set_pressed(!(1 << 0x4));
 
-        cpu.execute_next_instruction();
+        cpu.execute_next_instruction().unwrap();
 
         assert_eq!(cpu.pc, 0x204);
     }
@@ -1080,10 +1747,476 @@ mod tests {
     fn sknp_vx_dont_skip_if_pressed() {
         let mut cpu = load_new_cpu_with_instruction(0xE0A1);
         cpu.v[0] = 0x4;
-        cpu.keyboard.curr_keydowns = vec![0x4];
+        cpu.keyboard.press(0x4);
+
+        cpu.execute_next_instruction().unwrap();
+
+        assert_eq!(cpu.pc, 0x202);
+    }
+
+    #[test]
+    fn or_xy_resets_vf_with_quirk() {
+        let quirks = Quirks {
+            vf_reset: true,
+            ..Quirks::default()
+        };
+        let mut cpu = load_new_cpu_with_quirks(0x8011, quirks);
+        cpu.v[0xF] = 0x1;
+
+        cpu.execute_next_instruction().unwrap();
+
+        assert_eq!(cpu.v[0xF], 0x0);
+    }
+
+    #[test]
+    fn shr_uses_vy_with_quirk() {
+        let quirks = Quirks {
+            shift_uses_vy: true,
+            ..Quirks::default()
+        };
+        let mut cpu = load_new_cpu_with_quirks(0x8016, quirks);
+        cpu.v[0] = 0xFF;
+        cpu.v[1] = 0x09;
+
+        cpu.execute_next_instruction().unwrap();
+
+        assert_eq!(cpu.v[0], 0x4);
+        assert_eq!(cpu.v[1], 0x9);
+        assert_eq!(cpu.v[0xF], 0x1);
+    }
+
+    #[test]
+    fn ld_mem_i_vx_increments_i_with_quirk() {
+        let quirks = Quirks {
+            load_store_increments_i: true,
+            ..Quirks::default()
+        };
+        let mut cpu = load_new_cpu_with_quirks(0xF255, quirks);
+        cpu.vi = 0x600;
+
+        cpu.execute_next_instruction().unwrap();
+
+        assert_eq!(cpu.vi, 0x603);
+    }
+
+    #[test]
+    fn ld_mem_vx_i_increments_i_with_quirk() {
+        let quirks = Quirks {
+            load_store_increments_i: true,
+            ..Quirks::default()
+        };
+        let mut cpu = load_new_cpu_with_quirks(0xF265, quirks);
+        cpu.vi = 0x600;
 
-        cpu.execute_next_instruction();
+        cpu.execute_next_instruction().unwrap();
+
+        assert_eq!(cpu.vi, 0x603);
+    }
+
+    #[test]
+    fn jp_v0_uses_vx_with_quirk() {
+        let quirks = Quirks {
+            jump_uses_vx: true,
+            ..Quirks::default()
+        };
+        let mut cpu = load_new_cpu_with_quirks(0xB266, quirks);
+        cpu.v[0] = 0x1;
+        cpu.v[2] = 0x4;
+
+        cpu.execute_next_instruction().unwrap();
+
+        assert_eq!(cpu.pc, 0x26A);
+    }
+
+    #[test]
+    fn drw_wraps_by_default() {
+        let mut cpu = load_new_cpu_with_instruction(0xD011);
+        cpu.memory.data[0x600] = 0xFF;
+        cpu.vi = 0x600;
+        cpu.v[0] = 60;
+        cpu.v[1] = 31;
+
+        cpu.execute_next_instruction().unwrap();
+
+        assert!(cpu.display.screen[31][63]);
+        assert!(cpu.display.screen[31][0]);
+        assert!(cpu.display.screen[31][3]);
+    }
+
+    #[test]
+    fn drw_clips_with_quirk() {
+        let quirks = Quirks {
+            clip_sprites: true,
+            ..Quirks::default()
+        };
+        let mut cpu = load_new_cpu_with_quirks(0xD012, quirks);
+        cpu.memory.data[0x600] = 0xFF;
+        cpu.memory.data[0x601] = 0xFF;
+        cpu.vi = 0x600;
+        cpu.v[0] = 60;
+        cpu.v[1] = 31;
+
+        cpu.execute_next_instruction().unwrap();
+
+        assert!(cpu.display.screen[31][63]);
+        assert!(!cpu.display.screen[31][0]);
+        assert!(!cpu.display.screen[0][60]);
+    }
+
+    #[test]
+    fn drw_waits_for_vblank_with_quirk() {
+        let quirks = Quirks {
+            display_wait: true,
+            ..Quirks::default()
+        };
+        let mut cpu = load_new_cpu_with_quirks(0xD011, quirks);
+        cpu.memory.data[0x600] = 0x80;
+        cpu.vi = 0x600;
+
+        assert_eq!(
+            cpu.execute_next_instruction(),
+            Ok(StepOutcome::WaitingForVBlank)
+        );
+
+        assert_eq!(cpu.pc, 0x200);
+        assert!(!cpu.display.screen[0][0]);
+
+        cpu.vblank();
+        cpu.execute_next_instruction().unwrap();
 
         assert_eq!(cpu.pc, 0x202);
+        assert!(cpu.display.screen[0][0]);
+    }
+
+    #[test]
+    fn load_state_restores_saved_machine() {
+        let mut cpu = load_new_cpu_with_instruction(0xD015);
+        cpu.v[0xA] = 0x42;
+        cpu.vi = 0x50;
+        cpu.delay_timer = 20;
+        cpu.stack[0] = 0x300;
+        cpu.sp = 1;
+        cpu.execute_next_instruction().unwrap();
+
+        let state = cpu.save_state();
+        let saved_display = cpu.display.screen.clone();
+
+        let mut other = get_cpu();
+        other.load_state(&state).unwrap();
+
+        assert_eq!(other.pc, 0x202);
+        assert_eq!(other.v[0xA], 0x42);
+        assert_eq!(other.vi, 0x50);
+        assert_eq!(other.delay_timer, 20);
+        assert_eq!(other.stack[0], 0x300);
+        assert_eq!(other.sp, 1);
+        assert_eq!(other.memory.data, cpu.memory.data);
+        assert_eq!(other.display.screen, saved_display);
+        assert_eq!(other.snapshot(), cpu.snapshot());
+    }
+
+    #[test]
+    fn load_state_repeats_random_numbers() {
+        let mut cpu = load_new_cpu_with_instruction(0xC0FF);
+        cpu.seed_rng(0xC8);
+        let state = cpu.save_state();
+
+        cpu.execute_next_instruction().unwrap();
+        let first = cpu.v[0];
+        for _ in 0..4 {
+            cpu.load_state(&state).unwrap();
+            cpu.execute_next_instruction().unwrap();
+            assert_eq!(cpu.v[0], first);
+        }
+    }
+
+    #[test]
+    fn load_state_rejects_state_from_other_rom() {
+        let rom = RomLoader::new().load_bytes(&[0x12, 0x00]).unwrap();
+        let other_rom = RomLoader::new().load_bytes(&[0x12, 0x02]).unwrap();
+        let cpu = CPU::initialise(
+            Memory::initialise_with_rom(&rom),
+            Display::initialise(),
+            DummyKeyboard::initialise(),
+            Quirks::default(),
+        );
+        let mut other = CPU::initialise(
+            Memory::initialise_with_rom(&other_rom),
+            Display::initialise(),
+            DummyKeyboard::initialise(),
+            Quirks::default(),
+        );
+        other.v[0] = 0x1;
+
+        assert_eq!(
+            other.load_state(&cpu.save_state()),
+            Err(StateError::RomMismatch)
+        );
+        assert_eq!(other.v[0], 0x1);
+    }
+
+    #[test]
+    fn load_state_rejects_stack_pointer_past_the_stack() {
+        let mut cpu = get_cpu();
+        let mut snapshot = cpu.snapshot();
+        snapshot.sp = 17;
+
+        assert_eq!(cpu.restore(&snapshot), Err(StateError::InvalidRegisters));
+        assert_eq!(cpu.sp, 0);
+    }
+
+    #[test]
+    fn load_state_rejects_waiting_for_a_key_in_no_register() {
+        let mut cpu = get_cpu();
+        let mut snapshot = cpu.snapshot();
+        snapshot.waiting_for_key = Some(0x10);
+
+        assert_eq!(cpu.restore(&snapshot), Err(StateError::InvalidRegisters));
+        assert_eq!(cpu.state(), CpuState::Running);
+    }
+
+    #[test]
+    fn hires_switches_display_resolution() {
+        let mut cpu = load_new_cpu_with_instruction(0x00FF);
+        cpu.memory.insert_instruction(0x202, 0x00FE);
+
+        cpu.execute_next_instruction().unwrap();
+
+        assert!(cpu.display.is_hires());
+        assert_eq!(cpu.display.screen.len(), 64);
+        assert_eq!(cpu.display.screen[0].len(), 128);
+
+        cpu.execute_next_instruction().unwrap();
+
+        assert!(!cpu.display.is_hires());
+        assert_eq!(cpu.display.screen.len(), 32);
+    }
+
+    #[test]
+    fn scd_scrolls_display_down() {
+        let mut cpu = load_new_cpu_with_instruction(0x00C3);
+        cpu.display.screen[0][0] = true;
+
+        cpu.execute_next_instruction().unwrap();
+
+        assert!(!cpu.display.screen[0][0]);
+        assert!(cpu.display.screen[3][0]);
+    }
+
+    #[test]
+    fn scr_and_scl_scroll_display_4_pixels() {
+        let mut cpu = load_new_cpu_with_instruction(0x00FB);
+        cpu.memory.insert_instruction(0x202, 0x00FC);
+        cpu.display.screen[0][0] = true;
+
+        cpu.execute_next_instruction().unwrap();
+
+        assert!(cpu.display.screen[0][4]);
+
+        cpu.execute_next_instruction().unwrap();
+
+        assert!(cpu.display.screen[0][0]);
+        assert!(!cpu.display.screen[0][4]);
+    }
+
+    #[test]
+    fn exit_stops_execution() {
+        let mut cpu = load_new_cpu_with_instruction(0x00FD);
+        cpu.memory.insert_instruction(0x202, 0x6001);
+
+        cpu.execute().unwrap();
+
+        assert_eq!(cpu.state(), CpuState::Exited);
+        assert_eq!(cpu.execute_next_instruction(), Ok(StepOutcome::Exited));
+        assert_eq!(cpu.pc, 0x202);
+        assert_eq!(cpu.v[0], 0x0);
+    }
+
+    #[test]
+    fn drw_16x16_sprite_when_n_is_0() {
+        let mut cpu = load_new_cpu_with_instruction(0xD000);
+        cpu.memory.insert_instruction(0x202, 0xD000);
+        cpu.vi = 0x600;
+        for i in 0..32 {
+            cpu.memory.write(0x600 + i, 0xFF).unwrap();
+        }
+
+        cpu.execute_next_instruction().unwrap();
+
+        assert!(cpu
+            .display
+            .screen
+            .iter()
+            .take(16)
+            .all(|row| row[..16].iter().all(|p| *p)));
+        assert!(!cpu.display.screen[16][0]);
+        assert!(!cpu.display.screen[0][16]);
+        assert_eq!(cpu.v[0xF], 0);
+
+        cpu.execute_next_instruction().unwrap();
+
+        assert_eq!(cpu.v[0xF], 1);
+    }
+
+    #[test]
+    fn ld_hf_vx_points_to_big_digit() {
+        let mut cpu = load_new_cpu_with_instruction(0xF030);
+        cpu.v[0] = 0x2;
+
+        cpu.execute_next_instruction().unwrap();
+
+        assert_eq!(cpu.vi, 0x114);
+        assert_eq!(cpu.memory.get(0x114).unwrap(), 0xFF);
+        assert_eq!(cpu.memory.get(0x116).unwrap(), 0x03);
+    }
+
+    #[test]
+    fn ld_r_vx_and_ld_vx_r_round_trip_rpl_flags() {
+        let mut cpu = load_new_cpu_with_instruction(0xF275);
+        cpu.memory.insert_instruction(0x202, 0xF385);
+        cpu.v[0] = 0x1;
+        cpu.v[1] = 0x2;
+        cpu.v[2] = 0x3;
+        cpu.v[3] = 0x4;
+
+        cpu.execute_next_instruction().unwrap();
+
+        assert_eq!(cpu.rpl_flags[..4], [0x1, 0x2, 0x3, 0x0]);
+
+        cpu.v = [0xAA; 0x10];
+        cpu.execute_next_instruction().unwrap();
+
+        assert_eq!(cpu.v[..5], [0x1, 0x2, 0x3, 0x0, 0xAA]);
+    }
+
+    #[test]
+    fn load_state_restores_hires_display() {
+        let mut cpu = load_new_cpu_with_instruction(0x00FF);
+        cpu.execute_next_instruction().unwrap();
+        cpu.display.screen[63][127] = true;
+
+        let mut other = get_cpu();
+        other.load_state(&cpu.save_state()).unwrap();
+
+        assert!(other.display.is_hires());
+        assert!(other.display.screen[63][127]);
+    }
+
+    #[test]
+    fn ld_i_long_loads_16_bit_address() {
+        let mut cpu = get_xo_cpu();
+        cpu.memory.insert_instruction(0x200, 0xF000);
+        cpu.memory.insert_instruction(0x202, 0xBEEF);
+
+        let outcome = cpu.execute_next_instruction();
+
+        assert_eq!(
+            outcome,
+            Ok(StepOutcome::Executed(Instruction::LdILong { nnnn: 0xBEEF }))
+        );
+        assert_eq!(cpu.vi, 0xBEEF);
+        assert_eq!(cpu.pc, 0x204);
+    }
+
+    #[test]
+    fn skips_over_long_instructions() {
+        let mut cpu = load_new_cpu_with_instruction(0x3000);
+        cpu.memory.insert_instruction(0x202, 0xF000);
+        cpu.memory.insert_instruction(0x204, 0x1234);
+
+        cpu.execute_next_instruction().unwrap();
+
+        assert_eq!(cpu.pc, 0x206);
+    }
+
+    #[test]
+    fn ld_mem_i_range_stores_registers_without_changing_i() {
+        let mut cpu = load_new_cpu_with_instruction(0x5132);
+        cpu.memory.insert_instruction(0x202, 0x5312);
+        cpu.vi = 0x600;
+        cpu.v[1] = 0x11;
+        cpu.v[2] = 0x22;
+        cpu.v[3] = 0x33;
+
+        cpu.execute_next_instruction().unwrap();
+
+        assert_eq!(cpu.memory.data[0x600..0x604], [0x11, 0x22, 0x33, 0x00]);
+        assert_eq!(cpu.vi, 0x600);
+
+        cpu.vi = 0x700;
+        cpu.execute_next_instruction().unwrap();
+
+        assert_eq!(cpu.memory.data[0x700..0x703], [0x33, 0x22, 0x11]);
+    }
+
+    #[test]
+    fn ld_range_mem_i_loads_registers() {
+        let mut cpu = load_new_cpu_with_instruction(0x5243);
+        cpu.vi = 0x600;
+        cpu.memory.data[0x600..0x603].copy_from_slice(&[0x1, 0x2, 0x3]);
+
+        cpu.execute_next_instruction().unwrap();
+
+        assert_eq!(cpu.v[2..5], [0x1, 0x2, 0x3]);
+        assert_eq!(cpu.vi, 0x600);
+    }
+
+    #[test]
+    fn plane_selects_planes_to_draw_to() {
+        let mut cpu = load_new_cpu_with_instruction(0xF301);
+        cpu.memory.insert_instruction(0x202, 0xD001);
+        cpu.vi = 0x600;
+        cpu.memory.data[0x600] = 0x80;
+        cpu.memory.data[0x601] = 0xC0;
+
+        cpu.execute_next_instruction().unwrap();
+        cpu.execute_next_instruction().unwrap();
+
+        assert_eq!(cpu.display.selected_planes(), 0b11);
+        assert_eq!(cpu.display.pixel(0, 0), 0b11);
+        assert_eq!(cpu.display.pixel(1, 0), 0b10);
+    }
+
+    #[test]
+    fn scu_scrolls_display_up() {
+        let mut cpu = load_new_cpu_with_instruction(0x00D2);
+        cpu.display.screen[2][0] = true;
+
+        cpu.execute_next_instruction().unwrap();
+
+        assert!(cpu.display.screen[0][0]);
+        assert!(!cpu.display.screen[2][0]);
+    }
+
+    #[test]
+    fn ld_audio_and_pitch() {
+        let mut cpu = load_new_cpu_with_instruction(0xF002);
+        cpu.memory.insert_instruction(0x202, 0xF13A);
+        cpu.vi = 0x600;
+        cpu.memory.data[0x600..0x610].copy_from_slice(&[0xF0; 16]);
+        cpu.v[1] = 112;
+
+        assert_eq!(cpu.audio_playback_rate(), 4000.0);
+
+        cpu.execute_next_instruction().unwrap();
+        cpu.execute_next_instruction().unwrap();
+
+        assert_eq!(cpu.audio_pattern, [0xF0; 16]);
+        assert_eq!(cpu.pitch, 112);
+        assert_eq!(cpu.audio_playback_rate(), 8000.0);
+    }
+
+    #[test]
+    fn xo_chip_memory_is_addressable() {
+        let mut cpu = get_xo_cpu();
+        cpu.memory.insert_instruction(0x200, 0xF000);
+        cpu.memory.insert_instruction(0x202, 0xFFF0);
+        cpu.memory.insert_instruction(0x204, 0xF065);
+        cpu.memory.data[0xFFF0] = 0x42;
+
+        cpu.execute_next_instruction().unwrap();
+        cpu.execute_next_instruction().unwrap();
+
+        assert_eq!(cpu.v[0], 0x42);
     }
 }
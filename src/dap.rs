@@ -0,0 +1,1160 @@
+//! A Debug Adapter Protocol server, so editors like VS Code can debug a running program.
+//!
+//! Breakpoints are set on lines of the assembler or Octo source the program was built from,
+//! which a [`SourceMap`] turns into addresses, and may have conditions written as debugger
+//! [`Expression`]s. There is a single thread, whose call stack comes from [`CPU::stack`], with
+//! the registers and the stack as variables. Memory can be read and written, the program can be
+//! disassembled, and breakpoints can also be set on instructions in the disassembly.
+//!
+//! [`DapSession`] answers requests and [`DapServer`] carries them over a [`TcpStream`], running
+//! the program a frame at a time in between.
+
+use std::{
+    collections::BTreeMap,
+    convert::TryFrom,
+    fs,
+    io::{self, Write},
+    net::TcpStream,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    asm::Assembly,
+    breakpoints::{Cause, Expression, Register, Trigger},
+    cpu::CPU,
+    debugger::{self, Debugger, Stop},
+    json::Json,
+    keyboard::Keyboard,
+    scheduler::{Scheduler, TimeSource},
+    tcp,
+};
+
+// There is only one thread to report
+const THREAD_ID: i64 = 1;
+
+// The most instructions a disassemble request lists, enough to cover XO-CHIP's 64KB
+const MAX_DISASSEMBLED_INSTRUCTIONS: i64 = 0x8000;
+
+// The variable references of the two scopes
+const REGISTERS_REFERENCE: i64 = 1;
+const STACK_REFERENCE: i64 = 2;
+
+/// Maps between lines of source and the addresses of the instructions assembled from them.
+#[derive(Debug, Clone, Default)]
+pub struct SourceMap {
+    addresses: BTreeMap<(PathBuf, usize), u16>,
+    lines: BTreeMap<u16, (PathBuf, usize)>,
+}
+
+impl SourceMap {
+    /// Builds the map from an assembled program. Lines that didn't come from a file, because
+    /// the source was passed in as a string, are given the path `source`.
+    pub fn new(assembly: &Assembly, source: impl AsRef<Path>) -> Self {
+        let mut map = Self::default();
+
+        for (address, location) in assembly.source_map() {
+            let path = canonical(location.file.as_deref().unwrap_or(source.as_ref()));
+            // The first instruction on a line is where a breakpoint on it stops
+            map.addresses
+                .entry((path.clone(), location.line))
+                .or_insert(*address);
+            map.lines.insert(*address, (path, location.line));
+        }
+
+        map
+    }
+
+    /// The address of the first instruction on a line, or on the next line that has one, along
+    /// with the line it is on.
+    pub fn address_of(&self, path: impl AsRef<Path>, line: usize) -> Option<(u16, usize)> {
+        let path = canonical(path.as_ref());
+        self.addresses
+            .range((path.clone(), line)..)
+            .next()
+            .filter(|((file, _), _)| *file == path)
+            .map(|((_, line), address)| (*address, *line))
+    }
+
+    /// The file and line an instruction was assembled from.
+    pub fn location_of(&self, address: u16) -> Option<(&Path, usize)> {
+        self.lines
+            .get(&address)
+            .map(|(path, line)| (path.as_path(), *line))
+    }
+}
+
+/// Editors and assemblers may spell the same file differently, so paths are compared once
+/// they have been resolved. Paths that don't exist are left as they are.
+fn canonical(path: &Path) -> PathBuf {
+    fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf())
+}
+
+/// Whether a client is still attached after a request or [`DapServer::update`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Status {
+    Attached,
+    /// The client disconnected, and the program should run on.
+    Detached,
+    /// The client asked for the program to be terminated.
+    Killed,
+}
+
+/// The protocol, independent of how messages are carried.
+#[derive(Debug, Clone, Default)]
+pub struct DapSession {
+    debugger: Debugger,
+    source_map: Option<SourceMap>,
+    seq: i64,
+    messages: Vec<String>,
+    // Events raised while handling a request, which have to follow its response
+    events: Vec<Json>,
+    stop_on_entry: bool,
+
+    // Breakpoint ids for each source file, and for the disassembly, so each
+    // `setBreakpoints` or `setInstructionBreakpoints` can replace the last
+    source_breakpoints: BTreeMap<PathBuf, Vec<usize>>,
+    instruction_breakpoints: Vec<usize>,
+}
+
+impl DapSession {
+    /// Starts a session with the program paused, with breakpoints set on lines resolved by the
+    /// source map, if there is one.
+    pub fn new(source_map: Option<SourceMap>) -> Self {
+        Self {
+            source_map,
+            ..Self::default()
+        }
+    }
+
+    /// Whether the program is paused in the debugger.
+    pub fn is_paused(&self) -> bool {
+        self.debugger.is_paused()
+    }
+
+    /// The messages to send to the client, encoded as JSON, in the order they were raised.
+    pub fn take_messages(&mut self) -> Vec<String> {
+        std::mem::take(&mut self.messages)
+    }
+
+    /// Handles a message from the client.
+    pub fn handle<TKeyboard>(&mut self, message: &str, cpu: &mut CPU<TKeyboard>) -> Status
+    where
+        TKeyboard: Keyboard,
+    {
+        let request = match Json::parse(message) {
+            Ok(request) if request.get("type").as_str() == Some("request") => request,
+            // Anything that isn't a request can't be answered
+            _ => return Status::Attached,
+        };
+        let command = request.get("command").as_str().unwrap_or_default();
+        let arguments = request.get("arguments");
+
+        let (result, status) = match command {
+            "disconnect" if arguments.get("terminateDebuggee").as_bool() == Some(true) => {
+                (Ok(Json::Null), Status::Killed)
+            }
+            "disconnect" => {
+                self.debugger.breakpoints_mut().clear();
+                cpu.memory.record_accesses(false);
+                self.debugger.resume();
+                (Ok(Json::Null), Status::Detached)
+            }
+            command => (self.request(command, arguments, cpu), Status::Attached),
+        };
+
+        let mut response = vec![
+            ("seq".to_string(), Json::Null),
+            ("type".to_string(), "response".into()),
+            ("request_seq".to_string(), request.get("seq").clone()),
+            ("success".to_string(), result.is_ok().into()),
+            ("command".to_string(), command.into()),
+        ];
+        match result {
+            Ok(Json::Null) => {}
+            Ok(body) => response.push(("body".to_string(), body)),
+            Err(message) => response.push(("message".to_string(), message.into())),
+        }
+        self.send(Json::Object(response));
+
+        for event in std::mem::take(&mut self.events) {
+            self.send(event);
+        }
+
+        status
+    }
+
+    /// Runs a frame of the program, unless it is paused, raising an event if it stopped.
+    pub fn run_frame<TKeyboard, TClock>(
+        &mut self,
+        scheduler: &Scheduler<TClock>,
+        cpu: &mut CPU<TKeyboard>,
+    ) where
+        TKeyboard: Keyboard,
+        TClock: TimeSource,
+    {
+        if let Some(stop) = self.debugger.run_frame(scheduler, cpu) {
+            self.stopped(Some(stop));
+            for event in std::mem::take(&mut self.events) {
+                self.send(event);
+            }
+        }
+    }
+
+    fn request<TKeyboard>(
+        &mut self,
+        command: &str,
+        arguments: &Json,
+        cpu: &mut CPU<TKeyboard>,
+    ) -> Result<Json, String>
+    where
+        TKeyboard: Keyboard,
+    {
+        match command {
+            "initialize" => {
+                self.event("initialized", Json::Null);
+                Ok(Json::object([
+                    ("supportsConfigurationDoneRequest", true.into()),
+                    ("supportsConditionalBreakpoints", true.into()),
+                    ("supportsSetVariable", true.into()),
+                    ("supportsReadMemoryRequest", true.into()),
+                    ("supportsWriteMemoryRequest", true.into()),
+                    ("supportsDisassembleRequest", true.into()),
+                    ("supportsInstructionBreakpoints", true.into()),
+                ]))
+            }
+            // The program is already loaded, so there is nothing to launch or attach to
+            "launch" | "attach" => {
+                self.stop_on_entry = arguments.get("stopOnEntry").as_bool().unwrap_or(false);
+                Ok(Json::Null)
+            }
+            "configurationDone" => {
+                if self.stop_on_entry {
+                    self.event(
+                        "stopped",
+                        Json::object([("reason", "entry".into()), ("threadId", THREAD_ID.into())]),
+                    );
+                } else {
+                    self.debugger.resume();
+                }
+                Ok(Json::Null)
+            }
+            "setBreakpoints" => self.set_breakpoints(arguments),
+            "setInstructionBreakpoints" => self.set_instruction_breakpoints(arguments),
+            "threads" => Ok(Json::object([(
+                "threads",
+                vec![Json::object([
+                    ("id", THREAD_ID.into()),
+                    ("name", "CHIP-8".into()),
+                ])]
+                .into(),
+            )])),
+            "stackTrace" => Ok(self.stack_trace(cpu)),
+            "scopes" => Ok(Json::object([(
+                "scopes",
+                vec![
+                    scope("Registers", REGISTERS_REFERENCE),
+                    scope("Stack", STACK_REFERENCE),
+                ]
+                .into(),
+            )])),
+            "variables" => variables(arguments.get("variablesReference").as_i64(), cpu),
+            "setVariable" => set_variable(arguments, cpu),
+            "continue" => {
+                self.debugger.resume();
+                Ok(Json::object([("allThreadsContinued", true.into())]))
+            }
+            "next" => {
+                let stop = self.debugger.step_over(cpu);
+                if self.debugger.is_paused() {
+                    self.stopped(stop);
+                }
+                Ok(Json::Null)
+            }
+            "stepIn" => {
+                let stop = self.debugger.step(1, cpu);
+                self.stopped(stop);
+                Ok(Json::Null)
+            }
+            "stepOut" => {
+                if self.debugger.finish(cpu) {
+                    Ok(Json::Null)
+                } else {
+                    Err("Not in a subroutine".to_string())
+                }
+            }
+            "pause" => {
+                self.debugger.pause();
+                self.event(
+                    "stopped",
+                    Json::object([("reason", "pause".into()), ("threadId", THREAD_ID.into())]),
+                );
+                Ok(Json::Null)
+            }
+            "readMemory" => read_memory(arguments, cpu),
+            "writeMemory" => write_memory(arguments, cpu),
+            "disassemble" => self.disassemble(arguments, cpu),
+            "evaluate" => {
+                let expression = arguments.get("expression").as_str().unwrap_or_default();
+                let value = expression
+                    .parse::<Expression>()
+                    .map_err(|e| e.to_string())?
+                    .evaluate(cpu);
+                Ok(Json::object([
+                    ("result", format!("{} (0x{:X})", value, value).into()),
+                    ("variablesReference", 0.into()),
+                ]))
+            }
+            command => Err(format!("{} is not supported", command)),
+        }
+    }
+
+    fn set_breakpoints(&mut self, arguments: &Json) -> Result<Json, String> {
+        let path = canonical(Path::new(
+            arguments
+                .get("source")
+                .get("path")
+                .as_str()
+                .ok_or("The source has no path")?,
+        ));
+
+        let breakpoints = self.debugger.breakpoints_mut();
+        for id in self.source_breakpoints.remove(&path).unwrap_or_default() {
+            breakpoints.remove(id);
+        }
+
+        let mut ids = vec![];
+        let mut results = vec![];
+        for requested in arguments.get("breakpoints").as_array() {
+            let line = requested.get("line").as_i64().unwrap_or(0) as usize;
+            let result = match &self.source_map {
+                None => Err("There is no source map for the program".to_string()),
+                Some(map) => map
+                    .address_of(&path, line)
+                    .ok_or_else(|| "There is no code on or after this line".to_string())
+                    .and_then(|(address, line)| {
+                        let condition = parse_condition(requested)?;
+                        let id = breakpoints.add(Trigger::Address(address), condition);
+                        Ok((id, line))
+                    }),
+            };
+
+            results.push(match result {
+                Ok((id, line)) => {
+                    ids.push(id);
+                    Json::object([
+                        ("id", (id as i64).into()),
+                        ("verified", true.into()),
+                        ("line", (line as i64).into()),
+                    ])
+                }
+                Err(message) => {
+                    Json::object([("verified", false.into()), ("message", message.into())])
+                }
+            });
+        }
+        self.source_breakpoints.insert(path, ids);
+
+        Ok(Json::object([("breakpoints", results.into())]))
+    }
+
+    fn set_instruction_breakpoints(&mut self, arguments: &Json) -> Result<Json, String> {
+        let breakpoints = self.debugger.breakpoints_mut();
+        for id in self.instruction_breakpoints.drain(..) {
+            breakpoints.remove(id);
+        }
+
+        let mut results = vec![];
+        for requested in arguments.get("breakpoints").as_array() {
+            let address = requested
+                .get("instructionReference")
+                .as_str()
+                .and_then(parse_number)
+                .and_then(|address| {
+                    address.checked_add(requested.get("offset").as_i64().unwrap_or(0))
+                })
+                .filter(|address| (0..=0xFFFF).contains(address))
+                .ok_or_else(|| "Not an instruction address".to_string());
+
+            let result = address.and_then(|address| {
+                let condition = parse_condition(requested)?;
+                Ok(breakpoints.add(Trigger::Address(address as u16), condition))
+            });
+            results.push(match result {
+                Ok(id) => {
+                    self.instruction_breakpoints.push(id);
+                    Json::object([("id", (id as i64).into()), ("verified", true.into())])
+                }
+                Err(message) => {
+                    Json::object([("verified", false.into()), ("message", message.into())])
+                }
+            });
+        }
+
+        Ok(Json::object([("breakpoints", results.into())]))
+    }
+
+    fn stack_trace<TKeyboard>(&self, cpu: &CPU<TKeyboard>) -> Json
+    where
+        TKeyboard: Keyboard,
+    {
+        // The innermost frame is at the program counter, and each caller is at the `CALL` before
+        // the address it will return to, which are pushed from stack[0] up
+        let frames = std::iter::once((0, cpu.pc))
+            .chain(
+                (0..cpu.sp as usize)
+                    .rev()
+                    .map(|sp| (sp as i64 + 1, cpu.stack[sp].wrapping_sub(2))),
+            )
+            .map(|(id, address)| {
+                let mut frame = vec![
+                    ("id".to_string(), id.into()),
+                    ("name".to_string(), format!("0x{:03X}", address).into()),
+                    ("line".to_string(), 0.into()),
+                    ("column".to_string(), 0.into()),
+                    (
+                        "instructionPointerReference".to_string(),
+                        format!("0x{:X}", address).into(),
+                    ),
+                ];
+                if let Some((path, line)) = self.location_of(address) {
+                    frame[2].1 = (line as i64).into();
+                    frame[3].1 = 1.into();
+                    frame.push(("source".to_string(), source(path)));
+                }
+                Json::Object(frame)
+            })
+            .collect::<Vec<_>>();
+
+        Json::object([
+            ("totalFrames", (frames.len() as i64).into()),
+            ("stackFrames", frames.into()),
+        ])
+    }
+
+    fn disassemble<TKeyboard>(&self, arguments: &Json, cpu: &CPU<TKeyboard>) -> Result<Json, String>
+    where
+        TKeyboard: Keyboard,
+    {
+        let start = arguments
+            .get("memoryReference")
+            .as_str()
+            .and_then(parse_number)
+            .and_then(|address| address.checked_add(arguments.get("offset").as_i64().unwrap_or(0)))
+            .and_then(|address| {
+                arguments
+                    .get("instructionOffset")
+                    .as_i64()
+                    .unwrap_or(0)
+                    .checked_mul(2)
+                    .and_then(|offset| address.checked_add(offset))
+            })
+            .ok_or("Not a memory address")?;
+        let count = arguments
+            .get("instructionCount")
+            .as_i64()
+            .unwrap_or(0)
+            .clamp(0, MAX_DISASSEMBLED_INSTRUCTIONS);
+
+        // Every instruction is listed, even outside of memory, as the client expects exactly as
+        // many as it asked for, up to the number that fit in the largest memory
+        let mut instructions = vec![];
+        let mut address = start;
+        for _ in 0..count {
+            let decoded = u16::try_from(address)
+                .ok()
+                .and_then(|address| Some((address, debugger::decode_at(cpu, address)?)));
+
+            let mut instruction = vec![("address".to_string(), format!("0x{:X}", address).into())];
+            match decoded {
+                Some((at, decoded)) => {
+                    let bytes =
+                        &cpu.memory.data[at as usize..at as usize + decoded.size() as usize];
+                    let bytes: Vec<String> = bytes.iter().map(|b| format!("{:02X}", b)).collect();
+                    instruction.push(("instructionBytes".to_string(), bytes.join(" ").into()));
+                    instruction.push(("instruction".to_string(), decoded.to_string().into()));
+                    if let Some((path, line)) = self.location_of(at) {
+                        instruction.push(("location".to_string(), source(path)));
+                        instruction.push(("line".to_string(), (line as i64).into()));
+                    }
+                    address += decoded.size() as i64;
+                }
+                None => {
+                    instruction.push(("instruction".to_string(), "".into()));
+                    instruction.push(("presentationHint".to_string(), "invalid".into()));
+                    address += 2;
+                }
+            }
+            instructions.push(Json::Object(instruction));
+        }
+
+        Ok(Json::object([("instructions", instructions.into())]))
+    }
+
+    fn location_of(&self, address: u16) -> Option<(&Path, usize)> {
+        self.source_map.as_ref()?.location_of(address)
+    }
+
+    /// Raises the `stopped` event for a step or a stop while running, or the `exited` and
+    /// `terminated` events if the program exited.
+    fn stopped(&mut self, stop: Option<Stop>) {
+        let (reason, description, hit) = match stop {
+            None | Some(Stop::SteppedOver) | Some(Stop::Returned) => ("step", None, None),
+            Some(Stop::Breakpoint(hit)) => {
+                let reason = match hit.cause {
+                    Cause::Access(_) => "data breakpoint",
+                    _ if self.instruction_breakpoints.contains(&hit.id) => "instruction breakpoint",
+                    _ => "breakpoint",
+                };
+                (reason, Some(hit.to_string()), Some(hit.id))
+            }
+            Some(Stop::Fault(e)) => ("exception", Some(e.to_string()), None),
+            Some(Stop::Exited) => {
+                self.event("exited", Json::object([("exitCode", 0.into())]));
+                self.event("terminated", Json::Null);
+                return;
+            }
+        };
+
+        let mut body = vec![
+            ("reason".to_string(), reason.into()),
+            ("threadId".to_string(), THREAD_ID.into()),
+            ("allThreadsStopped".to_string(), true.into()),
+        ];
+        if let Some(description) = description {
+            body.push(("description".to_string(), description.clone().into()));
+            body.push(("text".to_string(), description.into()));
+        }
+        if let Some(id) = hit {
+            body.push((
+                "hitBreakpointIds".to_string(),
+                vec![(id as i64).into()].into(),
+            ));
+        }
+        self.event("stopped", Json::Object(body));
+    }
+
+    fn event(&mut self, event: &str, body: Json) {
+        let mut message = vec![
+            ("seq".to_string(), Json::Null),
+            ("type".to_string(), "event".into()),
+            ("event".to_string(), event.into()),
+        ];
+        if body != Json::Null {
+            message.push(("body".to_string(), body));
+        }
+        self.events.push(Json::Object(message));
+    }
+
+    /// Numbers a message and queues it to be sent.
+    fn send(&mut self, mut message: Json) {
+        self.seq += 1;
+        if let Json::Object(fields) = &mut message {
+            fields[0].1 = self.seq.into();
+        }
+        self.messages.push(message.to_string());
+    }
+}
+
+fn scope(name: &str, reference: i64) -> Json {
+    Json::object([
+        ("name", name.into()),
+        ("variablesReference", reference.into()),
+        ("expensive", false.into()),
+    ])
+}
+
+fn source(path: &Path) -> Json {
+    let name = path.file_name().unwrap_or(path.as_os_str());
+    Json::object([
+        ("name", name.to_string_lossy().into_owned().into()),
+        ("path", path.to_string_lossy().into_owned().into()),
+    ])
+}
+
+fn variable(name: String, value: String, memory_reference: Option<u16>) -> Json {
+    let mut variable = vec![
+        ("name".to_string(), name.into()),
+        ("value".to_string(), value.into()),
+        ("variablesReference".to_string(), 0.into()),
+    ];
+    if let Some(address) = memory_reference {
+        variable.push((
+            "memoryReference".to_string(),
+            format!("0x{:X}", address).into(),
+        ));
+    }
+    Json::Object(variable)
+}
+
+fn format_register<TKeyboard>(register: Register, cpu: &CPU<TKeyboard>) -> String
+where
+    TKeyboard: Keyboard,
+{
+    match register {
+        Register::I | Register::Pc => format!("0x{:03X}", register.read(cpu)),
+        Register::Sp => register.read(cpu).to_string(),
+        _ => format!("0x{:02X}", register.read(cpu)),
+    }
+}
+
+fn variables<TKeyboard>(reference: Option<i64>, cpu: &CPU<TKeyboard>) -> Result<Json, String>
+where
+    TKeyboard: Keyboard,
+{
+    let variables: Vec<Json> = match reference {
+        Some(REGISTERS_REFERENCE) => Register::ALL
+            .iter()
+            .map(|register| {
+                // I and PC point at memory, which the client can then show
+                let memory_reference = match register {
+                    Register::I | Register::Pc => Some(register.read(cpu)),
+                    _ => None,
+                };
+                variable(
+                    register.to_string(),
+                    format_register(*register, cpu),
+                    memory_reference,
+                )
+            })
+            .collect(),
+        Some(STACK_REFERENCE) => (0..cpu.sp as usize)
+            .rev()
+            .map(|sp| {
+                variable(
+                    format!("[{}]", sp),
+                    format!("0x{:03X}", cpu.stack[sp]),
+                    Some(cpu.stack[sp]),
+                )
+            })
+            .collect(),
+        _ => return Err("There are no such variables".to_string()),
+    };
+
+    Ok(Json::object([("variables", variables.into())]))
+}
+
+fn set_variable<TKeyboard>(arguments: &Json, cpu: &mut CPU<TKeyboard>) -> Result<Json, String>
+where
+    TKeyboard: Keyboard,
+{
+    if arguments.get("variablesReference").as_i64() != Some(REGISTERS_REFERENCE) {
+        return Err("Only registers can be set".to_string());
+    }
+
+    let name = arguments.get("name").as_str().unwrap_or_default();
+    let register = Register::from_name(name).ok_or_else(|| format!("There is no {}", name))?;
+    // Values are expressions, so `V1 + 1` works as well as a number
+    let value = arguments
+        .get("value")
+        .as_str()
+        .unwrap_or_default()
+        .parse::<Expression>()
+        .map_err(|e| e.to_string())?
+        .evaluate(cpu);
+
+    let written = u16::try_from(value).is_ok_and(|value| register.write(cpu, value));
+    if !written {
+        return Err(format!("{} doesn't fit in {}", value, register));
+    }
+
+    Ok(Json::object([(
+        "value",
+        format_register(register, cpu).into(),
+    )]))
+}
+
+fn read_memory<TKeyboard>(arguments: &Json, cpu: &CPU<TKeyboard>) -> Result<Json, String>
+where
+    TKeyboard: Keyboard,
+{
+    let start = memory_address(arguments)?;
+    let count = arguments.get("count").as_i64().unwrap_or(0).max(0) as usize;
+
+    let data = &cpu.memory.data;
+    let start = start.min(data.len());
+    let end = start.saturating_add(count).min(data.len());
+
+    Ok(Json::object([
+        ("address", format!("0x{:X}", start).into()),
+        ("data", base64_encode(&data[start..end]).into()),
+        ("unreadableBytes", ((count - (end - start)) as i64).into()),
+    ]))
+}
+
+fn write_memory<TKeyboard>(arguments: &Json, cpu: &mut CPU<TKeyboard>) -> Result<Json, String>
+where
+    TKeyboard: Keyboard,
+{
+    let start = memory_address(arguments)?;
+    let bytes = arguments
+        .get("data")
+        .as_str()
+        .and_then(base64_decode)
+        .ok_or("The data isn't valid base64")?;
+
+    let target = start
+        .checked_add(bytes.len())
+        .and_then(|end| cpu.memory.data.get_mut(start..end))
+        .ok_or("The data doesn't fit in memory")?;
+    target.copy_from_slice(&bytes);
+
+    Ok(Json::object([(
+        "bytesWritten",
+        (bytes.len() as i64).into(),
+    )]))
+}
+
+/// The address a memory request starts at, from its `memoryReference` and `offset`.
+fn memory_address(arguments: &Json) -> Result<usize, String> {
+    arguments
+        .get("memoryReference")
+        .as_str()
+        .and_then(parse_number)
+        .and_then(|address| address.checked_add(arguments.get("offset").as_i64().unwrap_or(0)))
+        .and_then(|address| usize::try_from(address).ok())
+        .ok_or_else(|| "Not a memory address".to_string())
+}
+
+/// Parses a decimal or `0x` prefixed hexadecimal number, the ways addresses are written.
+fn parse_number(value: &str) -> Option<i64> {
+    match value
+        .strip_prefix("0x")
+        .or_else(|| value.strip_prefix("0X"))
+    {
+        Some(hex) => i64::from_str_radix(hex, 16).ok(),
+        None => value.parse().ok(),
+    }
+}
+
+fn parse_condition(breakpoint: &Json) -> Result<Option<Expression>, String> {
+    match breakpoint.get("condition").as_str() {
+        Some(condition) if !condition.trim().is_empty() => condition
+            .parse()
+            .map(Some)
+            .map_err(|e| format!("Invalid condition: {}", e)),
+        _ => Ok(None),
+    }
+}
+
+const BASE64: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+fn base64_encode(bytes: &[u8]) -> String {
+    let mut encoded = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let group = chunk.iter().enumerate().fold(0u32, |group, (i, byte)| {
+            group | (*byte as u32) << (16 - i * 8)
+        });
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(BASE64[(group >> (18 - i * 6)) as usize & 0x3F] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+
+    encoded
+}
+
+fn base64_decode(text: &str) -> Option<Vec<u8>> {
+    let text = text.trim_end_matches('=');
+    let mut bytes = Vec::with_capacity(text.len() * 3 / 4);
+    let mut group = 0u32;
+    let mut bits = 0;
+
+    for c in text.bytes() {
+        let value = BASE64.iter().position(|b| *b == c)? as u32;
+        group = group << 6 | value;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            bytes.push((group >> bits) as u8);
+        }
+    }
+
+    Some(bytes)
+}
+
+/// Serves a [`DapSession`] over a TCP connection.
+#[derive(Debug)]
+pub struct DapServer {
+    stream: TcpStream,
+    session: DapSession,
+    input: Vec<u8>,
+}
+
+impl DapServer {
+    /// Serves a client that has connected, with the program paused until it is configured.
+    pub fn new(stream: TcpStream, source_map: Option<SourceMap>) -> Self {
+        // Messages are answered one at a time, so they shouldn't wait to be batched
+        let _ = stream.set_nodelay(true);
+
+        Self {
+            stream,
+            session: DapSession::new(source_map),
+            input: vec![],
+        }
+    }
+
+    /// Answers the requests that have arrived, then runs the frames that are due if the program
+    /// isn't paused. This never blocks waiting for the client.
+    pub fn update<TKeyboard, TClock>(
+        &mut self,
+        scheduler: &mut Scheduler<TClock>,
+        cpu: &mut CPU<TKeyboard>,
+    ) -> io::Result<Status>
+    where
+        TKeyboard: Keyboard,
+        TClock: TimeSource,
+    {
+        if !tcp::receive(&mut self.stream, &mut self.input)? {
+            return Ok(Status::Detached);
+        }
+
+        while let Some(message) = self.next_message() {
+            let status = self.session.handle(&message, cpu);
+            self.flush()?;
+            if status != Status::Attached {
+                return Ok(status);
+            }
+        }
+
+        if self.session.is_paused() {
+            // Don't try to catch up on the time spent paused
+            scheduler.reset();
+            return Ok(Status::Attached);
+        }
+
+        for _ in 0..scheduler.frames_due() {
+            self.session.run_frame(scheduler, cpu);
+            if self.session.is_paused() {
+                break;
+            }
+        }
+        self.flush()?;
+
+        Ok(Status::Attached)
+    }
+
+    /// Takes the next complete message from the input. Each is preceded by headers, of which
+    /// only `Content-Length` matters, and a blank line.
+    fn next_message(&mut self) -> Option<String> {
+        let header_end = self
+            .input
+            .windows(4)
+            .position(|window| window == b"\r\n\r\n")?;
+        let headers = String::from_utf8_lossy(&self.input[..header_end]).into_owned();
+        let length = headers.lines().find_map(|header| {
+            let (name, value) = header.split_once(':')?;
+            match name.trim().eq_ignore_ascii_case("Content-Length") {
+                true => value.trim().parse::<usize>().ok(),
+                false => None,
+            }
+        });
+
+        let start = header_end + 4;
+        let end = match length.and_then(|length| start.checked_add(length)) {
+            Some(end) => end,
+            None => {
+                // Without a usable length there is no telling where the message ends, so skip it
+                self.input.drain(..start);
+                return None;
+            }
+        };
+        if self.input.len() < end {
+            return None;
+        }
+
+        let message: Vec<u8> = self.input.drain(..end).skip(start).collect();
+        Some(String::from_utf8_lossy(&message).into_owned())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        for message in self.session.take_messages() {
+            write!(
+                self.stream,
+                "Content-Length: {}\r\n\r\n{}",
+                message.len(),
+                message
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{base64_decode, base64_encode, DapSession, SourceMap, Status};
+    use crate::{
+        asm::Assembler, chip8::cpu_from_asm, cpu::CPU, json::Json,
+        keyboard::dummy_keyboard::DummyKeyboard,
+    };
+
+    // 1 0x200 LD V0, 0
+    // 2 0x202 CALL inc
+    // 3 0x204 JP 0x202
+    // 4
+    // 5 0x206 inc: ADD V0, 1
+    // 6 0x208 RET
+    const PROGRAM: &str = "LD V0, 0\nCALL inc\nJP 0x202\n\ninc: ADD V0, 1\nRET";
+
+    fn get_session() -> (DapSession, CPU<DummyKeyboard>) {
+        let program = Assembler::new().assemble(PROGRAM).unwrap();
+        let cpu = cpu_from_asm(PROGRAM);
+
+        let session = DapSession::new(Some(SourceMap::new(&program, "game.c8asm")));
+        (session, cpu)
+    }
+
+    /// Sends a request and returns the response and the events that followed it.
+    fn request(
+        session: &mut DapSession,
+        cpu: &mut CPU<DummyKeyboard>,
+        command: &str,
+        arguments: &str,
+    ) -> (Json, Vec<Json>) {
+        let message = format!(
+            r#"{{"seq": 1, "type": "request", "command": "{}", "arguments": {}}}"#,
+            command, arguments
+        );
+        assert_eq!(session.handle(&message, cpu), Status::Attached);
+
+        let mut messages = session
+            .take_messages()
+            .into_iter()
+            .map(|message| Json::parse(&message).unwrap());
+        let response = messages.next().unwrap();
+        assert_eq!(response.get("command").as_str(), Some(command));
+        (response, messages.collect())
+    }
+
+    #[test]
+    fn should_map_lines_to_addresses() {
+        let program = Assembler::new().assemble(PROGRAM).unwrap();
+        let map = SourceMap::new(&program, "game.c8asm");
+
+        assert_eq!(map.address_of("game.c8asm", 2), Some((0x202, 2)));
+        // The blank line moves to the next line with code
+        assert_eq!(map.address_of("game.c8asm", 4), Some((0x206, 5)));
+        assert_eq!(map.address_of("game.c8asm", 7), None);
+        assert_eq!(map.address_of("other.c8asm", 1), None);
+        assert_eq!(map.location_of(0x208).map(|(_, line)| line), Some(6));
+    }
+
+    #[test]
+    fn should_initialize_and_stop_on_entry() {
+        let (mut session, mut cpu) = get_session();
+
+        let (response, events) = request(&mut session, &mut cpu, "initialize", "{}");
+        assert_eq!(response.get("success").as_bool(), Some(true));
+        assert_eq!(
+            response
+                .get("body")
+                .get("supportsConditionalBreakpoints")
+                .as_bool(),
+            Some(true)
+        );
+        assert_eq!(events[0].get("event").as_str(), Some("initialized"));
+
+        request(&mut session, &mut cpu, "launch", r#"{"stopOnEntry": true}"#);
+        let (_, events) = request(&mut session, &mut cpu, "configurationDone", "{}");
+        assert_eq!(events[0].get("body").get("reason").as_str(), Some("entry"));
+        assert!(session.is_paused());
+
+        let (response, _) = request(&mut session, &mut cpu, "nonsense", "{}");
+        assert_eq!(response.get("success").as_bool(), Some(false));
+    }
+
+    #[test]
+    fn should_set_breakpoints_on_source_lines() {
+        let (mut session, mut cpu) = get_session();
+
+        let (response, _) = request(
+            &mut session,
+            &mut cpu,
+            "setBreakpoints",
+            r#"{"source": {"path": "game.c8asm"}, "breakpoints": [{"line": 4},
+                {"line": 9}, {"line": 6, "condition": "V0 =="}]}"#,
+        );
+        let breakpoints = response.get("body").get("breakpoints").as_array();
+        assert_eq!(breakpoints[0].get("verified").as_bool(), Some(true));
+        assert_eq!(breakpoints[0].get("line").as_i64(), Some(5));
+        assert_eq!(breakpoints[1].get("verified").as_bool(), Some(false));
+        assert!(breakpoints[2]
+            .get("message")
+            .as_str()
+            .unwrap()
+            .starts_with("Invalid condition"));
+        assert!(session.debugger.breakpoints().has_address(0x206));
+
+        // Setting a file's breakpoints replaces the ones it had
+        request(
+            &mut session,
+            &mut cpu,
+            "setBreakpoints",
+            r#"{"source": {"path": "game.c8asm"}, "breakpoints": [{"line": 3}]}"#,
+        );
+        assert!(!session.debugger.breakpoints().has_address(0x206));
+        assert!(session.debugger.breakpoints().has_address(0x204));
+    }
+
+    #[test]
+    fn should_set_breakpoints_on_instructions() {
+        let (mut session, mut cpu) = get_session();
+
+        let (response, _) = request(
+            &mut session,
+            &mut cpu,
+            "setInstructionBreakpoints",
+            r#"{"breakpoints": [{"instructionReference": "0x200", "offset": 6},
+                {"instructionReference": "0x7fffffffffffffff", "offset": 1}]}"#,
+        );
+        let breakpoints = response.get("body").get("breakpoints").as_array();
+        assert_eq!(breakpoints[0].get("verified").as_bool(), Some(true));
+        assert_eq!(breakpoints[1].get("verified").as_bool(), Some(false));
+        assert!(session.debugger.breakpoints().has_address(0x206));
+    }
+
+    #[test]
+    fn should_step_and_show_the_stack() {
+        let (mut session, mut cpu) = get_session();
+
+        request(&mut session, &mut cpu, "stepIn", "{}");
+        let (_, events) = request(&mut session, &mut cpu, "stepIn", "{}");
+        assert_eq!(events[0].get("body").get("reason").as_str(), Some("step"));
+        assert_eq!(cpu.pc, 0x206);
+
+        let (response, _) = request(&mut session, &mut cpu, "stackTrace", r#"{"threadId": 1}"#);
+        let frames = response.get("body").get("stackFrames").as_array();
+        assert_eq!(frames.len(), 2);
+        assert_eq!(frames[0].get("line").as_i64(), Some(5));
+        assert_eq!(frames[1].get("line").as_i64(), Some(2));
+        assert_eq!(
+            frames[1].get("instructionPointerReference").as_str(),
+            Some("0x202")
+        );
+
+        let (response, _) = request(
+            &mut session,
+            &mut cpu,
+            "variables",
+            r#"{"variablesReference": 2}"#,
+        );
+        let stack = response.get("body").get("variables").as_array();
+        assert_eq!(stack[0].get("value").as_str(), Some("0x204"));
+    }
+
+    #[test]
+    fn should_read_and_write_registers_and_memory() {
+        let (mut session, mut cpu) = get_session();
+
+        let (response, _) = request(
+            &mut session,
+            &mut cpu,
+            "setVariable",
+            r#"{"variablesReference": 1, "name": "V3", "value": "0x10 + 2"}"#,
+        );
+        assert_eq!(response.get("body").get("value").as_str(), Some("0x12"));
+        assert_eq!(cpu.v[3], 0x12);
+
+        let (response, _) = request(
+            &mut session,
+            &mut cpu,
+            "setVariable",
+            r#"{"variablesReference": 1, "name": "V3", "value": "256"}"#,
+        );
+        assert_eq!(response.get("success").as_bool(), Some(false));
+
+        let (response, _) = request(
+            &mut session,
+            &mut cpu,
+            "variables",
+            r#"{"variablesReference": 1}"#,
+        );
+        let registers = response.get("body").get("variables").as_array();
+        assert_eq!(registers.len(), 21);
+        assert_eq!(registers[3].get("value").as_str(), Some("0x12"));
+
+        let (response, _) = request(
+            &mut session,
+            &mut cpu,
+            "writeMemory",
+            r#"{"memoryReference": "0x300", "offset": 1, "data": "3q2+7w=="}"#,
+        );
+        assert_eq!(response.get("body").get("bytesWritten").as_i64(), Some(4));
+        assert_eq!(cpu.memory.data[0x301..0x305], [0xDE, 0xAD, 0xBE, 0xEF]);
+
+        let (response, _) = request(
+            &mut session,
+            &mut cpu,
+            "readMemory",
+            r#"{"memoryReference": "0x302", "count": 2}"#,
+        );
+        assert_eq!(response.get("body").get("data").as_str(), Some("rb4="));
+
+        let (response, _) = request(
+            &mut session,
+            &mut cpu,
+            "evaluate",
+            r#"{"expression": "[0x301] + V3"}"#,
+        );
+        assert_eq!(
+            response.get("body").get("result").as_str(),
+            Some("240 (0xF0)")
+        );
+    }
+
+    #[test]
+    fn should_disassemble_within_bounds() {
+        let (mut session, mut cpu) = get_session();
+
+        let (response, _) = request(
+            &mut session,
+            &mut cpu,
+            "disassemble",
+            r#"{"memoryReference": "0x200", "instructionOffset": 1, "instructionCount": 2}"#,
+        );
+        let instructions = response.get("body").get("instructions").as_array();
+        assert_eq!(instructions.len(), 2);
+        assert_eq!(instructions[0].get("address").as_str(), Some("0x202"));
+        assert_eq!(instructions[0].get("line").as_i64(), Some(2));
+
+        let (response, _) = request(
+            &mut session,
+            &mut cpu,
+            "disassemble",
+            r#"{"memoryReference": "0x200", "instructionCount": 1000000000000}"#,
+        );
+        let instructions = response.get("body").get("instructions").as_array();
+        assert_eq!(instructions.len(), 0x8000);
+
+        for arguments in [
+            r#"{"memoryReference": "0x7FFFFFFFFFFFFFFF", "offset": 1, "instructionCount": 1}"#,
+            r#"{"memoryReference": "0x200", "instructionOffset": 5000000000000000000}"#,
+        ]
+        .iter()
+        {
+            let (response, _) = request(&mut session, &mut cpu, "disassemble", arguments);
+            assert_eq!(response.get("success").as_bool(), Some(false));
+        }
+
+        let (response, _) = request(
+            &mut session,
+            &mut cpu,
+            "readMemory",
+            r#"{"memoryReference": "0x7FFFFFFFFFFFFFFF", "offset": 1, "count": 1}"#,
+        );
+        assert_eq!(response.get("success").as_bool(), Some(false));
+    }
+
+    #[test]
+    fn should_round_trip_base64() {
+        for bytes in [&b""[..], b"f", b"fo", b"foo", b"foob", b"\xFF\x00\x80"].iter() {
+            let encoded = base64_encode(bytes);
+            assert_eq!(base64_decode(&encoded).as_deref(), Some(*bytes));
+        }
+        assert_eq!(base64_encode(b"foob"), "Zm9vYg==");
+        assert_eq!(base64_decode("Zm9v!"), None);
+    }
+}
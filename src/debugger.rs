@@ -0,0 +1,927 @@
+//! An interactive debugger: breakpoints, stepping and inspecting the machine.
+//!
+//! The [`Debugger`] runs commands typed by the user against a [`CPU`] and returns what to
+//! print, so any front-end can offer it. Stepping happens straight away, while `continue`,
+//! `next` over a `CALL` and `finish` run the program at its normal speed through
+//! [`Debugger::run_frame`] until something makes it stop. Breakpoints can be conditional, and
+//! watch memory, using the [`breakpoints`](crate::breakpoints) engine.
+
+use std::{error::Error, fmt, fmt::Write, str::FromStr};
+
+use crate::{
+    breakpoints::{Breakpoints, Expression, Hit, Register, Trigger, Watchpoint},
+    cpu::{CpuState, StepOutcome, CPU},
+    display::DebugDisplay,
+    error::Chip8Error,
+    instructions::Instruction,
+    keyboard::Keyboard,
+    memory::Access,
+    scheduler::{Scheduler, TimeSource},
+};
+
+// The number of instructions `disas` shows when no count is given
+const DEFAULT_DISASSEMBLY_LINES: u16 = 10;
+
+const HELP: &str = "\
+break <addr> [if <expr>]
+                    stop when the program reaches addr, and the condition holds
+break if <expr>     stop when the condition becomes true, e.g. `V5 == 0x20 && [I] > 3`
+watch [read|write|exec] <addr> [len] [if <expr>]
+                    stop when len bytes from addr are accessed (default write 1)
+info                list the breakpoints
+delete [n]          remove breakpoint n, or every breakpoint
+step [n]            execute n instructions (default 1)
+next                step, running over a CALL
+finish              run until the current subroutine returns
+continue            run until a breakpoint
+pause               stop running
+regs                show the registers
+mem <addr> <len>    show len bytes of memory from addr
+set <reg> <value>   set v0-vf, i, pc, sp, dt or st
+disas [addr] [n]    disassemble n instructions from addr (default pc)
+stack               show the call stack
+screen              show the display
+quit                exit the emulator";
+
+/// A debugger command, parsed from a line such as `break 0x204` or `set v3 0x10`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+    /// Adds a breakpoint or watchpoint, which only fires while the condition holds.
+    Break {
+        trigger: Trigger,
+        condition: Option<Expression>,
+    },
+    /// Deletes the breakpoint with the id, or every breakpoint.
+    Delete(Option<usize>),
+    Info,
+    Step(u32),
+    Next,
+    Finish,
+    Continue,
+    Pause,
+    Regs,
+    Mem {
+        address: u16,
+        len: u16,
+    },
+    Set {
+        register: Register,
+        value: u16,
+    },
+    /// Disassembles from the address, or the program counter.
+    Disas {
+        address: Option<u16>,
+        count: u16,
+    },
+    Stack,
+    Screen,
+    Help,
+    Quit,
+}
+
+/// A line that is not a valid [`Command`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CommandError(String);
+
+impl fmt::Display for CommandError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl Error for CommandError {}
+
+impl FromStr for Command {
+    type Err = CommandError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (s, condition) = match s.split_once(" if ") {
+            Some((s, condition)) => (s, Some(condition)),
+            None => (s, None),
+        };
+        let condition = condition
+            .map(|condition| condition.parse::<Expression>())
+            .transpose()
+            .map_err(|e| CommandError(format!("invalid condition, {}", e)))?;
+
+        let words: Vec<&str> = s.split_whitespace().collect();
+        let number = |i: usize, what: &str| -> Result<u16, CommandError> {
+            let word = words
+                .get(i)
+                .ok_or_else(|| CommandError(format!("expected {}", what)))?;
+            parse_number(word)
+                .ok_or_else(|| CommandError(format!("expected {}, found `{}`", what, word)))
+        };
+        let optional = |i: usize, what: &str| -> Result<Option<u16>, CommandError> {
+            match words.get(i) {
+                Some(_) => number(i, what).map(Some),
+                None => Ok(None),
+            }
+        };
+
+        let command = match words.first().copied().unwrap_or("") {
+            // A condition on its own fires whenever it becomes true
+            "break" | "b" if words.len() == 1 && condition.is_some() => Command::Break {
+                trigger: Trigger::Condition(condition.unwrap()),
+                condition: None,
+            },
+            "break" | "b" => Command::Break {
+                trigger: Trigger::Address(number(1, "an address")?),
+                condition,
+            },
+            "watch" | "w" => {
+                let (access, first) = match words.get(1).copied() {
+                    Some("read") => (Access::Read, 2),
+                    Some("write") => (Access::Write, 2),
+                    Some("exec") => (Access::Execute, 2),
+                    _ => (Access::Write, 1),
+                };
+                Command::Break {
+                    trigger: Trigger::Watch(Watchpoint {
+                        address: number(first, "an address")? as usize,
+                        len: optional(first + 1, "a length")?.unwrap_or(1) as usize,
+                        access,
+                    }),
+                    condition,
+                }
+            }
+            "info" | "i" => Command::Info,
+            "delete" | "d" => Command::Delete(optional(1, "a breakpoint")?.map(usize::from)),
+            "step" | "s" => Command::Step(optional(1, "a count")?.unwrap_or(1) as u32),
+            "next" | "n" => Command::Next,
+            "finish" => Command::Finish,
+            "continue" | "c" => Command::Continue,
+            "pause" => Command::Pause,
+            "regs" | "r" => Command::Regs,
+            "mem" | "m" => Command::Mem {
+                address: number(1, "an address")?,
+                len: number(2, "a length")?,
+            },
+            "set" => {
+                let name = words
+                    .get(1)
+                    .ok_or_else(|| CommandError("expected a register".to_string()))?;
+                let register = Register::from_name(name)
+                    .ok_or_else(|| CommandError(format!("unknown register `{}`", name)))?;
+                Command::Set {
+                    register,
+                    value: number(2, "a value")?,
+                }
+            }
+            "disas" => Command::Disas {
+                address: optional(1, "an address")?,
+                count: optional(2, "a count")?.unwrap_or(DEFAULT_DISASSEMBLY_LINES),
+            },
+            "stack" | "bt" => Command::Stack,
+            "screen" => Command::Screen,
+            "help" | "h" | "?" => Command::Help,
+            "quit" | "q" => Command::Quit,
+            "" => return Err(CommandError("expected a command".to_string())),
+            other => {
+                return Err(CommandError(format!(
+                    "unknown command `{}`, try `help`",
+                    other
+                )))
+            }
+        };
+
+        Ok(command)
+    }
+}
+
+/// Parses a decimal or `0x` prefixed hexadecimal number.
+fn parse_number(value: &str) -> Option<u16> {
+    match value.strip_prefix("0x") {
+        Some(hex) => u16::from_str_radix(hex, 16).ok(),
+        None => value.parse().ok(),
+    }
+}
+
+/// Why the program stopped running.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Stop {
+    Breakpoint(Hit),
+    /// A `next` finished running over a `CALL`.
+    SteppedOver,
+    /// A `finish` saw the subroutine return.
+    Returned,
+    Exited,
+    Fault(Chip8Error),
+}
+
+impl fmt::Display for Stop {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Stop::Breakpoint(hit) => write!(f, "{}", hit),
+            Stop::SteppedOver => write!(f, "Stepped over the call"),
+            Stop::Returned => write!(f, "Returned from the subroutine"),
+            Stop::Exited => write!(f, "The program exited"),
+            Stop::Fault(e) => write!(f, "CPU fault: {}", e),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Mode {
+    Paused,
+    Continue,
+    /// Running until the `CALL` at the stack depth `sp` returns to `return_to`.
+    StepOver {
+        sp: u8,
+        return_to: u16,
+    },
+    /// Running until the stack is shallower than `sp`.
+    Finish {
+        sp: u8,
+    },
+}
+
+/// Breakpoints and the commands that control and inspect a [`CPU`].
+#[derive(Debug, Clone)]
+pub struct Debugger {
+    breakpoints: Breakpoints,
+    mode: Mode,
+    last_line: Option<String>,
+}
+
+impl Default for Debugger {
+    fn default() -> Self {
+        Self {
+            breakpoints: Breakpoints::new(),
+            mode: Mode::Paused,
+            last_line: None,
+        }
+    }
+}
+
+impl Debugger {
+    /// Creates a debugger with the program paused.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.mode == Mode::Paused
+    }
+
+    pub fn pause(&mut self) {
+        self.mode = Mode::Paused;
+    }
+
+    /// Runs the program until something stops it.
+    pub fn resume(&mut self) {
+        self.run(Mode::Continue);
+    }
+
+    pub fn breakpoints(&self) -> &Breakpoints {
+        &self.breakpoints
+    }
+
+    /// The breakpoints, for front-ends that manage them without commands. Watchpoints added
+    /// this way need [`Memory::record_accesses`](crate::memory::Memory::record_accesses).
+    pub fn breakpoints_mut(&mut self) -> &mut Breakpoints {
+        &mut self.breakpoints
+    }
+
+    /// Parses and runs a line typed by the user. An empty line repeats the last command.
+    pub fn execute_line<TKeyboard>(
+        &mut self,
+        line: &str,
+        cpu: &mut CPU<TKeyboard>,
+    ) -> Result<String, CommandError>
+    where
+        TKeyboard: Keyboard,
+    {
+        let line = match (line.trim(), self.last_line.as_ref()) {
+            ("", Some(last)) => last.clone(),
+            (line, _) => line.to_string(),
+        };
+
+        let command = line.parse()?;
+        self.last_line = Some(line);
+
+        Ok(self.execute(command, cpu))
+    }
+
+    /// Runs a command, returning what to show the user.
+    pub fn execute<TKeyboard>(&mut self, command: Command, cpu: &mut CPU<TKeyboard>) -> String
+    where
+        TKeyboard: Keyboard,
+    {
+        match command {
+            Command::Break { trigger, condition } => {
+                let id = self.breakpoints.add(trigger, condition);
+                cpu.memory
+                    .record_accesses(self.breakpoints.watches_memory());
+                format!("Added {}", self.breakpoints.get(id).unwrap())
+            }
+            Command::Delete(id) => {
+                let output = match id {
+                    Some(id) if self.breakpoints.remove(id) => format!("Deleted breakpoint {}", id),
+                    Some(id) => format!("There is no breakpoint {}", id),
+                    None => {
+                        self.breakpoints.clear();
+                        "Deleted every breakpoint".to_string()
+                    }
+                };
+                cpu.memory
+                    .record_accesses(self.breakpoints.watches_memory());
+                output
+            }
+            Command::Info if self.breakpoints.is_empty() => "There are no breakpoints".to_string(),
+            Command::Info => {
+                let lines: Vec<String> = self.breakpoints.iter().map(|b| b.to_string()).collect();
+                lines.join("\n")
+            }
+            Command::Step(count) => {
+                let stop = self.step(count, cpu);
+                report(stop, cpu)
+            }
+            Command::Next => {
+                let stop = self.step_over(cpu);
+                if self.is_paused() {
+                    report(stop, cpu)
+                } else {
+                    String::new()
+                }
+            }
+            Command::Finish => {
+                if self.finish(cpu) {
+                    String::new()
+                } else {
+                    "Not in a subroutine".to_string()
+                }
+            }
+            Command::Continue => {
+                self.resume();
+                String::new()
+            }
+            Command::Pause => {
+                self.pause();
+                location(cpu)
+            }
+            Command::Regs => registers(cpu),
+            Command::Mem { address, len } => memory(cpu, address, len),
+            Command::Set { register, value } => {
+                if register.write(cpu, value) {
+                    registers(cpu)
+                } else {
+                    format!("0x{:X} is too large for {}", value, register)
+                }
+            }
+            Command::Disas { address, count } => {
+                disassemble(cpu, address.unwrap_or(cpu.pc), count, &self.breakpoints)
+            }
+            Command::Stack => stack(cpu),
+            Command::Screen => {
+                cpu.display.view_state();
+                String::new()
+            }
+            Command::Help => HELP.to_string(),
+            // Leaving is up to the front-end
+            Command::Quit => String::new(),
+        }
+    }
+
+    /// Runs a frame of the program, unless it is paused. Returns why it stopped, if it did.
+    pub fn run_frame<TKeyboard, TClock>(
+        &mut self,
+        scheduler: &Scheduler<TClock>,
+        cpu: &mut CPU<TKeyboard>,
+    ) -> Option<Stop>
+    where
+        TKeyboard: Keyboard,
+        TClock: TimeSource,
+    {
+        if self.is_paused() {
+            return None;
+        }
+
+        let mode = self.mode;
+        let breakpoints = &mut self.breakpoints;
+        let mut stop = None;
+
+        let result = scheduler.run_frame_until(cpu, |cpu| {
+            stop = match (breakpoints.check(cpu), mode) {
+                (Some(hit), _) => Some(Stop::Breakpoint(hit)),
+                (None, Mode::StepOver { sp, return_to }) if cpu.sp == sp && cpu.pc == return_to => {
+                    Some(Stop::SteppedOver)
+                }
+                (None, Mode::Finish { sp }) if cpu.sp < sp => Some(Stop::Returned),
+                _ => None,
+            };
+            stop.is_some()
+        });
+
+        match result {
+            Err(e) => stop = Some(Stop::Fault(e)),
+            Ok(_) if cpu.state() == CpuState::Exited => stop = Some(Stop::Exited),
+            Ok(_) => {}
+        }
+
+        if stop.is_some() {
+            self.pause();
+        }
+        stop
+    }
+
+    fn run(&mut self, mode: Mode) {
+        self.mode = mode;
+        self.breakpoints.resume();
+    }
+
+    /// Executes instructions straight away, returning what stopped them early, if anything.
+    pub fn step<TKeyboard>(&mut self, count: u32, cpu: &mut CPU<TKeyboard>) -> Option<Stop>
+    where
+        TKeyboard: Keyboard,
+    {
+        self.pause();
+
+        for _ in 0..count {
+            match step_instruction(cpu) {
+                Err(e) => return Some(Stop::Fault(e)),
+                Ok(StepOutcome::Exited) => return Some(Stop::Exited),
+                Ok(_) => {}
+            }
+
+            if let Some(hit) = self.breakpoints.check(cpu) {
+                return Some(Stop::Breakpoint(hit));
+            }
+        }
+
+        None
+    }
+
+    /// Steps over the next instruction. A `CALL` is left running until it returns, and
+    /// [`Debugger::run_frame`] reports [`Stop::SteppedOver`] when it has.
+    pub fn step_over<TKeyboard>(&mut self, cpu: &mut CPU<TKeyboard>) -> Option<Stop>
+    where
+        TKeyboard: Keyboard,
+    {
+        match decode_at(cpu, cpu.pc) {
+            Some(Instruction::Call { .. }) => {
+                self.run(Mode::StepOver {
+                    sp: cpu.sp,
+                    return_to: cpu.pc.wrapping_add(2),
+                });
+                None
+            }
+            _ => self.step(1, cpu),
+        }
+    }
+
+    /// Runs until the current subroutine returns. Returns false when not in a subroutine.
+    pub fn finish<TKeyboard>(&mut self, cpu: &CPU<TKeyboard>) -> bool
+    where
+        TKeyboard: Keyboard,
+    {
+        if cpu.sp == 0 {
+            return false;
+        }
+
+        self.run(Mode::Finish { sp: cpu.sp });
+        true
+    }
+}
+
+/// Describes where stepping stopped.
+fn report<TKeyboard>(stop: Option<Stop>, cpu: &CPU<TKeyboard>) -> String
+where
+    TKeyboard: Keyboard,
+{
+    match stop {
+        None => location(cpu),
+        Some(Stop::Exited) => Stop::Exited.to_string(),
+        Some(stop) => format!("{}\n{}", stop, location(cpu)),
+    }
+}
+
+/// Executes the next instruction. There is no frame to wait for when stepping, so an
+/// instruction waiting for the vertical blank gets one straight away.
+pub fn step_instruction<TKeyboard>(cpu: &mut CPU<TKeyboard>) -> Result<StepOutcome, Chip8Error>
+where
+    TKeyboard: Keyboard,
+{
+    match cpu.execute_next_instruction()? {
+        StepOutcome::WaitingForVBlank => {
+            cpu.vblank();
+            cpu.execute_next_instruction()
+        }
+        outcome => Ok(outcome),
+    }
+}
+
+/// Decodes the instruction at an address, or `None` when it runs off the end of memory.
+pub fn decode_at<TKeyboard>(cpu: &CPU<TKeyboard>, address: u16) -> Option<Instruction>
+where
+    TKeyboard: Keyboard,
+{
+    let word = |address: u16| {
+        let high = *cpu.memory.data.get(address as usize)?;
+        let low = *cpu.memory.data.get(address as usize + 1)?;
+        Some(u16::from_be_bytes([high, low]))
+    };
+
+    let raw = word(address)?;
+    if Instruction::is_long(raw) {
+        Some(Instruction::decode_long(
+            raw,
+            word(address.wrapping_add(2))?,
+        ))
+    } else {
+        Some(Instruction::decode(raw))
+    }
+}
+
+/// The instruction at the program counter, e.g. `0x204: DRW V0, V0, 0x5`.
+pub fn location<TKeyboard>(cpu: &CPU<TKeyboard>) -> String
+where
+    TKeyboard: Keyboard,
+{
+    let location = match decode_at(cpu, cpu.pc) {
+        Some(instruction) => format!("0x{:03X}: {}", cpu.pc, instruction),
+        None => format!("0x{:03X}: outside of memory", cpu.pc),
+    };
+
+    match cpu.state() {
+        CpuState::WaitingForKey { register } => format!(
+            "{} (waiting for a key to be released into V{:X})",
+            location, register
+        ),
+        _ => location,
+    }
+}
+
+fn registers<TKeyboard>(cpu: &CPU<TKeyboard>) -> String
+where
+    TKeyboard: Keyboard,
+{
+    let mut out = String::new();
+    for row in cpu.v.chunks(8).enumerate() {
+        let (start, values) = row;
+        let line: Vec<String> = values
+            .iter()
+            .enumerate()
+            .map(|(i, value)| format!("V{:X}=0x{:02X}", start * 8 + i, value))
+            .collect();
+        writeln!(out, "{}", line.join(" ")).unwrap();
+    }
+    write!(
+        out,
+        "I=0x{:03X} PC=0x{:03X} SP={} DT={} ST={}",
+        cpu.vi, cpu.pc, cpu.sp, cpu.delay_timer, cpu.sound_timer
+    )
+    .unwrap();
+
+    out
+}
+
+fn memory<TKeyboard>(cpu: &CPU<TKeyboard>, address: u16, len: u16) -> String
+where
+    TKeyboard: Keyboard,
+{
+    let start = address as usize;
+    let end = (start + len as usize).min(cpu.memory.data.len());
+    if start >= end {
+        return format!("0x{:03X} is outside of memory", address);
+    }
+
+    let lines: Vec<String> = cpu.memory.data[start..end]
+        .chunks(16)
+        .enumerate()
+        .map(|(i, row)| {
+            let bytes: Vec<String> = row.iter().map(|byte| format!("{:02X}", byte)).collect();
+            format!("0x{:03X}: {}", start + i * 16, bytes.join(" "))
+        })
+        .collect();
+
+    lines.join("\n")
+}
+
+fn disassemble<TKeyboard>(
+    cpu: &CPU<TKeyboard>,
+    start: u16,
+    count: u16,
+    breakpoints: &Breakpoints,
+) -> String
+where
+    TKeyboard: Keyboard,
+{
+    let mut lines = vec![];
+    let mut address = start;
+    for _ in 0..count {
+        let instruction = match decode_at(cpu, address) {
+            Some(instruction) => instruction,
+            None => break,
+        };
+
+        // `>` marks the next instruction to run and `*` a breakpoint
+        lines.push(format!(
+            "{}{} 0x{:03X}: {}",
+            if address == cpu.pc { '>' } else { ' ' },
+            if breakpoints.has_address(address) {
+                '*'
+            } else {
+                ' '
+            },
+            address,
+            instruction
+        ));
+        address = match address.checked_add(instruction.size()) {
+            Some(next) => next,
+            None => break,
+        };
+    }
+
+    if lines.is_empty() && count > 0 {
+        return format!("0x{:03X} is outside of memory", start);
+    }
+    lines.join("\n")
+}
+
+fn stack<TKeyboard>(cpu: &CPU<TKeyboard>) -> String
+where
+    TKeyboard: Keyboard,
+{
+    // The return addresses are pushed from stack[0] up, so the innermost call is on top
+    let mut lines = vec![format!("#0 0x{:03X}", cpu.pc)];
+    for (depth, sp) in (0..cpu.sp as usize).rev().enumerate() {
+        lines.push(format!(
+            "#{} 0x{:03X} (returns to 0x{:03X})",
+            depth + 1,
+            cpu.stack[sp].wrapping_sub(2),
+            cpu.stack[sp]
+        ));
+    }
+
+    lines.join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Command, CommandError, Debugger, Register, Stop};
+    use crate::{
+        breakpoints::{Cause, Hit, Trigger, Watchpoint},
+        chip8::cpu_from_asm,
+        cpu::CPU,
+        keyboard::dummy_keyboard::DummyKeyboard,
+        memory::Access,
+        scheduler::{ManualClock, Scheduler},
+    };
+
+    // main:    0x200 CALL count
+    //          0x202 CALL count
+    //          0x204 JP main
+    // count:   0x206 ADD V0, 1
+    //          0x208 RET
+    fn get_cpu() -> CPU<DummyKeyboard> {
+        cpu_from_asm("main: CALL count\nCALL count\nJP main\ncount: ADD V0, 1\nRET")
+    }
+
+    fn get_scheduler() -> Scheduler<ManualClock> {
+        Scheduler::with_clock(ManualClock::default()).instructions_per_second(600)
+    }
+
+    fn break_at(address: u16) -> Command {
+        Command::Break {
+            trigger: Trigger::Address(address),
+            condition: None,
+        }
+    }
+
+    fn hit_at(id: usize, address: u16) -> Option<Stop> {
+        Some(Stop::Breakpoint(Hit {
+            id,
+            cause: Cause::Address(address),
+        }))
+    }
+
+    #[test]
+    fn should_parse_commands() {
+        assert_eq!("break 0x204".parse(), Ok(break_at(0x204)));
+        assert_eq!(
+            "b 0x204 if v0 == 2".parse(),
+            Ok(Command::Break {
+                trigger: Trigger::Address(0x204),
+                condition: Some("v0 == 2".parse().unwrap())
+            })
+        );
+        assert_eq!(
+            "break if I > 0x300".parse(),
+            Ok(Command::Break {
+                trigger: Trigger::Condition("I > 0x300".parse().unwrap()),
+                condition: None
+            })
+        );
+        assert_eq!(
+            "watch read 0x300 4".parse(),
+            Ok(Command::Break {
+                trigger: Trigger::Watch(Watchpoint {
+                    address: 0x300,
+                    len: 4,
+                    access: Access::Read
+                }),
+                condition: None
+            })
+        );
+        assert_eq!("delete 2".parse(), Ok(Command::Delete(Some(2))));
+        assert_eq!("step".parse(), Ok(Command::Step(1)));
+        assert_eq!("s 5".parse(), Ok(Command::Step(5)));
+        assert_eq!("delete".parse(), Ok(Command::Delete(None)));
+        assert_eq!(
+            "mem 0x300 16".parse(),
+            Ok(Command::Mem {
+                address: 0x300,
+                len: 16
+            })
+        );
+        assert_eq!(
+            "set v3 0x10".parse(),
+            Ok(Command::Set {
+                register: Register::V(3),
+                value: 0x10
+            })
+        );
+        assert_eq!(
+            "disas".parse(),
+            Ok(Command::Disas {
+                address: None,
+                count: 10
+            })
+        );
+        assert!("set v16 1".parse::<Command>().is_err());
+        assert!("break".parse::<Command>().is_err());
+        assert!("break if V0 ==".parse::<Command>().is_err());
+        assert!("jump".parse::<Command>().is_err());
+        assert_eq!(
+            "s 70000".parse::<Command>(),
+            Err(CommandError("expected a count, found `70000`".to_string()))
+        );
+    }
+
+    #[test]
+    fn should_stop_at_breakpoints() {
+        let mut cpu = get_cpu();
+        let scheduler = get_scheduler();
+        let mut debugger = Debugger::new();
+
+        debugger.execute(break_at(0x206), &mut cpu);
+        debugger.execute(Command::Continue, &mut cpu);
+
+        assert_eq!(debugger.run_frame(&scheduler, &mut cpu), hit_at(1, 0x206));
+        assert!(debugger.is_paused());
+        assert_eq!(cpu.v[0], 0);
+
+        // Continuing runs the instruction at the breakpoint before stopping there again
+        debugger.execute(Command::Continue, &mut cpu);
+        assert_eq!(debugger.run_frame(&scheduler, &mut cpu), hit_at(1, 0x206));
+        assert_eq!(cpu.v[0], 1);
+    }
+
+    #[test]
+    fn should_not_run_while_paused() {
+        let mut cpu = get_cpu();
+        let mut debugger = Debugger::new();
+
+        assert_eq!(debugger.run_frame(&get_scheduler(), &mut cpu), None);
+        assert_eq!(cpu.pc, 0x200);
+    }
+
+    #[test]
+    fn should_step_instructions() {
+        let mut cpu = get_cpu();
+        let mut debugger = Debugger::new();
+
+        assert_eq!(
+            debugger.execute(Command::Step(3), &mut cpu),
+            "0x202: CALL 0x206"
+        );
+        assert_eq!(cpu.v[0], 1);
+
+        // An empty line repeats the last command
+        debugger.execute_line("step", &mut cpu).unwrap();
+        assert_eq!(
+            debugger.execute_line("", &mut cpu),
+            Ok("0x208: RET".to_string())
+        );
+    }
+
+    #[test]
+    fn should_step_over_calls() {
+        let mut cpu = get_cpu();
+        let scheduler = get_scheduler();
+        let mut debugger = Debugger::new();
+
+        debugger.execute(Command::Next, &mut cpu);
+
+        assert_eq!(
+            debugger.run_frame(&scheduler, &mut cpu),
+            Some(Stop::SteppedOver)
+        );
+        assert_eq!(cpu.pc, 0x202);
+        assert_eq!(cpu.v[0], 1);
+    }
+
+    #[test]
+    fn should_finish_subroutines() {
+        let mut cpu = get_cpu();
+        let scheduler = get_scheduler();
+        let mut debugger = Debugger::new();
+
+        debugger.execute(Command::Step(1), &mut cpu);
+        assert_eq!(
+            debugger.execute(Command::Stack, &mut cpu),
+            "#0 0x206\n#1 0x200 (returns to 0x202)"
+        );
+        debugger.execute(Command::Finish, &mut cpu);
+
+        assert_eq!(
+            debugger.run_frame(&scheduler, &mut cpu),
+            Some(Stop::Returned)
+        );
+        assert_eq!(cpu.pc, 0x202);
+    }
+
+    #[test]
+    fn should_show_and_set_registers() {
+        let mut cpu = get_cpu();
+        let mut debugger = Debugger::new();
+
+        let registers = debugger.execute_line("set v3 0x10", &mut cpu).unwrap();
+
+        assert_eq!(cpu.v[3], 0x10);
+        assert!(registers.contains("V3=0x10"));
+        assert!(registers.ends_with("I=0x000 PC=0x200 SP=0 DT=0 ST=0"));
+        assert_eq!(
+            debugger.execute_line("set v3 0x100", &mut cpu),
+            Ok("0x100 is too large for V3".to_string())
+        );
+    }
+
+    #[test]
+    fn should_show_memory_and_disassembly() {
+        let mut cpu = get_cpu();
+        let mut debugger = Debugger::new();
+        debugger.execute(break_at(0x204), &mut cpu);
+
+        assert_eq!(
+            debugger.execute_line("mem 0x200 4", &mut cpu),
+            Ok("0x200: 22 06 22 06".to_string())
+        );
+        assert_eq!(
+            debugger.execute_line("disas 0x200 3", &mut cpu),
+            Ok(">  0x200: CALL 0x206\n   0x202: CALL 0x206\n * 0x204: JP 0x200".to_string())
+        );
+        assert_eq!(
+            debugger.execute_line("disas 0xFFFF", &mut cpu),
+            Ok("0xFFFF is outside of memory".to_string())
+        );
+    }
+
+    #[test]
+    fn should_report_which_breakpoint_fired() {
+        let mut cpu = get_cpu();
+        let scheduler = get_scheduler();
+        let mut debugger = Debugger::new();
+
+        debugger
+            .execute_line("break 0x206 if V0 == 1", &mut cpu)
+            .unwrap();
+        assert_eq!(
+            debugger.execute_line("watch exec 0x204", &mut cpu),
+            Ok("Added 2: watch exec 0x204 1".to_string())
+        );
+        debugger.execute(Command::Continue, &mut cpu);
+
+        // The first call runs with V0 == 0, so the conditional breakpoint lets it through
+        assert_eq!(debugger.run_frame(&scheduler, &mut cpu), hit_at(1, 0x206));
+        assert_eq!(cpu.v[0], 1);
+
+        debugger.execute(Command::Continue, &mut cpu);
+        let stop = debugger.run_frame(&scheduler, &mut cpu).unwrap();
+        assert_eq!(stop.to_string(), "Watchpoint 2, executing 0x204");
+
+        assert_eq!(
+            debugger.execute(Command::Info, &mut cpu),
+            "1: break 0x206 if V0 == 1\n2: watch exec 0x204 1"
+        );
+        debugger.execute_line("delete 1", &mut cpu).unwrap();
+        assert_eq!(
+            debugger.execute(Command::Info, &mut cpu),
+            "2: watch exec 0x204 1"
+        );
+    }
+
+    #[test]
+    fn should_step_until_a_watchpoint_fires() {
+        let mut cpu = cpu_from_asm("LD I, 0x300\nLD V0, 7\nLD B, V0\nLD V1, 1");
+        let mut debugger = Debugger::new();
+
+        debugger.execute_line("watch 0x302", &mut cpu).unwrap();
+
+        assert_eq!(
+            debugger.execute(Command::Step(4), &mut cpu),
+            "Watchpoint 1, wrote 0x07 to 0x302\n0x206: LD V1, 0x01"
+        );
+    }
+}
@@ -0,0 +1,601 @@
+//! A disassembler that separates a ROM's code from its data.
+//!
+//! Rather than decoding every pair of bytes, the disassembler follows the program from its
+//! entry point: straight-line code, both sides of every skip, and the targets of `JP` and
+//! `CALL`. Anything that is never reached is shown as data. Bytes that are pointed at by
+//! `LD I, nnn` and then drawn with `DRW` are shown as sprites, with a picture of each row.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    convert::TryFrom,
+    fmt::Write,
+};
+
+use crate::{instructions::Instruction, memory::PROGRAM_START_OFFSET};
+
+// The number of unreached bytes shown on each line of data
+const DATA_BYTES_PER_LINE: usize = 8;
+
+// The column comments start at, so they line up
+const COMMENT_COLUMN: usize = 24;
+
+/// The assembly language to write the disassembly in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Syntax {
+    /// The mnemonics from Cowgod's Technical Reference, e.g. `LD V0, 0x05`.
+    Cowgod,
+    /// The syntax of the Octo assembler, e.g. `v0 := 0x05`.
+    Octo,
+}
+
+impl Syntax {
+    /// Looks up a syntax by the name used on the command line.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "cowgod" => Some(Syntax::Cowgod),
+            "octo" => Some(Syntax::Octo),
+            _ => None,
+        }
+    }
+
+    fn comment(self) -> &'static str {
+        match self {
+            Syntax::Cowgod => ";",
+            Syntax::Octo => "#",
+        }
+    }
+}
+
+// Why an address has a label. Later variants take priority when naming it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum LabelKind {
+    Data,
+    Sprite,
+    Jump,
+    Subroutine,
+    Entry,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ByteKind {
+    Data,
+    Code,
+    /// A row of a sprite that is `width` pixels wide.
+    Sprite {
+        width: u8,
+    },
+}
+
+/// The code, sprites and labels found by following a program.
+struct Analysis {
+    instructions: BTreeMap<u16, Instruction>,
+    kinds: Vec<ByteKind>,
+    labels: BTreeMap<u16, LabelKind>,
+}
+
+/// Disassembles programs.
+///
+/// ```
+/// use chip8_rs::disasm::{Disassembler, Syntax};
+///
+/// // LD V0, 0x05; JP 0x202
+/// let source = Disassembler::new()
+///     .syntax(Syntax::Octo)
+///     .disassemble(&[0x60, 0x05, 0x12, 0x02]);
+/// assert!(source.contains("v0 := 0x05"));
+/// assert!(source.contains("jump label_202"));
+/// ```
+#[derive(Debug, Clone, Copy)]
+pub struct Disassembler {
+    origin: u16,
+    syntax: Syntax,
+}
+
+impl Default for Disassembler {
+    fn default() -> Self {
+        Self {
+            origin: PROGRAM_START_OFFSET as u16,
+            syntax: Syntax::Cowgod,
+        }
+    }
+}
+
+impl Disassembler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the address the program is loaded and started at. Defaults to `0x200`.
+    pub fn origin(mut self, origin: u16) -> Self {
+        self.origin = origin;
+        self
+    }
+
+    /// Sets the assembly language to write. Defaults to [`Syntax::Cowgod`].
+    pub fn syntax(mut self, syntax: Syntax) -> Self {
+        self.syntax = syntax;
+        self
+    }
+
+    /// Disassembles a program loaded at the origin.
+    pub fn disassemble(&self, program: &[u8]) -> String {
+        let analysis = self.analyse(program);
+        let comment = self.syntax.comment();
+        let end = self.origin as usize + program.len();
+
+        let mut out = String::new();
+        writeln!(
+            out,
+            "{} {} bytes loaded at 0x{:03X}",
+            comment,
+            program.len(),
+            self.origin
+        )
+        .unwrap();
+
+        // Addresses past the end of memory can't have labels
+        let has_label = |address: usize| {
+            u16::try_from(address).is_ok_and(|address| analysis.labels.contains_key(&address))
+        };
+
+        let mut address = self.origin as usize;
+        while address < end {
+            let offset = address - self.origin as usize;
+            if has_label(address) {
+                out.push('\n');
+                let name = label_name(&analysis, address as u16).unwrap();
+                match self.syntax {
+                    Syntax::Cowgod => writeln!(out, "{}:", name).unwrap(),
+                    Syntax::Octo => writeln!(out, ": {}", name).unwrap(),
+                }
+            }
+
+            let (text, note, size) = match (
+                analysis.instructions.get(&(address as u16)),
+                analysis.kinds[offset],
+            ) {
+                (Some(instruction), _) => {
+                    let size = instruction.size() as usize;
+                    let raw = program[offset..offset + size]
+                        .iter()
+                        .map(|byte| format!("{:02X}", byte))
+                        .collect::<String>();
+                    let text = self.format_instruction(&analysis, *instruction);
+
+                    // A label inside the instruction, like a jump into the address of a
+                    // `LD I, LONG`, can only be written by splitting it into data
+                    match (address + 1..address + size).find(|inner| has_label(*inner)) {
+                        Some(inner) => (
+                            self.format_bytes(&program[offset..inner - self.origin as usize]),
+                            format!("{:03X}: {} {}", address, raw, text),
+                            inner - address,
+                        ),
+                        None => (text, format!("{:03X}: {}", address, raw), size),
+                    }
+                }
+                (None, ByteKind::Sprite { width }) => {
+                    // Wide sprites have two bytes to a row, as long as both are there
+                    let size = if width == 16
+                        && offset + 1 < program.len()
+                        && analysis.kinds[offset + 1] == (ByteKind::Sprite { width })
+                        && !has_label(address + 1)
+                    {
+                        2
+                    } else {
+                        1
+                    };
+                    let row = &program[offset..offset + size];
+                    (
+                        self.format_bytes(row),
+                        format!("{:03X}: {}", address, sprite_row(row)),
+                        size,
+                    )
+                }
+                (None, _) => {
+                    let mut size = 1;
+                    while size < DATA_BYTES_PER_LINE
+                        && offset + size < program.len()
+                        && analysis.kinds[offset + size] == ByteKind::Data
+                        && !has_label(address + size)
+                    {
+                        size += 1;
+                    }
+                    (
+                        self.format_bytes(&program[offset..offset + size]),
+                        format!("{:03X}", address),
+                        size,
+                    )
+                }
+            };
+
+            writeln!(
+                out,
+                "    {:width$} {} {}",
+                text,
+                comment,
+                note,
+                width = COMMENT_COLUMN
+            )
+            .unwrap();
+            address += size;
+        }
+
+        out
+    }
+
+    /// Follows every path through the program from the origin.
+    fn analyse(&self, program: &[u8]) -> Analysis {
+        let origin = self.origin as usize;
+        let end = origin + program.len();
+        let in_program = |address: u16| (origin..end).contains(&(address as usize));
+        let word_at = |address: u16| {
+            let offset = (address as usize).checked_sub(origin)?;
+            let bytes = program.get(offset..offset + 2)?;
+            Some(u16::from_be_bytes([bytes[0], bytes[1]]))
+        };
+        let decode_at = |address: u16| {
+            let raw = word_at(address)?;
+            if Instruction::is_long(raw) {
+                Some(Instruction::decode_long(
+                    raw,
+                    word_at(address.wrapping_add(2))?,
+                ))
+            } else {
+                Some(Instruction::decode(raw))
+            }
+        };
+
+        let mut instructions = BTreeMap::new();
+        let mut kinds = vec![ByteKind::Data; program.len()];
+        let mut labels = BTreeMap::new();
+        let mut label = |address: u16, kind: LabelKind| {
+            if in_program(address) {
+                let existing = labels.entry(address).or_insert(kind);
+                *existing = (*existing).max(kind);
+            }
+        };
+        label(self.origin, LabelKind::Entry);
+
+        // Each path carries the address I was last set to, if it is still known
+        let mut pending: Vec<(u16, Option<u16>)> = vec![(self.origin, None)];
+        let mut visited = BTreeSet::new();
+        while let Some((address, mut i)) = pending.pop() {
+            if !in_program(address) || !visited.insert(address) {
+                continue;
+            }
+
+            let instruction = match decode_at(address) {
+                Some(Instruction::Unknown(_)) | None => continue,
+                Some(instruction) => instruction,
+            };
+            instructions.insert(address, instruction);
+            let next = address.wrapping_add(instruction.size());
+
+            match instruction {
+                Instruction::Jp { nnn } => {
+                    label(nnn, LabelKind::Jump);
+                    pending.push((nnn, i));
+                }
+                Instruction::Call { nnn } => {
+                    label(nnn, LabelKind::Subroutine);
+                    // The subroutine may have moved I
+                    pending.push((nnn, None));
+                    pending.push((next, None));
+                }
+                // Where `JP V0, nnn` goes depends on V0, so the path ends here
+                Instruction::Ret | Instruction::Exit | Instruction::JpV0 { .. } => {}
+                Instruction::SeByte { .. }
+                | Instruction::SneByte { .. }
+                | Instruction::SeXY { .. }
+                | Instruction::SneXY { .. }
+                | Instruction::Skp { .. }
+                | Instruction::Sknp { .. } => {
+                    let skipped = decode_at(next).map_or(2, |skipped| skipped.size());
+                    pending.push((next, i));
+                    pending.push((next.wrapping_add(skipped), i));
+                }
+                Instruction::LdI { nnn } => {
+                    label(nnn, LabelKind::Data);
+                    pending.push((next, Some(nnn)));
+                }
+                Instruction::LdILong { nnnn } => {
+                    label(nnnn, LabelKind::Data);
+                    pending.push((next, Some(nnnn)));
+                }
+                Instruction::AddIX { .. }
+                | Instruction::LdFont { .. }
+                | Instruction::LdHiFont { .. } => {
+                    i = None;
+                    pending.push((next, i));
+                }
+                Instruction::Draw { n, .. } => {
+                    if let Some(sprite) = i {
+                        let (len, width) = if n == 0 { (32, 16) } else { (n as usize, 8) };
+                        label(sprite, LabelKind::Sprite);
+                        for address in sprite as usize..sprite as usize + len {
+                            if (origin..end).contains(&address) {
+                                kinds[address - origin] = ByteKind::Sprite { width };
+                            }
+                        }
+                    }
+                    pending.push((next, i));
+                }
+                _ => pending.push((next, i)),
+            }
+        }
+
+        // Code wins over sprites, for programs that draw themselves
+        for (address, instruction) in instructions.iter() {
+            let start = *address as usize - origin;
+            let stop = (start + instruction.size() as usize).min(program.len());
+            for kind in kinds[start..stop].iter_mut() {
+                *kind = ByteKind::Code;
+            }
+        }
+
+        Analysis {
+            instructions,
+            kinds,
+            labels,
+        }
+    }
+
+    fn format_instruction(&self, analysis: &Analysis, instruction: Instruction) -> String {
+        let address = |nnn: u16| {
+            label_name(analysis, nnn).unwrap_or_else(|| match instruction {
+                Instruction::LdILong { .. } => format!("0x{:04X}", nnn),
+                _ => format!("0x{:03X}", nnn),
+            })
+        };
+
+        match self.syntax {
+            Syntax::Cowgod => match instruction {
+                Instruction::Jp { nnn } => format!("JP {}", address(nnn)),
+                Instruction::Call { nnn } => format!("CALL {}", address(nnn)),
+                Instruction::LdI { nnn } => format!("LD I, {}", address(nnn)),
+                Instruction::JpV0 { nnn } => format!("JP V0, {}", address(nnn)),
+                Instruction::LdILong { nnnn } => format!("LD I, LONG {}", address(nnnn)),
+                _ => instruction.to_string(),
+            },
+            Syntax::Octo => octo(instruction, address),
+        }
+    }
+
+    fn format_bytes(&self, bytes: &[u8]) -> String {
+        let bytes: Vec<String> = bytes.iter().map(|byte| format!("0x{:02X}", byte)).collect();
+        match self.syntax {
+            Syntax::Cowgod => format!("DB {}", bytes.join(", ")),
+            Syntax::Octo => bytes.join(" "),
+        }
+    }
+}
+
+fn label_name(analysis: &Analysis, address: u16) -> Option<String> {
+    let prefix = match analysis.labels.get(&address)? {
+        LabelKind::Entry => return Some("main".to_string()),
+        LabelKind::Subroutine => "sub",
+        LabelKind::Jump => "label",
+        LabelKind::Sprite => "sprite",
+        LabelKind::Data => "data",
+    };
+
+    Some(format!("{}_{:03X}", prefix, address))
+}
+
+/// Draws a row of a sprite, `#` for a set pixel and `.` for a clear one.
+fn sprite_row(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .flat_map(|byte| (0..8).rev().map(move |bit| (byte >> bit) & 1))
+        .map(|pixel| if pixel == 1 { '#' } else { '.' })
+        .collect()
+}
+
+/// Writes an instruction in Octo's syntax. `address` names the target of jumps and loads.
+fn octo(instruction: Instruction, address: impl Fn(u16) -> String) -> String {
+    match instruction {
+        // Octo has no mnemonic for these, so they are written as the bytes they assemble to
+        Instruction::Sys { nnn } => format!("0x{:02X} 0x{:02X}", nnn >> 8, nnn & 0xFF),
+        Instruction::Unknown(raw) => format!("0x{:02X} 0x{:02X}", raw >> 8, raw & 0xFF),
+        Instruction::Cls => "clear".to_string(),
+        Instruction::Ret => "return".to_string(),
+        Instruction::ScrollUp { n } => format!("scroll-up {}", n),
+        Instruction::ScrollDown { n } => format!("scroll-down {}", n),
+        Instruction::ScrollRight => "scroll-right".to_string(),
+        Instruction::ScrollLeft => "scroll-left".to_string(),
+        Instruction::Exit => "exit".to_string(),
+        Instruction::LoRes => "lores".to_string(),
+        Instruction::HiRes => "hires".to_string(),
+        Instruction::Jp { nnn } => format!("jump {}", address(nnn)),
+        // Subroutines are called by name, and `:call` is only needed for bare addresses
+        Instruction::Call { nnn } => match address(nnn) {
+            name if name.starts_with("0x") => format!(":call {}", name),
+            name => name,
+        },
+        // Octo's conditions say when the next instruction runs, the opposite of when it is skipped
+        Instruction::SeByte { x, kk } => format!("if v{:x} != 0x{:02X} then", x, kk),
+        Instruction::SneByte { x, kk } => format!("if v{:x} == 0x{:02X} then", x, kk),
+        Instruction::SeXY { x, y } => format!("if v{:x} != v{:x} then", x, y),
+        Instruction::SneXY { x, y } => format!("if v{:x} == v{:x} then", x, y),
+        Instruction::Skp { x } => format!("if v{:x} -key then", x),
+        Instruction::Sknp { x } => format!("if v{:x} key then", x),
+        Instruction::StoreRange { x, y } => format!("save v{:x} - v{:x}", x, y),
+        Instruction::LoadRange { x, y } => format!("load v{:x} - v{:x}", x, y),
+        Instruction::LdByte { x, kk } => format!("v{:x} := 0x{:02X}", x, kk),
+        Instruction::AddByte { x, kk } => format!("v{:x} += 0x{:02X}", x, kk),
+        Instruction::LdXY { x, y } => format!("v{:x} := v{:x}", x, y),
+        Instruction::OrXY { x, y } => format!("v{:x} |= v{:x}", x, y),
+        Instruction::AndXY { x, y } => format!("v{:x} &= v{:x}", x, y),
+        Instruction::XorXY { x, y } => format!("v{:x} ^= v{:x}", x, y),
+        Instruction::AddXY { x, y } => format!("v{:x} += v{:x}", x, y),
+        Instruction::SubXY { x, y } => format!("v{:x} -= v{:x}", x, y),
+        Instruction::ShrXY { x, y } => format!("v{:x} >>= v{:x}", x, y),
+        Instruction::SubnXY { x, y } => format!("v{:x} =- v{:x}", x, y),
+        Instruction::ShlXY { x, y } => format!("v{:x} <<= v{:x}", x, y),
+        Instruction::LdI { nnn } => format!("i := {}", address(nnn)),
+        Instruction::JpV0 { nnn } => format!("jump0 {}", address(nnn)),
+        Instruction::Rnd { x, kk } => format!("v{:x} := random 0x{:02X}", x, kk),
+        Instruction::Draw { x, y, n } => format!("sprite v{:x} v{:x} {}", x, y, n),
+        Instruction::LdILong { nnnn } => format!("i := long {}", address(nnnn)),
+        Instruction::Plane { n } => format!("plane {}", n),
+        Instruction::LdAudio => "audio".to_string(),
+        Instruction::LdXDt { x } => format!("v{:x} := delay", x),
+        Instruction::LdXKey { x } => format!("v{:x} := key", x),
+        Instruction::LdDtX { x } => format!("delay := v{:x}", x),
+        Instruction::LdStX { x } => format!("buzzer := v{:x}", x),
+        Instruction::AddIX { x } => format!("i += v{:x}", x),
+        Instruction::LdFont { x } => format!("i := hex v{:x}", x),
+        Instruction::LdPitch { x } => format!("pitch := v{:x}", x),
+        Instruction::LdHiFont { x } => format!("i := bighex v{:x}", x),
+        Instruction::LdBcd { x } => format!("bcd v{:x}", x),
+        Instruction::StoreRegs { x } => format!("save v{:x}", x),
+        Instruction::LoadRegs { x } => format!("load v{:x}", x),
+        Instruction::StoreFlags { x } => format!("saveflags v{:x}", x),
+        Instruction::LoadFlags { x } => format!("loadflags v{:x}", x),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Disassembler, Syntax};
+
+    // LD I, 0x20A; LD V0, 0x00; DRW V0, V0, 5; CALL 0x210; JP 0x208
+    // then a 5 byte sprite, an unreached byte and a subroutine that returns
+    const PROGRAM: [u8; 18] = [
+        0xA2, 0x0A, 0x60, 0x00, 0xD0, 0x05, 0x22, 0x10, 0x12, 0x08, //
+        0xF0, 0x90, 0x90, 0x90, 0xF0, //
+        0xFF, //
+        0x00, 0xEE,
+    ];
+
+    fn lines(source: &str) -> Vec<String> {
+        // Drop the comments, which hold addresses and raw bytes
+        source
+            .lines()
+            .filter_map(|line| line.split([';', '#']).next())
+            .map(|line| line.trim().to_string())
+            .filter(|line| !line.is_empty())
+            .collect()
+    }
+
+    #[test]
+    fn should_disassemble_reachable_code_with_labels() {
+        let source = Disassembler::new().disassemble(&PROGRAM);
+
+        assert_eq!(
+            lines(&source),
+            vec![
+                "main:",
+                "LD I, sprite_20A",
+                "LD V0, 0x00",
+                "DRW V0, V0, 0x5",
+                "CALL sub_210",
+                "label_208:",
+                "JP label_208",
+                "sprite_20A:",
+                "DB 0xF0",
+                "DB 0x90",
+                "DB 0x90",
+                "DB 0x90",
+                "DB 0xF0",
+                "DB 0xFF",
+                "sub_210:",
+                "RET",
+            ]
+        );
+    }
+
+    #[test]
+    fn should_draw_sprite_rows() {
+        let source = Disassembler::new().disassemble(&PROGRAM);
+
+        assert!(source.contains("; 20A: ####...."));
+        assert!(source.contains("; 20B: #..#...."));
+    }
+
+    #[test]
+    fn should_disassemble_octo_syntax() {
+        let source = Disassembler::new()
+            .syntax(Syntax::Octo)
+            .disassemble(&PROGRAM);
+
+        assert_eq!(
+            lines(&source)[..7],
+            [
+                ": main",
+                "i := sprite_20A",
+                "v0 := 0x00",
+                "sprite v0 v0 5",
+                "sub_210",
+                ": label_208",
+                "jump label_208",
+            ]
+        );
+    }
+
+    #[test]
+    fn should_follow_both_sides_of_skips() {
+        // SE V0, 0x01; JP 0x208; LD V1, 0x02; RET; LD V2, 0x03; RET
+        let program = [
+            0x30, 0x01, 0x12, 0x08, 0x61, 0x02, 0x00, 0xEE, 0x62, 0x03, 0x00, 0xEE,
+        ];
+        let source = Disassembler::new().disassemble(&program);
+
+        assert!(source.contains("LD V1, 0x02"));
+        assert!(source.contains("LD V2, 0x03"));
+        assert!(!source.contains("DB"));
+    }
+
+    #[test]
+    fn should_show_unreached_bytes_as_data() {
+        // JP 0x200 followed by bytes that look like instructions
+        let program = [0x12, 0x00, 0x00, 0xE0, 0x00, 0xEE];
+        let source = Disassembler::new().disassemble(&program);
+
+        assert_eq!(
+            lines(&source),
+            vec!["main:", "JP main", "DB 0x00, 0xE0, 0x00, 0xEE"]
+        );
+    }
+
+    #[test]
+    fn should_decode_long_loads_and_wide_sprites() {
+        // LD I, LONG 0x0208; DRW V0, V0, 0; EXIT; then a 16x16 sprite
+        let mut program = vec![0xF0, 0x00, 0x02, 0x08, 0xD0, 0x00, 0x00, 0xFD];
+        program.extend_from_slice(&[0xFF; 32]);
+        let source = Disassembler::new().disassemble(&program);
+
+        assert!(source.contains("LD I, LONG sprite_208"));
+        assert!(source.contains("DB 0xFF, 0xFF"));
+        assert!(source.contains("################"));
+    }
+    #[test]
+    fn should_split_instructions_that_are_jumped_into() {
+        // LD I, LONG 0x1202; JP 0x202, which lands on the second half of the load: JP 0x202
+        let program = [0xF0, 0x00, 0x12, 0x02, 0x12, 0x02];
+        let source = Disassembler::new().disassemble(&program);
+
+        assert_eq!(
+            lines(&source),
+            vec![
+                "main:",
+                "DB 0xF0, 0x00",
+                "label_202:",
+                "JP label_202",
+                "JP label_202"
+            ]
+        );
+        assert!(source.contains("; 200: F0001202 LD I, LONG 0x1202"));
+    }
+
+    #[test]
+    fn should_draw_sprites_at_the_end_of_memory() {
+        // LD I, LONG 0xFFFF; DRW V0, V0, 0; EXIT; then a 16x16 sprite starting on the last address
+        let mut program = vec![0xF0, 0x00, 0xFF, 0xFF, 0xD0, 0x00, 0x00, 0xFD];
+        program.extend_from_slice(&[0xFF; 9]);
+        let source = Disassembler::new().origin(0xFFF0).disassemble(&program);
+
+        assert!(source.contains("sprite_FFFF:"));
+        assert!(source.contains("; FFFF: ################"));
+    }
+}
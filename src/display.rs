@@ -1,88 +1,447 @@
-pub(crate) const SCREEN_WIDTH: usize = 64;
-pub(crate) const SCREEN_HEIGHT: usize = 32;
+use crate::png;
+
+pub const SCREEN_WIDTH: usize = 64;
+pub const SCREEN_HEIGHT: usize = 32;
+
+// The SUPER-CHIP high resolution mode doubles the screen in both directions
+pub const HIRES_SCREEN_WIDTH: usize = 128;
+pub const HIRES_SCREEN_HEIGHT: usize = 64;
+
+// Colours for each combination of the two XO-CHIP planes: neither, the first, the second and both.
+// Programs that only draw to the first plane are shown in black and white.
+pub const PALETTE: [u32; 4] = [0xFFFFFFFF, 0x0, 0xFFFF6600, 0xFF662200];
+
+// Grey levels for the same combinations when saving greyscale screenshots
+const GREYSCALE_PALETTE: [u8; 4] = [0xFF, 0x00, 0xAA, 0x55];
 
 #[derive(Debug)]
-pub(crate) struct Display {
-    pub screen: [[bool; SCREEN_WIDTH]; SCREEN_HEIGHT],
+pub struct Display {
+    /// The pixels of the first plane, indexed by row then column. The size depends on the
+    /// resolution. This is the only plane used by CHIP-8 and SUPER-CHIP programs.
+    pub screen: Vec<Vec<bool>>,
+    /// The XO-CHIP second plane, the same size as `screen`.
+    pub second_plane: Vec<Vec<bool>>,
+    hires: bool,
+    // A bitmask of the planes that drawing, clearing and scrolling affect
+    selected_planes: u8,
 }
 
 impl Display {
     pub fn initialise() -> Self {
         Self {
-            screen: [[false; SCREEN_WIDTH]; SCREEN_HEIGHT],
+            screen: vec![vec![false; SCREEN_WIDTH]; SCREEN_HEIGHT],
+            second_plane: vec![vec![false; SCREEN_WIDTH]; SCREEN_HEIGHT],
+            hires: false,
+            selected_planes: 0b01,
         }
     }
 
+    pub fn width(&self) -> usize {
+        if self.hires {
+            HIRES_SCREEN_WIDTH
+        } else {
+            SCREEN_WIDTH
+        }
+    }
+
+    pub fn height(&self) -> usize {
+        if self.hires {
+            HIRES_SCREEN_HEIGHT
+        } else {
+            SCREEN_HEIGHT
+        }
+    }
+
+    pub fn is_hires(&self) -> bool {
+        self.hires
+    }
+
+    /// Switches between the 64x32 and 128x64 resolutions, clearing every plane.
+    pub fn set_hires(&mut self, hires: bool) {
+        self.hires = hires;
+        self.screen = vec![vec![false; self.width()]; self.height()];
+        self.second_plane = vec![vec![false; self.width()]; self.height()];
+    }
+
+    /// The planes affected by drawing, as a bitmask where bit 0 is the first plane.
+    pub fn selected_planes(&self) -> u8 {
+        self.selected_planes
+    }
+
+    /// Selects the planes that drawing, clearing and scrolling affect. Only the low 2 bits are used.
+    pub fn select_planes(&mut self, planes: u8) {
+        self.selected_planes = planes & 0b11;
+    }
+
+    /// The number of planes currently selected.
+    pub fn selected_plane_count(&self) -> usize {
+        self.selected_planes.count_ones() as usize
+    }
+
+    /// The palette index of the pixel at (x, y): bit 0 is set by the first plane, bit 1 by the second.
+    pub fn pixel(&self, x: usize, y: usize) -> u8 {
+        self.screen[y][x] as u8 | (self.second_plane[y][x] as u8) << 1
+    }
+
     pub fn clear_screen(&mut self) {
-        self.screen = [[false; SCREEN_WIDTH]; SCREEN_HEIGHT];
+        let (width, height) = (self.width(), self.height());
+
+        for plane in self.planes_mut() {
+            *plane = vec![vec![false; width]; height];
+        }
         Display::draw();
     }
 
+    /// Scrolls the screen down by `n` pixels, leaving blank rows at the top.
+    pub fn scroll_down(&mut self, n: usize) {
+        let n = n.min(self.height());
+        let width = self.width();
+
+        for plane in self.planes_mut() {
+            plane.rotate_right(n);
+            plane[..n]
+                .iter_mut()
+                .for_each(|row| *row = vec![false; width]);
+        }
+    }
+
+    /// Scrolls the screen up by `n` pixels, leaving blank rows at the bottom.
+    pub fn scroll_up(&mut self, n: usize) {
+        let n = n.min(self.height());
+        let (width, height) = (self.width(), self.height());
+
+        for plane in self.planes_mut() {
+            plane.rotate_left(n);
+            plane[height - n..]
+                .iter_mut()
+                .for_each(|row| *row = vec![false; width]);
+        }
+    }
+
+    /// Scrolls the screen right by `n` pixels, leaving blank columns on the left.
+    pub fn scroll_right(&mut self, n: usize) {
+        let n = n.min(self.width());
+
+        for row in self.planes_mut().flat_map(|plane| plane.iter_mut()) {
+            row.rotate_right(n);
+            row[..n].iter_mut().for_each(|p| *p = false);
+        }
+    }
+
+    /// Scrolls the screen left by `n` pixels, leaving blank columns on the right.
+    pub fn scroll_left(&mut self, n: usize) {
+        let n = n.min(self.width());
+        let width = self.width();
+
+        for row in self.planes_mut().flat_map(|plane| plane.iter_mut()) {
+            row.rotate_left(n);
+            row[width - n..].iter_mut().for_each(|p| *p = false);
+        }
+    }
+
+    /// The screen as 0RGB colours from [`PALETTE`], row by row.
     pub fn get_buffer(&self) -> Vec<u32> {
-        self.screen
-            .iter()
-            .flatten()
-            .map(|x| if *x { 0x0 as u32 } else { 0xFFFFFFFF as u32 })
-            .collect()
+        self.pixels().map(|p| PALETTE[p as usize]).collect()
     }
 
-    // TODO: Write tests for this
-    pub fn display_sprite(&mut self, location: (&usize, &usize), sprite: &[u8]) -> bool {
-        let (x, y) = location;
+    /// Renders the screen as a plain (ASCII) PBM image, with pixels set in any plane in black.
+    pub fn to_pbm(&self) -> String {
+        let mut pbm = format!("P1\n{} {}\n", self.width(), self.height());
 
-        let mut did_overwrite = false;
-        let mut y_offset = 0;
+        for y in 0..self.height() {
+            let pixels: Vec<&str> = (0..self.width())
+                .map(|x| if self.pixel(x, y) != 0 { "1" } else { "0" })
+                .collect();
+            pbm.push_str(&pixels.join(" "));
+            pbm.push('\n');
+        }
 
-        for spr_row in sprite {
-            for n in 0..8 {
-                let mut curr_y = *y + y_offset;
+        pbm
+    }
 
-                // In order to render the pixels in the correct order
-                // we must print the most significant byte to the display first
-                let mut curr_x = *x + (7 - n);
+    /// Renders the screen as a greyscale PNG image, with pixels set in the first plane in black.
+    pub fn to_png(&self) -> Vec<u8> {
+        let pixels: Vec<u8> = self
+            .pixels()
+            .map(|p| GREYSCALE_PALETTE[p as usize])
+            .collect();
 
-                // calculate if we need to wrap around
-                // part of the sprite
-                if curr_y >= SCREEN_HEIGHT {
-                    curr_y %= SCREEN_HEIGHT;
-                }
+        png::encode_greyscale(self.width(), self.height(), &pixels)
+    }
 
-                if curr_x >= SCREEN_WIDTH {
-                    curr_x %= SCREEN_WIDTH;
-                }
+    /// Draws an 8 pixel wide sprite with its top left corner at `location`, XOR'ing it onto the screen.
+    ///
+    /// The starting position always wraps around the screen. Pixels that run over the edge
+    /// are either clipped (`clip`) or wrapped around to the opposite side.
+    /// When more than one plane is selected `sprite` holds the data for each plane in turn.
+    /// Returns true if any pixel that was set has been turned off.
+    pub fn display_sprite(
+        &mut self,
+        location: (&usize, &usize),
+        sprite: &[u8],
+        clip: bool,
+    ) -> bool {
+        self.draw_planes(location, sprite, 8, clip)
+    }
 
-                // extract the bit at position n of the byte
-                let bit = (spr_row & (1 << n)) != 0;
+    /// Draws a 16 pixel wide SUPER-CHIP sprite, given as two bytes per row, the same way as
+    /// [`Display::display_sprite`].
+    pub fn display_wide_sprite(
+        &mut self,
+        location: (&usize, &usize),
+        sprite: &[u8],
+        clip: bool,
+    ) -> bool {
+        self.draw_planes(location, sprite, 16, clip)
+    }
 
-                // indicate if the setting of the new pixel will
-                // overwrite the previous pixel (i.e change state)
-                if self.screen[curr_y][curr_x] == true && bit == false {
-                    did_overwrite = true;
-                }
+    fn draw_planes(
+        &mut self,
+        location: (&usize, &usize),
+        sprite: &[u8],
+        sprite_width: usize,
+        clip: bool,
+    ) -> bool {
+        let count = self.selected_plane_count();
+        if count == 0 {
+            return false;
+        }
 
-                self.screen[curr_y][curr_x] ^= bit;
-            }
-            y_offset += 1;
+        let (width, height) = (self.width(), self.height());
+        let per_plane = sprite.len() / count;
+        let mut did_overwrite = false;
+
+        for (plane, data) in self.planes_mut().zip(sprite.chunks(per_plane.max(1))) {
+            let rows: Vec<u16> = if sprite_width == 16 {
+                data.chunks(2)
+                    .map(|row| u16::from_be_bytes([row[0], *row.get(1).unwrap_or(&0)]))
+                    .collect()
+            } else {
+                data.iter().map(|row| *row as u16).collect()
+            };
+
+            did_overwrite |= draw_rows(plane, (width, height), location, &rows, sprite_width, clip);
         }
 
         did_overwrite
     }
 
+    fn planes_mut(&mut self) -> impl Iterator<Item = &mut Vec<Vec<bool>>> {
+        let selected = self.selected_planes;
+
+        vec![&mut self.screen, &mut self.second_plane]
+            .into_iter()
+            .enumerate()
+            .filter(move |(i, _)| selected & (1 << i) != 0)
+            .map(|(_, plane)| plane)
+    }
+
+    /// The palette index of every pixel, row by row. See [`Display::pixel`].
+    pub fn pixels(&self) -> impl Iterator<Item = u8> + '_ {
+        (0..self.height()).flat_map(move |y| (0..self.width()).map(move |x| self.pixel(x, y)))
+    }
+
     fn draw() {}
 }
 
+fn draw_rows(
+    plane: &mut [Vec<bool>],
+    (width, height): (usize, usize),
+    location: (&usize, &usize),
+    rows: &[u16],
+    sprite_width: usize,
+    clip: bool,
+) -> bool {
+    let x = *location.0 % width;
+    let y = *location.1 % height;
+
+    let mut did_overwrite = false;
+
+    for (y_offset, spr_row) in rows.iter().enumerate() {
+        let mut curr_y = y + y_offset;
+
+        // calculate if we need to wrap around
+        // part of the sprite
+        if curr_y >= height {
+            if clip {
+                break;
+            }
+            curr_y %= height;
+        }
+
+        for n in 0..sprite_width {
+            // In order to render the pixels in the correct order
+            // we must print the most significant bit to the display first
+            let mut curr_x = x + (sprite_width - 1 - n);
+
+            if curr_x >= width {
+                if clip {
+                    continue;
+                }
+                curr_x %= width;
+            }
+
+            // extract the bit at position n of the row
+            let bit = (spr_row & (1 << n)) != 0;
+
+            // indicate if the setting of the new pixel will
+            // overwrite the previous pixel (i.e change state)
+            if plane[curr_y][curr_x] && bit {
+                did_overwrite = true;
+            }
+
+            plane[curr_y][curr_x] ^= bit;
+        }
+    }
+
+    did_overwrite
+}
+
 impl DebugDisplay for Display {
     fn view_state(&self) {
-        for r in self.screen {
-            for x in r {
-                let p = if !x { "." } else { "X" };
+        for y in 0..self.height() {
+            for x in 0..self.width() {
+                let p = match self.pixel(x, y) {
+                    0 => ".",
+                    1 => "X",
+                    2 => "o",
+                    _ => "#",
+                };
                 print!("{}", p);
             }
-            println!("");
+            println!();
         }
     }
 }
 
-pub(crate) trait DebugDisplay {
+pub trait DebugDisplay {
     fn view_state(&self);
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{Display, PALETTE};
+
+    #[test]
+    fn should_render_screen_as_pbm() {
+        let mut display = Display::initialise();
+        display.screen[0][0] = true;
+        display.screen[1][63] = true;
+
+        let pbm = display.to_pbm();
+        let mut lines = pbm.lines();
+
+        assert_eq!(lines.next(), Some("P1"));
+        assert_eq!(lines.next(), Some("64 32"));
+        assert!(lines.next().unwrap().starts_with("1 0 0"));
+        assert!(lines.next().unwrap().ends_with("0 0 1"));
+        assert_eq!(lines.count(), 30);
+    }
+
+    #[test]
+    fn should_resize_for_hires() {
+        let mut display = Display::initialise();
+        display.screen[0][0] = true;
+
+        display.set_hires(true);
+
+        assert_eq!(display.screen.len(), 64);
+        assert_eq!(display.screen[0].len(), 128);
+        assert!(!display.screen[0][0]);
+        assert_eq!(display.get_buffer().len(), 128 * 64);
+    }
+
+    #[test]
+    fn should_scroll_down() {
+        let mut display = Display::initialise();
+        display.screen[0][5] = true;
+        display.screen[31][5] = true;
+
+        display.scroll_down(4);
+
+        assert!(!display.screen[0][5]);
+        assert!(display.screen[4][5]);
+        assert_eq!(display.screen.len(), 32);
+        assert_eq!(display.screen.iter().flatten().filter(|p| **p).count(), 1);
+    }
+
+    #[test]
+    fn should_scroll_left_and_right() {
+        let mut display = Display::initialise();
+        display.screen[3][2] = true;
+        display.screen[3][63] = true;
+
+        display.scroll_right(4);
+
+        assert!(display.screen[3][6]);
+        assert!(!display.screen[3][3]);
+        assert_eq!(display.screen[3].iter().filter(|p| **p).count(), 1);
+
+        display.scroll_left(4);
+
+        assert!(display.screen[3][2]);
+        assert!(!display.screen[3][63]);
+    }
+
+    #[test]
+    fn should_draw_wide_sprite() {
+        let mut display = Display::initialise();
+
+        let collision = display.display_wide_sprite((&0, &0), &[0x80, 0x01, 0xFF, 0xFF], false);
+
+        assert!(!collision);
+        assert!(display.screen[0][0]);
+        assert!(!display.screen[0][1]);
+        assert!(display.screen[0][15]);
+        assert!(display.screen[1].iter().take(16).all(|p| *p));
+        assert!(display.display_wide_sprite((&0, &0), &[0x80, 0x00], false));
+    }
+
+    #[test]
+    fn should_scroll_up_selected_planes() {
+        let mut display = Display::initialise();
+        display.screen[5][0] = true;
+        display.second_plane[5][0] = true;
+
+        display.scroll_up(2);
+
+        assert!(display.screen[3][0]);
+        assert!(display.second_plane[5][0]);
+        assert_eq!(display.screen.len(), 32);
+    }
+
+    #[test]
+    fn should_draw_each_selected_plane() {
+        let mut display = Display::initialise();
+        display.select_planes(0b11);
+
+        display.display_sprite((&0, &0), &[0x80, 0xC0], false);
+
+        assert_eq!(display.pixel(0, 0), 0b11);
+        assert_eq!(display.pixel(1, 0), 0b10);
+        assert_eq!(display.get_buffer()[1], PALETTE[2]);
+
+        display.select_planes(0b10);
+        assert!(display.display_sprite((&0, &0), &[0x80], false));
+        assert_eq!(display.pixel(0, 0), 0b01);
+    }
+
+    #[test]
+    fn should_only_clear_selected_planes() {
+        let mut display = Display::initialise();
+        display.screen[0][0] = true;
+        display.second_plane[0][0] = true;
+
+        display.select_planes(0b10);
+        display.clear_screen();
+
+        assert_eq!(display.pixel(0, 0), 0b01);
+    }
+
+    #[test]
+    fn should_render_screen_as_png() {
+        let png = Display::initialise().to_png();
+
+        assert_eq!(&png[1..4], b"PNG");
+    }
+}
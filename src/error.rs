@@ -0,0 +1,34 @@
+use std::{error::Error, fmt};
+
+/// Faults raised by the CPU while executing a program.
+///
+/// When an instruction faults the program counter is left pointing at it, so the
+/// state of the CPU can be inspected to find out what went wrong.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Chip8Error {
+    /// A `CALL` was made with every stack slot already in use.
+    StackOverflow,
+    /// A `RET` was made outside of a subroutine.
+    StackUnderflow,
+    /// An instruction tried to read or write outside of memory.
+    MemoryOutOfBounds { addr: usize },
+    /// The opcode at `pc` does not decode to a known instruction.
+    InvalidOpcode { pc: u16, raw: u16 },
+}
+
+impl fmt::Display for Chip8Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Chip8Error::StackOverflow => write!(f, "stack overflow"),
+            Chip8Error::StackUnderflow => write!(f, "stack underflow"),
+            Chip8Error::MemoryOutOfBounds { addr } => {
+                write!(f, "out of bounds memory access at {:#05x}", addr)
+            }
+            Chip8Error::InvalidOpcode { pc, raw } => {
+                write!(f, "invalid opcode {:#06x} at {:#05x}", raw, pc)
+            }
+        }
+    }
+}
+
+impl Error for Chip8Error {}
@@ -0,0 +1,696 @@
+//! A GDB Remote Serial Protocol stub, so GDB or any other RSP client can debug a running
+//! program over TCP.
+//!
+//! There is no CHIP-8 architecture in GDB, so the registers are described by the
+//! [`target_description`]: `v0`-`vf`, `vi`, `pc`, `sp`, `dt` and `st`, with the 16 bit ones
+//! sent little endian. Memory can be read and written, and software breakpoints, write
+//! (`Z2`) and read (`Z3`) watchpoints, single-stepping and interrupting are supported.
+//!
+//! [`GdbStub`] answers packets and [`GdbServer`] carries them over a [`TcpStream`], running
+//! the program a frame at a time in between.
+
+use std::{
+    collections::BTreeMap,
+    fmt::Write as _,
+    io::{self, Write},
+    net::TcpStream,
+};
+
+use crate::{
+    breakpoints::{Breakpoints, Cause, Register, Trigger, Watchpoint},
+    cpu::{CpuState, StepOutcome, CPU},
+    debugger,
+    keyboard::Keyboard,
+    memory::Access,
+    scheduler::{Scheduler, TimeSource},
+    tcp,
+};
+
+// The largest packet GDB may send us, which bounds the size of memory writes
+const PACKET_SIZE: usize = 0x1000;
+
+// Signals reported in stop replies
+const SIGINT: u8 = 2;
+const SIGTRAP: u8 = 5;
+const SIGSEGV: u8 = 11;
+
+fn register_bytes(register: Register) -> usize {
+    match register {
+        Register::I | Register::Pc => 2,
+        _ => 1,
+    }
+}
+
+fn register_name(register: Register) -> String {
+    match register {
+        // `i` alone is too easily mistaken for a variable
+        Register::I => "vi".to_string(),
+        register => register.to_string().to_lowercase(),
+    }
+}
+
+/// The XML GDB reads to learn the registers.
+pub fn target_description() -> String {
+    let mut xml = String::from(
+        "<?xml version=\"1.0\"?>\n<!DOCTYPE target SYSTEM \"gdb-target.dtd\">\n\
+         <target version=\"1.0\">\n  <feature name=\"org.chip8-rs.cpu\">\n",
+    );
+    // GDB numbers the registers in the order they are described
+    for register in Register::ALL.iter() {
+        let kind = match register {
+            Register::I => "data_ptr",
+            Register::Pc => "code_ptr",
+            _ => "uint8",
+        };
+        writeln!(
+            xml,
+            "    <reg name=\"{}\" bitsize=\"{}\" type=\"{}\"/>",
+            register_name(*register),
+            register_bytes(*register) * 8,
+            kind
+        )
+        .unwrap();
+    }
+    xml.push_str("  </feature>\n</target>\n");
+
+    xml
+}
+
+/// What the transport should do after a packet.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Action {
+    Reply(String),
+    /// Run the program until something stops it, then send a stop reply.
+    Continue,
+    /// Reply `OK` and let the program run on without the debugger.
+    Detach,
+    Kill,
+}
+
+/// The protocol, independent of how packets are carried.
+#[derive(Debug, Clone, Default)]
+pub struct GdbStub {
+    breakpoints: Breakpoints,
+    // Breakpoint ids by their `Z` packet type and address
+    inserted: BTreeMap<(u8, usize), usize>,
+}
+
+impl GdbStub {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Answers a packet, without its `$` and checksum.
+    pub fn handle<TKeyboard>(&mut self, packet: &str, cpu: &mut CPU<TKeyboard>) -> Action
+    where
+        TKeyboard: Keyboard,
+    {
+        let reply = |reply: &str| Action::Reply(reply.to_string());
+        let (command, args) = packet.split_at(packet.len().min(1));
+
+        match command {
+            "?" => Action::Reply(signal(SIGTRAP)),
+            "g" => Action::Reply(
+                Register::ALL
+                    .iter()
+                    .map(|r| read_register(*r, cpu))
+                    .collect(),
+            ),
+            "G" => {
+                let mut rest = args;
+                for register in Register::ALL.iter() {
+                    let len = register_bytes(*register) * 2;
+                    let written = rest
+                        .get(..len)
+                        .and_then(parse_le)
+                        .is_some_and(|value| register.write(cpu, value));
+                    if !written {
+                        return reply("E01");
+                    }
+                    rest = &rest[len..];
+                }
+                reply("OK")
+            }
+            "p" => match parse_hex(args).and_then(|n| Register::ALL.get(n)) {
+                Some(register) => Action::Reply(read_register(*register, cpu)),
+                None => reply("E01"),
+            },
+            "P" => {
+                let written = args.split_once('=').is_some_and(|(n, value)| {
+                    let register = parse_hex(n).and_then(|n| Register::ALL.get(n));
+                    match (register, parse_le(value)) {
+                        (Some(register), Some(value)) => register.write(cpu, value),
+                        _ => false,
+                    }
+                });
+                reply(if written { "OK" } else { "E01" })
+            }
+            "m" => match parse_pair(args).and_then(|(address, len)| {
+                cpu.memory
+                    .data
+                    .get(address..)
+                    .map(|data| &data[..len.min(data.len())])
+            }) {
+                Some(data) if !data.is_empty() => Action::Reply(to_hex(data)),
+                _ => reply("E01"),
+            },
+            "M" => {
+                let write = args.split_once(':').and_then(|(range, data)| {
+                    let (address, len) = parse_pair(range)?;
+                    let bytes = from_hex(data).filter(|bytes| bytes.len() == len)?;
+                    let target = cpu
+                        .memory
+                        .data
+                        .get_mut(address..address.checked_add(len)?)?;
+                    target.copy_from_slice(&bytes);
+                    Some(())
+                });
+                reply(if write.is_some() { "OK" } else { "E01" })
+            }
+            "Z" | "z" => self.breakpoint(command == "Z", args, cpu),
+            "c" => self.resume(args, cpu),
+            "s" => self.step(args, cpu),
+            "D" => Action::Detach,
+            "k" => Action::Kill,
+            // There is only one thread
+            "H" | "T" => reply("OK"),
+            _ => self.query(packet, cpu),
+        }
+    }
+
+    fn query<TKeyboard>(&mut self, packet: &str, cpu: &mut CPU<TKeyboard>) -> Action
+    where
+        TKeyboard: Keyboard,
+    {
+        let reply = |reply: &str| Action::Reply(reply.to_string());
+
+        if packet.starts_with("qSupported") {
+            return Action::Reply(format!(
+                "PacketSize={:x};qXfer:features:read+;swbreak+;QStartNoAckMode+",
+                PACKET_SIZE
+            ));
+        }
+        if let Some(range) = packet.strip_prefix("qXfer:features:read:target.xml:") {
+            let xml = target_description();
+            return match parse_pair(range) {
+                Some((offset, _)) if offset >= xml.len() => reply("l"),
+                Some((offset, len)) if offset.saturating_add(len) >= xml.len() => {
+                    Action::Reply(format!("l{}", &xml[offset..]))
+                }
+                Some((offset, len)) => Action::Reply(format!("m{}", &xml[offset..offset + len])),
+                None => reply("E01"),
+            };
+        }
+
+        match packet {
+            "QStartNoAckMode" => reply("OK"),
+            "qAttached" => reply("1"),
+            "qC" => reply("QC1"),
+            "qfThreadInfo" => reply("m1"),
+            "qsThreadInfo" => reply("l"),
+            "vCont?" => reply("vCont;c;C;s;S"),
+            _ if packet.starts_with("vCont;c") || packet.starts_with("vCont;C") => {
+                self.resume("", cpu)
+            }
+            _ if packet.starts_with("vCont;s") || packet.starts_with("vCont;S") => {
+                self.step("", cpu)
+            }
+            // An empty reply tells the client the packet isn't supported
+            _ => reply(""),
+        }
+    }
+
+    fn breakpoint<TKeyboard>(
+        &mut self,
+        insert: bool,
+        args: &str,
+        cpu: &mut CPU<TKeyboard>,
+    ) -> Action
+    where
+        TKeyboard: Keyboard,
+    {
+        let parsed = args.split_once(',').and_then(|(kind, rest)| {
+            let (address, len) = parse_pair(rest)?;
+            Some((kind.parse::<u8>().ok()?, address, len))
+        });
+        let (kind, address, len) = match parsed {
+            Some(parsed) if parsed.1 < cpu.memory.data.len() => parsed,
+            _ => return Action::Reply("E01".to_string()),
+        };
+
+        let trigger = match kind {
+            // Hardware breakpoints behave the same as software ones here
+            0 | 1 => Trigger::Address(address as u16),
+            2 | 3 => Trigger::Watch(Watchpoint {
+                address,
+                len,
+                access: if kind == 2 {
+                    Access::Write
+                } else {
+                    Access::Read
+                },
+            }),
+            _ => return Action::Reply(String::new()),
+        };
+
+        if insert {
+            let id = self.breakpoints.add(trigger, None);
+            if let Some(replaced) = self.inserted.insert((kind, address), id) {
+                self.breakpoints.remove(replaced);
+            }
+        } else if let Some(id) = self.inserted.remove(&(kind, address)) {
+            self.breakpoints.remove(id);
+        }
+        cpu.memory
+            .record_accesses(self.breakpoints.watches_memory());
+
+        Action::Reply("OK".to_string())
+    }
+
+    fn resume<TKeyboard>(&mut self, address: &str, cpu: &mut CPU<TKeyboard>) -> Action
+    where
+        TKeyboard: Keyboard,
+    {
+        if !jump(address, cpu) {
+            return Action::Reply("E01".to_string());
+        }
+        self.breakpoints.resume();
+
+        Action::Continue
+    }
+
+    fn step<TKeyboard>(&mut self, address: &str, cpu: &mut CPU<TKeyboard>) -> Action
+    where
+        TKeyboard: Keyboard,
+    {
+        if !jump(address, cpu) {
+            return Action::Reply("E01".to_string());
+        }
+
+        let reply = match debugger::step_instruction(cpu) {
+            Err(_) => signal(SIGSEGV),
+            Ok(StepOutcome::Exited) => "W00".to_string(),
+            Ok(_) => match self.breakpoints.check(cpu) {
+                Some(hit) => stop_reply(&hit.cause),
+                None => signal(SIGTRAP),
+            },
+        };
+
+        Action::Reply(reply)
+    }
+
+    /// Runs a frame of the program, returning the stop reply if it stopped.
+    pub fn run_frame<TKeyboard, TClock>(
+        &mut self,
+        scheduler: &Scheduler<TClock>,
+        cpu: &mut CPU<TKeyboard>,
+    ) -> Option<String>
+    where
+        TKeyboard: Keyboard,
+        TClock: TimeSource,
+    {
+        let breakpoints = &mut self.breakpoints;
+        let mut hit = None;
+        let result = scheduler.run_frame_until(cpu, |cpu| {
+            hit = breakpoints.check(cpu);
+            hit.is_some()
+        });
+
+        match (result, hit) {
+            (Err(_), _) => Some(signal(SIGSEGV)),
+            (Ok(_), _) if cpu.state() == CpuState::Exited => Some("W00".to_string()),
+            (Ok(_), Some(hit)) => Some(stop_reply(&hit.cause)),
+            (Ok(_), None) => None,
+        }
+    }
+
+    /// The reply for the program being interrupted by the client.
+    pub fn interrupted(&self) -> String {
+        signal(SIGINT)
+    }
+}
+
+fn signal(signal: u8) -> String {
+    format!("S{:02x}", signal)
+}
+
+fn stop_reply(cause: &Cause) -> String {
+    match cause {
+        Cause::Access(access) if access.access == Access::Write => {
+            format!("T{:02x}watch:{:x};", SIGTRAP, access.address)
+        }
+        Cause::Access(access) if access.access == Access::Read => {
+            format!("T{:02x}rwatch:{:x};", SIGTRAP, access.address)
+        }
+        _ => format!("T{:02x}swbreak:;", SIGTRAP),
+    }
+}
+
+fn read_register<TKeyboard>(register: Register, cpu: &CPU<TKeyboard>) -> String
+where
+    TKeyboard: Keyboard,
+{
+    let bytes = register.read(cpu).to_le_bytes();
+    to_hex(&bytes[..register_bytes(register)])
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) || !hex.is_ascii() {
+        return None;
+    }
+
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok())
+        .collect()
+}
+
+/// Moves the pc to the address a `c` or `s` packet resumes from, if it has one, returning
+/// whether the address was inside memory.
+fn jump<TKeyboard>(address: &str, cpu: &mut CPU<TKeyboard>) -> bool
+where
+    TKeyboard: Keyboard,
+{
+    if address.is_empty() {
+        return true;
+    }
+    match parse_hex(address).filter(|address| *address < cpu.memory.data.len()) {
+        Some(address) => {
+            cpu.pc = address as u16;
+            true
+        }
+        None => false,
+    }
+}
+
+fn parse_hex(hex: &str) -> Option<usize> {
+    usize::from_str_radix(hex, 16).ok()
+}
+
+/// Parses a little endian register value of up to 2 bytes.
+fn parse_le(hex: &str) -> Option<u16> {
+    let bytes = from_hex(hex).filter(|bytes| !bytes.is_empty() && bytes.len() <= 2)?;
+    Some(
+        bytes
+            .iter()
+            .rev()
+            .fold(0, |value, byte| value << 8 | *byte as u16),
+    )
+}
+
+/// Parses the `address,length` arguments of memory and breakpoint packets.
+fn parse_pair(args: &str) -> Option<(usize, usize)> {
+    let (a, b) = args.split_once(',')?;
+    Some((parse_hex(a)?, parse_hex(b)?))
+}
+
+/// Whether a debugger is still attached after [`GdbServer::update`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Status {
+    Attached,
+    /// The client detached or disconnected, and the program should run on.
+    Detached,
+    /// The client asked for the program to be killed.
+    Killed,
+}
+
+enum Input {
+    Packet(String),
+    Interrupt,
+    BadChecksum,
+}
+
+/// Serves a [`GdbStub`] over a TCP connection.
+#[derive(Debug)]
+pub struct GdbServer {
+    stream: TcpStream,
+    stub: GdbStub,
+    input: Vec<u8>,
+    acknowledge: bool,
+    running: bool,
+}
+
+impl GdbServer {
+    /// Serves a client that has connected, with the program stopped.
+    pub fn new(stream: TcpStream) -> Self {
+        // Packets are small and answered one at a time, so they shouldn't wait to be batched
+        let _ = stream.set_nodelay(true);
+
+        Self {
+            stream,
+            stub: GdbStub::new(),
+            input: vec![],
+            acknowledge: true,
+            running: false,
+        }
+    }
+
+    /// Answers the packets that have arrived, then runs the frames that are due if the client
+    /// has continued the program. This never blocks waiting for the client.
+    pub fn update<TKeyboard, TClock>(
+        &mut self,
+        scheduler: &mut Scheduler<TClock>,
+        cpu: &mut CPU<TKeyboard>,
+    ) -> io::Result<Status>
+    where
+        TKeyboard: Keyboard,
+        TClock: TimeSource,
+    {
+        if !tcp::receive(&mut self.stream, &mut self.input)? {
+            return Ok(Status::Detached);
+        }
+
+        while let Some(input) = self.next_input() {
+            match input {
+                Input::Interrupt if self.running => {
+                    self.running = false;
+                    let reply = self.stub.interrupted();
+                    self.send(&reply)?;
+                }
+                Input::Interrupt => {}
+                Input::BadChecksum => self.stream.write_all(b"-")?,
+                Input::Packet(packet) => {
+                    if self.acknowledge {
+                        self.stream.write_all(b"+")?;
+                    }
+
+                    match self.stub.handle(&packet, cpu) {
+                        Action::Reply(reply) => {
+                            self.send(&reply)?;
+                            if packet == "QStartNoAckMode" {
+                                self.acknowledge = false;
+                            }
+                        }
+                        Action::Continue => self.running = true,
+                        Action::Detach => {
+                            self.send("OK")?;
+                            return Ok(Status::Detached);
+                        }
+                        Action::Kill => return Ok(Status::Killed),
+                    }
+                }
+            }
+        }
+
+        if !self.running {
+            // Don't try to catch up on the time spent stopped
+            scheduler.reset();
+            return Ok(Status::Attached);
+        }
+
+        for _ in 0..scheduler.frames_due() {
+            if let Some(reply) = self.stub.run_frame(scheduler, cpu) {
+                self.running = false;
+                self.send(&reply)?;
+                break;
+            }
+        }
+
+        Ok(Status::Attached)
+    }
+
+    /// Takes the next complete packet or interrupt from the input.
+    fn next_input(&mut self) -> Option<Input> {
+        loop {
+            match self.input.first()? {
+                0x03 => {
+                    self.input.remove(0);
+                    return Some(Input::Interrupt);
+                }
+                b'$' => break,
+                // Acknowledgements, and anything else between packets, are skipped
+                _ => {
+                    self.input.remove(0);
+                }
+            }
+        }
+
+        let end = self.input.iter().position(|byte| *byte == b'#')?;
+        if self.input.len() < end + 3 {
+            return None;
+        }
+
+        let packet: Vec<u8> = self.input.drain(..end + 3).collect();
+        let data = &packet[1..end];
+        let checksum = std::str::from_utf8(&packet[end + 1..])
+            .ok()
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+
+        if self.acknowledge && checksum != Some(checksum_of(data)) {
+            return Some(Input::BadChecksum);
+        }
+        Some(Input::Packet(String::from_utf8_lossy(data).into_owned()))
+    }
+
+    fn send(&mut self, data: &str) -> io::Result<()> {
+        // The few characters that frame packets have to be escaped
+        let mut escaped = Vec::with_capacity(data.len());
+        for byte in data.bytes() {
+            match byte {
+                b'$' | b'#' | b'}' | b'*' => escaped.extend_from_slice(&[b'}', byte ^ 0x20]),
+                _ => escaped.push(byte),
+            }
+        }
+
+        let mut packet = vec![b'$'];
+        packet.extend_from_slice(&escaped);
+        packet.extend_from_slice(format!("#{:02x}", checksum_of(&escaped)).as_bytes());
+        self.stream.write_all(&packet)
+    }
+}
+
+fn checksum_of(data: &[u8]) -> u8 {
+    data.iter().fold(0, |sum, byte| sum.wrapping_add(*byte))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{target_description, Action, GdbStub};
+    use crate::{
+        cpu::CPU, display::Display, keyboard::dummy_keyboard::DummyKeyboard, memory::Memory,
+        quirks::Quirks,
+    };
+
+    fn get_cpu() -> CPU<DummyKeyboard> {
+        CPU::initialise(
+            Memory::initialise(),
+            Display::initialise(),
+            DummyKeyboard::initialise(),
+            Quirks::default(),
+        )
+    }
+
+    fn reply(stub: &mut GdbStub, packet: &str, cpu: &mut CPU<DummyKeyboard>) -> String {
+        match stub.handle(packet, cpu) {
+            Action::Reply(reply) => reply,
+            action => panic!("{} gave {:?}", packet, action),
+        }
+    }
+
+    #[test]
+    fn should_read_and_write_registers() {
+        let mut cpu = get_cpu();
+        let mut stub = GdbStub::new();
+        cpu.v[0xF] = 0xAB;
+        cpu.vi = 0x1234;
+
+        let registers = reply(&mut stub, "g", &mut cpu);
+        assert_eq!(
+            registers,
+            format!("{}ab{}{}", "00".repeat(15), "3412", "0002000000")
+        );
+
+        assert_eq!(reply(&mut stub, "P11=0403", &mut cpu), "OK");
+        assert_eq!(cpu.pc, 0x304);
+        assert_eq!(reply(&mut stub, "p11", &mut cpu), "0403");
+        assert_eq!(reply(&mut stub, "P0=100", &mut cpu), "E01");
+        assert_eq!(reply(&mut stub, "p15", &mut cpu), "E01");
+
+        let written = registers.replace("3412", "0003");
+        assert_eq!(reply(&mut stub, &format!("G{}", written), &mut cpu), "OK");
+        assert_eq!(cpu.vi, 0x300);
+    }
+
+    #[test]
+    fn should_read_and_write_memory() {
+        let mut cpu = get_cpu();
+        let mut stub = GdbStub::new();
+
+        assert_eq!(reply(&mut stub, "M300,3:0a0b0c", &mut cpu), "OK");
+        assert_eq!(cpu.memory.data[0x300..0x303], [0x0A, 0x0B, 0x0C]);
+        assert_eq!(reply(&mut stub, "m2ff,3", &mut cpu), "000a0b");
+
+        // Reads past the end are cut short, writes are refused
+        assert_eq!(reply(&mut stub, "mffe,4", &mut cpu), "0000");
+        assert_eq!(reply(&mut stub, "m1000,1", &mut cpu), "E01");
+        assert_eq!(reply(&mut stub, "Mfff,2:0102", &mut cpu), "E01");
+        assert_eq!(reply(&mut stub, "M300,2:01", &mut cpu), "E01");
+    }
+
+    #[test]
+    fn should_serve_the_target_description_in_chunks() {
+        let mut cpu = get_cpu();
+        let mut stub = GdbStub::new();
+        let xml = target_description();
+
+        let first = reply(&mut stub, "qXfer:features:read:target.xml:0,10", &mut cpu);
+        assert_eq!(first, format!("m{}", &xml[..0x10]));
+        let rest = reply(
+            &mut stub,
+            "qXfer:features:read:target.xml:10,1000",
+            &mut cpu,
+        );
+        assert_eq!(rest, format!("l{}", &xml[0x10..]));
+        let huge = reply(
+            &mut stub,
+            "qXfer:features:read:target.xml:10,ffffffffffffffff",
+            &mut cpu,
+        );
+        assert_eq!(huge, rest);
+
+        assert!(xml.contains("<reg name=\"vf\" bitsize=\"8\" type=\"uint8\"/>"));
+        assert!(xml.contains("<reg name=\"pc\" bitsize=\"16\" type=\"code_ptr\"/>"));
+    }
+
+    #[test]
+    fn should_step_and_stop_at_breakpoints() {
+        let mut cpu = get_cpu();
+        let mut stub = GdbStub::new();
+        // 0x200 ADD V0, 1
+        // 0x202 JP 0x200
+        cpu.memory.data[0x200..0x204].copy_from_slice(&[0x70, 0x01, 0x12, 0x00]);
+
+        assert_eq!(reply(&mut stub, "s", &mut cpu), "S05");
+        assert_eq!(cpu.pc, 0x202);
+
+        assert_eq!(reply(&mut stub, "Z0,200,2", &mut cpu), "OK");
+        assert_eq!(reply(&mut stub, "s", &mut cpu), "T05swbreak:;");
+        assert_eq!(reply(&mut stub, "z0,200,2", &mut cpu), "OK");
+        assert_eq!(reply(&mut stub, "s", &mut cpu), "S05");
+        assert_eq!(cpu.v[0], 2);
+
+        assert_eq!(stub.handle("c", &mut cpu), Action::Continue);
+        assert_eq!(reply(&mut stub, "Z4,300,1", &mut cpu), "");
+        assert_eq!(reply(&mut stub, "vUnknown", &mut cpu), "");
+    }
+
+    #[test]
+    fn should_reject_addresses_past_the_end_of_memory() {
+        let mut cpu = get_cpu();
+        let mut stub = GdbStub::new();
+
+        assert_eq!(reply(&mut stub, "Z0,10200,2", &mut cpu), "E01");
+        assert_eq!(reply(&mut stub, "Z2,1000,1", &mut cpu), "E01");
+        assert_eq!(reply(&mut stub, "z0,10200,2", &mut cpu), "E01");
+        assert_eq!(reply(&mut stub, "c10200", &mut cpu), "E01");
+        assert_eq!(reply(&mut stub, "s10200", &mut cpu), "E01");
+        assert_eq!(cpu.pc, 0x200);
+
+        assert_eq!(reply(&mut stub, "s202", &mut cpu), "S05");
+        assert_eq!(cpu.pc, 0x204);
+    }
+}
@@ -0,0 +1,351 @@
+use std::{
+    cell::RefCell,
+    fs::{self, File},
+    io::{BufWriter, Write},
+    rc::Rc,
+};
+
+use chip8_rs::{
+    cpu::{CpuState, CPU},
+    dap::{self, DapServer},
+    debugger::Command,
+    display::{DebugDisplay, SCREEN_HEIGHT, SCREEN_WIDTH},
+    error::Chip8Error,
+    gdb::{GdbServer, Status},
+    keyboard::{minifb_keyboard::MiniFbKeyboard, Keyboard},
+    keymap::KeyMap,
+    memory::Memory,
+    movie::Movie,
+    quirks::Quirks,
+    rewind::Rewind,
+    rom::Rom,
+    scheduler::{Scheduler, SystemClock},
+    trace::Tracer,
+    Chip8,
+};
+use minifb::{Key, Window, WindowOptions};
+
+use crate::repl::Repl;
+
+// The number of quick save slots, cycled through with F6
+const SAVE_SLOTS: u8 = 10;
+
+/// How to run a ROM in the window.
+pub struct Options {
+    pub quirks: Quirks,
+    pub instructions_per_second: u32,
+    /// The seed for `CXKK`'s random numbers.
+    pub seed: u64,
+    pub keymap: KeyMap,
+    /// Starts paused, with the debugger reading commands from the terminal.
+    pub debug: bool,
+    pub gdb: Option<GdbServer>,
+    pub dap: Option<DapServer>,
+    pub tracer: Option<Tracer<BufWriter<File>>>,
+    /// Where to write a movie of the keys pressed, when the window is closed.
+    pub record: Option<String>,
+    pub replay: Option<Movie>,
+}
+
+/// A movie being recorded or replayed, which sets the keys a frame at a time so every frame
+/// sees the same keys when it is replayed.
+enum MovieMode {
+    Recording { path: String, movie: Movie },
+    Replaying { movie: Movie, frame: usize },
+}
+
+impl MovieMode {
+    /// Sets the keys for the next frame, from the window when recording or from the movie when
+    /// replaying. Returns false when a replay has run out of frames.
+    fn next_frame(&mut self, keyboard: &mut MiniFbKeyboard, keys: &[Key]) -> bool {
+        match self {
+            MovieMode::Recording { movie, .. } => {
+                keyboard.update_state(keys);
+                movie.record_frame(keyboard.pressed());
+                true
+            }
+            MovieMode::Replaying { movie, frame } => match movie.frames.get(*frame) {
+                Some(pressed) => {
+                    keyboard.set_pressed(*pressed);
+                    *frame += 1;
+                    true
+                }
+                None => false,
+            },
+        }
+    }
+}
+
+/// Runs the given ROM in a minifb window until the window is closed or ESC is pressed. When
+/// debugging, the program starts paused and is controlled from the terminal, by GDB, or by a
+/// debug adapter client.
+pub fn run(rom: &Rom, options: Options) {
+    let Options {
+        quirks,
+        instructions_per_second,
+        seed,
+        keymap,
+        debug,
+        mut gdb,
+        mut dap,
+        mut tracer,
+        record,
+        replay,
+    } = options;
+
+    let window: Rc<RefCell<_>> = Rc::new(RefCell::new(
+        Window::new(
+            "Chip8.rs - ESC to exit - F1: Debug, F2: Step, F3: Stop, F4: Continue, F5: Save, F6: Slot, F9: Load, Backspace: Rewind",
+            SCREEN_WIDTH,
+            SCREEN_HEIGHT,
+            WindowOptions {
+                scale: minifb::Scale::X8,
+                scale_mode: minifb::ScaleMode::Stretch,
+                ..WindowOptions::default()
+            },
+        )
+        .unwrap_or_else(|e| {
+            panic!("{}", e);
+        }),
+    ));
+
+    let keyboard = MiniFbKeyboard::initialise(&window, keymap);
+    let mut cpu = Chip8::from_rom(rom, keyboard, quirks).into_cpu();
+    cpu.seed_rng(seed);
+
+    let mut movie = match (record, replay) {
+        (Some(path), _) => Some(MovieMode::Recording {
+            path,
+            movie: Movie::new(rom, seed, quirks, instructions_per_second),
+        }),
+        (_, Some(movie)) => Some(MovieMode::Replaying { movie, frame: 0 }),
+        _ => None,
+    };
+
+    let mut inner_window = window.borrow_mut();
+    inner_window.limit_update_rate(Some(std::time::Duration::from_micros(16000)));
+
+    let mut scheduler = Scheduler::new().instructions_per_second(instructions_per_second);
+    let mut should_run = true;
+    let mut save_slot = 0;
+    let mut rewind = Rewind::default();
+    let mut repl = if debug { Some(Repl::start(&cpu)) } else { None };
+    while inner_window.is_open() && !inner_window.is_key_down(Key::Escape) {
+        if inner_window.is_key_pressed(Key::F1, minifb::KeyRepeat::No) {
+            println!("Dumping memory to chip8rs_memdump.log");
+            dump_memory(&cpu.memory);
+        }
+
+        if inner_window.is_key_pressed(Key::F6, minifb::KeyRepeat::No) {
+            save_slot = (save_slot + 1) % SAVE_SLOTS;
+            println!("Selected save slot {}", save_slot);
+        }
+
+        if inner_window.is_key_pressed(Key::F5, minifb::KeyRepeat::No) {
+            let path = save_slot_path(rom, save_slot);
+            match fs::write(&path, cpu.save_state()) {
+                Ok(()) => println!("Saved state to {}", path),
+                Err(e) => eprintln!("Unable to save state to {}: {}", path, e),
+            }
+        }
+
+        if movie.is_some()
+            && (inner_window.is_key_pressed(Key::F9, minifb::KeyRepeat::No)
+                || inner_window.is_key_pressed(Key::Backspace, minifb::KeyRepeat::No))
+        {
+            println!(
+                "Loading states and rewinding are disabled while a movie is recorded or replayed"
+            );
+        } else if inner_window.is_key_pressed(Key::F9, minifb::KeyRepeat::No) {
+            let path = save_slot_path(rom, save_slot);
+            let result = fs::read(&path)
+                .map_err(|e| e.to_string())
+                .and_then(|state| cpu.load_state(&state).map_err(|e| e.to_string()));
+            match result {
+                Ok(()) => {
+                    println!("Loaded state from {}", path);
+                    scheduler.reset();
+                    rewind.clear();
+                }
+                Err(e) => eprintln!("Unable to load state from {}: {}", path, e),
+            }
+        }
+
+        // The keys held down rather than the presses, so held keys stay held and releases are seen
+        let keys = inner_window.get_keys().unwrap_or_default();
+        if movie.is_none() {
+            cpu.keyboard.update_state(&keys);
+        }
+
+        let result = if movie.is_none() && inner_window.is_key_down(Key::Backspace) {
+            // The window updates at 60Hz, so this steps back one frame per frame
+            if let Some(snapshot) = rewind.pop() {
+                cpu.restore(snapshot)
+                    .expect("rewind snapshots are taken from the running machine");
+            }
+            scheduler.reset();
+            Ok(())
+        } else if let Some(repl) = repl.as_mut() {
+            if !repl.update(&mut scheduler, &mut cpu) {
+                break;
+            }
+            Ok(())
+        } else if let Some(server) = gdb.as_mut() {
+            match server.update(&mut scheduler, &mut cpu) {
+                Ok(Status::Attached) => {}
+                Ok(Status::Detached) => {
+                    println!("GDB detached");
+                    gdb = None;
+                }
+                Ok(Status::Killed) => break,
+                Err(e) => {
+                    eprintln!("Lost the connection to GDB: {}", e);
+                    gdb = None;
+                }
+            }
+            Ok(())
+        } else if let Some(server) = dap.as_mut() {
+            match server.update(&mut scheduler, &mut cpu) {
+                Ok(dap::Status::Attached) => {}
+                Ok(dap::Status::Detached) => {
+                    println!("Debug adapter client disconnected");
+                    dap = None;
+                }
+                Ok(dap::Status::Killed) => break,
+                Err(e) => {
+                    eprintln!("Lost the connection to the client: {}", e);
+                    dap = None;
+                }
+            }
+            Ok(())
+        } else if should_run {
+            run_due_frames(
+                &mut scheduler,
+                &mut cpu,
+                &mut rewind,
+                tracer.as_mut(),
+                movie.as_mut().map(|movie| (movie, keys.as_slice())),
+            )
+        } else if movie.is_none() && inner_window.is_key_pressed(Key::F2, minifb::KeyRepeat::Yes) {
+            cpu.execute_next_instruction().map(|_| ())
+        } else {
+            Ok(())
+        };
+
+        if let Err(e) = result {
+            eprintln!("CPU fault: {}", e);
+            cpu.view_state();
+            should_run = false;
+        }
+
+        if let Some(MovieMode::Replaying {
+            movie: replayed,
+            frame,
+        }) = &movie
+        {
+            if *frame == replayed.frames.len() {
+                match replayed.verify(&cpu) {
+                    Ok(()) => println!("Replayed {} frames, matching the recording", frame),
+                    Err(e) => eprintln!("Replayed {} frames, but {}", frame, e),
+                }
+                // Stop where the recording did, rather than carrying on with the window's keys
+                should_run = false;
+                movie = None;
+            }
+        }
+
+        if let Some(repl) = repl.as_mut() {
+            // The window keys drive the debugger, which prints where the program is
+            let debugger = repl.debugger_mut();
+            if inner_window.is_key_pressed(Key::F2, minifb::KeyRepeat::Yes) {
+                println!("{}", debugger.execute(Command::Step(1), &mut cpu));
+            }
+            if inner_window.is_key_pressed(Key::F3, minifb::KeyRepeat::No) {
+                println!("{}", debugger.execute(Command::Pause, &mut cpu));
+            }
+            if inner_window.is_key_pressed(Key::F4, minifb::KeyRepeat::No) {
+                debugger.resume();
+            }
+        } else if inner_window.is_key_pressed(Key::F3, minifb::KeyRepeat::No) {
+            should_run = false;
+        }
+
+        if repl.is_none() && inner_window.is_key_pressed(Key::F4, minifb::KeyRepeat::No) {
+            // Don't try to catch up on the time spent paused
+            scheduler.reset();
+            should_run = true;
+        }
+
+        inner_window
+            // The buffer is stretched to fill the window, whatever resolution the program uses
+            .update_with_buffer(
+                &cpu.display.get_buffer(),
+                cpu.display.width(),
+                cpu.display.height(),
+            )
+            .unwrap();
+
+        // The debugger stays open after the program exits, so it can still be inspected
+        if cpu.state() == CpuState::Exited && repl.is_none() && gdb.is_none() && dap.is_none() {
+            println!("Program exited");
+            break;
+        }
+    }
+
+    if let Some(MovieMode::Recording {
+        path,
+        movie: mut recorded,
+    }) = movie
+    {
+        recorded.finish(&cpu);
+        match fs::write(&path, recorded.to_bytes()) {
+            Ok(()) => println!("Recorded {} frames to {}", recorded.frames.len(), path),
+            Err(e) => eprintln!("Unable to write the movie to {}: {}", path, e),
+        }
+    }
+
+    if let Some(tracer) = tracer {
+        if let Err(e) = tracer.finish() {
+            eprintln!("Unable to write the trace: {}", e);
+        }
+    }
+}
+
+/// Runs every frame that is due, recording each one so it can be rewound, and tracing it if
+/// there is a trace. With a movie, the keys are set from the window's `keys` or the movie
+/// before each frame.
+fn run_due_frames(
+    scheduler: &mut Scheduler<SystemClock>,
+    cpu: &mut CPU<MiniFbKeyboard>,
+    rewind: &mut Rewind,
+    mut tracer: Option<&mut Tracer<BufWriter<File>>>,
+    mut movie: Option<(&mut MovieMode, &[Key])>,
+) -> Result<(), Chip8Error> {
+    for _ in 0..scheduler.frames_due() {
+        if let Some((movie, keys)) = movie.as_mut() {
+            if !movie.next_frame(&mut cpu.keyboard, keys) {
+                break;
+            }
+        }
+
+        match tracer.as_deref_mut() {
+            Some(tracer) => {
+                tracer.run_frame(scheduler, cpu, scheduler.instructions_per_frame())?;
+            }
+            None => scheduler.run_frame(cpu)?,
+        }
+        rewind.push(cpu.snapshot());
+    }
+
+    Ok(())
+}
+
+/// Save slots are kept per ROM, so every game has its own set.
+fn save_slot_path(rom: &Rom, slot: u8) -> String {
+    format!("chip8rs_{}_{}.state", &rom.sha1_hex()[..8], slot)
+}
+
+fn dump_memory(memory: &Memory) {
+    let mut file = File::create("chip8rs_memdump.log").unwrap();
+    file.write_all(&memory.data).unwrap();
+}
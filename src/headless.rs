@@ -0,0 +1,468 @@
+//! Runs programs without a window, for regression testing ROMs in CI.
+//!
+//! Key presses are scripted ahead of time with a [`KeyScript`], and the program is run for a
+//! fixed number of frames or instructions. Afterwards the screen can be saved with
+//! [`crate::display::Display::to_png`] or [`crate::display::Display::to_pbm`], and the
+//! registers with [`registers_json`].
+
+use std::{error::Error, fmt, io::Write, str::FromStr};
+
+use crate::{
+    cpu::{CpuState, CPU},
+    error::Chip8Error,
+    keyboard::{dummy_keyboard::DummyKeyboard, Keyboard},
+    scheduler::{ManualClock, Scheduler, DEFAULT_INSTRUCTIONS_PER_SECOND},
+    trace::Tracer,
+};
+
+/// A key being pressed or released at the start of a frame.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ScriptedKey {
+    pub frame: u64,
+    pub key: u8,
+    pub pressed: bool,
+}
+
+/// A syntax error in a key script.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyScriptError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for KeyScriptError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl Error for KeyScriptError {}
+
+/// A list of key presses and releases, ordered by frame.
+///
+/// Scripts are written one event per line as `<frame> <down|up> <key>`, where frames count
+/// from 0 and the key is a hex digit. Blank lines and anything after a `#` are ignored.
+///
+/// ```
+/// use chip8_rs::headless::KeyScript;
+///
+/// let script: KeyScript = "# start the game\n10 down 5\n12 up 5".parse().unwrap();
+/// assert_eq!(script.events().len(), 2);
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct KeyScript {
+    events: Vec<ScriptedKey>,
+}
+
+impl KeyScript {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn press(mut self, frame: u64, key: u8) -> Self {
+        self.push(frame, key, true);
+        self
+    }
+
+    pub fn release(mut self, frame: u64, key: u8) -> Self {
+        self.push(frame, key, false);
+        self
+    }
+
+    pub fn events(&self) -> &[ScriptedKey] {
+        &self.events
+    }
+
+    fn push(&mut self, frame: u64, key: u8, pressed: bool) {
+        // Keep events ordered by frame, and in the order given within a frame
+        let index = self.events.partition_point(|e| e.frame <= frame);
+        self.events.insert(
+            index,
+            ScriptedKey {
+                frame,
+                key,
+                pressed,
+            },
+        );
+    }
+
+    /// Whether anything happens in `frame` or after it.
+    fn has_events_from(&self, frame: u64) -> bool {
+        self.events.last().is_some_and(|e| e.frame >= frame)
+    }
+
+    fn apply(&self, frame: u64, keyboard: &mut DummyKeyboard) {
+        for event in self.events.iter().filter(|e| e.frame == frame) {
+            if event.pressed {
+                keyboard.press(event.key);
+            } else {
+                keyboard.release(event.key);
+            }
+        }
+    }
+}
+
+impl FromStr for KeyScript {
+    type Err = KeyScriptError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut script = KeyScript::new();
+
+        for (index, line) in s.lines().enumerate() {
+            let error = |message: &str| KeyScriptError {
+                line: index + 1,
+                message: message.to_string(),
+            };
+
+            let line = line.split('#').next().unwrap().trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.len() != 3 {
+                return Err(error("expected `<frame> <down|up> <key>`"));
+            }
+
+            let frame = fields[0]
+                .parse()
+                .map_err(|_| error("frame is not a number"))?;
+            let pressed = match fields[1] {
+                "down" => true,
+                "up" => false,
+                _ => return Err(error("expected `down` or `up`")),
+            };
+            let key = match u8::from_str_radix(fields[2], 16) {
+                Ok(key) if key <= 0xF => key,
+                _ => return Err(error("key must be a hex digit between 0 and F")),
+            };
+
+            script.push(frame, key, pressed);
+        }
+
+        Ok(script)
+    }
+}
+
+/// How long to run a program for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RunLimit {
+    Frames(u64),
+    Instructions(u64),
+}
+
+/// What a [`HeadlessRunner`] managed to run.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RunSummary {
+    pub frames: u64,
+    pub instructions: u64,
+}
+
+/// Runs a CPU as fast as possible, frame by frame, feeding it scripted key input.
+#[derive(Debug)]
+pub struct HeadlessRunner {
+    scheduler: Scheduler<ManualClock>,
+    script: KeyScript,
+}
+
+impl Default for HeadlessRunner {
+    fn default() -> Self {
+        Self {
+            scheduler: Scheduler::with_clock(ManualClock::default())
+                .instructions_per_second(DEFAULT_INSTRUCTIONS_PER_SECOND),
+            script: KeyScript::new(),
+        }
+    }
+}
+
+impl HeadlessRunner {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the emulated CPU speed, which decides how many instructions make up a frame.
+    pub fn instructions_per_second(mut self, ips: u32) -> Self {
+        self.scheduler = self.scheduler.instructions_per_second(ips);
+        self
+    }
+
+    pub fn script(mut self, script: KeyScript) -> Self {
+        self.script = script;
+        self
+    }
+
+    /// Runs `cpu` until `limit` is reached, or the program exits or faults. When counting
+    /// instructions it also stops once the program waits for a key the script never presses.
+    pub fn run(
+        &self,
+        cpu: &mut CPU<DummyKeyboard>,
+        limit: RunLimit,
+    ) -> Result<RunSummary, Chip8Error> {
+        self.run_frames(cpu, limit, |scheduler, cpu, budget| {
+            scheduler.run_frame_limited(cpu, budget)
+        })
+    }
+
+    /// Runs `cpu` like [`HeadlessRunner::run`], writing each instruction to the trace.
+    pub fn run_traced<W>(
+        &self,
+        cpu: &mut CPU<DummyKeyboard>,
+        limit: RunLimit,
+        tracer: &mut Tracer<W>,
+    ) -> Result<RunSummary, Chip8Error>
+    where
+        W: Write,
+    {
+        self.run_frames(cpu, limit, |scheduler, cpu, budget| {
+            tracer.run_frame(scheduler, cpu, budget)
+        })
+    }
+
+    fn run_frames<F>(
+        &self,
+        cpu: &mut CPU<DummyKeyboard>,
+        limit: RunLimit,
+        mut run_frame: F,
+    ) -> Result<RunSummary, Chip8Error>
+    where
+        F: FnMut(&Scheduler<ManualClock>, &mut CPU<DummyKeyboard>, u32) -> Result<u32, Chip8Error>,
+    {
+        let mut summary = RunSummary::default();
+
+        // A program waiting for a key keeps running frames, so the script can press one
+        while cpu.state() != CpuState::Exited {
+            let budget = match limit {
+                RunLimit::Frames(frames) if summary.frames >= frames => break,
+                RunLimit::Instructions(instructions) if summary.instructions >= instructions => {
+                    break
+                }
+                // No instructions will run if no key is ever pressed, so this would never end
+                RunLimit::Instructions(_)
+                    if matches!(cpu.state(), CpuState::WaitingForKey { .. })
+                        && !self.script.has_events_from(summary.frames) =>
+                {
+                    break
+                }
+                RunLimit::Frames(_) => u32::MAX,
+                RunLimit::Instructions(instructions) => {
+                    (instructions - summary.instructions).min(u32::MAX as u64) as u32
+                }
+            };
+
+            self.script.apply(summary.frames, &mut cpu.keyboard);
+            summary.instructions += run_frame(&self.scheduler, cpu, budget)? as u64;
+            summary.frames += 1;
+        }
+
+        Ok(summary)
+    }
+}
+
+/// Serialises the CPU registers as a JSON object.
+pub fn registers_json<TKeyboard>(cpu: &CPU<TKeyboard>) -> String
+where
+    TKeyboard: Keyboard,
+{
+    let list = |values: Vec<String>| format!("[{}]", values.join(", "));
+
+    format!(
+        concat!(
+            "{{\n",
+            "  \"pc\": {},\n",
+            "  \"i\": {},\n",
+            "  \"sp\": {},\n",
+            "  \"delay_timer\": {},\n",
+            "  \"sound_timer\": {},\n",
+            "  \"v\": {},\n",
+            "  \"stack\": {}\n",
+            "}}\n"
+        ),
+        cpu.pc,
+        cpu.vi,
+        cpu.sp,
+        cpu.delay_timer,
+        cpu.sound_timer,
+        list(cpu.v.iter().map(|v| v.to_string()).collect()),
+        list(cpu.stack.iter().map(|s| s.to_string()).collect()),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        cpu::{CpuState, CPU},
+        display::Display,
+        error::Chip8Error,
+        keyboard::{dummy_keyboard::DummyKeyboard, Keyboard},
+        memory::Memory,
+        quirks::Quirks,
+    };
+
+    use super::{registers_json, HeadlessRunner, KeyScript, RunLimit, RunSummary, ScriptedKey};
+
+    fn get_cpu(program: &[u16]) -> CPU<DummyKeyboard> {
+        let mut memory = Memory::initialise();
+        for (i, op) in program.iter().enumerate() {
+            memory.insert_instruction(0x200 + i * 2, *op);
+        }
+
+        CPU::initialise(
+            memory,
+            Display::initialise(),
+            DummyKeyboard::initialise(),
+            Quirks::default(),
+        )
+    }
+
+    #[test]
+    fn should_parse_key_script() {
+        let script: KeyScript = "\n# comment\n20 up a\n10 down A # press\n".parse().unwrap();
+
+        assert_eq!(
+            script.events(),
+            &[
+                ScriptedKey {
+                    frame: 10,
+                    key: 0xA,
+                    pressed: true
+                },
+                ScriptedKey {
+                    frame: 20,
+                    key: 0xA,
+                    pressed: false
+                }
+            ]
+        );
+    }
+
+    #[test]
+    fn should_report_line_of_invalid_key_script() {
+        let error = "1 down 1\n2 down 10".parse::<KeyScript>().unwrap_err();
+
+        assert_eq!(error.line, 2);
+    }
+
+    #[test]
+    fn should_run_for_frames() {
+        // ADD V0, 1; JP 0x200
+        let mut cpu = get_cpu(&[0x7001, 0x1200]);
+        let runner = HeadlessRunner::new().instructions_per_second(600);
+
+        let summary = runner.run(&mut cpu, RunLimit::Frames(3)).unwrap();
+
+        assert_eq!(
+            summary,
+            RunSummary {
+                frames: 3,
+                instructions: 30
+            }
+        );
+        assert_eq!(cpu.v[0], 15);
+    }
+
+    #[test]
+    fn should_run_for_instructions() {
+        let mut cpu = get_cpu(&[0x7001, 0x1200]);
+        let runner = HeadlessRunner::new().instructions_per_second(600);
+
+        let summary = runner.run(&mut cpu, RunLimit::Instructions(25)).unwrap();
+
+        assert_eq!(
+            summary,
+            RunSummary {
+                frames: 3,
+                instructions: 25
+            }
+        );
+        assert_eq!(cpu.v[0], 13);
+    }
+
+    #[test]
+    fn should_press_scripted_keys() {
+        // SKNP V0; ADD V1, 1; JP 0x200
+        let mut cpu = get_cpu(&[0xE0A1, 0x7101, 0x1200]);
+        cpu.v[0] = 0x5;
+        let runner = HeadlessRunner::new()
+            .instructions_per_second(180)
+            .script(KeyScript::new().press(1, 0x5).release(2, 0x5));
+
+        runner.run(&mut cpu, RunLimit::Frames(3)).unwrap();
+
+        // V1 is only incremented while 5 is held down
+        assert_eq!(cpu.v[1], 1);
+        assert_eq!(cpu.keyboard.pressed(), 0);
+    }
+
+    #[test]
+    fn should_wait_for_scripted_key_release() {
+        // LD V2, K; EXIT
+        let mut cpu = get_cpu(&[0xF20A, 0x00FD]);
+        cpu.delay_timer = 10;
+        let runner = HeadlessRunner::new().script(KeyScript::new().press(2, 0xB).release(4, 0xB));
+
+        let summary = runner.run(&mut cpu, RunLimit::Frames(4)).unwrap();
+        assert_eq!(summary.instructions, 1);
+        assert_eq!(cpu.state(), CpuState::WaitingForKey { register: 2 });
+        // The timers keep running while waiting
+        assert_eq!(cpu.delay_timer, 6);
+
+        runner.run(&mut cpu, RunLimit::Frames(5)).unwrap();
+        assert_eq!(cpu.v[2], 0xB);
+        assert_eq!(cpu.state(), CpuState::Exited);
+    }
+
+    #[test]
+    fn should_stop_counting_instructions_when_no_key_will_be_pressed() {
+        // ADD V0, 1; LD V2, K; EXIT
+        let mut cpu = get_cpu(&[0x7001, 0xF20A, 0x00FD]);
+
+        let summary = HeadlessRunner::new()
+            .run(&mut cpu, RunLimit::Instructions(100))
+            .unwrap();
+        assert_eq!(summary.instructions, 2);
+        assert_eq!(cpu.state(), CpuState::WaitingForKey { register: 2 });
+
+        // A key pressed and released in a later frame still gets through
+        let runner = HeadlessRunner::new().script(KeyScript::new().press(3, 0x7).release(5, 0x7));
+        let summary = runner.run(&mut cpu, RunLimit::Instructions(100)).unwrap();
+        assert_eq!(summary.instructions, 1);
+        assert_eq!(cpu.v[2], 0x7);
+        assert_eq!(cpu.state(), CpuState::Exited);
+    }
+
+    #[test]
+    fn should_stop_when_program_exits() {
+        // ADD V0, 1; EXIT
+        let mut cpu = get_cpu(&[0x7001, 0x00FD]);
+
+        let summary = HeadlessRunner::new()
+            .run(&mut cpu, RunLimit::Instructions(100))
+            .unwrap();
+
+        assert_eq!(summary.instructions, 2);
+        assert_eq!(cpu.state(), CpuState::Exited);
+    }
+
+    #[test]
+    fn should_stop_on_fault() {
+        let mut cpu = get_cpu(&[0x00EE]);
+
+        assert_eq!(
+            HeadlessRunner::new().run(&mut cpu, RunLimit::Frames(1)),
+            Err(Chip8Error::StackUnderflow)
+        );
+    }
+
+    #[test]
+    fn should_serialise_registers_as_json() {
+        let mut cpu = get_cpu(&[]);
+        cpu.v[0xF] = 1;
+        cpu.vi = 0x300;
+
+        let json = registers_json(&cpu);
+
+        assert!(json.contains("\"pc\": 512,"));
+        assert!(json.contains("\"i\": 768,"));
+        assert!(json.contains("\"v\": [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1],"));
+    }
+}
@@ -1 +1,485 @@
+use std::fmt;
 
+use crate::opcode::OpCode;
+
+/// A decoded CHIP-8 instruction.
+///
+/// Variants follow the mnemonics used in Cowgod's Technical Reference.
+/// Register operands (`x`, `y`) are register indexes, `kk` is an immediate byte,
+/// `n` an immediate nibble and `nnn` a 12-bit address.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Instruction {
+    /// `0nnn` - Jump to a machine code routine. Ignored by modern interpreters.
+    Sys { nnn: u16 },
+    /// `00E0` - Clear the display.
+    Cls,
+    /// `00EE` - Return from a subroutine.
+    Ret,
+    /// `00Dn` - Scroll the display up `n` pixels. XO-CHIP.
+    ScrollUp { n: u8 },
+    /// `00Cn` - Scroll the display down `n` pixels. SUPER-CHIP.
+    ScrollDown { n: u8 },
+    /// `00FB` - Scroll the display right 4 pixels. SUPER-CHIP.
+    ScrollRight,
+    /// `00FC` - Scroll the display left 4 pixels. SUPER-CHIP.
+    ScrollLeft,
+    /// `00FD` - Exit the interpreter. SUPER-CHIP.
+    Exit,
+    /// `00FE` - Switch to the 64x32 low resolution mode. SUPER-CHIP.
+    LoRes,
+    /// `00FF` - Switch to the 128x64 high resolution mode. SUPER-CHIP.
+    HiRes,
+    /// `1nnn` - Jump to location `nnn`.
+    Jp { nnn: u16 },
+    /// `2nnn` - Call subroutine at `nnn`.
+    Call { nnn: u16 },
+    /// `3xkk` - Skip next instruction if `Vx == kk`.
+    SeByte { x: u8, kk: u8 },
+    /// `4xkk` - Skip next instruction if `Vx != kk`.
+    SneByte { x: u8, kk: u8 },
+    /// `5xy0` - Skip next instruction if `Vx == Vy`.
+    SeXY { x: u8, y: u8 },
+    /// `5xy2` - Store registers `Vx` through `Vy` in memory starting at location `I`. XO-CHIP.
+    StoreRange { x: u8, y: u8 },
+    /// `5xy3` - Read registers `Vx` through `Vy` from memory starting at location `I`. XO-CHIP.
+    LoadRange { x: u8, y: u8 },
+    /// `6xkk` - Set `Vx = kk`.
+    LdByte { x: u8, kk: u8 },
+    /// `7xkk` - Set `Vx = Vx + kk`.
+    AddByte { x: u8, kk: u8 },
+    /// `8xy0` - Set `Vx = Vy`.
+    LdXY { x: u8, y: u8 },
+    /// `8xy1` - Set `Vx = Vx OR Vy`.
+    OrXY { x: u8, y: u8 },
+    /// `8xy2` - Set `Vx = Vx AND Vy`.
+    AndXY { x: u8, y: u8 },
+    /// `8xy3` - Set `Vx = Vx XOR Vy`.
+    XorXY { x: u8, y: u8 },
+    /// `8xy4` - Set `Vx = Vx + Vy`, set `VF = carry`.
+    AddXY { x: u8, y: u8 },
+    /// `8xy5` - Set `Vx = Vx - Vy`, set `VF = NOT borrow`.
+    SubXY { x: u8, y: u8 },
+    /// `8xy6` - Set `Vx = Vx SHR 1`.
+    ShrXY { x: u8, y: u8 },
+    /// `8xy7` - Set `Vx = Vy - Vx`, set `VF = NOT borrow`.
+    SubnXY { x: u8, y: u8 },
+    /// `8xyE` - Set `Vx = Vx SHL 1`.
+    ShlXY { x: u8, y: u8 },
+    /// `9xy0` - Skip next instruction if `Vx != Vy`.
+    SneXY { x: u8, y: u8 },
+    /// `Annn` - Set `I = nnn`.
+    LdI { nnn: u16 },
+    /// `Bnnn` - Jump to location `nnn + V0`.
+    JpV0 { nnn: u16 },
+    /// `Cxkk` - Set `Vx = random byte AND kk`.
+    Rnd { x: u8, kk: u8 },
+    /// `Dxyn` - Display `n`-byte sprite starting at memory location `I` at `(Vx, Vy)`, set `VF = collision`.
+    /// `Dxy0` draws a 16x16 sprite (SUPER-CHIP).
+    Draw { x: u8, y: u8, n: u8 },
+    /// `F000 nnnn` - Set `I = nnnn`, the 16-bit address in the word after the opcode. XO-CHIP.
+    LdILong { nnnn: u16 },
+    /// `Fn01` - Select the planes `n` that drawing, clearing and scrolling affect. XO-CHIP.
+    Plane { n: u8 },
+    /// `F002` - Load the 16 byte audio pattern from memory starting at location `I`. XO-CHIP.
+    LdAudio,
+    /// `Ex9E` - Skip next instruction if the key with the value of `Vx` is pressed.
+    Skp { x: u8 },
+    /// `ExA1` - Skip next instruction if the key with the value of `Vx` is not pressed.
+    Sknp { x: u8 },
+    /// `Fx07` - Set `Vx = delay timer`.
+    LdXDt { x: u8 },
+    /// `Fx0A` - Wait for a key press, store the value of the key in `Vx`.
+    LdXKey { x: u8 },
+    /// `Fx15` - Set `delay timer = Vx`.
+    LdDtX { x: u8 },
+    /// `Fx18` - Set `sound timer = Vx`.
+    LdStX { x: u8 },
+    /// `Fx1E` - Set `I = I + Vx`.
+    AddIX { x: u8 },
+    /// `Fx29` - Set `I` = location of sprite for digit `Vx`.
+    LdFont { x: u8 },
+    /// `Fx3A` - Set the audio pitch register to `Vx`. XO-CHIP.
+    LdPitch { x: u8 },
+    /// `Fx30` - Set `I` = location of the 10-byte sprite for digit `Vx`. SUPER-CHIP.
+    LdHiFont { x: u8 },
+    /// `Fx33` - Store BCD representation of `Vx` in memory locations `I`, `I+1`, and `I+2`.
+    LdBcd { x: u8 },
+    /// `Fx55` - Store registers `V0` through `Vx` in memory starting at location `I`.
+    StoreRegs { x: u8 },
+    /// `Fx65` - Read registers `V0` through `Vx` from memory starting at location `I`.
+    LoadRegs { x: u8 },
+    /// `Fx75` - Store registers `V0` through `Vx` in the RPL user flags. SUPER-CHIP.
+    StoreFlags { x: u8 },
+    /// `Fx85` - Read registers `V0` through `Vx` from the RPL user flags. SUPER-CHIP.
+    LoadFlags { x: u8 },
+    /// Any opcode that does not decode to a known instruction.
+    Unknown(u16),
+}
+
+// The opcode of the only instruction that is 4 bytes long
+const LONG_LOAD_OPCODE: u16 = 0xF000;
+
+impl Instruction {
+    /// Decodes a single 2 byte opcode.
+    ///
+    /// The XO-CHIP `F000 nnnn` instruction needs the word after it as well and decodes as
+    /// [`Instruction::Unknown`] here; see [`Instruction::is_long`] and [`Instruction::decode_long`].
+    pub fn decode(raw: u16) -> Self {
+        Self::from(&OpCode::new(raw))
+    }
+
+    /// Whether `raw` is the first word of a 4 byte instruction.
+    pub fn is_long(raw: u16) -> bool {
+        raw == LONG_LOAD_OPCODE
+    }
+
+    /// Decodes a 4 byte instruction from its opcode and the word after it.
+    pub fn decode_long(raw: u16, next: u16) -> Self {
+        if Self::is_long(raw) {
+            Instruction::LdILong { nnnn: next }
+        } else {
+            Self::decode(raw)
+        }
+    }
+
+    /// The number of bytes the instruction takes up in memory.
+    pub fn size(&self) -> u16 {
+        match self {
+            Instruction::LdILong { .. } => 4,
+            _ => 2,
+        }
+    }
+
+    /// Encodes the instruction as the bytes it is stored as in memory, the reverse of decoding.
+    ///
+    /// Operands are masked to the bits the opcode has room for.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let xy = |id: u16, x: u8, y: u8, n: u16| {
+            id << 12 | (x as u16 & 0xF) << 8 | (y as u16 & 0xF) << 4 | n
+        };
+        let xkk = |id: u16, x: u8, kk: u8| id << 12 | (x as u16 & 0xF) << 8 | kk as u16;
+        let nnn = |id: u16, nnn: u16| id << 12 | (nnn & 0xFFF);
+
+        let raw = match *self {
+            Instruction::Sys { nnn: address } => nnn(0x0, address),
+            Instruction::Cls => 0x00E0,
+            Instruction::Ret => 0x00EE,
+            Instruction::ScrollUp { n } => 0x00D0 | (n as u16 & 0xF),
+            Instruction::ScrollDown { n } => 0x00C0 | (n as u16 & 0xF),
+            Instruction::ScrollRight => 0x00FB,
+            Instruction::ScrollLeft => 0x00FC,
+            Instruction::Exit => 0x00FD,
+            Instruction::LoRes => 0x00FE,
+            Instruction::HiRes => 0x00FF,
+            Instruction::Jp { nnn: address } => nnn(0x1, address),
+            Instruction::Call { nnn: address } => nnn(0x2, address),
+            Instruction::SeByte { x, kk } => xkk(0x3, x, kk),
+            Instruction::SneByte { x, kk } => xkk(0x4, x, kk),
+            Instruction::SeXY { x, y } => xy(0x5, x, y, 0x0),
+            Instruction::StoreRange { x, y } => xy(0x5, x, y, 0x2),
+            Instruction::LoadRange { x, y } => xy(0x5, x, y, 0x3),
+            Instruction::LdByte { x, kk } => xkk(0x6, x, kk),
+            Instruction::AddByte { x, kk } => xkk(0x7, x, kk),
+            Instruction::LdXY { x, y } => xy(0x8, x, y, 0x0),
+            Instruction::OrXY { x, y } => xy(0x8, x, y, 0x1),
+            Instruction::AndXY { x, y } => xy(0x8, x, y, 0x2),
+            Instruction::XorXY { x, y } => xy(0x8, x, y, 0x3),
+            Instruction::AddXY { x, y } => xy(0x8, x, y, 0x4),
+            Instruction::SubXY { x, y } => xy(0x8, x, y, 0x5),
+            Instruction::ShrXY { x, y } => xy(0x8, x, y, 0x6),
+            Instruction::SubnXY { x, y } => xy(0x8, x, y, 0x7),
+            Instruction::ShlXY { x, y } => xy(0x8, x, y, 0xE),
+            Instruction::SneXY { x, y } => xy(0x9, x, y, 0x0),
+            Instruction::LdI { nnn: address } => nnn(0xA, address),
+            Instruction::JpV0 { nnn: address } => nnn(0xB, address),
+            Instruction::Rnd { x, kk } => xkk(0xC, x, kk),
+            Instruction::Draw { x, y, n } => xy(0xD, x, y, n as u16 & 0xF),
+            Instruction::LdILong { nnnn } => {
+                let mut bytes = LONG_LOAD_OPCODE.to_be_bytes().to_vec();
+                bytes.extend_from_slice(&nnnn.to_be_bytes());
+                return bytes;
+            }
+            Instruction::Plane { n } => xkk(0xF, n, 0x01),
+            Instruction::LdAudio => 0xF002,
+            Instruction::Skp { x } => xkk(0xE, x, 0x9E),
+            Instruction::Sknp { x } => xkk(0xE, x, 0xA1),
+            Instruction::LdXDt { x } => xkk(0xF, x, 0x07),
+            Instruction::LdXKey { x } => xkk(0xF, x, 0x0A),
+            Instruction::LdDtX { x } => xkk(0xF, x, 0x15),
+            Instruction::LdStX { x } => xkk(0xF, x, 0x18),
+            Instruction::AddIX { x } => xkk(0xF, x, 0x1E),
+            Instruction::LdFont { x } => xkk(0xF, x, 0x29),
+            Instruction::LdHiFont { x } => xkk(0xF, x, 0x30),
+            Instruction::LdPitch { x } => xkk(0xF, x, 0x3A),
+            Instruction::LdBcd { x } => xkk(0xF, x, 0x33),
+            Instruction::StoreRegs { x } => xkk(0xF, x, 0x55),
+            Instruction::LoadRegs { x } => xkk(0xF, x, 0x65),
+            Instruction::StoreFlags { x } => xkk(0xF, x, 0x75),
+            Instruction::LoadFlags { x } => xkk(0xF, x, 0x85),
+            Instruction::Unknown(raw) => raw,
+        };
+
+        raw.to_be_bytes().to_vec()
+    }
+}
+
+impl From<&OpCode> for Instruction {
+    fn from(op: &OpCode) -> Self {
+        let x = op.x();
+        let y = op.y();
+        let kk = op.kk();
+        let n = op.n();
+        let nnn = op.nnn();
+
+        match op.id() {
+            0x0 => match op.raw() {
+                0x00E0 => Instruction::Cls,
+                0x00EE => Instruction::Ret,
+                0x00FB => Instruction::ScrollRight,
+                0x00FC => Instruction::ScrollLeft,
+                0x00FD => Instruction::Exit,
+                0x00FE => Instruction::LoRes,
+                0x00FF => Instruction::HiRes,
+                _ if x == 0x0 && y == 0xC => Instruction::ScrollDown { n },
+                _ if x == 0x0 && y == 0xD => Instruction::ScrollUp { n },
+                _ => Instruction::Sys { nnn },
+            },
+            0x1 => Instruction::Jp { nnn },
+            0x2 => Instruction::Call { nnn },
+            0x3 => Instruction::SeByte { x, kk },
+            0x4 => Instruction::SneByte { x, kk },
+            0x5 => match n {
+                0x0 => Instruction::SeXY { x, y },
+                0x2 => Instruction::StoreRange { x, y },
+                0x3 => Instruction::LoadRange { x, y },
+                _ => Instruction::Unknown(op.raw()),
+            },
+            0x6 => Instruction::LdByte { x, kk },
+            0x7 => Instruction::AddByte { x, kk },
+            0x8 => match n {
+                0x0 => Instruction::LdXY { x, y },
+                0x1 => Instruction::OrXY { x, y },
+                0x2 => Instruction::AndXY { x, y },
+                0x3 => Instruction::XorXY { x, y },
+                0x4 => Instruction::AddXY { x, y },
+                0x5 => Instruction::SubXY { x, y },
+                0x6 => Instruction::ShrXY { x, y },
+                0x7 => Instruction::SubnXY { x, y },
+                0xE => Instruction::ShlXY { x, y },
+                _ => Instruction::Unknown(op.raw()),
+            },
+            0x9 if n == 0x0 => Instruction::SneXY { x, y },
+            0xA => Instruction::LdI { nnn },
+            0xB => Instruction::JpV0 { nnn },
+            0xC => Instruction::Rnd { x, kk },
+            0xD => Instruction::Draw { x, y, n },
+            0xE => match kk {
+                0x9E => Instruction::Skp { x },
+                0xA1 => Instruction::Sknp { x },
+                _ => Instruction::Unknown(op.raw()),
+            },
+            0xF => match kk {
+                0x01 => Instruction::Plane { n: x },
+                0x02 if x == 0x0 => Instruction::LdAudio,
+                0x07 => Instruction::LdXDt { x },
+                0x0A => Instruction::LdXKey { x },
+                0x15 => Instruction::LdDtX { x },
+                0x18 => Instruction::LdStX { x },
+                0x1E => Instruction::AddIX { x },
+                0x29 => Instruction::LdFont { x },
+                0x30 => Instruction::LdHiFont { x },
+                0x3A => Instruction::LdPitch { x },
+                0x33 => Instruction::LdBcd { x },
+                0x55 => Instruction::StoreRegs { x },
+                0x65 => Instruction::LoadRegs { x },
+                0x75 => Instruction::StoreFlags { x },
+                0x85 => Instruction::LoadFlags { x },
+                _ => Instruction::Unknown(op.raw()),
+            },
+            _ => Instruction::Unknown(op.raw()),
+        }
+    }
+}
+
+impl From<OpCode> for Instruction {
+    fn from(op: OpCode) -> Self {
+        Self::from(&op)
+    }
+}
+
+/// Formats the instruction using Cowgod's mnemonics, e.g. `DRW V1, V2, 0x5`.
+///
+/// Opcodes that do not decode are formatted as a data word, e.g. `DW 0x5001`.
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Instruction::Sys { nnn } => write!(f, "SYS 0x{:03X}", nnn),
+            Instruction::Cls => write!(f, "CLS"),
+            Instruction::Ret => write!(f, "RET"),
+            Instruction::ScrollUp { n } => write!(f, "SCU 0x{:X}", n),
+            Instruction::ScrollDown { n } => write!(f, "SCD 0x{:X}", n),
+            Instruction::ScrollRight => write!(f, "SCR"),
+            Instruction::ScrollLeft => write!(f, "SCL"),
+            Instruction::Exit => write!(f, "EXIT"),
+            Instruction::LoRes => write!(f, "LOW"),
+            Instruction::HiRes => write!(f, "HIGH"),
+            Instruction::Jp { nnn } => write!(f, "JP 0x{:03X}", nnn),
+            Instruction::Call { nnn } => write!(f, "CALL 0x{:03X}", nnn),
+            Instruction::SeByte { x, kk } => write!(f, "SE V{:X}, 0x{:02X}", x, kk),
+            Instruction::SneByte { x, kk } => write!(f, "SNE V{:X}, 0x{:02X}", x, kk),
+            Instruction::SeXY { x, y } => write!(f, "SE V{:X}, V{:X}", x, y),
+            Instruction::StoreRange { x, y } => write!(f, "LD [I], V{:X}-V{:X}", x, y),
+            Instruction::LoadRange { x, y } => write!(f, "LD V{:X}-V{:X}, [I]", x, y),
+            Instruction::LdByte { x, kk } => write!(f, "LD V{:X}, 0x{:02X}", x, kk),
+            Instruction::AddByte { x, kk } => write!(f, "ADD V{:X}, 0x{:02X}", x, kk),
+            Instruction::LdXY { x, y } => write!(f, "LD V{:X}, V{:X}", x, y),
+            Instruction::OrXY { x, y } => write!(f, "OR V{:X}, V{:X}", x, y),
+            Instruction::AndXY { x, y } => write!(f, "AND V{:X}, V{:X}", x, y),
+            Instruction::XorXY { x, y } => write!(f, "XOR V{:X}, V{:X}", x, y),
+            Instruction::AddXY { x, y } => write!(f, "ADD V{:X}, V{:X}", x, y),
+            Instruction::SubXY { x, y } => write!(f, "SUB V{:X}, V{:X}", x, y),
+            Instruction::ShrXY { x, y } => write!(f, "SHR V{:X}, V{:X}", x, y),
+            Instruction::SubnXY { x, y } => write!(f, "SUBN V{:X}, V{:X}", x, y),
+            Instruction::ShlXY { x, y } => write!(f, "SHL V{:X}, V{:X}", x, y),
+            Instruction::SneXY { x, y } => write!(f, "SNE V{:X}, V{:X}", x, y),
+            Instruction::LdI { nnn } => write!(f, "LD I, 0x{:03X}", nnn),
+            Instruction::JpV0 { nnn } => write!(f, "JP V0, 0x{:03X}", nnn),
+            Instruction::Rnd { x, kk } => write!(f, "RND V{:X}, 0x{:02X}", x, kk),
+            Instruction::Draw { x, y, n } => write!(f, "DRW V{:X}, V{:X}, 0x{:X}", x, y, n),
+            Instruction::LdILong { nnnn } => write!(f, "LD I, LONG 0x{:04X}", nnnn),
+            Instruction::Plane { n } => write!(f, "PLANE 0x{:X}", n),
+            Instruction::LdAudio => write!(f, "LD AUDIO, [I]"),
+            Instruction::Skp { x } => write!(f, "SKP V{:X}", x),
+            Instruction::Sknp { x } => write!(f, "SKNP V{:X}", x),
+            Instruction::LdXDt { x } => write!(f, "LD V{:X}, DT", x),
+            Instruction::LdXKey { x } => write!(f, "LD V{:X}, K", x),
+            Instruction::LdDtX { x } => write!(f, "LD DT, V{:X}", x),
+            Instruction::LdStX { x } => write!(f, "LD ST, V{:X}", x),
+            Instruction::AddIX { x } => write!(f, "ADD I, V{:X}", x),
+            Instruction::LdFont { x } => write!(f, "LD F, V{:X}", x),
+            Instruction::LdHiFont { x } => write!(f, "LD HF, V{:X}", x),
+            Instruction::LdPitch { x } => write!(f, "LD PITCH, V{:X}", x),
+            Instruction::LdBcd { x } => write!(f, "LD B, V{:X}", x),
+            Instruction::StoreRegs { x } => write!(f, "LD [I], V{:X}", x),
+            Instruction::LoadRegs { x } => write!(f, "LD V{:X}, [I]", x),
+            Instruction::StoreFlags { x } => write!(f, "LD R, V{:X}", x),
+            Instruction::LoadFlags { x } => write!(f, "LD V{:X}, R", x),
+            Instruction::Unknown(raw) => write!(f, "DW 0x{:04X}", raw),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Instruction;
+
+    #[test]
+    fn should_decode_system_instructions() {
+        assert_eq!(Instruction::decode(0x00E0), Instruction::Cls);
+        assert_eq!(Instruction::decode(0x00EE), Instruction::Ret);
+        assert_eq!(Instruction::decode(0x0123), Instruction::Sys { nnn: 0x123 });
+    }
+
+    #[test]
+    fn should_decode_super_chip_instructions() {
+        assert_eq!(
+            Instruction::decode(0x00C4),
+            Instruction::ScrollDown { n: 4 }
+        );
+        assert_eq!(Instruction::decode(0x00FB), Instruction::ScrollRight);
+        assert_eq!(Instruction::decode(0x00FC), Instruction::ScrollLeft);
+        assert_eq!(Instruction::decode(0x00FD), Instruction::Exit);
+        assert_eq!(Instruction::decode(0x00FE), Instruction::LoRes);
+        assert_eq!(Instruction::decode(0x00FF), Instruction::HiRes);
+        assert_eq!(Instruction::decode(0xF130), Instruction::LdHiFont { x: 1 });
+        assert_eq!(
+            Instruction::decode(0xF775),
+            Instruction::StoreFlags { x: 7 }
+        );
+        assert_eq!(Instruction::decode(0xF785), Instruction::LoadFlags { x: 7 });
+        assert_eq!(Instruction::decode(0x01C4), Instruction::Sys { nnn: 0x1C4 });
+    }
+
+    #[test]
+    fn should_decode_xo_chip_instructions() {
+        assert_eq!(Instruction::decode(0x00D2), Instruction::ScrollUp { n: 2 });
+        assert_eq!(
+            Instruction::decode(0x5132),
+            Instruction::StoreRange { x: 1, y: 3 }
+        );
+        assert_eq!(
+            Instruction::decode(0x5313),
+            Instruction::LoadRange { x: 3, y: 1 }
+        );
+        assert_eq!(Instruction::decode(0xF201), Instruction::Plane { n: 2 });
+        assert_eq!(Instruction::decode(0xF002), Instruction::LdAudio);
+        assert_eq!(Instruction::decode(0xF43A), Instruction::LdPitch { x: 4 });
+        assert_eq!(Instruction::decode(0xF102), Instruction::Unknown(0xF102));
+    }
+
+    #[test]
+    fn should_decode_long_instructions() {
+        assert!(Instruction::is_long(0xF000));
+        assert_eq!(Instruction::decode(0xF000), Instruction::Unknown(0xF000));
+
+        let long = Instruction::decode_long(0xF000, 0x1234);
+
+        assert_eq!(long, Instruction::LdILong { nnnn: 0x1234 });
+        assert_eq!(long.size(), 4);
+        assert_eq!(long.to_string(), "LD I, LONG 0x1234");
+        assert_eq!(Instruction::decode_long(0x00E0, 0x1234), Instruction::Cls);
+    }
+
+    #[test]
+    fn should_encode_to_the_decoded_bytes() {
+        for raw in 0..=0xFFFF {
+            assert_eq!(
+                Instruction::decode(raw).to_bytes(),
+                raw.to_be_bytes().to_vec(),
+                "{:04X}",
+                raw
+            );
+        }
+
+        assert_eq!(
+            Instruction::LdILong { nnnn: 0x1234 }.to_bytes(),
+            vec![0xF0, 0x00, 0x12, 0x34]
+        );
+    }
+
+    #[test]
+    fn should_decode_register_operands() {
+        assert_eq!(
+            Instruction::decode(0x8AB4),
+            Instruction::AddXY { x: 0xA, y: 0xB }
+        );
+        assert_eq!(
+            Instruction::decode(0xD125),
+            Instruction::Draw { x: 1, y: 2, n: 5 }
+        );
+        assert_eq!(Instruction::decode(0xF365), Instruction::LoadRegs { x: 3 });
+    }
+
+    #[test]
+    fn should_decode_unknown_opcodes() {
+        assert_eq!(Instruction::decode(0x5121), Instruction::Unknown(0x5121));
+        assert_eq!(Instruction::decode(0x8128), Instruction::Unknown(0x8128));
+        assert_eq!(Instruction::decode(0x9121), Instruction::Unknown(0x9121));
+        assert_eq!(Instruction::decode(0xE1FF), Instruction::Unknown(0xE1FF));
+        assert_eq!(Instruction::decode(0xF1FF), Instruction::Unknown(0xF1FF));
+    }
+
+    #[test]
+    fn should_format_mnemonics() {
+        assert_eq!(Instruction::decode(0x1200).to_string(), "JP 0x200");
+        assert_eq!(Instruction::decode(0x6A0F).to_string(), "LD VA, 0x0F");
+        assert_eq!(Instruction::decode(0x8AB4).to_string(), "ADD VA, VB");
+        assert_eq!(Instruction::decode(0xD125).to_string(), "DRW V1, V2, 0x5");
+        assert_eq!(Instruction::decode(0xF255).to_string(), "LD [I], V2");
+        assert_eq!(Instruction::decode(0xF20A).to_string(), "LD V2, K");
+        assert_eq!(Instruction::decode(0x00C4).to_string(), "SCD 0x4");
+        assert_eq!(Instruction::decode(0xF130).to_string(), "LD HF, V1");
+        assert_eq!(Instruction::decode(0xF775).to_string(), "LD R, V7");
+        assert_eq!(Instruction::decode(0xFFFF).to_string(), "DW 0xFFFF");
+    }
+}
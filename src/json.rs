@@ -0,0 +1,337 @@
+//! Just enough JSON for the debug adapter's messages.
+//!
+//! Objects keep their keys in order, and numbers are kept as `f64`, which is exact for every
+//! integer the protocol sends.
+
+use std::{fmt, iter::Peekable, str::Chars};
+
+// How deep arrays and objects can nest, so a hostile message can't overflow the stack
+const MAX_DEPTH: usize = 64;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Json {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>),
+}
+
+impl Json {
+    /// Builds an object from its keys and values.
+    pub fn object<const N: usize>(fields: [(&str, Json); N]) -> Self {
+        Json::Object(
+            IntoIterator::into_iter(fields)
+                .map(|(key, value)| (key.to_string(), value))
+                .collect(),
+        )
+    }
+
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut chars = text.chars().peekable();
+        let value = parse_value(&mut chars, 0)?;
+
+        skip_whitespace(&mut chars);
+        match chars.next() {
+            None => Ok(value),
+            Some(c) => Err(format!("unexpected `{}` after the value", c)),
+        }
+    }
+
+    /// The value of a key in an object, or [`Json::Null`] when there isn't one.
+    pub fn get(&self, key: &str) -> &Json {
+        match self {
+            Json::Object(fields) => fields
+                .iter()
+                .find(|(name, _)| name == key)
+                .map_or(&Json::Null, |(_, value)| value),
+            _ => &Json::Null,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Json::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn as_i64(&self) -> Option<i64> {
+        match self {
+            Json::Number(n) if n.fract() == 0.0 => Some(*n as i64),
+            _ => None,
+        }
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        match self {
+            Json::Bool(b) => Some(*b),
+            _ => None,
+        }
+    }
+
+    pub fn as_array(&self) -> &[Json] {
+        match self {
+            Json::Array(values) => values,
+            _ => &[],
+        }
+    }
+}
+
+impl From<&str> for Json {
+    fn from(s: &str) -> Self {
+        Json::String(s.to_string())
+    }
+}
+
+impl From<String> for Json {
+    fn from(s: String) -> Self {
+        Json::String(s)
+    }
+}
+
+impl From<bool> for Json {
+    fn from(b: bool) -> Self {
+        Json::Bool(b)
+    }
+}
+
+impl From<i64> for Json {
+    fn from(n: i64) -> Self {
+        Json::Number(n as f64)
+    }
+}
+
+impl From<Vec<Json>> for Json {
+    fn from(values: Vec<Json>) -> Self {
+        Json::Array(values)
+    }
+}
+
+impl fmt::Display for Json {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Json::Null => write!(f, "null"),
+            Json::Bool(b) => write!(f, "{}", b),
+            Json::Number(n) => write!(f, "{}", n),
+            Json::String(s) => write_string(f, s),
+            Json::Array(values) => {
+                write!(f, "[")?;
+                for (i, value) in values.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ",")?;
+                    }
+                    write!(f, "{}", value)?;
+                }
+                write!(f, "]")
+            }
+            Json::Object(fields) => {
+                write!(f, "{{")?;
+                for (i, (key, value)) in fields.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ",")?;
+                    }
+                    write_string(f, key)?;
+                    write!(f, ":{}", value)?;
+                }
+                write!(f, "}}")
+            }
+        }
+    }
+}
+
+fn write_string(f: &mut fmt::Formatter<'_>, s: &str) -> fmt::Result {
+    write!(f, "\"")?;
+    for c in s.chars() {
+        match c {
+            '"' => write!(f, "\\\"")?,
+            '\\' => write!(f, "\\\\")?,
+            '\n' => write!(f, "\\n")?,
+            '\r' => write!(f, "\\r")?,
+            '\t' => write!(f, "\\t")?,
+            c if (c as u32) < 0x20 => write!(f, "\\u{:04x}", c as u32)?,
+            c => write!(f, "{}", c)?,
+        }
+    }
+    write!(f, "\"")
+}
+
+type Input<'a> = Peekable<Chars<'a>>;
+
+fn skip_whitespace(chars: &mut Input) {
+    while chars.next_if(|c| c.is_whitespace()).is_some() {}
+}
+
+fn expect(chars: &mut Input, expected: char) -> Result<(), String> {
+    skip_whitespace(chars);
+    match chars.next() {
+        Some(c) if c == expected => Ok(()),
+        Some(c) => Err(format!("expected `{}`, found `{}`", expected, c)),
+        None => Err(format!("expected `{}`", expected)),
+    }
+}
+
+fn parse_value(chars: &mut Input, depth: usize) -> Result<Json, String> {
+    skip_whitespace(chars);
+    if depth >= MAX_DEPTH && matches!(chars.peek(), Some('{') | Some('[')) {
+        return Err(format!("values are nested more than {} deep", MAX_DEPTH));
+    }
+
+    match chars.peek().copied() {
+        Some('{') => {
+            chars.next();
+            let mut fields = vec![];
+            skip_whitespace(chars);
+            if chars.next_if_eq(&'}').is_some() {
+                return Ok(Json::Object(fields));
+            }
+            loop {
+                skip_whitespace(chars);
+                let key = parse_string(chars)?;
+                expect(chars, ':')?;
+                fields.push((key, parse_value(chars, depth + 1)?));
+
+                skip_whitespace(chars);
+                match chars.next() {
+                    Some(',') => {}
+                    Some('}') => return Ok(Json::Object(fields)),
+                    _ => return Err("expected `,` or `}` in an object".to_string()),
+                }
+            }
+        }
+        Some('[') => {
+            chars.next();
+            let mut values = vec![];
+            skip_whitespace(chars);
+            if chars.next_if_eq(&']').is_some() {
+                return Ok(Json::Array(values));
+            }
+            loop {
+                values.push(parse_value(chars, depth + 1)?);
+
+                skip_whitespace(chars);
+                match chars.next() {
+                    Some(',') => {}
+                    Some(']') => return Ok(Json::Array(values)),
+                    _ => return Err("expected `,` or `]` in an array".to_string()),
+                }
+            }
+        }
+        Some('"') => parse_string(chars).map(Json::String),
+        Some(c) if c == '-' || c.is_ascii_digit() => {
+            let mut number = String::new();
+            while let Some(c) = chars.next_if(|c| "+-.eE".contains(*c) || c.is_ascii_digit()) {
+                number.push(c);
+            }
+            number
+                .parse()
+                .map(Json::Number)
+                .map_err(|_| format!("invalid number `{}`", number))
+        }
+        Some(c) if c.is_ascii_alphabetic() => {
+            let mut word = String::new();
+            while let Some(c) = chars.next_if(|c| c.is_ascii_alphabetic()) {
+                word.push(c);
+            }
+            match word.as_str() {
+                "true" => Ok(Json::Bool(true)),
+                "false" => Ok(Json::Bool(false)),
+                "null" => Ok(Json::Null),
+                _ => Err(format!("unexpected `{}`", word)),
+            }
+        }
+        Some(c) => Err(format!("unexpected `{}`", c)),
+        None => Err("expected a value".to_string()),
+    }
+}
+
+fn parse_string(chars: &mut Input) -> Result<String, String> {
+    expect(chars, '"')?;
+
+    let mut s = String::new();
+    loop {
+        match chars.next() {
+            Some('"') => return Ok(s),
+            Some('\\') => {
+                let c = match chars.next() {
+                    Some('n') => '\n',
+                    Some('r') => '\r',
+                    Some('t') => '\t',
+                    Some('b') => '\u{8}',
+                    Some('f') => '\u{c}',
+                    Some('u') => {
+                        let hex: String = chars.by_ref().take(4).collect();
+                        let code = u32::from_str_radix(&hex, 16)
+                            .map_err(|_| format!("invalid escape `\\u{}`", hex))?;
+                        // Surrogate pairs aren't needed for anything the protocol sends
+                        char::from_u32(code).unwrap_or(char::REPLACEMENT_CHARACTER)
+                    }
+                    Some(c) => c,
+                    None => return Err("unterminated string".to_string()),
+                };
+                s.push(c);
+            }
+            Some(c) => s.push(c),
+            None => return Err("unterminated string".to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Json;
+
+    #[test]
+    fn should_parse_messages() {
+        let json = Json::parse(
+            r#" {"seq": 3, "type": "request", "arguments": {"lines": [1, -2.5e1], "ok": true,
+                "path": "C:\\roms\\a \"b\"\u0041", "none": null}} "#,
+        )
+        .unwrap();
+
+        assert_eq!(json.get("seq").as_i64(), Some(3));
+        assert_eq!(json.get("type").as_str(), Some("request"));
+        let arguments = json.get("arguments");
+        assert_eq!(
+            arguments.get("lines").as_array(),
+            [Json::Number(1.0), Json::Number(-25.0)]
+        );
+        assert_eq!(arguments.get("ok").as_bool(), Some(true));
+        assert_eq!(arguments.get("path").as_str(), Some("C:\\roms\\a \"b\"A"));
+        assert_eq!(arguments.get("none"), &Json::Null);
+        assert_eq!(arguments.get("missing"), &Json::Null);
+    }
+
+    #[test]
+    fn should_reject_malformed_messages() {
+        assert!(Json::parse("{\"a\": }").is_err());
+        assert!(Json::parse("[1, 2").is_err());
+        assert!(Json::parse("\"open").is_err());
+        assert!(Json::parse("{} {}").is_err());
+        assert!(Json::parse("nope").is_err());
+
+        let nested = |depth: usize| format!("{}{}", "[".repeat(depth), "]".repeat(depth));
+        assert!(Json::parse(&nested(64)).is_ok());
+        assert_eq!(
+            Json::parse(&nested(100_000)),
+            Err("values are nested more than 64 deep".to_string())
+        );
+    }
+
+    #[test]
+    fn should_write_what_it_parses() {
+        let json = Json::object([
+            ("name", "V0\n\"x\"".into()),
+            ("value", 32.into()),
+            ("items", vec![Json::Null, true.into()].into()),
+        ]);
+
+        let text = json.to_string();
+        assert_eq!(
+            text,
+            r#"{"name":"V0\n\"x\"","value":32,"items":[null,true]}"#
+        );
+        assert_eq!(Json::parse(&text), Ok(json));
+    }
+}
@@ -1,34 +1,43 @@
-use std::convert::{TryFrom, TryInto};
+use super::{KeyEvent, KeyState, Keyboard};
 
-use super::Keyboard;
-
-/// Used exclusively for testing components that rely on a keyboard
-/// but the dependancy on the Window is not a good fit for testing.
-pub(crate) struct DummyKeyboard {
-    pub curr_keydowns: Vec<u8>,
+/// A keyboard with no backing device.
+///
+/// Used for testing components that rely on a keyboard, and by front-ends that
+/// do not have a window to read keys from. Keys are pressed and released by hand.
+#[derive(Debug, Default)]
+pub struct DummyKeyboard {
+    state: KeyState,
 }
 
 impl Keyboard for DummyKeyboard {
-    fn update_state(&mut self, keys: &[u8]) {
-        // Do nothing
-        // During testting it is intended that the developer will manipulate the keybords state
-        // externally. This is done by accessing curr_keydowns.
-        ()
+    fn pressed(&self) -> u16 {
+        self.state.pressed()
+    }
+
+    fn poll_event(&mut self) -> Option<KeyEvent> {
+        self.state.poll_event()
     }
 
-    fn get_current_keydowns(&self) -> &Vec<u8> {
-        &self.curr_keydowns
+    fn reset(&mut self, pressed: u16) {
+        self.state.reset(pressed);
     }
 }
 
 impl DummyKeyboard {
     pub fn initialise() -> Self {
-        Self {
-            curr_keydowns: vec![],
-        }
+        Self::default()
+    }
+
+    pub fn press(&mut self, key: u8) {
+        self.state.press(key);
+    }
+
+    pub fn release(&mut self, key: u8) {
+        self.state.release(key);
     }
 
-    fn get_current_keydowns(&self) -> &Vec<u8> {
-        &self.curr_keydowns
+    /// Sets the held keys as a bitmask, with bit `n` set if key `n` is down.
+    pub fn set_pressed(&mut self, pressed: u16) {
+        self.state.set_pressed(pressed);
     }
 }
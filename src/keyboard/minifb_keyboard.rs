@@ -1,35 +1,49 @@
-use std::{
-    cell::RefCell,
-    convert::{TryFrom, TryInto},
-    error::Error,
-    rc::Rc,
-};
+use std::{cell::RefCell, rc::Rc};
 
 use minifb::{Key, Window};
 
-use super::Keyboard;
+use super::{KeyEvent, KeyState, Keyboard};
+use crate::keymap::KeyMap;
 
-pub(crate) struct MiniFbKeyboard<'keyboard> {
-    window: &'keyboard Rc<RefCell<Window>>,
-    current_keydowns: Vec<u8>,
+pub struct MiniFbKeyboard<'keyboard> {
+    _window: &'keyboard Rc<RefCell<Window>>,
+    state: KeyState,
+    keymap: KeyMap,
 }
 
 impl<'a> Keyboard for MiniFbKeyboard<'a> {
-    fn update_state(&mut self, keys: &[u8]) {
-        // TODO: maybe update the current pressed keys more intelligently...
-        self.current_keydowns = keys.into();
+    fn pressed(&self) -> u16 {
+        self.state.pressed()
     }
 
-    fn get_current_keydowns(&self) -> &Vec<u8> {
-        &self.current_keydowns
+    fn poll_event(&mut self) -> Option<KeyEvent> {
+        self.state.poll_event()
+    }
+
+    fn reset(&mut self, pressed: u16) {
+        self.state.reset(pressed);
     }
 }
 
 impl<'a> MiniFbKeyboard<'a> {
-    pub fn initialise(window: &'a Rc<RefCell<Window>>) -> Self {
+    pub fn initialise(window: &'a Rc<RefCell<Window>>, keymap: KeyMap) -> Self {
         Self {
-            window,
-            current_keydowns: vec![],
+            _window: window,
+            state: KeyState::default(),
+            keymap,
         }
     }
+
+    /// Updates the held keys from the window's keys that are down, once a frame.
+    pub fn update_state(&mut self, keys: &[Key]) {
+        // The keymap names keys the way minifb does, e.g. `Key1` or `NumPad4`
+        let names: Vec<String> = keys.iter().map(|key| format!("{:?}", key)).collect();
+        let pressed = self.keymap.pressed(names.iter().map(String::as_str));
+        self.state.set_pressed(pressed);
+    }
+
+    /// Sets the held keys directly, e.g. from a movie being replayed.
+    pub fn set_pressed(&mut self, pressed: u16) {
+        self.state.set_pressed(pressed);
+    }
 }
@@ -1,10 +1,133 @@
-use std::convert::{TryFrom, TryInto};
-
 pub mod dummy_keyboard;
+#[cfg(feature = "gui")]
 pub mod minifb_keyboard;
 
-pub(crate) trait Keyboard {
-    fn update_state(&mut self, keys: &[u8]);
+use std::collections::VecDeque;
+
+// Events nobody has polled are dropped, oldest first, past this many
+const MAX_QUEUED_EVENTS: usize = 64;
+
+/// A key on the hex keypad going down or coming back up.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyEvent {
+    Down(u8),
+    Up(u8),
+}
+
+/// The hex keypad as the CPU sees it: which keys are held, and the presses and releases that
+/// haven't been handled yet.
+pub trait Keyboard {
+    /// The keys held down, as a bitmask with bit `n` set if key `n` is down.
+    fn pressed(&self) -> u16;
+
+    /// Takes the oldest key event that hasn't been polled yet.
+    fn poll_event(&mut self) -> Option<KeyEvent>;
+
+    /// Replaces the held keys and forgets any queued events, e.g. when restoring a save state.
+    fn reset(&mut self, pressed: u16);
+
+    fn is_pressed(&self, key: u8) -> bool {
+        key <= 0xF && self.pressed() & (1 << key) != 0
+    }
+}
+
+/// The held keys and queued events, for [`Keyboard`] implementations to build on.
+///
+/// ```
+/// use chip8_rs::keyboard::{KeyEvent, KeyState};
+///
+/// let mut state = KeyState::default();
+/// state.set_pressed(0b1010);
+/// state.set_pressed(0b0010);
+///
+/// assert_eq!(state.pressed(), 0b0010);
+/// assert_eq!(state.poll_event(), Some(KeyEvent::Down(1)));
+/// assert_eq!(state.poll_event(), Some(KeyEvent::Down(3)));
+/// assert_eq!(state.poll_event(), Some(KeyEvent::Up(3)));
+/// assert_eq!(state.poll_event(), None);
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct KeyState {
+    pressed: u16,
+    events: VecDeque<KeyEvent>,
+}
+
+impl KeyState {
+    pub fn pressed(&self) -> u16 {
+        self.pressed
+    }
+
+    pub fn press(&mut self, key: u8) {
+        self.set_pressed(self.pressed | 1 << (key & 0xF));
+    }
+
+    pub fn release(&mut self, key: u8) {
+        self.set_pressed(self.pressed & !(1 << (key & 0xF)));
+    }
+
+    /// Sets the held keys, queueing an event for each key that changed, lowest key first.
+    pub fn set_pressed(&mut self, pressed: u16) {
+        let changed = self.pressed ^ pressed;
+        for key in (0..0x10).filter(|key| changed & (1 << key) != 0) {
+            if self.events.len() == MAX_QUEUED_EVENTS {
+                self.events.pop_front();
+            }
+
+            self.events.push_back(if pressed & (1 << key) != 0 {
+                KeyEvent::Down(key)
+            } else {
+                KeyEvent::Up(key)
+            });
+        }
+
+        self.pressed = pressed;
+    }
+
+    pub fn poll_event(&mut self) -> Option<KeyEvent> {
+        self.events.pop_front()
+    }
+
+    pub fn reset(&mut self, pressed: u16) {
+        self.pressed = pressed;
+        self.events.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{KeyEvent, KeyState, MAX_QUEUED_EVENTS};
+
+    #[test]
+    fn should_queue_presses_and_releases() {
+        let mut state = KeyState::default();
+        state.press(0xA);
+        state.press(0xA);
+        state.press(0x2);
+        state.release(0xA);
+
+        assert_eq!(state.pressed(), 1 << 0x2);
+        assert_eq!(state.poll_event(), Some(KeyEvent::Down(0xA)));
+        assert_eq!(state.poll_event(), Some(KeyEvent::Down(0x2)));
+        assert_eq!(state.poll_event(), Some(KeyEvent::Up(0xA)));
+        assert_eq!(state.poll_event(), None);
+
+        state.reset(1 << 0xF);
+        assert_eq!(state.pressed(), 1 << 0xF);
+        assert_eq!(state.poll_event(), None);
+    }
+
+    #[test]
+    fn should_drop_oldest_events_when_full() {
+        let mut state = KeyState::default();
+        for _ in 0..MAX_QUEUED_EVENTS {
+            state.press(0x1);
+            state.release(0x1);
+        }
+        state.press(0x2);
 
-    fn get_current_keydowns(&self) -> &Vec<u8>;
+        let events: Vec<KeyEvent> = std::iter::from_fn(|| state.poll_event()).collect();
+        assert_eq!(events.len(), MAX_QUEUED_EVENTS);
+        assert_eq!(events[0], KeyEvent::Up(0x1));
+        assert_eq!(events.last(), Some(&KeyEvent::Down(0x2)));
+    }
 }
@@ -0,0 +1,330 @@
+//! Which host keys press which keys on the hex keypad.
+//!
+//! Host keys are named the way the front-end names them, e.g. `Q`, `Space` or `NumPad4` for the
+//! minifb window, ignoring case. A bare digit like `1` names the digit key above the letters.
+//!
+//! A [`KeyConfig`] holds the bindings from a config file, with defaults for every ROM under
+//! `[keys]` and overrides for a ROM under `[roms.<sha1>]`:
+//!
+//! ```toml
+//! [keys]
+//! preset = "qwerty"
+//! # Both Z and Space press A
+//! A = ["Z", "Space"]
+//!
+//! [roms.0123456789abcdef0123456789abcdef01234567]
+//! preset = "hex"
+//! 5 = ["Up", "W"]
+//! ```
+//!
+//! A table that names a preset starts over from it, otherwise it changes the bindings it
+//! inherits. Each key listed replaces that key's host keys, and `[]` unbinds it.
+
+use std::{error::Error, fmt, str::FromStr};
+
+use crate::toml::{self, Value};
+
+/// The bindings for the 16 keys of the hex keypad, each to any number of host keys.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct KeyMap {
+    bindings: [Vec<String>; 16],
+}
+
+impl KeyMap {
+    /// `0`-`9` and `A`-`F` press the key with the same name.
+    pub fn hex() -> Self {
+        Self::from_layout("0123456789ABCDEF")
+    }
+
+    /// The keypad's 4x4 grid laid over the left of a QWERTY keyboard, from `1 2 3 4` to
+    /// `Z X C V`, the layout most emulators use.
+    pub fn qwerty() -> Self {
+        Self::from_layout("X123QWEASDZC4RFV")
+    }
+
+    /// Looks up a preset by the name used in the config file.
+    pub fn preset(name: &str) -> Option<Self> {
+        match name {
+            "hex" => Some(Self::hex()),
+            "qwerty" => Some(Self::qwerty()),
+            _ => None,
+        }
+    }
+
+    /// Binds each key to the host key at its position in `layout`.
+    fn from_layout(layout: &str) -> Self {
+        let mut map = Self::default();
+        for (key, host_key) in layout.chars().enumerate() {
+            map.bind(key as u8, &[host_key.to_string()]);
+        }
+
+        map
+    }
+
+    /// Replaces the host keys that press `key`.
+    pub fn bind<S>(&mut self, key: u8, host_keys: &[S])
+    where
+        S: AsRef<str>,
+    {
+        self.bindings[(key & 0xF) as usize] = host_keys
+            .iter()
+            .map(|host_key| normalise(host_key.as_ref()))
+            .collect();
+    }
+
+    /// The host keys that press `key`, in lower case and with bare digits named like `key1`.
+    pub fn host_keys(&self, key: u8) -> &[String] {
+        &self.bindings[(key & 0xF) as usize]
+    }
+
+    /// The keys pressed by the host keys held down, as a bitmask with bit `n` set if key `n` is
+    /// down.
+    pub fn pressed<'a, I>(&self, host_keys: I) -> u16
+    where
+        I: IntoIterator<Item = &'a str>,
+    {
+        host_keys
+            .into_iter()
+            .map(normalise)
+            .fold(0, |pressed, host_key| {
+                self.bindings
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, bound)| bound.contains(&host_key))
+                    .fold(pressed, |pressed, (key, _)| pressed | 1 << key)
+            })
+    }
+}
+
+fn normalise(host_key: &str) -> String {
+    match host_key.as_bytes() {
+        [digit] if digit.is_ascii_digit() => format!("key{}", *digit as char),
+        _ => host_key.to_ascii_lowercase(),
+    }
+}
+
+/// A mistake in a key config, with the line it is on.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyConfigError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for KeyConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl Error for KeyConfigError {}
+
+/// The changes one table of the config makes to the bindings.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+struct Bindings {
+    preset: Option<KeyMap>,
+    keys: Vec<(u8, Vec<String>)>,
+}
+
+impl Bindings {
+    fn apply(&self, map: &mut KeyMap) {
+        if let Some(preset) = &self.preset {
+            *map = preset.clone();
+        }
+        for (key, host_keys) in &self.keys {
+            map.bind(*key, host_keys);
+        }
+    }
+}
+
+/// Key bindings loaded from a config file, see the [module documentation](self).
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct KeyConfig {
+    defaults: Bindings,
+    roms: Vec<(String, Bindings)>,
+}
+
+impl KeyConfig {
+    /// The bindings for the ROM with the given SHA-1, which start from the hex preset when the
+    /// config doesn't pick one.
+    pub fn keymap(&self, rom_sha1: &str) -> KeyMap {
+        let mut map = KeyMap::hex();
+        self.defaults.apply(&mut map);
+        if let Some((_, bindings)) = self
+            .roms
+            .iter()
+            .find(|(sha1, _)| sha1.eq_ignore_ascii_case(rom_sha1))
+        {
+            bindings.apply(&mut map);
+        }
+
+        map
+    }
+}
+
+impl FromStr for KeyConfig {
+    type Err = KeyConfigError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let tables = toml::parse(s).map_err(|e| KeyConfigError {
+            line: e.line,
+            message: e.message,
+        })?;
+
+        let mut config = KeyConfig::default();
+        for table in tables {
+            let error = |message: String| KeyConfigError {
+                line: table.line,
+                message,
+            };
+
+            let path: Vec<&str> = table.path.iter().map(String::as_str).collect();
+            match path.as_slice() {
+                [] => {
+                    if let Some(entry) = table.entries.first() {
+                        return Err(KeyConfigError {
+                            line: entry.line,
+                            message: format!("`{}` must be under a table like [keys]", entry.key),
+                        });
+                    }
+                }
+                ["keys"] => config.defaults = parse_bindings(&table)?,
+                ["roms", sha1]
+                    if sha1.len() == 40 && sha1.chars().all(|c| c.is_ascii_hexdigit()) =>
+                {
+                    config
+                        .roms
+                        .push((sha1.to_string(), parse_bindings(&table)?));
+                }
+                ["roms", sha1] => {
+                    return Err(error(format!(
+                        "`{}` is not a SHA-1, which is 40 hex digits",
+                        sha1
+                    )))
+                }
+                _ => {
+                    return Err(error(format!(
+                        "unknown table [{}], expected [keys] or [roms.<sha1>]",
+                        table.path.join(".")
+                    )))
+                }
+            }
+        }
+
+        Ok(config)
+    }
+}
+
+fn parse_bindings(table: &toml::Table) -> Result<Bindings, KeyConfigError> {
+    let mut bindings = Bindings::default();
+
+    for entry in &table.entries {
+        let error = |message: &str| KeyConfigError {
+            line: entry.line,
+            message: message.to_string(),
+        };
+
+        if entry.key == "preset" {
+            let preset = match &entry.value {
+                Value::String(name) => KeyMap::preset(name),
+                _ => None,
+            };
+            bindings.preset =
+                Some(preset.ok_or_else(|| error("preset must be \"hex\" or \"qwerty\""))?);
+            continue;
+        }
+
+        let key = match u8::from_str_radix(&entry.key, 16) {
+            Ok(key) if key <= 0xF && entry.key.len() == 1 => key,
+            _ => {
+                return Err(error(&format!(
+                    "unknown setting `{}`, expected `preset` or a key between 0 and F",
+                    entry.key
+                )))
+            }
+        };
+
+        let host_keys = match &entry.value {
+            Value::String(host_key) => vec![host_key.clone()],
+            Value::Array(values) => values
+                .iter()
+                .map(|value| match value {
+                    Value::String(host_key) => Ok(host_key.clone()),
+                    _ => Err(error("host keys must be strings")),
+                })
+                .collect::<Result<_, _>>()?,
+            _ => return Err(error("expected a host key or a list of them")),
+        };
+
+        bindings.keys.push((key, host_keys));
+    }
+
+    Ok(bindings)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{KeyConfig, KeyMap};
+
+    const SHA1: &str = "0123456789abcdef0123456789abcdef01234567";
+
+    #[test]
+    fn should_map_host_keys_with_presets() {
+        let qwerty = KeyMap::qwerty();
+        assert_eq!(qwerty.pressed(vec!["Key1", "v"]), 1 << 0x1 | 1 << 0xF);
+        assert_eq!(qwerty.pressed(vec!["X", "Key4"]), 1 << 0x0 | 1 << 0xC);
+        assert_eq!(qwerty.pressed(vec!["Key9", "Escape"]), 0);
+
+        let hex = KeyMap::hex();
+        assert_eq!(
+            hex.pressed(vec!["Key0", "A", "F"]),
+            1 << 0x0 | 1 << 0xA | 1 << 0xF
+        );
+        assert_eq!(hex.host_keys(0x7), ["key7"]);
+    }
+
+    #[test]
+    fn should_bind_several_host_keys_to_a_key() {
+        let mut map = KeyMap::hex();
+        map.bind(0x5, &["Up", "W"]);
+
+        assert_eq!(map.pressed(vec!["up"]), 1 << 0x5);
+        assert_eq!(map.pressed(vec!["W"]), 1 << 0x5);
+        assert_eq!(map.pressed(vec!["Key5"]), 0);
+    }
+
+    #[test]
+    fn should_override_defaults_per_rom() {
+        let config: KeyConfig = format!(
+            "[keys]\npreset = \"qwerty\"\nA = [\"Z\", \"Space\"]\n\n[roms.{}]\n5 = \"Up\"\n\n[roms.{}]\npreset = \"hex\"\n",
+            SHA1.to_ascii_uppercase(),
+            "f".repeat(40)
+        )
+        .parse()
+        .unwrap();
+
+        let defaults = config.keymap(&"0".repeat(40));
+        assert_eq!(defaults.host_keys(0xA), ["z", "space"]);
+        assert_eq!(defaults.host_keys(0x5), ["w"]);
+
+        let rom = config.keymap(SHA1);
+        assert_eq!(rom.host_keys(0xA), ["z", "space"]);
+        assert_eq!(rom.host_keys(0x5), ["up"]);
+
+        assert_eq!(config.keymap(&"f".repeat(40)), KeyMap::hex());
+        assert_eq!("".parse::<KeyConfig>().unwrap().keymap(SHA1), KeyMap::hex());
+    }
+
+    #[test]
+    fn should_reject_invalid_configs() {
+        let line = |text: &str| text.parse::<KeyConfig>().unwrap_err().line;
+
+        assert_eq!(line("[keys]\npreset = \"dvorak\""), 2);
+        assert_eq!(line("[keys]\n\nG = \"Q\""), 3);
+        assert_eq!(line("[keys]\n1 = [\"Q\", 2]"), 2);
+        assert_eq!(line("[keys]\n1 = true"), 2);
+        assert_eq!(line("1 = \"Q\""), 1);
+        assert_eq!(line("[keys]\n[roms.abc]"), 2);
+        assert_eq!(line("[key]"), 1);
+        assert_eq!(line("[keys]\n1 = \"Q"), 2);
+    }
+}
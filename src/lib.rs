@@ -0,0 +1,41 @@
+//! A CHIP-8 interpreter core.
+//!
+//! The machine is based on Cowgod's Technical Spec for Chip-8
+//! http://devernay.free.fr/hacks/chip8/C8TECH10.HTM
+//!
+//! Nothing in this crate depends on a windowing library; front-ends provide
+//! their own [`keyboard::Keyboard`] implementation and render the
+//! [`display::Display`] however they see fit. The bundled minifb front-end is
+//! only built with the `gui` feature.
+
+pub mod asm;
+pub mod breakpoints;
+mod chip8;
+pub mod cpu;
+pub mod dap;
+pub mod debugger;
+pub mod disasm;
+pub mod display;
+pub mod error;
+pub mod gdb;
+pub mod headless;
+pub mod instructions;
+mod json;
+pub mod keyboard;
+pub mod keymap;
+pub mod memory;
+pub mod movie;
+pub mod opcode;
+mod png;
+pub mod quirks;
+pub mod rewind;
+pub mod rng;
+pub mod rom;
+pub mod scheduler;
+mod sha1;
+pub mod state;
+mod tcp;
+mod toml;
+pub mod trace;
+
+pub use chip8::Chip8;
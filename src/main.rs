@@ -1,19 +1,30 @@
-use std::{cell::RefCell, fs::File, io::Write, rc::Rc};
+use std::{
+    fs::{self, File},
+    io::BufWriter,
+    net::TcpListener,
+    path::{Path, PathBuf},
+    process,
+};
 
-use crate::{
-    cpu::CPU,
-    display::{Display, SCREEN_HEIGHT, SCREEN_WIDTH},
-    keyboard::{minifb_keyboard::MiniFbKeyboard, Keyboard},
-    memory::Memory,
+use chip8_rs::{
+    asm::{octo, AsmError, Assembler, Assembly},
+    cpu::CpuState,
+    dap::{DapServer, SourceMap},
+    disasm::{Disassembler, Syntax},
+    gdb::GdbServer,
+    headless::{self, HeadlessRunner, KeyScript, RunLimit},
+    keyboard::dummy_keyboard::DummyKeyboard,
+    movie::Movie,
+    quirks::Quirks,
+    rom::{Rom, RomLoader},
+    trace::{self, TraceFilter, Tracer},
+    Chip8,
 };
-use clap::{load_yaml, App};
-use minifb::{Key, Window, WindowOptions};
+use clap::{load_yaml, App, ArgMatches};
 
-mod cpu;
-mod display;
-mod keyboard;
-mod memory;
-mod opcode;
+#[cfg(feature = "gui")]
+mod gui;
+mod repl;
 
 // Chip-8 CPU based on Cowgod's Technical Spec for Chip-8
 // http://devernay.free.fr/hacks/chip8/C8TECH10.HTM
@@ -21,106 +32,448 @@ fn main() {
     let yaml = load_yaml!("../cli.yml");
     let matches = App::from_yaml(yaml).get_matches();
 
-    let window: Rc<RefCell<_>> = Rc::new(RefCell::new(
-        Window::new(
-            "Chip8.rs - ESC to exit - F1: Debug, F2: Step, F3: Stop, F4: Continue",
-            SCREEN_WIDTH,
-            SCREEN_HEIGHT,
-            WindowOptions {
-                scale: minifb::Scale::X8,
-                scale_mode: minifb::ScaleMode::Stretch,
-                ..WindowOptions::default()
-            },
-        )
-        .unwrap_or_else(|e| {
-            panic!("{}", e);
-        }),
-    ));
-
-    let memory = Memory::initialise_from_file(matches.value_of("INPUT").unwrap());
-    let display = Display::initialise();
-    let keyboard = MiniFbKeyboard::initialise(&window);
-    let mut cpu = CPU::initialise(memory, display, keyboard);
-
-    let mut inner_window = window.borrow_mut();
-    inner_window.limit_update_rate(Some(std::time::Duration::from_micros(16000)));
-
-    let last_cycle_time = chrono::Utc::now().time();
-    let mut should_run = true;
-    while inner_window.is_open() && !inner_window.is_key_down(Key::Escape) {
-        let curr_cycle_time = chrono::Utc::now().time();
-
-        if curr_cycle_time - last_cycle_time
-            > chrono::Duration::milliseconds(cpu::DELAY_INCREMENT.into())
-        {
-            cpu.decrement_delay_timer();
+    if let Some(matches) = matches.subcommand_matches("disasm") {
+        run_disasm(matches);
+        return;
+    }
+
+    if let Some(matches) = matches.subcommand_matches("asm") {
+        run_asm(matches);
+        return;
+    }
+
+    if let Some(matches) = matches.subcommand_matches("trace-diff") {
+        run_trace_diff(matches);
+        return;
+    }
+
+    let path = matches.value_of("INPUT").unwrap();
+    let profile = matches.value_of("quirks").unwrap();
+    let quirks = Quirks::preset(profile).unwrap();
+    let instructions_per_second: u32 = matches
+        .value_of("ips")
+        .unwrap()
+        .parse()
+        .unwrap_or_else(|_| exit_with_error("Invalid instructions per second"));
+    let load_address = parse_number(matches.value_of("load-address").unwrap())
+        .unwrap_or_else(|| exit_with_error("Invalid load address"));
+
+    // A replay loads the ROM the way it was when it was recorded
+    let replay = matches.value_of("replay").map(read_movie);
+    let loader = match &replay {
+        Some(movie) => movie.rom_loader(),
+        None if profile == "xochip" => RomLoader::new().load_address(load_address).xo_chip(),
+        None => RomLoader::new().load_address(load_address),
+    };
+
+    let rom = loader
+        .load_file(path)
+        .unwrap_or_else(|e| exit_with_error(&format!("Unable to load {}: {}", path, e)));
+    if let (Some(movie), Some(movie_path)) = (&replay, matches.value_of("replay")) {
+        movie.check_rom(&rom).unwrap_or_else(|e| {
+            exit_with_error(&format!("Unable to replay {}: {}", movie_path, e))
+        });
+    }
+
+    println!(
+        "Loaded {} ({} bytes, sha1 {})",
+        path,
+        rom.size(),
+        rom.sha1_hex()
+    );
+
+    // A replay runs with the quirks, speed and seed it was recorded with
+    let (quirks, instructions_per_second, seed) = match &replay {
+        Some(movie) => (movie.quirks, movie.instructions_per_second, movie.seed),
+        None => (
+            quirks,
+            instructions_per_second,
+            matches.value_of("seed").map_or_else(rand::random, |seed| {
+                seed.parse()
+                    .unwrap_or_else(|_| exit_with_error("Invalid seed"))
+            }),
+        ),
+    };
+
+    let tracer = matches
+        .value_of("trace")
+        .map(|path| open_trace(path, &matches));
+
+    if matches.is_present("headless") {
+        run_headless(
+            &rom,
+            quirks,
+            instructions_per_second,
+            seed,
+            &matches,
+            tracer,
+            replay,
+        );
+        return;
+    }
+
+    let debug = matches.is_present("debug");
+    let gdb = matches.value_of("gdb").map(|port| {
+        let port: u16 = port
+            .parse()
+            .unwrap_or_else(|_| exit_with_error("Invalid GDB port"));
+        wait_for_gdb(port)
+    });
+    let dap = matches.value_of("dap").map(|port| {
+        let port: u16 = port
+            .parse()
+            .unwrap_or_else(|_| exit_with_error("Invalid DAP port"));
+        let source_map = matches.value_of("source").map(|source| {
+            let program = assemble(Path::new(source), rom.load_address())
+                .unwrap_or_else(|e| exit_with_error(&e.to_string()));
+            SourceMap::new(&program, source)
+        });
+        wait_for_dap(port, source_map)
+    });
+
+    #[cfg(feature = "gui")]
+    gui::run(
+        &rom,
+        gui::Options {
+            quirks,
+            instructions_per_second,
+            seed,
+            keymap: load_keymap(&rom, &matches),
+            debug,
+            gdb,
+            dap,
+            tracer,
+            record: matches.value_of("record").map(str::to_string),
+            replay,
+        },
+    );
+
+    #[cfg(not(feature = "gui"))]
+    {
+        if debug {
+            repl::run(&rom, quirks, instructions_per_second, seed);
+            return;
+        }
+
+        if let Some(gdb) = gdb {
+            run_gdb(&rom, quirks, instructions_per_second, seed, gdb);
+            return;
         }
 
-        if curr_cycle_time - last_cycle_time
-            > chrono::Duration::milliseconds(cpu::SOUND_DELAY_INCREMENT.into())
-        {
-            cpu.decrement_sound_timer();
+        if let Some(dap) = dap {
+            run_dap(&rom, quirks, instructions_per_second, seed, dap);
+            return;
         }
 
-        if inner_window.is_key_pressed(Key::F1, minifb::KeyRepeat::No) {
-            println!("Dumping memory to chip8rs_memdump.log");
-            dump_memory(&mut cpu.memory);
+        exit_with_error(
+            "chip8-rs was built without the `gui` feature (rebuild with `--features gui`, or use `--headless`)",
+        );
+    }
+}
+
+fn run_headless(
+    rom: &Rom,
+    quirks: Quirks,
+    instructions_per_second: u32,
+    seed: u64,
+    matches: &ArgMatches,
+    tracer: Option<Tracer<BufWriter<File>>>,
+    replay: Option<Movie>,
+) {
+    // A replay runs every frame of the movie, with its keys
+    let limit = match (
+        &replay,
+        matches.value_of("frames"),
+        matches.value_of("instructions"),
+    ) {
+        (Some(movie), _, _) => RunLimit::Frames(movie.frames.len() as u64),
+        (_, Some(frames), _) => RunLimit::Frames(
+            frames
+                .parse()
+                .unwrap_or_else(|_| exit_with_error("Invalid number of frames")),
+        ),
+        (_, _, Some(instructions)) => RunLimit::Instructions(
+            instructions
+                .parse()
+                .unwrap_or_else(|_| exit_with_error("Invalid number of instructions")),
+        ),
+        _ => exit_with_error("Headless mode needs either --frames, --instructions or --replay"),
+    };
+
+    let script = match (&replay, matches.value_of("keys")) {
+        (Some(movie), _) => movie.key_script(),
+        (_, Some(path)) => fs::read_to_string(path)
+            .unwrap_or_else(|e| exit_with_error(&format!("Unable to read {}: {}", path, e)))
+            .parse()
+            .unwrap_or_else(|e| exit_with_error(&format!("Invalid key script {}: {}", path, e))),
+        _ => KeyScript::new(),
+    };
+
+    let mut cpu = Chip8::from_rom(rom, DummyKeyboard::initialise(), quirks).into_cpu();
+    cpu.seed_rng(seed);
+    let runner = HeadlessRunner::new()
+        .instructions_per_second(instructions_per_second)
+        .script(script);
+    let result = match tracer {
+        Some(mut tracer) => {
+            let result = runner.run_traced(&mut cpu, limit, &mut tracer);
+            finish_trace(tracer);
+            result
         }
+        None => runner.run(&mut cpu, limit),
+    };
 
-        let keys: Vec<u8> = inner_window
-            .get_keys_pressed(minifb::KeyRepeat::Yes)
-            .unwrap()
-            .iter()
-            .filter_map(|k| key_to_u8(*k))
-            .collect();
+    // The outputs are still written after a fault, as they show where the program went wrong
+    if let Some(path) = matches.value_of("screenshot") {
+        let image = match Path::new(path).extension().and_then(|e| e.to_str()) {
+            Some("pbm") => cpu.display.to_pbm().into_bytes(),
+            _ => cpu.display.to_png(),
+        };
+        write_output(path, &image);
+    }
 
-        cpu.keyboard.update_state(&keys);
+    if let Some(path) = matches.value_of("registers") {
+        write_output(path, headless::registers_json(&cpu).as_bytes());
+    }
 
-        if should_run {
-            cpu.execute_next_instruction();
-        } else {
-            if inner_window.is_key_pressed(Key::F2, minifb::KeyRepeat::Yes) {
-                cpu.execute_next_instruction();
+    match result {
+        Ok(summary) => println!(
+            "Ran {} frames ({} instructions){}",
+            summary.frames,
+            summary.instructions,
+            if cpu.state() == CpuState::Exited {
+                ", the program exited"
+            } else {
+                ""
             }
+        ),
+        Err(e) => exit_with_error(&format!("CPU fault: {}", e)),
+    }
+
+    if let Some(movie) = replay {
+        match movie.verify(&cpu) {
+            Ok(()) => println!("The replay matches the recording"),
+            Err(e) => exit_with_error(&format!("Replay failed: {}", e)),
+        }
+    }
+}
+
+/// Reads a movie to replay.
+fn read_movie(path: &str) -> Movie {
+    fs::read(path)
+        .map_err(|e| e.to_string())
+        .and_then(|bytes| Movie::from_bytes(&bytes).map_err(|e| e.to_string()))
+        .unwrap_or_else(|e| exit_with_error(&format!("Unable to read {}: {}", path, e)))
+}
+
+/// The key bindings for the ROM, from `--config` or else `chip8rs.toml` in the working directory
+/// if there is one, and the hex layout if not.
+#[cfg(feature = "gui")]
+fn load_keymap(rom: &Rom, matches: &ArgMatches) -> chip8_rs::keymap::KeyMap {
+    use chip8_rs::keymap::{KeyConfig, KeyMap};
+
+    const DEFAULT_CONFIG: &str = "chip8rs.toml";
+
+    let path = match matches.value_of("config") {
+        Some(path) => path,
+        None if Path::new(DEFAULT_CONFIG).exists() => DEFAULT_CONFIG,
+        None => return KeyMap::hex(),
+    };
+
+    let config: KeyConfig = fs::read_to_string(path)
+        .unwrap_or_else(|e| exit_with_error(&format!("Unable to read {}: {}", path, e)))
+        .parse()
+        .unwrap_or_else(|e| exit_with_error(&format!("Invalid config {}: {}", path, e)));
+    config.keymap(&rom.sha1_hex())
+}
+
+/// Creates the trace file, with the filters given on the command line.
+fn open_trace(path: &str, matches: &ArgMatches) -> Tracer<BufWriter<File>> {
+    let mut filter = TraceFilter::new();
+    if let Some(range) = matches.value_of("trace-pc") {
+        let (start, end) = parse_range(range)
+            .filter(|(_, end)| *end <= 0xFFFF)
+            .unwrap_or_else(|| exit_with_error("Invalid trace address range"));
+        filter = filter.pcs(start as u16..=end as u16);
+    }
+    if let Some(range) = matches.value_of("trace-frames") {
+        let (start, end) =
+            parse_range(range).unwrap_or_else(|| exit_with_error("Invalid trace frame range"));
+        filter = filter.frames(start as u64..=end as u64);
+    }
+
+    let file = File::create(path)
+        .unwrap_or_else(|e| exit_with_error(&format!("Unable to create {}: {}", path, e)));
+    Tracer::new(BufWriter::new(file)).filter(filter)
+}
+
+fn finish_trace(tracer: Tracer<BufWriter<File>>) {
+    if let Err(e) = tracer.finish() {
+        eprintln!("Unable to write the trace: {}", e);
+    }
+}
+
+fn run_trace_diff(matches: &ArgMatches) {
+    let read = |path: &str| {
+        fs::read_to_string(path)
+            .unwrap_or_else(|e| exit_with_error(&format!("Unable to read {}: {}", path, e)))
+    };
+    let left = read(matches.value_of("LEFT").unwrap());
+    let right = read(matches.value_of("RIGHT").unwrap());
+
+    match trace::diff(&left, &right) {
+        None => println!("The traces match ({} lines)", left.lines().count()),
+        Some(divergence) => {
+            println!("{}", divergence);
+            process::exit(1);
         }
+    }
+}
+
+/// Waits for a debugger to connect on the local port.
+fn wait_for_gdb(port: u16) -> GdbServer {
+    let listener = TcpListener::bind(("127.0.0.1", port))
+        .unwrap_or_else(|e| exit_with_error(&format!("Unable to listen on port {}: {}", port, e)));
+    println!("Waiting for GDB to connect on 127.0.0.1:{}", port);
+
+    let (stream, address) = listener
+        .accept()
+        .unwrap_or_else(|e| exit_with_error(&format!("Unable to accept GDB: {}", e)));
+    println!("GDB connected from {}", address);
+
+    GdbServer::new(stream)
+}
 
-        if inner_window.is_key_pressed(Key::F3, minifb::KeyRepeat::No) {
-            should_run = false;
+/// Serves GDB without a window, until it detaches.
+#[cfg(not(feature = "gui"))]
+fn run_gdb(rom: &Rom, quirks: Quirks, instructions_per_second: u32, seed: u64, mut gdb: GdbServer) {
+    use chip8_rs::{gdb::Status, scheduler::Scheduler};
+
+    let mut cpu = Chip8::from_rom(rom, DummyKeyboard::initialise(), quirks).into_cpu();
+    cpu.seed_rng(seed);
+    let mut scheduler = Scheduler::new().instructions_per_second(instructions_per_second);
+
+    loop {
+        match gdb.update(&mut scheduler, &mut cpu) {
+            Ok(Status::Attached) => std::thread::sleep(std::time::Duration::from_millis(1)),
+            Ok(_) => break,
+            Err(e) => exit_with_error(&format!("Lost the connection to GDB: {}", e)),
         }
+    }
+}
+
+/// Waits for an editor to connect on the local port.
+fn wait_for_dap(port: u16, source_map: Option<SourceMap>) -> DapServer {
+    let listener = TcpListener::bind(("127.0.0.1", port))
+        .unwrap_or_else(|e| exit_with_error(&format!("Unable to listen on port {}: {}", port, e)));
+    println!(
+        "Waiting for a debug adapter client to connect on 127.0.0.1:{}",
+        port
+    );
+
+    let (stream, address) = listener
+        .accept()
+        .unwrap_or_else(|e| exit_with_error(&format!("Unable to accept the client: {}", e)));
+    println!("Debug adapter client connected from {}", address);
 
-        if inner_window.is_key_pressed(Key::F4, minifb::KeyRepeat::No) {
-            should_run = true;
+    DapServer::new(stream, source_map)
+}
+
+/// Serves a debug adapter client without a window, until it disconnects.
+#[cfg(not(feature = "gui"))]
+fn run_dap(rom: &Rom, quirks: Quirks, instructions_per_second: u32, seed: u64, mut dap: DapServer) {
+    use chip8_rs::{dap::Status, scheduler::Scheduler};
+
+    let mut cpu = Chip8::from_rom(rom, DummyKeyboard::initialise(), quirks).into_cpu();
+    cpu.seed_rng(seed);
+    let mut scheduler = Scheduler::new().instructions_per_second(instructions_per_second);
+
+    loop {
+        match dap.update(&mut scheduler, &mut cpu) {
+            Ok(Status::Attached) => std::thread::sleep(std::time::Duration::from_millis(1)),
+            Ok(_) => break,
+            Err(e) => exit_with_error(&format!("Lost the connection to the client: {}", e)),
         }
+    }
+}
+
+fn run_disasm(matches: &ArgMatches) {
+    let path = matches.value_of("INPUT").unwrap();
+    let syntax = Syntax::from_name(matches.value_of("syntax").unwrap()).unwrap();
+    let load_address = parse_number(matches.value_of("load-address").unwrap())
+        .unwrap_or_else(|| exit_with_error("Invalid load address"));
+
+    // Any ROM that fits in XO-CHIP memory can be disassembled
+    let rom = RomLoader::new()
+        .xo_chip()
+        .load_address(load_address)
+        .load_file(path)
+        .unwrap_or_else(|e| exit_with_error(&format!("Unable to load {}: {}", path, e)));
+
+    let source = Disassembler::new()
+        .origin(rom.load_address() as u16)
+        .syntax(syntax)
+        .disassemble(rom.data());
+
+    match matches.value_of("output") {
+        Some(path) => write_output(path, source.as_bytes()),
+        None => print!("{}", source),
+    }
+}
+
+fn run_asm(matches: &ArgMatches) {
+    let path = Path::new(matches.value_of("INPUT").unwrap());
+    let output = matches
+        .value_of("output")
+        .map_or_else(|| path.with_extension("ch8"), PathBuf::from);
+    let load_address = parse_number(matches.value_of("load-address").unwrap())
+        .filter(|address| *address <= 0xFFFF)
+        .unwrap_or_else(|| exit_with_error("Invalid load address"));
+
+    let program = assemble(path, load_address).unwrap_or_else(|e| exit_with_error(&e.to_string()));
+
+    write_output(&output.to_string_lossy(), program.bytes());
+    println!(
+        "Assembled {} bytes to {}",
+        program.bytes().len(),
+        output.display()
+    );
+}
+
+/// Compiles Octo source, or assembles anything else to be loaded at the address.
+fn assemble(path: &Path, load_address: usize) -> Result<Assembly, AsmError> {
+    match path.extension().and_then(|e| e.to_str()) {
+        Some("8o") => octo::Compiler::new().compile_file(path),
+        _ => Assembler::new()
+            .origin(load_address as u16)
+            .assemble_file(path),
+    }
+}
+
+fn write_output(path: &str, contents: &[u8]) {
+    fs::write(path, contents)
+        .unwrap_or_else(|e| exit_with_error(&format!("Unable to write {}: {}", path, e)));
+}
+
+/// Parses a decimal or `0x` prefixed hexadecimal number.
+fn parse_number(value: &str) -> Option<usize> {
+    match value.strip_prefix("0x") {
+        Some(hex) => usize::from_str_radix(hex, 16).ok(),
+        None => value.parse().ok(),
+    }
+}
+
+/// Parses `START-END`, or a single number for a range of one.
+fn parse_range(value: &str) -> Option<(usize, usize)> {
+    let (start, end) = match value.split_once('-') {
+        Some((start, end)) => (parse_number(start)?, parse_number(end)?),
+        None => (parse_number(value)?, parse_number(value)?),
+    };
+
+    Some((start, end)).filter(|(start, end)| start <= end)
+}
 
-        inner_window
-            .update_with_buffer(&cpu.display.get_buffer(), SCREEN_WIDTH, SCREEN_HEIGHT)
-            .unwrap();
-    }
-}
-
-fn key_to_u8(key: Key) -> Option<u8> {
-    match key {
-        Key::Key0 => Some(0x0),
-        Key::Key1 => Some(0x1),
-        Key::Key2 => Some(0x2),
-        Key::Key3 => Some(0x3),
-        Key::Key4 => Some(0x4),
-        Key::Key5 => Some(0x5),
-        Key::Key6 => Some(0x6),
-        Key::Key7 => Some(0x7),
-        Key::Key8 => Some(0x8),
-        Key::Key9 => Some(0x9),
-        Key::A => Some(0xA),
-        Key::B => Some(0xB),
-        Key::C => Some(0xC),
-        Key::D => Some(0xD),
-        Key::E => Some(0xE),
-        Key::F => Some(0xF),
-        _ => None,
-    }
-}
-
-fn dump_memory(memory: &mut Memory) {
-    let mut file = File::create("chip8rs_memdump.log").unwrap();
-    file.write_all(&memory.data).unwrap();
+fn exit_with_error(message: &str) -> ! {
+    eprintln!("{}", message);
+    process::exit(1);
 }
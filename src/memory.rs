@@ -1,25 +1,88 @@
-use std::fs;
-
-use crate::display::DebugDisplay;
+use std::cell::RefCell;
+
+use crate::{display::DebugDisplay, error::Chip8Error, rom::Rom, sha1};
+
+// The SUPER-CHIP 8x10 digit sprites follow the small ones, 10 bytes apiece
+pub const BIG_DIGIT_SPRITES_OFFSET: usize = 0x100;
+
+#[rustfmt::skip]
+const BIG_DIGIT_SPRITES: [[u8; 10]; 0x10] = [
+    [0xFF, 0xFF, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xFF, 0xFF], // 0
+    [0x18, 0x78, 0x78, 0x18, 0x18, 0x18, 0x18, 0x18, 0xFF, 0xFF], // 1
+    [0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF], // 2
+    [0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF], // 3
+    [0xC3, 0xC3, 0xC3, 0xC3, 0xFF, 0xFF, 0x03, 0x03, 0x03, 0x03], // 4
+    [0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF], // 5
+    [0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0xC3, 0xC3, 0xFF, 0xFF], // 6
+    [0xFF, 0xFF, 0x03, 0x03, 0x06, 0x0C, 0x18, 0x18, 0x18, 0x18], // 7
+    [0xFF, 0xFF, 0xC3, 0xC3, 0xFF, 0xFF, 0xC3, 0xC3, 0xFF, 0xFF], // 8
+    [0xFF, 0xFF, 0xC3, 0xC3, 0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF], // 9
+    // SUPER-CHIP only has digits, the letters are what XO-CHIP interpreters provide
+    [0x7E, 0xFF, 0xC3, 0xC3, 0xC3, 0xFF, 0xFF, 0xC3, 0xC3, 0xC3], // A
+    [0xFC, 0xFC, 0xC3, 0xC3, 0xFC, 0xFC, 0xC3, 0xC3, 0xFC, 0xFC], // B
+    [0x3C, 0xFF, 0xC3, 0xC0, 0xC0, 0xC0, 0xC0, 0xC3, 0xFF, 0x3C], // C
+    [0xFC, 0xFE, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xFE, 0xFC], // D
+    [0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF], // E
+    [0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0xC0, 0xC0, 0xC0, 0xC0], // F
+];
 
 // 4KB of RAM for the CPU
-pub(crate) const MAX_MEM: usize = 0x1000;
+pub const MAX_MEM: usize = 0x1000;
+
+// XO-CHIP extends the address space to 64KB
+pub const XO_CHIP_MAX_MEM: usize = 0x10000;
 
 // Programs are restricted from using the first 512 bytes of the memory space
-pub(crate) const PROGRAM_START_OFFSET: usize = 0x200;
+pub const PROGRAM_START_OFFSET: usize = 0x200;
+
+// Programs written for the ETI 660 start at 0x600 instead
+pub const ETI_600_PROGRAM_START_OFFSET: usize = 0x600;
+
+/// How a program touched a byte of memory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    Read,
+    Write,
+    /// Fetched as part of an instruction.
+    Execute,
+}
 
-pub(crate) const ETI_600_PROGRAM_START_OFFSET: usize = 0x600;
+/// A byte the program read or wrote, recorded while [`Memory::record_accesses`] is enabled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryAccess {
+    pub address: usize,
+    pub access: Access,
+    pub value: u8,
+}
 
 #[derive(Debug)]
-pub(crate) struct Memory {
-    pub data: [u8; MAX_MEM],
+pub struct Memory {
+    pub data: Vec<u8>,
+
+    // Identifies the loaded ROM, so save states can't be restored over a different program
+    rom_sha1: Option<[u8; sha1::DIGEST_LEN]>,
+
+    // Reads happen through a shared reference, so the log needs to be mutable behind one
+    recording: bool,
+    accesses: RefCell<Vec<MemoryAccess>>,
 }
 
 impl Memory {
     pub fn initialise() -> Self {
-        let mut memory = Memory { data: [0; MAX_MEM] };
+        Self::with_size(MAX_MEM)
+    }
+
+    /// Creates memory with `size` bytes, e.g. [`XO_CHIP_MAX_MEM`] for XO-CHIP programs.
+    pub fn with_size(size: usize) -> Self {
+        let mut memory = Memory {
+            data: vec![0; size],
+            rom_sha1: None,
+            recording: false,
+            accesses: RefCell::new(vec![]),
+        };
 
         memory.setup_digit_sprites();
+        memory.setup_big_digit_sprites();
 
         memory
     }
@@ -143,24 +206,37 @@ impl Memory {
         self.data[0xF4] = 0x80;
     }
 
-    pub fn initialise_from_file(file: &str) -> Self {
-        // TODO: Handle this nicely
-        let mut memory = Self::initialise();
+    fn setup_big_digit_sprites(&mut self) {
+        for (i, sprite) in BIG_DIGIT_SPRITES.iter().enumerate() {
+            let start = BIG_DIGIT_SPRITES_OFFSET + i * sprite.len();
+            self.data[start..start + sprite.len()].copy_from_slice(sprite);
+        }
+    }
 
-        let file = fs::read(file).unwrap();
+    /// Creates memory the size the ROM was loaded for, with the ROM copied in.
+    pub fn initialise_with_rom(rom: &Rom) -> Self {
+        let mut memory = Self::with_size(rom.memory_size());
 
-        let mut i = 0x200;
-        for b in file {
-            if i > MAX_MEM {
-                break;
-            }
-            memory.data[i] = b;
-            i += 1;
-        }
+        memory.load_rom(rom);
 
         memory
     }
 
+    /// Copies the ROM into memory at its load address.
+    ///
+    /// A [`Rom`] is validated to fit when it is loaded, so this cannot fail.
+    pub fn load_rom(&mut self, rom: &Rom) {
+        let start = rom.load_address();
+
+        self.data[start..start + rom.size()].copy_from_slice(rom.data());
+        self.rom_sha1 = Some(rom.sha1());
+    }
+
+    /// The SHA-1 of the last ROM loaded into memory.
+    pub fn rom_sha1(&self) -> Option<[u8; sha1::DIGEST_LEN]> {
+        self.rom_sha1
+    }
+
     pub fn insert_instruction(&mut self, index: usize, ins: u16) {
         // TODO: Ensure that ops only start at even addresses (see spec line 193)
 
@@ -168,19 +244,52 @@ impl Memory {
         self.data[index + 1] = (ins & 0x00FF) as u8;
     }
 
-    pub fn write(&mut self, index: usize, val: u8) {
-        self.data[index] = val;
+    pub fn write(&mut self, index: usize, val: u8) -> Result<(), Chip8Error> {
+        let cell = self
+            .data
+            .get_mut(index)
+            .ok_or(Chip8Error::MemoryOutOfBounds { addr: index })?;
+        *cell = val;
+
+        self.record(index, Access::Write, val);
+        Ok(())
     }
 
-    pub fn get(&self, index: usize) -> u8 {
-        // TODO: Support ETI_600 offset
-        // TODO: Handle this error better - maybe return some Result variant.
-        if index > MAX_MEM {
-            panic!("Out of bound memory expcetion");
-        }
+    pub fn get(&self, index: usize) -> Result<u8, Chip8Error> {
+        let val = self.fetch(index)?;
+
+        self.record(index, Access::Read, val);
+        Ok(val)
+    }
 
-        // TODO: Remove this unwrap
-        *self.data.get(index).unwrap()
+    /// Reads a byte of an instruction, which isn't recorded as a read.
+    pub fn fetch(&self, index: usize) -> Result<u8, Chip8Error> {
+        self.data
+            .get(index)
+            .copied()
+            .ok_or(Chip8Error::MemoryOutOfBounds { addr: index })
+    }
+
+    /// Starts or stops recording the reads and writes made through [`Memory::get`] and
+    /// [`Memory::write`], for watchpoints.
+    pub fn record_accesses(&mut self, enabled: bool) {
+        self.recording = enabled;
+        self.accesses.get_mut().clear();
+    }
+
+    /// The accesses recorded since the last call.
+    pub fn take_accesses(&self) -> Vec<MemoryAccess> {
+        self.accesses.take()
+    }
+
+    fn record(&self, address: usize, access: Access, value: u8) {
+        if self.recording {
+            self.accesses.borrow_mut().push(MemoryAccess {
+                address,
+                access,
+                value,
+            });
+        }
     }
 }
 
@@ -188,15 +297,15 @@ impl DebugDisplay for Memory {
     fn view_state(&self) {
         let mut r = 0;
         print!("{:02x}: ", r);
-        for i in 0..MAX_MEM {
+        for i in 0..self.data.len() {
             if i % 0x10 == 0 && i != 0 {
                 r += 1;
-                println!("");
+                println!();
                 print!("{:02x}: ", r);
             }
 
             print!("{:02x} ", self.data[i]);
         }
-        println!("");
+        println!();
     }
 }
@@ -0,0 +1,473 @@
+//! Movies: the keys held in every frame of a run, so it can be replayed exactly.
+//!
+//! A movie starts from power on with a given ROM, loaded at a given address into a given size
+//! of memory, and with given quirks, speed and random seed. These are stored with it along
+//! with SHA-1s of the memory and screen at the end. Replaying the same
+//! keys from the same start has to end with the same memory and screen, which
+//! [`Movie::verify`] checks.
+//!
+//! The file holds a magic number and format version, the ROM's SHA-1, its load address and
+//! memory size, the seed, the quirks as bit flags, the instructions per second, the number of
+//! frames and the keys of each frame, then the end state. All multi-byte values are big endian.
+
+use std::{error::Error, fmt};
+
+use crate::{
+    cpu::CPU,
+    headless::KeyScript,
+    keyboard::Keyboard,
+    quirks::Quirks,
+    rom::{Rom, RomLoader},
+    sha1,
+    state::{Reader, StateError},
+};
+
+const MAGIC: [u8; 4] = *b"C8MV";
+
+/// The version of the movie format written by [`Movie::to_bytes`].
+pub const FORMAT_VERSION: u16 = 2;
+
+/// Errors raised while reading or replaying a movie.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MovieError {
+    /// The data does not start with the movie magic number.
+    NotAMovie,
+    /// The movie was written by a newer or older, incompatible version.
+    UnsupportedVersion(u16),
+    /// The data ends before the movie does.
+    Truncated,
+    /// The movie was recorded with a different ROM.
+    RomMismatch,
+    /// The movie was recorded with the ROM loaded at a different address or into a different
+    /// size of memory.
+    LoadMismatch,
+    /// The replay ended with a different memory or screen to the recording.
+    Desync { memory: bool, display: bool },
+}
+
+impl fmt::Display for MovieError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MovieError::NotAMovie => write!(f, "not a movie"),
+            MovieError::UnsupportedVersion(version) => write!(
+                f,
+                "movie format version {} is not supported (expected {})",
+                version, FORMAT_VERSION
+            ),
+            MovieError::Truncated => write!(f, "movie is truncated"),
+            MovieError::RomMismatch => write!(f, "movie was recorded with a different ROM"),
+            MovieError::LoadMismatch => write!(
+                f,
+                "movie was recorded with the ROM loaded at a different address or memory size"
+            ),
+            MovieError::Desync { memory, display } => {
+                let differing = match (memory, display) {
+                    (true, true) => "memory and screen",
+                    (true, false) => "memory",
+                    _ => "screen",
+                };
+                write!(
+                    f,
+                    "the replay ended with a different {} to the recording",
+                    differing
+                )
+            }
+        }
+    }
+}
+
+impl Error for MovieError {}
+
+/// The SHA-1s of the memory and screen at the end of a movie.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EndState {
+    pub memory_sha1: [u8; sha1::DIGEST_LEN],
+    /// Taken over the width and height, then the palette index of each pixel.
+    pub display_sha1: [u8; sha1::DIGEST_LEN],
+}
+
+impl EndState {
+    pub fn of<TKeyboard>(cpu: &CPU<TKeyboard>) -> Self
+    where
+        TKeyboard: Keyboard,
+    {
+        let mut display = vec![];
+        display.extend_from_slice(&(cpu.display.width() as u16).to_be_bytes());
+        display.extend_from_slice(&(cpu.display.height() as u16).to_be_bytes());
+        display.extend(cpu.display.pixels());
+
+        Self {
+            memory_sha1: sha1::digest(&cpu.memory.data),
+            display_sha1: sha1::digest(&display),
+        }
+    }
+}
+
+/// A recording of the keys held in each frame, and everything else needed to replay it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Movie {
+    pub rom_sha1: [u8; sha1::DIGEST_LEN],
+    pub load_address: usize,
+    pub memory_size: usize,
+    /// The seed for `CXKK`'s random numbers, see [`CPU::seed_rng`].
+    pub seed: u64,
+    pub quirks: Quirks,
+    pub instructions_per_second: u32,
+    /// The keys held in each frame, as bitmasks with bit `n` set if key `n` is down.
+    pub frames: Vec<u16>,
+    /// The state after the last frame, set when the recording is finished.
+    pub end: Option<EndState>,
+}
+
+impl Movie {
+    /// Starts a recording of `rom`, for a machine whose RNG was seeded with `seed`.
+    pub fn new(rom: &Rom, seed: u64, quirks: Quirks, instructions_per_second: u32) -> Self {
+        Self {
+            rom_sha1: rom.sha1(),
+            load_address: rom.load_address(),
+            memory_size: rom.memory_size(),
+            seed,
+            quirks,
+            instructions_per_second,
+            frames: vec![],
+            end: None,
+        }
+    }
+
+    /// Records the keys held during the next frame.
+    pub fn record_frame(&mut self, pressed: u16) {
+        self.frames.push(pressed);
+    }
+
+    /// Ends the recording, keeping the state the machine ended in to check replays against.
+    pub fn finish<TKeyboard>(&mut self, cpu: &CPU<TKeyboard>)
+    where
+        TKeyboard: Keyboard,
+    {
+        self.end = Some(EndState::of(cpu));
+    }
+
+    /// A loader that loads the ROM the way it was when the movie was recorded.
+    pub fn rom_loader(&self) -> RomLoader {
+        RomLoader::new()
+            .load_address(self.load_address)
+            .memory_size(self.memory_size)
+    }
+
+    /// Checks the movie was recorded with `rom`, loaded the same way.
+    pub fn check_rom(&self, rom: &Rom) -> Result<(), MovieError> {
+        if rom.sha1() != self.rom_sha1 {
+            Err(MovieError::RomMismatch)
+        } else if rom.load_address() != self.load_address || rom.memory_size() != self.memory_size {
+            Err(MovieError::LoadMismatch)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Checks a replay of every frame ended in the same state as the recording. Movies that
+    /// were never finished have nothing to check against.
+    pub fn verify<TKeyboard>(&self, cpu: &CPU<TKeyboard>) -> Result<(), MovieError>
+    where
+        TKeyboard: Keyboard,
+    {
+        let end = match self.end {
+            Some(end) => end,
+            None => return Ok(()),
+        };
+
+        let actual = EndState::of(cpu);
+        let memory = actual.memory_sha1 != end.memory_sha1;
+        let display = actual.display_sha1 != end.display_sha1;
+        if memory || display {
+            Err(MovieError::Desync { memory, display })
+        } else {
+            Ok(())
+        }
+    }
+
+    /// The presses and releases that replay the movie in headless mode.
+    pub fn key_script(&self) -> KeyScript {
+        let mut script = KeyScript::new();
+        let mut held = 0;
+
+        // In key order, as a keyboard queues the events for keys that change together
+        for (frame, pressed) in self.frames.iter().enumerate() {
+            let changed = held ^ pressed;
+            for key in (0..0x10).filter(|key| changed & (1 << key) != 0) {
+                script = if pressed & (1 << key) != 0 {
+                    script.press(frame as u64, key)
+                } else {
+                    script.release(frame as u64, key)
+                };
+            }
+            held = *pressed;
+        }
+
+        script
+    }
+
+    /// Encodes the movie in the movie format.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = MAGIC.to_vec();
+        out.extend_from_slice(&FORMAT_VERSION.to_be_bytes());
+        out.extend_from_slice(&self.rom_sha1);
+        out.extend_from_slice(&(self.load_address as u32).to_be_bytes());
+        out.extend_from_slice(&(self.memory_size as u32).to_be_bytes());
+        out.extend_from_slice(&self.seed.to_be_bytes());
+        out.push(quirk_flags(&self.quirks));
+        out.extend_from_slice(&self.instructions_per_second.to_be_bytes());
+
+        out.extend_from_slice(&(self.frames.len() as u32).to_be_bytes());
+        for pressed in &self.frames {
+            out.extend_from_slice(&pressed.to_be_bytes());
+        }
+
+        match self.end {
+            Some(end) => {
+                out.push(1);
+                out.extend_from_slice(&end.memory_sha1);
+                out.extend_from_slice(&end.display_sha1);
+            }
+            None => {
+                out.push(0);
+                out.extend_from_slice(&[0; sha1::DIGEST_LEN * 2]);
+            }
+        }
+
+        out
+    }
+
+    /// Decodes a movie from the movie format.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, MovieError> {
+        let mut reader = Reader::new(bytes);
+
+        if reader.take(MAGIC.len()).ok() != Some(&MAGIC[..]) {
+            return Err(MovieError::NotAMovie);
+        }
+
+        let version = reader.u16().map_err(|_| MovieError::Truncated)?;
+        if version != FORMAT_VERSION {
+            return Err(MovieError::UnsupportedVersion(version));
+        }
+
+        // The reader only fails when it runs out of data
+        read_movie(&mut reader).map_err(|_| MovieError::Truncated)
+    }
+}
+
+fn read_movie(reader: &mut Reader) -> Result<Movie, StateError> {
+    let rom_sha1 = read_sha1(reader)?;
+    let load_address = reader.u32()? as usize;
+    let memory_size = reader.u32()? as usize;
+    let seed = reader.u64()?;
+    let quirks = quirks_from_flags(reader.u8()?);
+    let instructions_per_second = reader.u32()?;
+
+    let frame_count = reader.u32()? as usize;
+    let frames = (0..frame_count)
+        .map(|_| reader.u16())
+        .collect::<Result<_, _>>()?;
+
+    let finished = reader.u8()? != 0;
+    let memory_sha1 = read_sha1(reader)?;
+    let display_sha1 = read_sha1(reader)?;
+    let end = if finished {
+        Some(EndState {
+            memory_sha1,
+            display_sha1,
+        })
+    } else {
+        None
+    };
+
+    Ok(Movie {
+        rom_sha1,
+        load_address,
+        memory_size,
+        seed,
+        quirks,
+        instructions_per_second,
+        frames,
+        end,
+    })
+}
+
+fn read_sha1(reader: &mut Reader) -> Result<[u8; sha1::DIGEST_LEN], StateError> {
+    let mut sha1 = [0; sha1::DIGEST_LEN];
+    sha1.copy_from_slice(reader.take(sha1::DIGEST_LEN)?);
+    Ok(sha1)
+}
+
+// Each quirk is a bit, in the order they are declared
+fn quirk_flags(quirks: &Quirks) -> u8 {
+    [
+        quirks.shift_uses_vy,
+        quirks.load_store_increments_i,
+        quirks.jump_uses_vx,
+        quirks.clip_sprites,
+        quirks.vf_reset,
+        quirks.display_wait,
+    ]
+    .iter()
+    .enumerate()
+    .fold(0, |flags, (bit, set)| flags | (*set as u8) << bit)
+}
+
+fn quirks_from_flags(flags: u8) -> Quirks {
+    let set = |bit: u8| flags & (1 << bit) != 0;
+    Quirks {
+        shift_uses_vy: set(0),
+        load_store_increments_i: set(1),
+        jump_uses_vx: set(2),
+        clip_sprites: set(3),
+        vf_reset: set(4),
+        display_wait: set(5),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Movie, MovieError, FORMAT_VERSION};
+    use crate::{
+        cpu::CPU,
+        headless::{HeadlessRunner, RunLimit},
+        keyboard::dummy_keyboard::DummyKeyboard,
+        quirks::Quirks,
+        rom::{Rom, RomLoader},
+        scheduler::{ManualClock, Scheduler},
+        Chip8,
+    };
+
+    // LD I, 0x300; loop: LD V1, 5; SKNP V1; ADD V0, 1; LD [I], V0; JP loop
+    const PROGRAM: [u8; 12] = [
+        0xA3, 0x00, 0x61, 0x05, 0xE1, 0xA1, 0x70, 0x01, 0xF0, 0x55, 0x12, 0x02,
+    ];
+
+    fn get_rom() -> Rom {
+        RomLoader::new().load_bytes(&PROGRAM).unwrap()
+    }
+
+    fn get_cpu(rom: &Rom) -> CPU<DummyKeyboard> {
+        Chip8::from_rom(rom, DummyKeyboard::initialise(), Quirks::default()).into_cpu()
+    }
+
+    /// Records a run holding key 5 through frames 2 to 4.
+    fn record(rom: &Rom) -> Movie {
+        let mut movie = Movie::new(rom, 42, Quirks::default(), 600);
+        let mut cpu = get_cpu(rom);
+        let scheduler = Scheduler::with_clock(ManualClock::default()).instructions_per_second(600);
+
+        for frame in 0..8 {
+            let pressed = if (2..=4).contains(&frame) { 1 << 5 } else { 0 };
+            cpu.keyboard.set_pressed(pressed);
+            movie.record_frame(pressed);
+            scheduler.run_frame(&mut cpu).unwrap();
+        }
+
+        movie.finish(&cpu);
+        movie
+    }
+
+    fn replay(movie: &Movie, rom: &Rom) -> CPU<DummyKeyboard> {
+        let mut cpu = get_cpu(rom);
+        HeadlessRunner::new()
+            .instructions_per_second(movie.instructions_per_second)
+            .script(movie.key_script())
+            .run(&mut cpu, RunLimit::Frames(movie.frames.len() as u64))
+            .unwrap();
+        cpu
+    }
+
+    #[test]
+    fn should_replay_a_recording_exactly() {
+        let rom = get_rom();
+        let movie = record(&rom);
+        assert_eq!(movie.check_rom(&rom), Ok(()));
+
+        let cpu = replay(&movie, &rom);
+        assert_eq!(movie.verify(&cpu), Ok(()));
+
+        // Holding the key for one more frame counts more loops at 0x300
+        let mut longer = movie.clone();
+        longer.frames[5] = 1 << 5;
+        let cpu = replay(&longer, &rom);
+        assert_eq!(
+            longer.verify(&cpu),
+            Err(MovieError::Desync {
+                memory: true,
+                display: false
+            })
+        );
+
+        let other = RomLoader::new().load_bytes(&PROGRAM[..10]).unwrap();
+        assert_eq!(movie.check_rom(&other), Err(MovieError::RomMismatch));
+    }
+
+    #[test]
+    fn should_reload_the_rom_the_way_it_was_recorded() {
+        let rom = RomLoader::new()
+            .eti_660()
+            .xo_chip()
+            .load_bytes(&PROGRAM)
+            .unwrap();
+        let movie = Movie::new(&rom, 42, Quirks::default(), 600);
+
+        let reloaded = movie.rom_loader().load_bytes(&PROGRAM).unwrap();
+        assert_eq!(reloaded.load_address(), 0x600);
+        assert_eq!(reloaded.memory_size(), rom.memory_size());
+        assert_eq!(movie.check_rom(&reloaded), Ok(()));
+
+        assert_eq!(movie.check_rom(&get_rom()), Err(MovieError::LoadMismatch));
+        let bytes = movie.to_bytes();
+        assert_eq!(Movie::from_bytes(&bytes), Ok(movie));
+    }
+
+    #[test]
+    fn should_replay_random_numbers_from_the_seed() {
+        // LD I, 0x300; loop: RND V0, 0xFF; LD [I], V0; JP loop
+        let rom = RomLoader::new()
+            .load_bytes(&[0xA3, 0x00, 0xC0, 0xFF, 0xF0, 0x55, 0x12, 0x02])
+            .unwrap();
+        let run = |seed: u64| {
+            let mut cpu = get_cpu(&rom);
+            cpu.seed_rng(seed);
+            HeadlessRunner::new()
+                .run(&mut cpu, RunLimit::Frames(4))
+                .unwrap();
+            cpu
+        };
+
+        let mut movie = Movie::new(&rom, 42, Quirks::default(), 700);
+        movie.frames = vec![0; 4];
+        movie.finish(&run(movie.seed));
+
+        assert_eq!(movie.verify(&run(movie.seed)), Ok(()));
+        assert!(movie.verify(&run(movie.seed + 1)).is_err());
+    }
+
+    #[test]
+    fn should_round_trip_through_bytes() {
+        let mut movie = record(&get_rom());
+        movie.quirks = Quirks::cosmac_vip();
+
+        let bytes = movie.to_bytes();
+        assert_eq!(Movie::from_bytes(&bytes), Ok(movie.clone()));
+
+        movie.end = None;
+        assert_eq!(Movie::from_bytes(&movie.to_bytes()), Ok(movie));
+
+        assert_eq!(
+            Movie::from_bytes(&bytes[..bytes.len() - 1]),
+            Err(MovieError::Truncated)
+        );
+        assert_eq!(Movie::from_bytes(b"C8SS"), Err(MovieError::NotAMovie));
+
+        let mut newer = bytes;
+        newer[4..6].copy_from_slice(&(FORMAT_VERSION + 1).to_be_bytes());
+        assert_eq!(
+            Movie::from_bytes(&newer),
+            Err(MovieError::UnsupportedVersion(FORMAT_VERSION + 1))
+        );
+    }
+}
@@ -1,9 +1,12 @@
+use crate::instructions::Instruction;
+
 #[derive(Debug)]
 pub struct OpCode {
     inner: u16,
     id: u8,
     x: u8,
     y: u8,
+    n: u8,
     kk: u8,
     nnn: u16,
 }
@@ -15,6 +18,7 @@ impl OpCode {
             id: ((raw_opcode & 0xF000) >> 12) as u8,
             x: ((raw_opcode & 0x0F00) >> 8) as u8,
             y: ((raw_opcode & 0x00F0) >> 4) as u8,
+            n: (raw_opcode & 0x000F) as u8,
             kk: (raw_opcode & 0x00FF) as u8,
             nnn: (raw_opcode & 0x0FFF),
         }
@@ -36,6 +40,10 @@ impl OpCode {
         self.y
     }
 
+    pub fn n(&self) -> u8 {
+        self.n
+    }
+
     pub fn kk(&self) -> u8 {
         self.kk
     }
@@ -43,6 +51,11 @@ impl OpCode {
     pub fn nnn(&self) -> u16 {
         self.nnn
     }
+
+    /// Decodes the opcode into the instruction it represents
+    pub fn decode(&self) -> Instruction {
+        Instruction::from(self)
+    }
 }
 
 #[cfg(test)]
@@ -69,6 +82,11 @@ mod tests {
         assert_eq!(OpCode::new(0x1234).y(), 0x3);
     }
 
+    #[test]
+    fn should_generate_correct_n_value() {
+        assert_eq!(OpCode::new(0x1234).n(), 0x4);
+    }
+
     #[test]
     fn should_generate_correct_kk_value() {
         assert_eq!(OpCode::new(0x1234).kk(), 0x34);
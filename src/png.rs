@@ -0,0 +1,119 @@
+//! A minimal PNG encoder for greyscale screenshots.
+//!
+//! Image data is stored uncompressed (deflate "stored" blocks); CHIP-8 framebuffers are tiny
+//! so there is no need for a real compressor.
+
+const SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1A, b'\n'];
+
+// The largest payload a single stored deflate block can hold
+const MAX_STORED_BLOCK: usize = 0xFFFF;
+
+/// Encodes 8-bit greyscale `pixels` (row major, `width * height` long) as a PNG.
+pub(crate) fn encode_greyscale(width: usize, height: usize, pixels: &[u8]) -> Vec<u8> {
+    let mut png = SIGNATURE.to_vec();
+
+    let mut ihdr = vec![];
+    ihdr.extend_from_slice(&(width as u32).to_be_bytes());
+    ihdr.extend_from_slice(&(height as u32).to_be_bytes());
+    // Bit depth 8, colour type 0 (greyscale), default compression, filter and no interlacing
+    ihdr.extend_from_slice(&[8, 0, 0, 0, 0]);
+    write_chunk(&mut png, b"IHDR", &ihdr);
+
+    // Each scanline is prefixed with its filter type, 0 being no filter
+    let mut scanlines = Vec::with_capacity((width + 1) * height);
+    for row in pixels.chunks(width) {
+        scanlines.push(0);
+        scanlines.extend_from_slice(row);
+    }
+    write_chunk(&mut png, b"IDAT", &zlib_stored(&scanlines));
+
+    write_chunk(&mut png, b"IEND", &[]);
+
+    png
+}
+
+fn write_chunk(png: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    png.extend_from_slice(&(data.len() as u32).to_be_bytes());
+
+    let start = png.len();
+    png.extend_from_slice(kind);
+    png.extend_from_slice(data);
+    let crc = crc32(&png[start..]);
+
+    png.extend_from_slice(&crc.to_be_bytes());
+}
+
+fn zlib_stored(data: &[u8]) -> Vec<u8> {
+    // Deflate with a 32K window, no preset dictionary
+    let mut out = vec![0x78, 0x01];
+
+    let mut blocks = data.chunks(MAX_STORED_BLOCK).peekable();
+    if blocks.peek().is_none() {
+        out.extend_from_slice(&[0x01, 0x00, 0x00, 0xFF, 0xFF]);
+    }
+    while let Some(block) = blocks.next() {
+        let last = blocks.peek().is_none();
+        let len = block.len() as u16;
+
+        out.push(last as u8);
+        out.extend_from_slice(&len.to_le_bytes());
+        out.extend_from_slice(&(!len).to_le_bytes());
+        out.extend_from_slice(block);
+    }
+
+    out.extend_from_slice(&adler32(data).to_be_bytes());
+
+    out
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xFFFF_FFFFu32;
+
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            let mask = (!(crc & 1)).wrapping_add(1);
+            crc = (crc >> 1) ^ (0xEDB8_8320 & mask);
+        }
+    }
+
+    !crc
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let mut a = 1u32;
+    let mut b = 0u32;
+
+    for byte in data {
+        a = (a + *byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+
+    (b << 16) | a
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{adler32, crc32, encode_greyscale};
+
+    #[test]
+    fn should_calculate_crc32() {
+        assert_eq!(crc32(b"IEND"), 0xAE42_6082);
+    }
+
+    #[test]
+    fn should_calculate_adler32() {
+        assert_eq!(adler32(b"Wikipedia"), 0x11E6_0398);
+    }
+
+    #[test]
+    fn should_encode_png_chunks() {
+        let png = encode_greyscale(2, 2, &[0, 255, 255, 0]);
+
+        assert_eq!(&png[..8], b"\x89PNG\r\n\x1a\n");
+        assert_eq!(&png[12..16], b"IHDR");
+        assert_eq!(&png[16..20], &2u32.to_be_bytes());
+        assert_eq!(&png[20..24], &2u32.to_be_bytes());
+        assert_eq!(&png[png.len() - 12..], b"\0\0\0\0IEND\xae\x42\x60\x82");
+    }
+}
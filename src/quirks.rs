@@ -0,0 +1,94 @@
+/// Behaviours that differ between CHIP-8 interpreters.
+///
+/// The original COSMAC VIP interpreter and its successors (CHIP-48, SUPER-CHIP, XO-CHIP)
+/// disagree on a handful of instructions, and ROMs are usually written against one of them.
+/// See https://github.com/Timendus/chip8-test-suite#quirks-test for a description of each quirk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Quirks {
+    /// `8XY6`/`8XYE` shift `Vy` and store the result in `Vx`, rather than shifting `Vx` in place.
+    pub shift_uses_vy: bool,
+
+    /// `FX55`/`FX65` leave `I` pointing at the address after the last register stored or loaded.
+    pub load_store_increments_i: bool,
+
+    /// `BNNN` is treated as `BXNN` and jumps to `XNN + Vx` instead of `NNN + V0`.
+    pub jump_uses_vx: bool,
+
+    /// Sprites drawn over the edge of the screen are clipped instead of wrapping around.
+    pub clip_sprites: bool,
+
+    /// `8XY1`/`8XY2`/`8XY3` reset `VF` to 0.
+    pub vf_reset: bool,
+
+    /// `DXYN` waits for the start of the next frame before drawing, limiting draws to 60 per second.
+    pub display_wait: bool,
+}
+
+impl Quirks {
+    /// The original interpreter on the RCA COSMAC VIP.
+    pub fn cosmac_vip() -> Self {
+        Self {
+            shift_uses_vy: true,
+            load_store_increments_i: true,
+            jump_uses_vx: false,
+            clip_sprites: true,
+            vf_reset: true,
+            display_wait: true,
+        }
+    }
+
+    /// CHIP-48 for the HP-48 calculators.
+    pub fn chip48() -> Self {
+        Self {
+            shift_uses_vy: false,
+            load_store_increments_i: false,
+            jump_uses_vx: true,
+            clip_sprites: true,
+            vf_reset: false,
+            display_wait: false,
+        }
+    }
+
+    /// SUPER-CHIP 1.1, as used by most ROMs written for the HP-48.
+    pub fn super_chip() -> Self {
+        Self::chip48()
+    }
+
+    /// XO-CHIP, as implemented by Octo.
+    pub fn xo_chip() -> Self {
+        Self {
+            shift_uses_vy: true,
+            load_store_increments_i: true,
+            jump_uses_vx: false,
+            clip_sprites: false,
+            vf_reset: false,
+            display_wait: false,
+        }
+    }
+
+    /// Looks up a preset by the name used on the command line.
+    pub fn preset(name: &str) -> Option<Self> {
+        match name {
+            "default" => Some(Self::default()),
+            "vip" | "cosmac-vip" => Some(Self::cosmac_vip()),
+            "chip48" | "chip-48" => Some(Self::chip48()),
+            "schip" | "super-chip" => Some(Self::super_chip()),
+            "xochip" | "xo-chip" => Some(Self::xo_chip()),
+            _ => None,
+        }
+    }
+}
+
+/// The behaviour this interpreter had before quirks were configurable.
+impl Default for Quirks {
+    fn default() -> Self {
+        Self {
+            shift_uses_vy: false,
+            load_store_increments_i: false,
+            jump_uses_vx: false,
+            clip_sprites: false,
+            vf_reset: false,
+            display_wait: false,
+        }
+    }
+}
@@ -0,0 +1,123 @@
+use std::{
+    io::{self, BufRead, Write},
+    sync::mpsc::{self, Receiver, TryRecvError},
+    thread,
+};
+
+use chip8_rs::{
+    cpu::CPU,
+    debugger::{self, Command, Debugger},
+    keyboard::Keyboard,
+    scheduler::{Scheduler, SystemClock},
+};
+
+#[cfg(not(feature = "gui"))]
+use chip8_rs::{keyboard::dummy_keyboard::DummyKeyboard, quirks::Quirks, rom::Rom, Chip8};
+
+const PROMPT: &str = "(chip8) ";
+
+/// The debugger's command line, read from the terminal while the emulator keeps running.
+pub struct Repl {
+    debugger: Debugger,
+    lines: Receiver<String>,
+}
+
+impl Repl {
+    /// Starts reading commands from stdin, with the program paused at its first instruction.
+    pub fn start<TKeyboard>(cpu: &CPU<TKeyboard>) -> Self
+    where
+        TKeyboard: Keyboard,
+    {
+        let (sender, lines) = mpsc::channel();
+        // Reading stdin blocks, so it gets a thread of its own
+        thread::spawn(move || {
+            for line in io::stdin().lock().lines().map_while(Result::ok) {
+                if sender.send(line).is_err() {
+                    break;
+                }
+            }
+        });
+
+        println!("Debugging, type `help` for the commands");
+        println!("{}", debugger::location(cpu));
+        prompt();
+
+        Self {
+            debugger: Debugger::new(),
+            lines,
+        }
+    }
+
+    #[cfg(feature = "gui")]
+    pub fn debugger_mut(&mut self) -> &mut Debugger {
+        &mut self.debugger
+    }
+
+    /// Runs any commands that were typed, then the frames that are due unless the program is
+    /// paused. Returns false once the user quits or closes stdin.
+    pub fn update<TKeyboard>(
+        &mut self,
+        scheduler: &mut Scheduler<SystemClock>,
+        cpu: &mut CPU<TKeyboard>,
+    ) -> bool
+    where
+        TKeyboard: Keyboard,
+    {
+        loop {
+            let line = match self.lines.try_recv() {
+                Ok(line) => line,
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => return false,
+            };
+
+            if line.parse() == Ok(Command::Quit) {
+                return false;
+            }
+
+            match self.debugger.execute_line(&line, cpu) {
+                Ok(output) if output.is_empty() => {}
+                Ok(output) => println!("{}", output),
+                Err(e) => println!("{}", e),
+            }
+
+            if self.debugger.is_paused() {
+                prompt();
+            }
+        }
+
+        if self.debugger.is_paused() {
+            // Don't try to catch up on the time spent paused
+            scheduler.reset();
+            return true;
+        }
+
+        for _ in 0..scheduler.frames_due() {
+            if let Some(stop) = self.debugger.run_frame(scheduler, cpu) {
+                println!("\n{}", stop);
+                println!("{}", debugger::location(cpu));
+                prompt();
+                break;
+            }
+        }
+
+        true
+    }
+}
+
+fn prompt() {
+    print!("{}", PROMPT);
+    io::stdout().flush().unwrap();
+}
+
+/// Debugs the given ROM from the terminal alone, for builds without a window.
+#[cfg(not(feature = "gui"))]
+pub fn run(rom: &Rom, quirks: Quirks, instructions_per_second: u32, seed: u64) {
+    let mut cpu = Chip8::from_rom(rom, DummyKeyboard::initialise(), quirks).into_cpu();
+    cpu.seed_rng(seed);
+    let mut scheduler = Scheduler::new().instructions_per_second(instructions_per_second);
+    let mut repl = Repl::start(&cpu);
+
+    while repl.update(&mut scheduler, &mut cpu) {
+        thread::sleep(std::time::Duration::from_millis(1));
+    }
+}
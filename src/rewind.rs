@@ -0,0 +1,258 @@
+//! A rolling history of machine states, for stepping execution backwards.
+//!
+//! Only the newest [`Snapshot`] is kept whole. Every older frame is stored as the changes
+//! needed to turn the frame after it back into it, which for most games is a handful of
+//! bytes of memory and a few pixels.
+
+use std::collections::VecDeque;
+
+use crate::state::Snapshot;
+
+/// About 10 seconds of frames at 60Hz.
+pub const DEFAULT_REWIND_FRAMES: usize = 600;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Frame {
+    /// The memory and screen are the same size as the next frame's, so only the differences
+    /// are stored. `registers` has empty memory and screen.
+    Delta {
+        registers: Snapshot,
+        // Runs of bytes that differ from the next frame, with the offset they start at
+        memory: Vec<(usize, Vec<u8>)>,
+        // The pixels that differ from the next frame, with their index
+        screen: Vec<(usize, u8)>,
+    },
+    /// The whole state, used when the memory or screen changed size.
+    Full(Snapshot),
+}
+
+impl Frame {
+    /// Records how to get from `next` back to `previous`.
+    fn between(previous: Snapshot, next: &Snapshot) -> Self {
+        if previous.memory.len() != next.memory.len() || previous.screen.len() != next.screen.len()
+        {
+            return Frame::Full(previous);
+        }
+
+        let mut memory: Vec<(usize, Vec<u8>)> = vec![];
+        for (i, (old, new)) in previous.memory.iter().zip(next.memory.iter()).enumerate() {
+            if old == new {
+                continue;
+            }
+
+            match memory.last_mut() {
+                Some((start, run)) if *start + run.len() == i => run.push(*old),
+                _ => memory.push((i, vec![*old])),
+            }
+        }
+
+        let screen = previous
+            .screen
+            .iter()
+            .zip(next.screen.iter())
+            .enumerate()
+            .filter(|(_, (old, new))| old != new)
+            .map(|(i, (old, _))| (i, *old))
+            .collect();
+
+        Frame::Delta {
+            registers: Snapshot {
+                memory: vec![],
+                screen: vec![],
+                ..previous
+            },
+            memory,
+            screen,
+        }
+    }
+
+    /// Rebuilds the frame from the frame after it.
+    fn apply(self, next: &Snapshot) -> Snapshot {
+        match self {
+            Frame::Full(snapshot) => snapshot,
+            Frame::Delta {
+                registers,
+                memory: memory_runs,
+                screen: screen_changes,
+            } => {
+                let mut memory = next.memory.clone();
+                for (start, run) in memory_runs {
+                    memory[start..start + run.len()].copy_from_slice(&run);
+                }
+
+                let mut screen = next.screen.clone();
+                for (i, pixel) in screen_changes {
+                    screen[i] = pixel;
+                }
+
+                Snapshot {
+                    memory,
+                    screen,
+                    ..registers
+                }
+            }
+        }
+    }
+}
+
+/// A ring buffer of the last few frames of machine state.
+///
+/// Push a [`Snapshot`] at the end of every frame, then [`Rewind::pop`] to step back through
+/// them. Once full, the oldest frames are dropped.
+#[derive(Debug, Clone)]
+pub struct Rewind {
+    capacity: usize,
+    latest: Option<Snapshot>,
+    // Ordered oldest first; the back turns `latest` into the frame before it
+    history: VecDeque<Frame>,
+}
+
+impl Default for Rewind {
+    fn default() -> Self {
+        Self::new(DEFAULT_REWIND_FRAMES)
+    }
+}
+
+impl Rewind {
+    /// Creates a buffer that remembers up to `capacity` frames.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity: capacity.max(1),
+            latest: None,
+            history: VecDeque::new(),
+        }
+    }
+
+    /// The number of frames that can be rewound to, including the latest.
+    pub fn len(&self) -> usize {
+        self.history.len() + self.latest.is_some() as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.latest.is_none()
+    }
+
+    pub fn clear(&mut self) {
+        self.latest = None;
+        self.history.clear();
+    }
+
+    /// Records the state at the end of a frame.
+    pub fn push(&mut self, snapshot: Snapshot) {
+        if let Some(previous) = self.latest.take() {
+            self.history.push_back(Frame::between(previous, &snapshot));
+        }
+        self.latest = Some(snapshot);
+
+        while self.len() > self.capacity {
+            self.history.pop_front();
+        }
+    }
+
+    /// Steps back a frame, returning the state the machine was in at the end of it.
+    ///
+    /// The oldest frame is never removed, so holding rewind stops there rather than running
+    /// out of history. Returns `None` if nothing has been recorded.
+    pub fn pop(&mut self) -> Option<&Snapshot> {
+        if let Some(frame) = self.history.pop_back() {
+            let next = self.latest.take()?;
+            self.latest = Some(frame.apply(&next));
+        }
+
+        self.latest.as_ref()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        cpu::CPU, display::Display, keyboard::dummy_keyboard::DummyKeyboard, memory::Memory,
+        quirks::Quirks,
+    };
+
+    use super::{Frame, Rewind};
+
+    /// A CPU that counts up in V0 and writes the count to memory, drawing it as it goes.
+    fn get_cpu() -> CPU<DummyKeyboard> {
+        let mut memory = Memory::initialise();
+        // ADD V0, 1; LD I, 0x300; LD B, V0; LD F, V0; DRW V1, V1, 5; JP 0x200
+        for (i, op) in [0x7001, 0xA300, 0xF033, 0xF029, 0xD115, 0x1200]
+            .iter()
+            .enumerate()
+        {
+            memory.insert_instruction(0x200 + i * 2, *op);
+        }
+
+        CPU::initialise(
+            memory,
+            Display::initialise(),
+            DummyKeyboard::initialise(),
+            Quirks::default(),
+        )
+    }
+
+    fn run_frame(cpu: &mut CPU<DummyKeyboard>) {
+        for _ in 0..6 {
+            cpu.execute_next_instruction().unwrap();
+        }
+    }
+
+    #[test]
+    fn should_rewind_frame_by_frame() {
+        let mut cpu = get_cpu();
+        let mut rewind = Rewind::new(10);
+        let mut snapshots = vec![];
+
+        for _ in 0..5 {
+            run_frame(&mut cpu);
+            snapshots.push(cpu.snapshot());
+            rewind.push(cpu.snapshot());
+        }
+
+        assert_eq!(rewind.len(), 5);
+        for expected in snapshots.iter().rev().skip(1) {
+            assert_eq!(rewind.pop(), Some(expected));
+        }
+        assert_eq!(rewind.len(), 1);
+    }
+
+    #[test]
+    fn should_stop_at_oldest_frame() {
+        let mut cpu = get_cpu();
+        let mut rewind = Rewind::new(3);
+        let mut snapshots = vec![];
+
+        for _ in 0..5 {
+            run_frame(&mut cpu);
+            snapshots.push(cpu.snapshot());
+            rewind.push(cpu.snapshot());
+        }
+
+        assert_eq!(rewind.len(), 3);
+        rewind.pop();
+        rewind.pop();
+        assert_eq!(rewind.pop(), Some(&snapshots[2]));
+        assert_eq!(rewind.pop(), Some(&snapshots[2]));
+    }
+
+    #[test]
+    fn should_store_only_changes() {
+        let mut cpu = get_cpu();
+        run_frame(&mut cpu);
+        let previous = cpu.snapshot();
+        run_frame(&mut cpu);
+
+        match Frame::between(previous, &cpu.snapshot()) {
+            Frame::Delta { memory, .. } => {
+                // Only the BCD of V0 at 0x300 changes
+                assert_eq!(memory, vec![(0x302, vec![1])]);
+            }
+            Frame::Full(_) => panic!("expected a delta"),
+        }
+    }
+
+    #[test]
+    fn should_return_nothing_when_empty() {
+        assert_eq!(Rewind::default().pop(), None);
+    }
+}
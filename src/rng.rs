@@ -0,0 +1,136 @@
+//! Where `CXKK` gets its random numbers from.
+//!
+//! The CPU draws from a [`RandomSource`], a [`SeededRng`] by default. Giving it the same seed
+//! makes a run repeatable, and its state is kept in save states so loading one repeats the
+//! numbers that followed it. Tests can script the numbers with a [`ScriptedRng`].
+
+use std::fmt;
+
+/// A source of random bytes for the CPU.
+pub trait RandomSource: fmt::Debug {
+    fn next_byte(&mut self) -> u8;
+
+    /// The state to keep in a save state, or `None` if the source can't be restored.
+    fn save(&self) -> Option<u64> {
+        None
+    }
+
+    /// Picks up from a state returned by [`RandomSource::save`].
+    fn restore(&mut self, _state: u64) {}
+}
+
+/// A fast generator (SplitMix64) whose whole state is a `u64`, starting from a seed.
+///
+/// ```
+/// use chip8_rs::rng::{RandomSource, SeededRng};
+///
+/// let mut a = SeededRng::new(7);
+/// let mut b = SeededRng::new(7);
+/// assert_eq!(a.next_byte(), b.next_byte());
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SeededRng {
+    state: u64,
+}
+
+impl SeededRng {
+    pub fn new(seed: u64) -> Self {
+        Self { state: seed }
+    }
+
+    /// Starts from a seed picked by the OS.
+    pub fn from_entropy() -> Self {
+        Self::new(rand::random())
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+}
+
+impl RandomSource for SeededRng {
+    fn next_byte(&mut self) -> u8 {
+        // The high bits are the best mixed
+        (self.next_u64() >> 56) as u8
+    }
+
+    fn save(&self) -> Option<u64> {
+        Some(self.state)
+    }
+
+    fn restore(&mut self, state: u64) {
+        self.state = state;
+    }
+}
+
+/// Gives out the bytes it was made with in order, starting over when it runs out.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ScriptedRng {
+    bytes: Vec<u8>,
+    next: usize,
+}
+
+impl ScriptedRng {
+    /// # Panics
+    ///
+    /// If `bytes` is empty.
+    pub fn new(bytes: Vec<u8>) -> Self {
+        assert!(!bytes.is_empty(), "a scripted RNG needs at least one byte");
+        Self { bytes, next: 0 }
+    }
+}
+
+impl RandomSource for ScriptedRng {
+    fn next_byte(&mut self) -> u8 {
+        let byte = self.bytes[self.next];
+        self.next = (self.next + 1) % self.bytes.len();
+        byte
+    }
+
+    fn save(&self) -> Option<u64> {
+        Some(self.next as u64)
+    }
+
+    fn restore(&mut self, state: u64) {
+        self.next = state as usize % self.bytes.len();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{RandomSource, ScriptedRng, SeededRng};
+
+    #[test]
+    fn should_repeat_from_a_seed_or_saved_state() {
+        let bytes = |rng: &mut SeededRng| (0..16).map(|_| rng.next_byte()).collect::<Vec<u8>>();
+
+        let mut rng = SeededRng::new(1);
+        let first = bytes(&mut rng);
+        assert_eq!(first, bytes(&mut SeededRng::new(1)));
+        assert_ne!(first, bytes(&mut SeededRng::new(2)));
+
+        let saved = rng.save().unwrap();
+        let next = bytes(&mut rng);
+        rng.restore(saved);
+        assert_eq!(bytes(&mut rng), next);
+    }
+
+    #[test]
+    fn should_give_scripted_bytes_in_order() {
+        let mut rng = ScriptedRng::new(vec![1, 2, 3]);
+        assert_eq!(rng.next_byte(), 1);
+
+        let saved = rng.save().unwrap();
+        assert_eq!(
+            [rng.next_byte(), rng.next_byte(), rng.next_byte()],
+            [2, 3, 1]
+        );
+
+        rng.restore(saved);
+        assert_eq!(rng.next_byte(), 2);
+    }
+}
@@ -0,0 +1,257 @@
+use std::{
+    error::Error,
+    fmt, fs,
+    io::{self, Read},
+    path::Path,
+};
+
+use crate::{
+    memory::{ETI_600_PROGRAM_START_OFFSET, MAX_MEM, PROGRAM_START_OFFSET, XO_CHIP_MAX_MEM},
+    sha1,
+};
+
+/// Errors raised while loading a ROM.
+#[derive(Debug)]
+pub enum RomError {
+    /// The ROM does not fit between the load address and the end of memory.
+    RomTooLarge { size: usize, max: usize },
+    /// The ROM contains no data.
+    Empty,
+    /// The ROM could not be read.
+    Io(io::Error),
+}
+
+impl fmt::Display for RomError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RomError::RomTooLarge { size, max } => write!(
+                f,
+                "ROM is {} bytes but at most {} bytes can be loaded",
+                size, max
+            ),
+            RomError::Empty => write!(f, "ROM is empty"),
+            RomError::Io(e) => write!(f, "unable to read ROM: {}", e),
+        }
+    }
+}
+
+impl Error for RomError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            RomError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for RomError {
+    fn from(e: io::Error) -> Self {
+        RomError::Io(e)
+    }
+}
+
+/// A validated program image, ready to be copied into [`crate::memory::Memory`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Rom {
+    data: Vec<u8>,
+    load_address: usize,
+    memory_size: usize,
+    sha1: [u8; sha1::DIGEST_LEN],
+}
+
+impl Rom {
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    pub fn size(&self) -> usize {
+        self.data.len()
+    }
+
+    /// The address the first byte of the ROM is loaded at, and where execution starts.
+    pub fn load_address(&self) -> usize {
+        self.load_address
+    }
+
+    /// The size of the memory the ROM was validated against, and should be run with.
+    pub fn memory_size(&self) -> usize {
+        self.memory_size
+    }
+
+    pub fn sha1(&self) -> [u8; sha1::DIGEST_LEN] {
+        self.sha1
+    }
+
+    pub fn sha1_hex(&self) -> String {
+        sha1::to_hex(&self.sha1)
+    }
+}
+
+/// Loads and validates ROMs.
+///
+/// ```
+/// use chip8_rs::rom::RomLoader;
+///
+/// let rom = RomLoader::new().load_bytes(&[0x00, 0xE0]).unwrap();
+/// assert_eq!(rom.load_address(), 0x200);
+/// assert_eq!(rom.size(), 2);
+/// ```
+#[derive(Debug, Clone, Copy)]
+pub struct RomLoader {
+    load_address: usize,
+    memory_size: usize,
+}
+
+impl Default for RomLoader {
+    fn default() -> Self {
+        Self {
+            load_address: PROGRAM_START_OFFSET,
+            memory_size: MAX_MEM,
+        }
+    }
+}
+
+impl RomLoader {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the address the ROM is loaded at. Defaults to `0x200`.
+    pub fn load_address(mut self, load_address: usize) -> Self {
+        self.load_address = load_address;
+        self
+    }
+
+    /// Loads the ROM at `0x600`, as expected by programs written for the ETI 660.
+    pub fn eti_660(self) -> Self {
+        self.load_address(ETI_600_PROGRAM_START_OFFSET)
+    }
+
+    /// Sets the size of memory the ROM has to fit in. Defaults to 4KB.
+    pub fn memory_size(mut self, memory_size: usize) -> Self {
+        self.memory_size = memory_size;
+        self
+    }
+
+    /// Allows ROMs to use the 64KB XO-CHIP address space.
+    pub fn xo_chip(self) -> Self {
+        self.memory_size(XO_CHIP_MAX_MEM)
+    }
+
+    pub fn load_file<P: AsRef<Path>>(&self, path: P) -> Result<Rom, RomError> {
+        let data = fs::read(path)?;
+
+        self.load_vec(data)
+    }
+
+    pub fn load_reader<R: Read>(&self, mut reader: R) -> Result<Rom, RomError> {
+        let mut data = vec![];
+        reader.read_to_end(&mut data)?;
+
+        self.load_vec(data)
+    }
+
+    pub fn load_bytes(&self, bytes: &[u8]) -> Result<Rom, RomError> {
+        self.load_vec(bytes.to_vec())
+    }
+
+    fn load_vec(&self, data: Vec<u8>) -> Result<Rom, RomError> {
+        let max = self.memory_size.saturating_sub(self.load_address);
+
+        if data.is_empty() {
+            return Err(RomError::Empty);
+        }
+
+        if data.len() > max {
+            return Err(RomError::RomTooLarge {
+                size: data.len(),
+                max,
+            });
+        }
+
+        Ok(Rom {
+            sha1: sha1::digest(&data),
+            load_address: self.load_address,
+            memory_size: self.memory_size,
+            data,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::{RomError, RomLoader};
+
+    #[test]
+    fn should_load_rom_from_reader() {
+        let rom = RomLoader::new()
+            .load_reader(Cursor::new(vec![0x12, 0x00]))
+            .unwrap();
+
+        assert_eq!(rom.data(), &[0x12, 0x00]);
+        assert_eq!(rom.load_address(), 0x200);
+    }
+
+    #[test]
+    fn should_report_sha1_of_rom() {
+        let rom = RomLoader::new().load_bytes(b"abc").unwrap();
+
+        assert_eq!(rom.sha1_hex(), "a9993e364706816aba3e25717850c26c9cd0d89d");
+    }
+
+    #[test]
+    fn should_reject_empty_rom() {
+        assert!(matches!(
+            RomLoader::new().load_bytes(&[]),
+            Err(RomError::Empty)
+        ));
+    }
+
+    #[test]
+    fn should_accept_rom_filling_memory() {
+        assert!(RomLoader::new().load_bytes(&[0; 3584]).is_ok());
+    }
+
+    #[test]
+    fn should_reject_rom_larger_than_memory() {
+        assert!(matches!(
+            RomLoader::new().load_bytes(&[0; 3585]),
+            Err(RomError::RomTooLarge {
+                size: 3585,
+                max: 3584
+            })
+        ));
+    }
+
+    #[test]
+    fn should_limit_rom_size_by_load_address() {
+        assert!(matches!(
+            RomLoader::new().eti_660().load_bytes(&[0; 2561]),
+            Err(RomError::RomTooLarge {
+                size: 2561,
+                max: 2560
+            })
+        ));
+    }
+
+    #[test]
+    fn should_accept_large_rom_for_xo_chip() {
+        let rom = RomLoader::new().xo_chip().load_bytes(&[0; 0x8000]).unwrap();
+
+        assert_eq!(rom.memory_size(), 0x10000);
+        assert!(matches!(
+            RomLoader::new().xo_chip().load_bytes(&[0; 0xFE01]),
+            Err(RomError::RomTooLarge { max: 0xFE00, .. })
+        ));
+    }
+
+    #[test]
+    fn should_report_missing_file() {
+        assert!(matches!(
+            RomLoader::new().load_file("does/not/exist.ch8"),
+            Err(RomError::Io(_))
+        ));
+    }
+}
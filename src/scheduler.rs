@@ -0,0 +1,350 @@
+use std::{
+    cell::Cell,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    cpu::{StepOutcome, CPU},
+    error::Chip8Error,
+    keyboard::Keyboard,
+};
+
+// The delay and sound timers count down at 60Hz, and the display is refreshed at the same rate
+pub const FRAMES_PER_SECOND: u32 = 60;
+
+// A COSMAC VIP runs roughly 500-1000 instructions a second depending on the instruction mix
+pub const DEFAULT_INSTRUCTIONS_PER_SECOND: u32 = 700;
+
+// If the host falls this far behind (e.g. the window was dragged) the missed frames are dropped
+// rather than run back to back.
+const MAX_CATCH_UP_FRAMES: u64 = 10;
+
+/// A monotonic source of time for the [`Scheduler`].
+pub trait TimeSource {
+    /// The time elapsed since some fixed point in the past.
+    fn now(&self) -> Duration;
+}
+
+/// Wall clock time.
+#[derive(Debug)]
+pub struct SystemClock {
+    start: Instant,
+}
+
+impl Default for SystemClock {
+    fn default() -> Self {
+        Self {
+            start: Instant::now(),
+        }
+    }
+}
+
+impl TimeSource for SystemClock {
+    fn now(&self) -> Duration {
+        self.start.elapsed()
+    }
+}
+
+/// A clock that only moves when told to, for driving the scheduler deterministically.
+#[derive(Debug, Default)]
+pub struct ManualClock {
+    now: Cell<Duration>,
+}
+
+impl ManualClock {
+    pub fn advance(&self, by: Duration) {
+        self.now.set(self.now.get() + by);
+    }
+}
+
+impl TimeSource for ManualClock {
+    fn now(&self) -> Duration {
+        self.now.get()
+    }
+}
+
+/// Paces the CPU against a [`TimeSource`].
+///
+/// Time is divided into 60Hz frames. Each frame ticks the timers once and then runs a fixed
+/// number of instructions, so the CPU speed is independent of how often the front-end polls.
+#[derive(Debug)]
+pub struct Scheduler<TClock>
+where
+    TClock: TimeSource,
+{
+    clock: TClock,
+    instructions_per_frame: u32,
+
+    // Frames are counted from `epoch` so that rounding never accumulates into drift
+    epoch: Duration,
+    frames_run: u64,
+}
+
+impl Default for Scheduler<SystemClock> {
+    fn default() -> Self {
+        Self::with_clock(SystemClock::default())
+    }
+}
+
+impl Scheduler<SystemClock> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl<TClock> Scheduler<TClock>
+where
+    TClock: TimeSource,
+{
+    pub fn with_clock(clock: TClock) -> Self {
+        let epoch = clock.now();
+
+        Self {
+            clock,
+            instructions_per_frame: 0,
+            epoch,
+            frames_run: 0,
+        }
+        .instructions_per_second(DEFAULT_INSTRUCTIONS_PER_SECOND)
+    }
+
+    /// Sets the CPU speed. The speed is rounded to a whole number of instructions per frame.
+    pub fn instructions_per_second(mut self, ips: u32) -> Self {
+        self.instructions_per_frame =
+            (ips.saturating_add(FRAMES_PER_SECOND / 2) / FRAMES_PER_SECOND).max(1);
+        self
+    }
+
+    pub fn instructions_per_frame(&self) -> u32 {
+        self.instructions_per_frame
+    }
+
+    pub fn clock(&self) -> &TClock {
+        &self.clock
+    }
+
+    /// Returns how many frames have started since the last call, and marks them as run.
+    pub fn frames_due(&mut self) -> u32 {
+        let elapsed = self.clock.now().saturating_sub(self.epoch);
+        let frames_elapsed =
+            (elapsed.as_nanos() * FRAMES_PER_SECOND as u128 / 1_000_000_000) as u64;
+
+        let mut due = frames_elapsed - self.frames_run;
+        if due > MAX_CATCH_UP_FRAMES {
+            due = MAX_CATCH_UP_FRAMES;
+        }
+        self.frames_run = frames_elapsed;
+
+        due as u32
+    }
+
+    /// Forgets any time that has passed, e.g. while execution was paused.
+    pub fn reset(&mut self) {
+        self.epoch = self.clock.now();
+        self.frames_run = 0;
+    }
+
+    /// Runs a single frame: ticks the timers, then executes this frame's instructions.
+    ///
+    /// The frame ends early if the CPU is blocked until the next frame or the program has exited.
+    pub fn run_frame<TKeyboard>(&self, cpu: &mut CPU<TKeyboard>) -> Result<(), Chip8Error>
+    where
+        TKeyboard: Keyboard,
+    {
+        self.run_frame_limited(cpu, self.instructions_per_frame)
+            .map(|_| ())
+    }
+
+    /// Runs a single frame like [`Scheduler::run_frame`], but executes no more than
+    /// `max_instructions`. Returns the number of instructions executed.
+    pub fn run_frame_limited<TKeyboard>(
+        &self,
+        cpu: &mut CPU<TKeyboard>,
+        max_instructions: u32,
+    ) -> Result<u32, Chip8Error>
+    where
+        TKeyboard: Keyboard,
+    {
+        let mut remaining = max_instructions;
+        self.run_frame_until(cpu, |_| {
+            if remaining == 0 {
+                return true;
+            }
+            remaining -= 1;
+            false
+        })
+    }
+
+    /// Runs a single frame like [`Scheduler::run_frame`], but calls `should_stop` before each
+    /// instruction and ends the frame early, without executing it, if it returns `true`.
+    /// Returns the number of instructions executed.
+    pub fn run_frame_until<TKeyboard, F>(
+        &self,
+        cpu: &mut CPU<TKeyboard>,
+        mut should_stop: F,
+    ) -> Result<u32, Chip8Error>
+    where
+        TKeyboard: Keyboard,
+        F: FnMut(&CPU<TKeyboard>) -> bool,
+    {
+        cpu.vblank();
+        cpu.decrement_delay_timer();
+        cpu.decrement_sound_timer();
+
+        let mut executed = 0;
+        while executed < self.instructions_per_frame && !should_stop(cpu) {
+            match cpu.execute_next_instruction()? {
+                StepOutcome::WaitingForVBlank
+                | StepOutcome::WaitingForKey
+                | StepOutcome::Exited => break,
+                StepOutcome::Executed(_) => {}
+            }
+            executed += 1;
+        }
+
+        Ok(executed)
+    }
+
+    /// Runs every frame that is due. Returns the number of frames run.
+    pub fn update<TKeyboard>(&mut self, cpu: &mut CPU<TKeyboard>) -> Result<u32, Chip8Error>
+    where
+        TKeyboard: Keyboard,
+    {
+        let due = self.frames_due();
+
+        for _ in 0..due {
+            self.run_frame(cpu)?;
+        }
+
+        Ok(due)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::{
+        cpu::CPU, display::Display, keyboard::dummy_keyboard::DummyKeyboard, memory::Memory,
+        quirks::Quirks,
+    };
+
+    use super::{ManualClock, Scheduler};
+
+    const FRAME: Duration = Duration::from_nanos(16_666_667);
+
+    /// A CPU running `ADD V0, 1` followed by a jump back to it.
+    fn get_counting_cpu() -> CPU<DummyKeyboard> {
+        let mut memory = Memory::initialise();
+        memory.insert_instruction(0x200, 0x7001);
+        memory.insert_instruction(0x202, 0x1200);
+
+        CPU::initialise(
+            memory,
+            Display::initialise(),
+            DummyKeyboard::initialise(),
+            Quirks::default(),
+        )
+    }
+
+    fn get_scheduler(ips: u32) -> Scheduler<ManualClock> {
+        Scheduler::with_clock(ManualClock::default()).instructions_per_second(ips)
+    }
+
+    #[test]
+    fn should_default_to_700_instructions_per_second() {
+        assert_eq!(Scheduler::new().instructions_per_frame(), 12);
+    }
+
+    #[test]
+    fn should_not_overflow_at_the_largest_speed() {
+        assert_eq!(
+            get_scheduler(u32::MAX).instructions_per_frame(),
+            u32::MAX / 60
+        );
+    }
+
+    #[test]
+    fn should_not_run_before_a_frame_has_passed() {
+        let mut scheduler = get_scheduler(600);
+        let mut cpu = get_counting_cpu();
+
+        scheduler.clock().advance(FRAME / 2);
+
+        assert_eq!(scheduler.update(&mut cpu), Ok(0));
+        assert_eq!(cpu.v[0], 0);
+    }
+
+    #[test]
+    fn should_run_instructions_per_frame() {
+        let mut scheduler = get_scheduler(600);
+        let mut cpu = get_counting_cpu();
+
+        scheduler.clock().advance(FRAME);
+
+        assert_eq!(scheduler.update(&mut cpu), Ok(1));
+        // 10 instructions, every other one is the ADD
+        assert_eq!(cpu.v[0], 5);
+    }
+
+    #[test]
+    fn should_tick_timers_at_60hz() {
+        let mut scheduler = get_scheduler(600);
+        let mut cpu = get_counting_cpu();
+        cpu.delay_timer = 0xFF;
+        cpu.sound_timer = 0xFF;
+
+        for _ in 0..100 {
+            scheduler.clock().advance(Duration::from_millis(10));
+            scheduler.update(&mut cpu).unwrap();
+        }
+
+        assert_eq!(cpu.delay_timer, 0xFF - 60);
+        assert_eq!(cpu.sound_timer, 0xFF - 60);
+    }
+
+    #[test]
+    fn should_drop_frames_when_far_behind() {
+        let mut scheduler = get_scheduler(600);
+        let mut cpu = get_counting_cpu();
+
+        scheduler.clock().advance(Duration::from_secs(5));
+
+        assert_eq!(scheduler.update(&mut cpu), Ok(10));
+        scheduler.clock().advance(FRAME);
+        assert_eq!(scheduler.update(&mut cpu), Ok(1));
+    }
+
+    #[test]
+    fn should_limit_instructions_run_in_frame() {
+        let scheduler = get_scheduler(600);
+        let mut cpu = get_counting_cpu();
+
+        assert_eq!(scheduler.run_frame_limited(&mut cpu, 3), Ok(3));
+        assert_eq!(cpu.v[0], 2);
+    }
+
+    #[test]
+    fn should_stop_frame_before_instruction() {
+        let scheduler = get_scheduler(600);
+        let mut cpu = get_counting_cpu();
+
+        assert_eq!(
+            scheduler.run_frame_until(&mut cpu, |cpu| cpu.v[0] == 4),
+            Ok(7)
+        );
+        assert_eq!(cpu.v[0], 4);
+        assert_eq!(cpu.pc, 0x202);
+    }
+
+    #[test]
+    fn should_forget_time_passed_when_reset() {
+        let mut scheduler = get_scheduler(600);
+        let mut cpu = get_counting_cpu();
+
+        scheduler.clock().advance(FRAME * 3);
+        scheduler.reset();
+
+        assert_eq!(scheduler.update(&mut cpu), Ok(0));
+    }
+}
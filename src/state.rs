@@ -0,0 +1,377 @@
+//! Save states: a snapshot of the whole machine and its binary encoding.
+//!
+//! A save state starts with a header holding a magic number, the format version and the SHA-1
+//! of the ROM that was loaded, followed by the registers, memory and screen. All multi-byte
+//! values are big endian.
+
+use std::{error::Error, fmt};
+
+use crate::sha1;
+
+const MAGIC: [u8; 4] = *b"C8SS";
+
+// The XO-CHIP display has two planes
+const PLANES: u8 = 2;
+
+/// The version of the save state format written by [`Snapshot::to_bytes`].
+pub const FORMAT_VERSION: u16 = 6;
+
+/// Errors raised while restoring a save state.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StateError {
+    /// The data does not start with the save state magic number.
+    NotASaveState,
+    /// The save state was written by a newer or older, incompatible version.
+    UnsupportedVersion(u16),
+    /// The data ends before the save state does.
+    Truncated,
+    /// The save state was made while a different ROM was loaded.
+    RomMismatch,
+    /// The memory or screen in the save state is a different size to the machine's.
+    SizeMismatch,
+    /// A register in the save state holds a value the machine can't, like a stack pointer past
+    /// the end of the stack or a key wait for a register that doesn't exist.
+    InvalidRegisters,
+}
+
+impl fmt::Display for StateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StateError::NotASaveState => write!(f, "not a save state"),
+            StateError::UnsupportedVersion(version) => write!(
+                f,
+                "save state format version {} is not supported (expected {})",
+                version, FORMAT_VERSION
+            ),
+            StateError::Truncated => write!(f, "save state is truncated"),
+            StateError::RomMismatch => write!(f, "save state was made with a different ROM"),
+            StateError::SizeMismatch => {
+                write!(f, "save state memory or screen size does not match")
+            }
+            StateError::InvalidRegisters => write!(f, "save state has invalid registers"),
+        }
+    }
+}
+
+impl Error for StateError {}
+
+/// The complete state of a machine at a point in time.
+///
+/// Taken with [`crate::cpu::CPU::snapshot`] and restored with [`crate::cpu::CPU::restore`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Snapshot {
+    /// The SHA-1 of the ROM in memory, if the memory was loaded from a [`crate::rom::Rom`].
+    pub rom_sha1: Option<[u8; sha1::DIGEST_LEN]>,
+
+    pub v: [u8; 0x10],
+    pub vi: u16,
+    pub pc: u16,
+    pub sp: u8,
+    pub stack: [u16; 16],
+    pub rpl_flags: [u8; 0x10],
+    pub audio_pattern: [u8; 0x10],
+    pub pitch: u8,
+    pub delay_timer: u8,
+    pub sound_timer: u8,
+    pub vblank: bool,
+    /// Whether the program has exited with `00FD`.
+    pub exited: bool,
+    /// The register an `FX0A` waiting for a key will store it in.
+    pub waiting_for_key: Option<u8>,
+
+    /// The keys held down, as a bitmask with bit `n` set if key `n` is down.
+    pub keys: u16,
+    /// The state of `CXKK`'s random number source, if it can be saved.
+    pub rng_state: Option<u64>,
+
+    pub memory: Vec<u8>,
+
+    pub screen_width: usize,
+    pub screen_height: usize,
+    /// The palette index of each pixel, row by row. Bit 0 is the first plane, bit 1 the second.
+    pub screen: Vec<u8>,
+    /// The display planes selected for drawing.
+    pub selected_planes: u8,
+}
+
+impl Snapshot {
+    /// Encodes the snapshot in the save state format.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = MAGIC.to_vec();
+        out.extend_from_slice(&FORMAT_VERSION.to_be_bytes());
+
+        match self.rom_sha1 {
+            Some(sha1) => {
+                out.push(1);
+                out.extend_from_slice(&sha1);
+            }
+            None => {
+                out.push(0);
+                out.extend_from_slice(&[0; sha1::DIGEST_LEN]);
+            }
+        }
+
+        out.extend_from_slice(&self.v);
+        out.extend_from_slice(&self.vi.to_be_bytes());
+        out.extend_from_slice(&self.pc.to_be_bytes());
+        out.push(self.sp);
+        for address in self.stack.iter() {
+            out.extend_from_slice(&address.to_be_bytes());
+        }
+        out.extend_from_slice(&self.rpl_flags);
+        out.extend_from_slice(&self.audio_pattern);
+        out.push(self.pitch);
+        out.push(self.delay_timer);
+        out.push(self.sound_timer);
+        out.push(self.vblank as u8);
+        out.push(self.exited as u8);
+        match self.waiting_for_key {
+            Some(register) => out.extend_from_slice(&[1, register]),
+            None => out.extend_from_slice(&[0, 0]),
+        }
+        out.extend_from_slice(&self.keys.to_be_bytes());
+        match self.rng_state {
+            Some(state) => {
+                out.push(1);
+                out.extend_from_slice(&state.to_be_bytes());
+            }
+            None => {
+                out.push(0);
+                out.extend_from_slice(&[0; 8]);
+            }
+        }
+
+        out.extend_from_slice(&(self.memory.len() as u32).to_be_bytes());
+        out.extend_from_slice(&self.memory);
+
+        out.extend_from_slice(&(self.screen_width as u16).to_be_bytes());
+        out.extend_from_slice(&(self.screen_height as u16).to_be_bytes());
+        out.push(self.selected_planes);
+        // Each plane is packed 8 pixels to a byte, most significant bit first
+        for plane in 0..PLANES {
+            for pixels in self.screen.chunks(8) {
+                let byte = pixels.iter().enumerate().fold(0u8, |byte, (i, pixel)| {
+                    byte | (((pixel >> plane) & 1) << (7 - i))
+                });
+                out.push(byte);
+            }
+        }
+
+        out
+    }
+
+    /// Decodes a snapshot from the save state format.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, StateError> {
+        let mut reader = Reader::new(bytes);
+
+        if reader.take(MAGIC.len()).ok() != Some(&MAGIC[..]) {
+            return Err(StateError::NotASaveState);
+        }
+
+        let version = reader.u16()?;
+        if version != FORMAT_VERSION {
+            return Err(StateError::UnsupportedVersion(version));
+        }
+
+        let has_rom = reader.u8()? != 0;
+        let mut sha1 = [0; sha1::DIGEST_LEN];
+        sha1.copy_from_slice(reader.take(sha1::DIGEST_LEN)?);
+        let rom_sha1 = if has_rom { Some(sha1) } else { None };
+
+        let mut v = [0; 0x10];
+        v.copy_from_slice(reader.take(0x10)?);
+        let vi = reader.u16()?;
+        let pc = reader.u16()?;
+        let sp = reader.u8()?;
+        let mut stack = [0; 16];
+        for address in stack.iter_mut() {
+            *address = reader.u16()?;
+        }
+        let mut rpl_flags = [0; 0x10];
+        rpl_flags.copy_from_slice(reader.take(0x10)?);
+        let mut audio_pattern = [0; 0x10];
+        audio_pattern.copy_from_slice(reader.take(0x10)?);
+        let pitch = reader.u8()?;
+        let delay_timer = reader.u8()?;
+        let sound_timer = reader.u8()?;
+        let vblank = reader.u8()? != 0;
+        let exited = reader.u8()? != 0;
+        let waiting = reader.u8()? != 0;
+        let register = reader.u8()?;
+        let waiting_for_key = if waiting { Some(register) } else { None };
+        let keys = reader.u16()?;
+        let has_rng_state = reader.u8()? != 0;
+        let rng_state = reader.u64()?;
+        let rng_state = if has_rng_state { Some(rng_state) } else { None };
+
+        let memory_len = reader.u32()? as usize;
+        let memory = reader.take(memory_len)?.to_vec();
+
+        let screen_width = reader.u16()? as usize;
+        let screen_height = reader.u16()? as usize;
+        let selected_planes = reader.u8()?;
+        let pixel_count = screen_width * screen_height;
+        let mut screen = vec![0; pixel_count];
+        for plane in 0..PLANES {
+            let bits = reader
+                .take(pixel_count.div_ceil(8))?
+                .iter()
+                .flat_map(|byte| (0..8).rev().map(move |i| (byte >> i) & 1));
+
+            for (pixel, bit) in screen.iter_mut().zip(bits) {
+                *pixel |= bit << plane;
+            }
+        }
+
+        Ok(Snapshot {
+            rom_sha1,
+            v,
+            vi,
+            pc,
+            sp,
+            stack,
+            rpl_flags,
+            audio_pattern,
+            pitch,
+            delay_timer,
+            sound_timer,
+            vblank,
+            exited,
+            waiting_for_key,
+            keys,
+            rng_state,
+            memory,
+            screen_width,
+            screen_height,
+            screen,
+            selected_planes,
+        })
+    }
+}
+
+/// Reads big endian values from the front of a buffer, failing with [`StateError::Truncated`]
+/// when it runs out.
+pub(crate) struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    pub(crate) fn new(bytes: &'a [u8]) -> Self {
+        Self { bytes }
+    }
+
+    pub(crate) fn take(&mut self, len: usize) -> Result<&'a [u8], StateError> {
+        if self.bytes.len() < len {
+            return Err(StateError::Truncated);
+        }
+
+        let (taken, rest) = self.bytes.split_at(len);
+        self.bytes = rest;
+
+        Ok(taken)
+    }
+
+    pub(crate) fn u8(&mut self) -> Result<u8, StateError> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub(crate) fn u16(&mut self) -> Result<u16, StateError> {
+        let bytes = self.take(2)?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    pub(crate) fn u32(&mut self) -> Result<u32, StateError> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    pub(crate) fn u64(&mut self) -> Result<u64, StateError> {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(self.take(8)?);
+        Ok(u64::from_be_bytes(bytes))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Snapshot, StateError, FORMAT_VERSION};
+
+    fn get_snapshot() -> Snapshot {
+        let mut stack = [0; 16];
+        stack[0] = 0x234;
+
+        Snapshot {
+            rom_sha1: Some([0xAB; 20]),
+            v: [0x7; 0x10],
+            vi: 0x300,
+            pc: 0x206,
+            sp: 1,
+            stack,
+            rpl_flags: [0x3; 0x10],
+            audio_pattern: [0x55; 0x10],
+            pitch: 70,
+            delay_timer: 30,
+            sound_timer: 2,
+            vblank: true,
+            exited: false,
+            waiting_for_key: Some(0xA),
+            keys: 0b1000_0000_0010_0000,
+            rng_state: Some(0x0123_4567_89AB_CDEF),
+            memory: (0..=255).cycle().take(0x10000).collect(),
+            screen_width: 5,
+            screen_height: 3,
+            screen: vec![
+                1, 0, 0, 0, 3, //
+                0, 2, 1, 0, 0, //
+                0, 0, 0, 0, 1,
+            ],
+            selected_planes: 0b11,
+        }
+    }
+
+    #[test]
+    fn should_round_trip_snapshot() {
+        let snapshot = get_snapshot();
+
+        assert_eq!(Snapshot::from_bytes(&snapshot.to_bytes()), Ok(snapshot));
+    }
+
+    #[test]
+    fn should_write_header() {
+        let bytes = get_snapshot().to_bytes();
+
+        assert_eq!(&bytes[..4], b"C8SS");
+        assert_eq!(&bytes[4..6], &FORMAT_VERSION.to_be_bytes());
+        assert_eq!(bytes[6], 1);
+        assert_eq!(&bytes[7..27], &[0xAB; 20]);
+    }
+
+    #[test]
+    fn should_reject_other_data() {
+        assert_eq!(
+            Snapshot::from_bytes(b"\x12\x00\x00\xE0"),
+            Err(StateError::NotASaveState)
+        );
+    }
+
+    #[test]
+    fn should_reject_unsupported_version() {
+        let mut bytes = get_snapshot().to_bytes();
+        bytes[5] = 0xFF;
+
+        assert_eq!(
+            Snapshot::from_bytes(&bytes),
+            Err(StateError::UnsupportedVersion(0xFF))
+        );
+    }
+
+    #[test]
+    fn should_reject_truncated_state() {
+        let bytes = get_snapshot().to_bytes();
+
+        assert_eq!(
+            Snapshot::from_bytes(&bytes[..bytes.len() - 1]),
+            Err(StateError::Truncated)
+        );
+    }
+}
@@ -0,0 +1,25 @@
+//! Reading from the debuggers' connections without holding up the emulator.
+
+use std::{
+    io::{self, ErrorKind, Read},
+    net::TcpStream,
+};
+
+/// Appends whatever the client has sent to `input`, without waiting for more. Returns false
+/// once the connection is closed.
+pub fn receive(stream: &mut TcpStream, input: &mut Vec<u8>) -> io::Result<bool> {
+    stream.set_nonblocking(true)?;
+    let mut buffer = [0; 0x1000];
+    let result = loop {
+        match stream.read(&mut buffer) {
+            Ok(0) => break Ok(false),
+            Ok(n) => input.extend_from_slice(&buffer[..n]),
+            Err(e) if e.kind() == ErrorKind::WouldBlock => break Ok(true),
+            Err(e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(e) => break Err(e),
+        }
+    };
+    stream.set_nonblocking(false)?;
+
+    result
+}
@@ -0,0 +1,335 @@
+//! Just enough TOML for the key mapping config.
+//!
+//! A document is a list of tables, each with a dotted header like `[roms.abc123]` and the
+//! `key = value` pairs under it, with the line each was on so errors can point at it. Values
+//! are strings, integers, booleans and arrays of them; inline tables, dates and floats aren't
+//! supported.
+
+use std::{iter::Peekable, str::Chars};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Value {
+    String(String),
+    Integer(i64),
+    Boolean(bool),
+    Array(Vec<Value>),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    pub key: String,
+    pub value: Value,
+    pub line: usize,
+}
+
+/// A table and its entries. Entries before the first header are in a table with an empty path.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Table {
+    pub path: Vec<String>,
+    pub line: usize,
+    pub entries: Vec<Entry>,
+}
+
+/// A syntax error, with the line it is on.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TomlError {
+    pub line: usize,
+    pub message: String,
+}
+
+pub fn parse(text: &str) -> Result<Vec<Table>, TomlError> {
+    let mut parser = Parser {
+        chars: text.chars().peekable(),
+        line: 1,
+    };
+    let mut tables = vec![Table {
+        path: vec![],
+        line: 1,
+        entries: vec![],
+    }];
+
+    loop {
+        parser.skip_whitespace();
+        let line = parser.line;
+        let error = |message: String| TomlError { line, message };
+
+        match parser.chars.peek() {
+            None => break,
+            Some('[') => {
+                parser.chars.next();
+                let path = parser.key_path(']').map_err(error)?;
+                if tables.iter().any(|table| table.path == path) {
+                    return Err(error(format!(
+                        "table `{}` is defined twice",
+                        path.join(".")
+                    )));
+                }
+                tables.push(Table {
+                    path,
+                    line,
+                    entries: vec![],
+                });
+            }
+            Some(_) => {
+                let key = parser.key().map_err(error)?;
+                parser.expect('=').map_err(error)?;
+                let value = parser.value().map_err(|message| TomlError {
+                    line: parser.line,
+                    message,
+                })?;
+
+                let table = tables.last_mut().unwrap();
+                if table.entries.iter().any(|entry| entry.key == key) {
+                    return Err(error(format!("`{}` is set twice", key)));
+                }
+                table.entries.push(Entry { key, value, line });
+            }
+        }
+
+        parser.end_of_line().map_err(|message| TomlError {
+            line: parser.line,
+            message,
+        })?;
+    }
+
+    Ok(tables)
+}
+
+struct Parser<'a> {
+    chars: Peekable<Chars<'a>>,
+    line: usize,
+}
+
+impl Parser<'_> {
+    fn skip_spaces(&mut self) {
+        while self.chars.next_if(|c| *c == ' ' || *c == '\t').is_some() {}
+    }
+
+    fn skip_comment(&mut self) {
+        if self.chars.next_if_eq(&'#').is_some() {
+            while self.chars.next_if(|c| *c != '\n').is_some() {}
+        }
+    }
+
+    fn newline(&mut self) -> bool {
+        self.chars.next_if_eq(&'\r');
+        if self.chars.next_if_eq(&'\n').is_some() {
+            self.line += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Spaces, comments and newlines, which can come between lines and between array values.
+    fn skip_whitespace(&mut self) {
+        loop {
+            self.skip_spaces();
+            self.skip_comment();
+            if !self.newline() {
+                return;
+            }
+        }
+    }
+
+    fn end_of_line(&mut self) -> Result<(), String> {
+        self.skip_spaces();
+        self.skip_comment();
+        match self.chars.peek().copied() {
+            None => Ok(()),
+            Some(_) if self.newline() => Ok(()),
+            Some(c) => Err(format!("unexpected `{}` at the end of the line", c)),
+        }
+    }
+
+    fn expect(&mut self, expected: char) -> Result<(), String> {
+        self.skip_spaces();
+        match self.chars.next() {
+            Some(c) if c == expected => Ok(()),
+            Some(c) => Err(format!("expected `{}`, found `{}`", expected, c)),
+            None => Err(format!("expected `{}`", expected)),
+        }
+    }
+
+    fn key(&mut self) -> Result<String, String> {
+        self.skip_spaces();
+        if self.chars.peek() == Some(&'"') {
+            return self.string();
+        }
+
+        let mut key = String::new();
+        while let Some(c) = self
+            .chars
+            .next_if(|c| c.is_ascii_alphanumeric() || *c == '_' || *c == '-')
+        {
+            key.push(c);
+        }
+
+        if key.is_empty() {
+            match self.chars.peek() {
+                Some(c) if *c != '\n' && *c != '\r' => {
+                    Err(format!("expected a key, found `{}`", c))
+                }
+                _ => Err("expected a key".to_string()),
+            }
+        } else {
+            Ok(key)
+        }
+    }
+
+    /// Dotted keys, as in a table header, up to the closing bracket.
+    fn key_path(&mut self, end: char) -> Result<Vec<String>, String> {
+        let mut path = vec![self.key()?];
+        loop {
+            self.skip_spaces();
+            match self.chars.next() {
+                Some('.') => path.push(self.key()?),
+                Some(c) if c == end => return Ok(path),
+                _ => return Err(format!("expected `.` or `{}` in the table header", end)),
+            }
+        }
+    }
+
+    fn value(&mut self) -> Result<Value, String> {
+        self.skip_spaces();
+        match self.chars.peek().copied() {
+            Some('"') => self.string().map(Value::String),
+            Some('[') => {
+                self.chars.next();
+                let mut values = vec![];
+                loop {
+                    self.skip_whitespace();
+                    if self.chars.next_if_eq(&']').is_some() {
+                        return Ok(Value::Array(values));
+                    }
+                    values.push(self.value()?);
+
+                    self.skip_whitespace();
+                    match self.chars.next() {
+                        Some(',') => {}
+                        Some(']') => return Ok(Value::Array(values)),
+                        _ => return Err("expected `,` or `]` in an array".to_string()),
+                    }
+                }
+            }
+            Some(c) if c.is_ascii_alphanumeric() || c == '+' || c == '-' => {
+                let mut word = String::new();
+                while let Some(c) = self
+                    .chars
+                    .next_if(|c| c.is_ascii_alphanumeric() || "+-_".contains(*c))
+                {
+                    word.push(c);
+                }
+
+                match word.as_str() {
+                    "true" => Ok(Value::Boolean(true)),
+                    "false" => Ok(Value::Boolean(false)),
+                    _ => parse_integer(&word)
+                        .map(Value::Integer)
+                        .ok_or_else(|| format!("invalid value `{}`", word)),
+                }
+            }
+            Some(c) => Err(format!("unexpected `{}`", c)),
+            None => Err("expected a value".to_string()),
+        }
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        self.expect('"')?;
+
+        let mut s = String::new();
+        loop {
+            match self.chars.next() {
+                Some('"') => return Ok(s),
+                Some('\\') => {
+                    let c = match self.chars.next() {
+                        Some('n') => '\n',
+                        Some('r') => '\r',
+                        Some('t') => '\t',
+                        Some('"') => '"',
+                        Some('\\') => '\\',
+                        Some(c) => return Err(format!("invalid escape `\\{}`", c)),
+                        None => return Err("unterminated string".to_string()),
+                    };
+                    s.push(c);
+                }
+                Some('\n') | None => return Err("unterminated string".to_string()),
+                Some(c) => s.push(c),
+            }
+        }
+    }
+}
+
+fn parse_integer(word: &str) -> Option<i64> {
+    let digits = word.replace('_', "");
+    let (negative, digits) = match digits.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, digits.strip_prefix('+').unwrap_or(&digits)),
+    };
+
+    let value = match digits.get(..2) {
+        Some("0x") => i64::from_str_radix(&digits[2..], 16),
+        Some("0o") => i64::from_str_radix(&digits[2..], 8),
+        Some("0b") => i64::from_str_radix(&digits[2..], 2),
+        _ => digits.parse(),
+    }
+    .ok()?;
+
+    Some(if negative { -value } else { value })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse, Value};
+
+    #[test]
+    fn should_parse_tables_and_values() {
+        let tables = parse(
+            "top = 1 # a comment\n\n[keys]\npreset = \"qw\\\"erty\"\nA = [\"Z\",\n  \"Space\", # too\n]\n\n[roms.\"ab cd\"]\nx = 0x1F\ny = -1_000\nz = false\n",
+        )
+        .unwrap();
+
+        assert_eq!(tables.len(), 3);
+        assert_eq!(tables[0].entries[0].value, Value::Integer(1));
+
+        assert_eq!(tables[1].path, ["keys"]);
+        assert_eq!(tables[1].line, 3);
+        assert_eq!(tables[1].entries[0].key, "preset");
+        assert_eq!(
+            tables[1].entries[0].value,
+            Value::String("qw\"erty".to_string())
+        );
+        assert_eq!(
+            tables[1].entries[1].value,
+            Value::Array(vec![
+                Value::String("Z".to_string()),
+                Value::String("Space".to_string())
+            ])
+        );
+        assert_eq!(tables[1].entries[1].line, 5);
+
+        assert_eq!(tables[2].path, ["roms", "ab cd"]);
+        let values: Vec<&Value> = tables[2].entries.iter().map(|e| &e.value).collect();
+        assert_eq!(
+            values,
+            [
+                &Value::Integer(0x1F),
+                &Value::Integer(-1000),
+                &Value::Boolean(false)
+            ]
+        );
+    }
+
+    #[test]
+    fn should_report_the_line_of_errors() {
+        let line = |text: &str| parse(text).unwrap_err().line;
+
+        assert_eq!(line("a = 1\nb = \"open\n"), 2);
+        assert_eq!(line("[keys]\na = 1\na = 2"), 3);
+        assert_eq!(line("[keys]\n[keys]"), 2);
+        assert_eq!(line("a = 1 2"), 1);
+        assert_eq!(line("a = [1,\n2\n3]"), 3);
+        assert_eq!(line("\n= 1"), 2);
+        assert_eq!(line("a = nope"), 1);
+    }
+}
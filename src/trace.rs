@@ -0,0 +1,378 @@
+//! Execution traces, one line per instruction, for comparing runs with each other or with other
+//! emulators.
+//!
+//! Each line shows the frame, the instruction and the registers as they were before it ran:
+//!
+//! ```text
+//! frame=0 pc=0200 op=6005 v=00000000000000000000000000000000 i=0000 sp=00 dt=00 st=00 ; LD V0, 0x05
+//! ```
+//!
+//! The `v` field holds `V0` to `VF` as 16 pairs of hex digits. [`diff`] finds the first line
+//! where two traces diverge and names the fields that differ.
+
+use std::{
+    fmt,
+    io::{self, Write},
+    ops::RangeInclusive,
+};
+
+use crate::{
+    cpu::CPU,
+    debugger,
+    error::Chip8Error,
+    keyboard::Keyboard,
+    scheduler::{Scheduler, TimeSource},
+};
+
+/// Which instructions to trace. Everything is traced by default.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TraceFilter {
+    pcs: Option<RangeInclusive<u16>>,
+    frames: Option<RangeInclusive<u64>>,
+}
+
+impl TraceFilter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Only traces instructions at addresses in the range.
+    pub fn pcs(mut self, pcs: RangeInclusive<u16>) -> Self {
+        self.pcs = Some(pcs);
+        self
+    }
+
+    /// Only traces instructions run during the frames in the range, counting from 0.
+    pub fn frames(mut self, frames: RangeInclusive<u64>) -> Self {
+        self.frames = Some(frames);
+        self
+    }
+
+    pub fn matches(&self, frame: u64, pc: u16) -> bool {
+        self.pcs.as_ref().is_none_or(|pcs| pcs.contains(&pc))
+            && self
+                .frames
+                .as_ref()
+                .is_none_or(|frames| frames.contains(&frame))
+    }
+}
+
+/// Formats the trace line for the instruction at the program counter.
+pub fn trace_line<TKeyboard>(frame: u64, cpu: &CPU<TKeyboard>) -> String
+where
+    TKeyboard: Keyboard,
+{
+    let op = match (
+        cpu.memory.data.get(cpu.pc as usize),
+        cpu.memory.data.get(cpu.pc as usize + 1),
+    ) {
+        (Some(high), Some(low)) => format!("{:02X}{:02X}", high, low),
+        _ => "????".to_string(),
+    };
+    let mnemonic = debugger::decode_at(cpu, cpu.pc).map_or_else(String::new, |i| i.to_string());
+    let v: String = cpu.v.iter().map(|v| format!("{:02X}", v)).collect();
+
+    format!(
+        "frame={} pc={:04X} op={} v={} i={:04X} sp={:02X} dt={:02X} st={:02X} ; {}",
+        frame, cpu.pc, op, v, cpu.vi, cpu.sp, cpu.delay_timer, cpu.sound_timer, mnemonic
+    )
+}
+
+/// Writes a trace of the instructions run, a frame at a time.
+///
+/// Write errors don't stop the program; the first one is kept and returned by
+/// [`Tracer::finish`].
+#[derive(Debug)]
+pub struct Tracer<W>
+where
+    W: Write,
+{
+    out: W,
+    filter: TraceFilter,
+    frame: u64,
+    error: Option<io::Error>,
+}
+
+impl<W> Tracer<W>
+where
+    W: Write,
+{
+    pub fn new(out: W) -> Self {
+        Self {
+            out,
+            filter: TraceFilter::default(),
+            frame: 0,
+            error: None,
+        }
+    }
+
+    pub fn filter(mut self, filter: TraceFilter) -> Self {
+        self.filter = filter;
+        self
+    }
+
+    /// The number of frames run so far, which is the number of the next one.
+    pub fn frame(&self) -> u64 {
+        self.frame
+    }
+
+    /// Runs a frame like [`Scheduler::run_frame_limited`], tracing each instruction it runs.
+    pub fn run_frame<TKeyboard, TClock>(
+        &mut self,
+        scheduler: &Scheduler<TClock>,
+        cpu: &mut CPU<TKeyboard>,
+        max_instructions: u32,
+    ) -> Result<u32, Chip8Error>
+    where
+        TKeyboard: Keyboard,
+        TClock: TimeSource,
+    {
+        let frame = self.frame;
+        self.frame += 1;
+
+        let filter = &self.filter;
+        let mut remaining = max_instructions;
+        let mut lines = vec![];
+        let result = scheduler.run_frame_until(cpu, |cpu| {
+            if remaining == 0 {
+                return true;
+            }
+            remaining -= 1;
+
+            lines.push(
+                filter
+                    .matches(frame, cpu.pc)
+                    .then(|| trace_line(frame, cpu)),
+            );
+            false
+        });
+
+        // A draw waiting for the next frame, or a fault, ends the frame without running the
+        // last instruction it started, so only the ones that ran are written
+        let ran = match &result {
+            Ok(executed) => *executed as usize,
+            Err(_) => lines.len().saturating_sub(1),
+        };
+        for line in lines.into_iter().take(ran).flatten() {
+            self.write_line(&line);
+        }
+
+        result
+    }
+
+    fn write_line(&mut self, line: &str) {
+        if self.error.is_none() {
+            if let Err(e) = writeln!(self.out, "{}", line) {
+                self.error = Some(e);
+            }
+        }
+    }
+
+    /// Flushes the trace, returning the writer or the first error writing to it.
+    pub fn finish(mut self) -> io::Result<W> {
+        if let Some(e) = self.error.take() {
+            return Err(e);
+        }
+
+        self.out.flush()?;
+        Ok(self.out)
+    }
+}
+
+/// Where two traces first differ.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Divergence {
+    /// The line number, starting at 1.
+    pub line: usize,
+    /// The line in each trace, or `None` where that trace had already ended.
+    pub left: Option<String>,
+    pub right: Option<String>,
+    /// The names of the fields that differ, e.g. `pc` or `V3`.
+    pub fields: Vec<String>,
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "The traces diverge at line {}:", self.line)?;
+        writeln!(f, "< {}", self.left.as_deref().unwrap_or("(end of trace)"))?;
+        write!(f, "> {}", self.right.as_deref().unwrap_or("(end of trace)"))?;
+        if !self.fields.is_empty() {
+            write!(f, "\nDiffering: {}", self.fields.join(", "))?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Finds the first line where two traces differ, or `None` if they are the same.
+pub fn diff(left: &str, right: &str) -> Option<Divergence> {
+    let mut left_lines = left.lines();
+    let mut right_lines = right.lines();
+
+    let mut line = 0;
+    loop {
+        line += 1;
+        match (left_lines.next(), right_lines.next()) {
+            (None, None) => return None,
+            (Some(l), Some(r)) if l == r => {}
+            (l, r) => {
+                return Some(Divergence {
+                    line,
+                    left: l.map(str::to_string),
+                    right: r.map(str::to_string),
+                    fields: match (l, r) {
+                        (Some(l), Some(r)) => differing_fields(l, r),
+                        _ => vec![],
+                    },
+                })
+            }
+        }
+    }
+}
+
+/// The `key=value` fields of a line, with the `v` field split into each register.
+fn fields(line: &str) -> Vec<(String, String)> {
+    let registers = line.split(" ; ").next().unwrap_or_default();
+    let mut fields = vec![];
+
+    for field in registers.split_whitespace() {
+        match field.split_once('=') {
+            Some(("v", values)) if values.len() == 32 && values.is_ascii() => {
+                for x in 0..16 {
+                    fields.push((format!("V{:X}", x), values[x * 2..x * 2 + 2].to_string()));
+                }
+            }
+            Some((key, value)) => fields.push((key.to_string(), value.to_string())),
+            None => fields.push((field.to_string(), String::new())),
+        }
+    }
+
+    fields
+}
+
+fn differing_fields(left: &str, right: &str) -> Vec<String> {
+    let left_fields = fields(left);
+    let right_fields = fields(right);
+
+    let mut differing: Vec<String> = left_fields
+        .iter()
+        .filter(|field| !right_fields.contains(field))
+        .map(|(key, _)| key.clone())
+        .collect();
+    // Fields only the right line has
+    for (key, _) in &right_fields {
+        if !left_fields.iter().any(|(k, _)| k == key) {
+            differing.push(key.clone());
+        }
+    }
+
+    // Lines that are otherwise alike can still differ in the decoded mnemonic or spacing
+    if differing.is_empty() {
+        differing.push("text".to_string());
+    }
+    differing
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{diff, trace_line, TraceFilter, Tracer};
+    use crate::{
+        cpu::CPU,
+        display::Display,
+        keyboard::dummy_keyboard::DummyKeyboard,
+        memory::Memory,
+        quirks::Quirks,
+        scheduler::{ManualClock, Scheduler},
+    };
+
+    fn get_cpu(program: &[u16]) -> CPU<DummyKeyboard> {
+        let mut memory = Memory::initialise();
+        for (i, op) in program.iter().enumerate() {
+            memory.insert_instruction(0x200 + i * 2, *op);
+        }
+
+        CPU::initialise(
+            memory,
+            Display::initialise(),
+            DummyKeyboard::initialise(),
+            Quirks::default(),
+        )
+    }
+
+    #[test]
+    fn should_format_trace_line() {
+        let mut cpu = get_cpu(&[0x6005]);
+        cpu.v[0xF] = 0xAB;
+        cpu.vi = 0x123;
+        cpu.delay_timer = 2;
+
+        assert_eq!(
+            trace_line(7, &cpu),
+            format!(
+                "frame=7 pc=0200 op=6005 v={}AB i=0123 sp=00 dt=02 st=00 ; LD V0, 0x05",
+                "00".repeat(15)
+            )
+        );
+    }
+
+    #[test]
+    fn should_trace_instructions_that_match_the_filter() {
+        // LD V0, 1; ADD V0, 1; JP 0x202
+        let mut cpu = get_cpu(&[0x6001, 0x7001, 0x1202]);
+        let scheduler = Scheduler::with_clock(ManualClock::default());
+        let mut tracer =
+            Tracer::new(vec![]).filter(TraceFilter::new().pcs(0x202..=0x202).frames(1..=1));
+
+        tracer.run_frame(&scheduler, &mut cpu, 3).unwrap();
+        tracer.run_frame(&scheduler, &mut cpu, 4).unwrap();
+        tracer.run_frame(&scheduler, &mut cpu, 4).unwrap();
+        assert_eq!(tracer.frame(), 3);
+
+        let trace = String::from_utf8(tracer.finish().unwrap()).unwrap();
+        let lines: Vec<&str> = trace.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].starts_with("frame=1 pc=0202 op=7001 v=02"));
+        assert!(lines[1].starts_with("frame=1 pc=0202 op=7001 v=03"));
+    }
+
+    #[test]
+    fn should_not_trace_a_draw_waiting_for_the_next_frame() {
+        // DRW V0, V0, 1 twice, with the display wait quirk
+        let mut cpu = get_cpu(&[0xD001, 0xD001]);
+        cpu.quirks.display_wait = true;
+        let scheduler = Scheduler::with_clock(ManualClock::default());
+        let mut tracer = Tracer::new(vec![]);
+
+        tracer.run_frame(&scheduler, &mut cpu, 10).unwrap();
+        tracer.run_frame(&scheduler, &mut cpu, 1).unwrap();
+
+        let trace = String::from_utf8(tracer.finish().unwrap()).unwrap();
+        let pcs: Vec<&str> = trace.lines().map(|line| &line[8..15]).collect();
+        assert_eq!(pcs, ["pc=0200", "pc=0202"]);
+    }
+
+    #[test]
+    fn should_find_first_divergence() {
+        let line = |pc: &str, v3: &str| {
+            format!(
+                "frame=0 pc={} op=6005 v=000000{}{} i=0000 sp=00 dt=00 st=00 ; LD V0, 0x05",
+                pc,
+                v3,
+                "00".repeat(12)
+            )
+        };
+        let left = format!("{}\n{}\n", line("0200", "00"), line("0202", "01"));
+        let right = format!("{}\n{}\n", line("0200", "00"), line("0204", "02"));
+
+        assert_eq!(diff(&left, &left), None);
+
+        let divergence = diff(&left, &right).unwrap();
+        assert_eq!(divergence.line, 2);
+        assert_eq!(divergence.fields, ["pc", "V3"]);
+
+        let divergence = diff(&left, &line("0200", "00")).unwrap();
+        assert_eq!(divergence.line, 2);
+        assert_eq!(divergence.right, None);
+        assert!(divergence.to_string().contains("> (end of trace)"));
+    }
+}
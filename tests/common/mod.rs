@@ -0,0 +1,70 @@
+//! What the tests driving the debuggers over TCP have in common: the program they debug and an
+//! emulator serving it on its own thread.
+
+use std::{
+    net::{TcpListener, TcpStream},
+    thread,
+    time::Duration,
+};
+
+use chip8_rs::{
+    asm::Assembler,
+    cpu::CPU,
+    keyboard::dummy_keyboard::DummyKeyboard,
+    quirks::Quirks,
+    rom::RomLoader,
+    scheduler::{ManualClock, Scheduler},
+    Chip8,
+};
+
+// 1 0x200 LD V0, 0
+// 2 0x202 ADD V0, 1
+// 3 0x204 LD I, 0x300
+// 4 0x206 LD [I], V0
+// 5 0x208 JP 0x202
+pub const PROGRAM: &str = "LD V0, 0\nloop: ADD V0, 1\nLD I, 0x300\nLD [I], V0\nJP loop";
+
+/// Assembles a program and loads it into a CPU with the default quirks, ready to run it.
+pub fn cpu_from_asm(source: &str) -> CPU<DummyKeyboard> {
+    let program = Assembler::new().assemble(source).unwrap();
+    let rom = RomLoader::new().load_bytes(program.bytes()).unwrap();
+
+    Chip8::from_rom(&rom, DummyKeyboard::initialise(), Quirks::default()).into_cpu()
+}
+
+/// Runs [`PROGRAM`] on its own thread, serving a client with the server `connect` makes until
+/// `update` returns the status it finished with. Returns the thread and the client's end of
+/// the connection.
+pub fn serve<TServer, TStatus>(
+    connect: impl FnOnce(TcpStream) -> TServer + Send + 'static,
+    mut update: impl FnMut(&mut TServer, &mut Scheduler<ManualClock>, &mut CPU<DummyKeyboard>) -> Option<TStatus>
+        + Send
+        + 'static,
+) -> (thread::JoinHandle<TStatus>, TcpStream)
+where
+    TStatus: Send + 'static,
+{
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap();
+    let server = thread::spawn(move || {
+        let mut cpu = cpu_from_asm(PROGRAM);
+        let mut scheduler = Scheduler::with_clock(ManualClock::default());
+
+        let (stream, _) = listener.accept().unwrap();
+        let mut server = connect(stream);
+        loop {
+            scheduler.clock().advance(Duration::from_millis(17));
+            match update(&mut server, &mut scheduler, &mut cpu) {
+                Some(status) => return status,
+                None => thread::sleep(Duration::from_millis(1)),
+            }
+        }
+    });
+
+    let stream = TcpStream::connect(address).unwrap();
+    stream.set_nodelay(true).unwrap();
+    stream
+        .set_read_timeout(Some(Duration::from_secs(10)))
+        .unwrap();
+    (server, stream)
+}
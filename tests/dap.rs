@@ -0,0 +1,128 @@
+//! Drives the debug adapter over TCP with a scripted client, the way an editor would.
+
+mod common;
+
+use std::{
+    io::{BufRead, BufReader, Read, Write},
+    net::TcpStream,
+};
+
+use chip8_rs::{
+    asm::Assembler,
+    dap::{DapServer, SourceMap, Status},
+};
+
+use common::PROGRAM;
+
+struct Client {
+    reader: BufReader<TcpStream>,
+    stream: TcpStream,
+    seq: u32,
+}
+
+impl Client {
+    fn send(&mut self, command: &str, arguments: &str) {
+        self.seq += 1;
+        let message = format!(
+            r#"{{"seq":{},"type":"request","command":"{}","arguments":{}}}"#,
+            self.seq, command, arguments
+        );
+        write!(
+            self.stream,
+            "Content-Length: {}\r\n\r\n{}",
+            message.len(),
+            message
+        )
+        .unwrap();
+    }
+
+    fn receive(&mut self) -> String {
+        let mut length = None;
+        loop {
+            let mut header = String::new();
+            self.reader.read_line(&mut header).unwrap();
+            match header.trim_end().split_once(": ") {
+                Some(("Content-Length", value)) => length = value.parse().ok(),
+                _ if header == "\r\n" => break,
+                _ => panic!("unexpected header {:?}", header),
+            }
+        }
+
+        let mut message = vec![0; length.unwrap()];
+        self.reader.read_exact(&mut message).unwrap();
+        String::from_utf8(message).unwrap()
+    }
+
+    /// Sends a request and returns its response, checking it succeeded.
+    fn request(&mut self, command: &str, arguments: &str) -> String {
+        self.send(command, arguments);
+        let response = self.receive();
+        assert!(
+            response.contains(&format!(r#""command":"{}""#, command)),
+            "{}",
+            response
+        );
+        assert!(response.contains(r#""success":true"#), "{}", response);
+        response
+    }
+}
+
+#[test]
+fn should_debug_a_program_over_tcp() {
+    // Runs until the client disconnects
+    let (server, stream) = common::serve(
+        |stream| {
+            let program = Assembler::new().assemble(PROGRAM).unwrap();
+            DapServer::new(stream, Some(SourceMap::new(&program, "loop.c8asm")))
+        },
+        |server, scheduler, cpu| match server.update(scheduler, cpu).unwrap() {
+            Status::Attached => None,
+            status => Some(status),
+        },
+    );
+    let mut client = Client {
+        reader: BufReader::new(stream.try_clone().unwrap()),
+        stream,
+        seq: 0,
+    };
+
+    // A length too large to add to the header's is skipped rather than overflowing
+    write!(client.stream, "Content-Length: {}\r\n\r\n", usize::MAX).unwrap();
+    assert!(client
+        .request("initialize", r#"{"adapterID":"chip8"}"#)
+        .contains(r#""supportsConfigurationDoneRequest":true"#));
+    assert!(client.receive().contains(r#""event":"initialized""#));
+    client.request("launch", r#"{"stopOnEntry":false}"#);
+
+    let breakpoints = client.request(
+        "setBreakpoints",
+        r#"{"source":{"path":"loop.c8asm"},"breakpoints":[{"line":4,"condition":"V0 == 3"}]}"#,
+    );
+    assert!(breakpoints.contains(r#""verified":true,"line":4"#));
+
+    client.request("configurationDone", "{}");
+    let stopped = client.receive();
+    assert!(stopped.contains(r#""reason":"breakpoint""#), "{}", stopped);
+
+    let registers = client.request("variables", r#"{"variablesReference":1}"#);
+    assert!(registers.contains(r#"{"name":"V0","value":"0x03""#));
+    assert!(registers.contains(r#"{"name":"PC","value":"0x206""#));
+
+    let trace = client.request("stackTrace", r#"{"threadId":1}"#);
+    assert!(trace.contains(r#""line":4"#));
+    assert!(trace.contains(r#""path":"loop.c8asm""#));
+
+    client.request("next", r#"{"threadId":1}"#);
+    assert!(client.receive().contains(r#""reason":"step""#));
+    // The V0 written to 0x300 is base64 for 0x03
+    assert!(client
+        .request("readMemory", r#"{"memoryReference":"0x300","count":1}"#)
+        .contains(r#""data":"Aw==""#));
+
+    client.request("continue", r#"{"threadId":1}"#);
+    client.request("pause", r#"{"threadId":1}"#);
+    assert!(client.receive().contains(r#""reason":"pause""#));
+
+    client.request("disconnect", "{}");
+    assert_eq!(server.join().unwrap(), Status::Detached);
+}
@@ -0,0 +1,107 @@
+//! Drives the GDB stub over TCP with a scripted client, the way GDB would.
+
+mod common;
+
+use std::{
+    io::{Read, Write},
+    net::TcpStream,
+};
+
+use chip8_rs::gdb::{GdbServer, Status};
+
+struct Client {
+    stream: TcpStream,
+}
+
+impl Client {
+    fn send(&mut self, packet: &str) {
+        let checksum = packet.bytes().fold(0u8, |sum, byte| sum.wrapping_add(byte));
+        write!(self.stream, "${}#{:02x}", packet, checksum).unwrap();
+    }
+
+    fn read_byte(&mut self) -> u8 {
+        let mut byte = [0];
+        self.stream.read_exact(&mut byte).unwrap();
+        byte[0]
+    }
+
+    /// Reads a reply, checking its checksum and acknowledging it.
+    fn receive(&mut self) -> String {
+        while self.read_byte() != b'$' {}
+
+        let mut data = vec![];
+        loop {
+            match self.read_byte() {
+                b'#' => break,
+                byte => data.push(byte),
+            }
+        }
+        let checksum = [self.read_byte(), self.read_byte()];
+        let checksum = u8::from_str_radix(std::str::from_utf8(&checksum).unwrap(), 16).unwrap();
+        assert_eq!(
+            checksum,
+            data.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte))
+        );
+        self.stream.write_all(b"+").unwrap();
+
+        String::from_utf8(data).unwrap()
+    }
+
+    fn command(&mut self, packet: &str) -> String {
+        self.send(packet);
+        self.receive()
+    }
+}
+
+#[test]
+fn should_debug_a_program_over_tcp() {
+    // Runs until the client detaches
+    let (server, stream) = common::serve(GdbServer::new, |server, scheduler, cpu| {
+        match server.update(scheduler, cpu).unwrap() {
+            Status::Attached => None,
+            status => Some(status),
+        }
+    });
+    let mut client = Client { stream };
+
+    assert!(client
+        .command("qSupported:multiprocess+;swbreak+")
+        .contains("qXfer:features:read+"));
+    assert_eq!(client.command("?"), "S05");
+    let xml = client.command("qXfer:features:read:target.xml:0,fff");
+    assert!(xml.starts_with("l<?xml"));
+    assert!(xml.contains("name=\"vi\""));
+
+    // All the registers: V0-VF, I, PC (little endian), SP, DT and ST
+    let registers = client.command("g");
+    assert_eq!(registers.len(), 23 * 2);
+    assert_eq!(&registers[32..40], "00000002");
+
+    assert_eq!(client.command("Z0,208,2"), "OK");
+    assert_eq!(client.command("c"), "T05swbreak:;");
+    assert_eq!(client.command("p11"), "0802");
+    assert_eq!(client.command("p0"), "01");
+    assert_eq!(client.command("m300,2"), "0100");
+
+    assert_eq!(client.command("s"), "S05");
+    assert_eq!(client.command("p11"), "0202");
+
+    assert_eq!(client.command("z0,208,2"), "OK");
+    assert_eq!(client.command("Z2,300,1"), "OK");
+    assert_eq!(client.command("c"), "T05watch:300;");
+    assert_eq!(client.command("m300,1"), "02");
+    assert_eq!(client.command("z2,300,1"), "OK");
+
+    assert_eq!(client.command("M300,2:abcd"), "OK");
+    assert_eq!(client.command("m300,2"), "abcd");
+    assert_eq!(client.command("P0=7f"), "OK");
+    assert!(client.command("g").starts_with("7f"));
+
+    // The program runs freely until it is interrupted
+    client.send("c");
+    client.stream.write_all(&[0x03]).unwrap();
+    assert_eq!(client.receive(), "S02");
+
+    assert_eq!(client.command("D"), "OK");
+    assert_eq!(server.join().unwrap(), Status::Detached);
+}
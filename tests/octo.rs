@@ -0,0 +1,32 @@
+//! Compiles every Octo program in `tests/octo` and checks the ROM against the `.hex` file next
+//! to it, which holds the expected bytes.
+
+use std::{fs, path::Path};
+
+use chip8_rs::asm::octo::Compiler;
+
+#[test]
+fn should_compile_corpus_to_known_bytes() {
+    let corpus = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/octo");
+    let mut sources: Vec<_> = fs::read_dir(&corpus)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|extension| extension == "8o"))
+        .collect();
+    sources.sort();
+    assert!(!sources.is_empty(), "no programs in {}", corpus.display());
+
+    for source in sources {
+        let expected: Vec<u8> = fs::read_to_string(source.with_extension("hex"))
+            .unwrap()
+            .split_whitespace()
+            .map(|byte| u8::from_str_radix(byte, 16).unwrap())
+            .collect();
+
+        let program = Compiler::new()
+            .compile_file(&source)
+            .unwrap_or_else(|e| panic!("{}", e));
+
+        assert_eq!(program.bytes(), &expected[..], "{}", source.display());
+    }
+}